@@ -1,5 +1,6 @@
 use crate::{
     audio::{AudioSourceFormat, AudioSourceID},
+    filter::TrackFilter,
     widgets::arrangement::*,
     AudioBlock, AudioBlockID,
 };
@@ -9,14 +10,25 @@ use std::{collections::HashMap, ops::Range, sync::Arc};
 #[derive(Clone, Data, Lens)]
 pub struct Arrangement {
     pub tracks: Arc<Vec<Track>>,
+    pub tempo_changes: Arc<Vec<TempoChange>>,
+    pub markers: Arc<Vec<Marker>>,
     pub beats: usize,
+    /// How far every odd subdivision of the grid is pushed back, from 0, straight, to
+    /// [`MAX_SWING`], a triplet feel.
+    pub swing: f64,
 }
 
+/// Swing that puts the odd subdivisions on the last note of a triplet.
+pub const MAX_SWING: f64 = 0.66;
+
 impl Arrangement {
     pub fn new() -> Self {
         Self {
             tracks: Arc::new(vec![Track::new()]),
+            tempo_changes: Arc::new(Vec::new()),
+            markers: Arc::new(Vec::new()),
             beats: 4,
+            swing: 0.0,
         }
     }
 
@@ -28,33 +40,306 @@ impl Arrangement {
         Arc::make_mut(&mut self.tracks).remove(idx);
     }
 
+    /// Clears every track, see [`Track::clear`]. Returns false if there was nothing to clear.
+    pub fn clear(&mut self) -> bool {
+        if self.tracks.iter().all(Track::is_empty) {
+            return false;
+        }
+
+        for track in Arc::make_mut(&mut self.tracks) {
+            track.clear();
+        }
+
+        true
+    }
+
+    /// Moves the block at `block_index` to the track at `target`, keeping its bounds. Returns its
+    /// index on the new track, or `None` if it doesn't fit there.
+    pub fn move_block_to_track(
+        &mut self,
+        track_index: usize,
+        block_index: usize,
+        target: usize,
+        overlap: usize,
+    ) -> Option<usize> {
+        let block = self
+            .tracks
+            .get(track_index)?
+            .blocks
+            .get(block_index)?
+            .clone();
+
+        // try it on a copy first, so nothing is removed if the block doesn't fit
+        let mut target_track = self.tracks.get(target)?.clone();
+        let index = target_track.add_block(block, overlap)?;
+
+        let tracks = Arc::make_mut(&mut self.tracks);
+        tracks[target] = target_track;
+        tracks[track_index].blocks.remove(block_index);
+        tracks[track_index].calculate_beats();
+
+        Some(index)
+    }
+
+    /// Whether any block plays `audio_block_id`, the blocks of frozen tracks included.
+    pub fn uses_audio_block(&self, audio_block_id: AudioBlockID) -> bool {
+        self.tracks.iter().any(|track| {
+            track
+                .blocks
+                .iter()
+                .chain(track.frozen.iter().flatten())
+                .any(|block| block.audio_block_id == audio_block_id)
+        })
+    }
+
+    /// Removes every block whose audio block isn't in `audio_blocks`.
+    pub fn retain_audio_blocks(&mut self, audio_blocks: &HashMap<AudioBlockID, AudioBlock>) {
+        let exists = |block: &Block| audio_blocks.contains_key(&block.audio_block_id);
+
+        // make_mut would copy the tracks even if nothing is removed
+        if self.tracks.iter().all(|track| {
+            track.blocks.iter().all(exists) && track.frozen.iter().flatten().all(exists)
+        }) {
+            return;
+        }
+
+        for track in Arc::make_mut(&mut self.tracks) {
+            track.blocks.retain(exists);
+
+            if let Some(frozen) = &mut track.frozen {
+                frozen.retain(exists);
+            }
+
+            track.calculate_beats();
+        }
+    }
+
     pub fn remove_audio_block(&mut self, audio_block_id: AudioBlockID) {
         for track in Arc::make_mut(&mut self.tracks) {
             track.remove_by_audio_block_id(audio_block_id);
         }
     }
 
+    /// Returns the beat after the last block of any track.
+    pub fn end_beat(&self) -> usize {
+        self.tracks
+            .iter()
+            .filter_map(|track| track.blocks.last())
+            .map(|block| block.bounds.end)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Adds a tempo change at `beat`, replacing any existing change on that beat.
+    /// Returns the index of the change.
+    pub fn add_tempo_change(&mut self, beat: usize, beats_per_minute: f64) -> usize {
+        let tempo_changes = Arc::make_mut(&mut self.tempo_changes);
+
+        match tempo_changes.binary_search_by_key(&beat, |change| change.beat) {
+            Ok(index) => {
+                tempo_changes[index].beats_per_minute = beats_per_minute;
+                index
+            }
+            Err(index) => {
+                tempo_changes.insert(index, TempoChange::new(beat, beats_per_minute));
+                index
+            }
+        }
+    }
+
+    pub fn remove_tempo_change(&mut self, index: usize) {
+        Arc::make_mut(&mut self.tempo_changes).remove(index);
+    }
+
+    /// Moves a tempo change to `target`, as long as it doesn't pass any of its neighbours.
+    pub fn move_tempo_change(&mut self, index: usize, target: usize) -> bool {
+        let prev = index.checked_sub(1).map(|i| self.tempo_changes[i].beat);
+        let next = self.tempo_changes.get(index + 1).map(|change| change.beat);
+
+        if prev.is_none_or(|prev| target > prev) && next.is_none_or(|next| target < next) {
+            Arc::make_mut(&mut self.tempo_changes)[index].beat = target;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn get_tempo_change(&self, beat: usize) -> Option<usize> {
+        self.tempo_changes
+            .binary_search_by_key(&beat, |change| change.beat)
+            .ok()
+    }
+
+    pub fn tempo_map(&self) -> TempoMap {
+        TempoMap {
+            changes: self
+                .tempo_changes
+                .iter()
+                .map(|change| (change.beat, change.beats_per_minute / 60.0))
+                .collect(),
+        }
+    }
+
+    /// Adds a marker at `beat`, keeping the markers sorted by beat.
+    /// Returns the index of the marker.
+    pub fn add_marker(&mut self, beat: usize, label: String) -> usize {
+        let markers = Arc::make_mut(&mut self.markers);
+        let index = markers
+            .iter()
+            .position(|marker| marker.beat > beat)
+            .unwrap_or(markers.len());
+
+        markers.insert(index, Marker { beat, label });
+
+        index
+    }
+
+    pub fn remove_marker(&mut self, index: usize) {
+        Arc::make_mut(&mut self.markers).remove(index);
+    }
+
     pub fn compile_index(
         &self,
         audio_blocks: &HashMap<AudioBlockID, AudioBlock>,
     ) -> ArrangementAudioSourceIndex {
-        let mut arrangement_index = ArrangementAudioSourceIndex::default();
+        let mut arrangement_index = ArrangementAudioSourceIndex {
+            tempo: self.tempo_map(),
+            beats_per_bar: self.beats,
+            end_beat: self.end_beat(),
+            ..Default::default()
+        };
 
-        for track in &*self.tracks {
-            track.compile_index(&mut arrangement_index, audio_blocks);
+        for (track_index, track) in self.tracks.iter().enumerate() {
+            track.compile_index(track_index, &mut arrangement_index, audio_blocks);
+            arrangement_index.volume.push(track.volume.clone());
+            arrangement_index.filters.push(track.filter);
+            arrangement_index.sends.push(track.delay_send);
         }
 
         arrangement_index
     }
+
+    /// Compiles only the track at `track_index`, for [`ArrangementAudioSourceIndex::replace_track`]
+    /// when nothing else changed.
+    pub fn compile_track_index(
+        &self,
+        track_index: usize,
+        audio_blocks: &HashMap<AudioBlockID, AudioBlock>,
+    ) -> TrackAudioSourceIndex {
+        let track = &self.tracks[track_index];
+        let mut arrangement_index = ArrangementAudioSourceIndex::default();
+        track.compile_index(track_index, &mut arrangement_index, audio_blocks);
+
+        TrackAudioSourceIndex {
+            track_index,
+            beats: arrangement_index.beats,
+            volume: track.volume.clone(),
+            filter: track.filter,
+            send: track.delay_send,
+            end_beat: self.end_beat(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Data, PartialEq)]
+pub struct TempoChange {
+    pub beat: usize,
+    pub beats_per_minute: f64,
+}
+
+impl TempoChange {
+    pub fn new(beat: usize, beats_per_minute: f64) -> Self {
+        Self {
+            beat,
+            beats_per_minute,
+        }
+    }
+}
+
+/// A named position in the arrangement, used for navigation.
+#[derive(Clone, Debug, Data, Lens, PartialEq)]
+pub struct Marker {
+    pub beat: usize,
+    pub label: String,
+}
+
+/// Describes how the tempo changes along the arrangement, used for converting between time and
+/// beats.
+///
+/// The tempo is piecewise constant, every change lasts until the next one. Before the first change
+/// the `base` tempo passed to each method is used.
+#[derive(Clone, Debug, Default)]
+pub struct TempoMap {
+    /// Pairs of beat and beats per second, sorted by beat.
+    pub changes: Vec<(usize, f64)>,
+}
+
+impl TempoMap {
+    pub fn beats_per_second(&self, beat: usize, base: f64) -> f64 {
+        self.changes
+            .iter()
+            .take_while(|(change_beat, _)| *change_beat <= beat)
+            .last()
+            .map_or(base, |(_, beats_per_second)| *beats_per_second)
+    }
+
+    /// Converts a time in seconds to a position in beats.
+    pub fn beat_at_time(&self, time: f64, base: f64) -> f64 {
+        let mut beat = 0.0;
+        let mut change_time = 0.0;
+        let mut beats_per_second = base;
+
+        for (change_beat, change_beats_per_second) in &self.changes {
+            let next_time = change_time + (*change_beat as f64 - beat) / beats_per_second;
+
+            if next_time > time {
+                break;
+            }
+
+            beat = *change_beat as f64;
+            change_time = next_time;
+            beats_per_second = *change_beats_per_second;
+        }
+
+        beat + (time - change_time) * beats_per_second
+    }
+
+    /// Converts a position in beats to a time in seconds.
+    pub fn time_at_beat(&self, beat: f64, base: f64) -> f64 {
+        let mut change_beat = 0.0;
+        let mut time = 0.0;
+        let mut beats_per_second = base;
+
+        for (next_beat, next_beats_per_second) in &self.changes {
+            let next_beat = *next_beat as f64;
+
+            if next_beat > beat {
+                break;
+            }
+
+            time += (next_beat - change_beat) / beats_per_second;
+            change_beat = next_beat;
+            beats_per_second = *next_beats_per_second;
+        }
+
+        time + (beat - change_beat) / beats_per_second
+    }
 }
 
 // A battle was fought here, it was long, it was tough, but in the end, the world was better for
 // it.
 //      -Hjalte Nannestad, during the rewrite of the track struct of October 2020.
-#[derive(Clone, Default)]
+#[derive(Clone, Default, PartialEq)]
 pub struct Track {
     pub beats: HashMap<usize, usize>,
     pub blocks: Vec<Block>,
+    /// The blocks from before the track was bounced, restored when it's unfrozen.
+    pub frozen: Option<Vec<Block>>,
+    /// Gain of the track over the arrangement, the blocks play at full volume without points.
+    pub volume: Automation,
+    pub filter: Option<TrackFilter>,
+    /// How much of the track goes to the delay, from 0 to 1.
+    pub delay_send: f32,
 }
 
 impl Track {
@@ -64,21 +349,74 @@ impl Track {
 
     pub fn remove_by_audio_block_id(&mut self, block_id: AudioBlockID) {
         self.blocks.retain(|block| block.audio_block_id != block_id);
+
+        if let Some(frozen) = &mut self.frozen {
+            frozen.retain(|block| block.audio_block_id != block_id);
+        }
+
+        self.calculate_beats();
+    }
+
+    /// Whether the track has no blocks, frozen ones included.
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty() && self.frozen.is_none()
+    }
+
+    /// Removes every block, frozen ones included, keeping the settings of the track. Returns
+    /// false if it was already empty.
+    pub fn clear(&mut self) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+
+        self.blocks.clear();
+        self.frozen = None;
+        self.calculate_beats();
+
+        true
+    }
+
+    /// The beats from the start of the first block to the end of the one that ends last.
+    pub fn span(&self) -> Option<Range<usize>> {
+        let end = self.blocks.iter().map(|block| block.bounds.end).max()?;
+
+        Some(self.blocks.first()?.bounds.start..end)
+    }
+
+    /// Replaces the blocks with `block`, a bounce of them, keeping the old ones for
+    /// [`Track::unfreeze`].
+    pub fn freeze(&mut self, block: Block) {
+        let blocks = std::mem::replace(&mut self.blocks, vec![block]);
+
+        self.frozen = Some(blocks);
         self.calculate_beats();
     }
 
+    /// Puts back the blocks from before [`Track::freeze`], returns the blocks of the bounce they
+    /// replace, `None` if the track wasn't frozen.
+    pub fn unfreeze(&mut self) -> Option<Vec<Block>> {
+        let blocks = self.frozen.take()?;
+        let bounce = std::mem::replace(&mut self.blocks, blocks);
+
+        self.calculate_beats();
+
+        Some(bounce)
+    }
+
     pub fn get_selection(&self, beat: usize) -> Option<Selection> {
-        let selected = self.beats.get(&beat);
-        let prev_selected = if beat > 0 {
-            self.beats.get(&(beat - 1))
-        } else {
-            None
-        };
-        if selected.is_some() && self.blocks[*selected.unwrap()].bounds.start == beat {
-            Some(Selection::Some(beat, *selected.unwrap()))
-        } else if prev_selected.is_some() && self.blocks[*prev_selected.unwrap()].bounds.end == beat
+        // check the blocks directly, overlapping blocks share beats in `self.beats`
+        if let Some(index) = self
+            .blocks
+            .iter()
+            .position(|block| block.bounds.start == beat)
         {
-            Some(Selection::Some(beat, *prev_selected.unwrap()))
+            Some(Selection::Some(beat, index))
+        } else if let Some(index) = self
+            .blocks
+            .iter()
+            .position(|block| block.bounds.end == beat)
+        {
+            Some(Selection::Some(beat, index))
         } else {
             Some(Selection::None(beat))
         }
@@ -99,9 +437,13 @@ impl Track {
     }
 
     pub fn get_block(&self, beat: usize) -> Option<&Block> {
+        self.get_block_index(beat).map(|index| &self.blocks[index])
+    }
+
+    pub fn get_block_index(&self, beat: usize) -> Option<usize> {
         if let Some(beat_index) = self.beats.get(&beat) {
             if beat >= self.blocks[*beat_index].bounds.start {
-                Some(&self.blocks[*beat_index])
+                Some(*beat_index)
             } else {
                 None
             }
@@ -110,17 +452,24 @@ impl Track {
         }
     }
 
-    pub fn get_space(&self, block_index: usize) -> Range<usize> {
+    /// Returns the range the bounds of the block at `block_index` may be moved within, allowing
+    /// it to overlap its neighbours by up to `overlap` beats.
+    pub fn get_space(&self, block_index: usize, overlap: usize) -> Range<usize> {
         let start = if block_index == 0 {
             0
         } else if let Some(block) = self.blocks.get(block_index - 1) {
-            block.bounds.end
+            // never let the start pass the start of the previous block
+            block
+                .bounds
+                .end
+                .saturating_sub(overlap)
+                .max(block.bounds.start + 1)
         } else {
             0
         };
 
         let end = if let Some(block) = self.blocks.get(block_index + 1) {
-            block.bounds.start
+            (block.bounds.start + overlap).min(block.bounds.end - 1)
         } else {
             usize::MAX
         };
@@ -128,8 +477,14 @@ impl Track {
         start..end
     }
 
-    pub fn move_block_bound(&mut self, block_index: usize, bound: usize, target: usize) -> bool {
-        let space = self.get_space(block_index);
+    pub fn move_block_bound(
+        &mut self,
+        block_index: usize,
+        bound: usize,
+        target: usize,
+        overlap: usize,
+    ) -> bool {
+        let space = self.get_space(block_index, overlap);
 
         match bound {
             b if b == self.blocks[block_index].bounds.start => {
@@ -154,29 +509,129 @@ impl Track {
         }
     }
 
-    pub fn add_block(&mut self, block: Block) -> Option<usize> {
-        let start_index = self.beats.get(&block.bounds.start);
-        let end_index = self.beats.get(&block.bounds.end);
+    /// Makes the block at `block_index` long enough to play its audio block, `len_beats` long,
+    /// `loops` times, rounded to a whole beat. Returns false if it already is that long or would
+    /// run into the next block.
+    pub fn set_block_loops(
+        &mut self,
+        block_index: usize,
+        len_beats: usize,
+        loops: f64,
+        overlap: usize,
+    ) -> bool {
+        let bounds = self.blocks[block_index].bounds.clone();
+        let len = (loops * len_beats as f64).round().max(1.0) as usize;
 
-        if start_index == end_index {
-            if let Some(index) = start_index {
-                let index = *index;
+        bounds.len() != len
+            && self.move_block_bound(block_index, bounds.end, bounds.start + len, overlap)
+    }
 
-                if block.bounds.end < self.blocks[index].bounds.start {
-                    self.blocks.insert(index, block);
-                    self.calculate_beats();
+    /// Shifts the block at `block_index` one beat, to the right if `right` is set. Returns false if
+    /// it would run into a neighbour or past the start.
+    pub fn nudge_block(&mut self, block_index: usize, right: bool, overlap: usize) -> bool {
+        let space = self.get_space(block_index, overlap);
+        let bounds = &mut self.blocks[block_index].bounds;
 
-                    Some(index)
-                } else {
-                    None
-                }
-            } else {
-                let index = self.blocks.len();
-                self.blocks.push(block);
-                self.calculate_beats();
+        if right && bounds.end < space.end {
+            *bounds = bounds.start + 1..bounds.end + 1;
+        } else if !right && bounds.start > space.start {
+            *bounds = bounds.start - 1..bounds.end - 1;
+        } else {
+            return false;
+        }
 
-                Some(index)
-            }
+        self.calculate_beats();
+
+        true
+    }
+
+    pub fn remove_block_index(&mut self, block_index: usize) {
+        self.blocks.remove(block_index);
+        self.calculate_beats();
+    }
+
+    /// Puts a copy of the block at `block_index` right after it, returns the index of the copy or
+    /// `None` if there's no room.
+    pub fn duplicate_block(&mut self, block_index: usize, overlap: usize) -> Option<usize> {
+        let mut block = self.blocks[block_index].clone();
+        let len = block.bounds.end - block.bounds.start;
+
+        block.bounds = block.bounds.end..block.bounds.end + len;
+
+        self.add_block(block, overlap)
+    }
+
+    /// Cuts the block at `block_index` in two at `beat`, the offset of the second part is shifted
+    /// so it carries on where the first part stops. Returns false if `beat` isn't inside the block.
+    pub fn split_block(&mut self, block_index: usize, beat: usize) -> bool {
+        let block = &mut self.blocks[block_index];
+
+        if beat <= block.bounds.start || beat >= block.bounds.end {
+            return false;
+        }
+
+        let mut second = block.clone();
+        second.bounds = beat..block.bounds.end;
+        second.offset -= (beat - block.bounds.start) as f32;
+        block.bounds.end = beat;
+
+        self.blocks.insert(block_index + 1, second);
+        self.calculate_beats();
+
+        true
+    }
+
+    /// Snaps the start of the block at `block_index` to the nearest multiple of `division` beats,
+    /// keeping its length. If that would collide with a neighbour, the nearest legal beat is used
+    /// instead.
+    pub fn quantize_block(&mut self, block_index: usize, division: usize, overlap: usize) -> bool {
+        let space = self.get_space(block_index, overlap);
+        let bounds = self.blocks[block_index].bounds.clone();
+        let len = bounds.end - bounds.start;
+
+        let target = (bounds.start + division / 2) / division * division;
+
+        if space.end.saturating_sub(len) < space.start {
+            return false;
+        }
+
+        let start = target.clamp(space.start, space.end - len);
+
+        if start == bounds.start {
+            return false;
+        }
+
+        self.blocks[block_index].bounds = start..start + len;
+        self.calculate_beats();
+
+        true
+    }
+
+    /// Returns the index of the block.
+    pub fn add_block(&mut self, block: Block, overlap: usize) -> Option<usize> {
+        let index = self
+            .blocks
+            .iter()
+            .position(|other| other.bounds.start > block.bounds.start)
+            .unwrap_or(self.blocks.len());
+
+        let prev = index.checked_sub(1).map(|i| &self.blocks[i]);
+        let next = self.blocks.get(index);
+
+        let fits_prev = prev.is_none_or(|prev| {
+            prev.bounds.start < block.bounds.start
+                && prev.bounds.end <= block.bounds.start + overlap
+                && prev.bounds.end < block.bounds.end
+        });
+        let fits_next = next.is_none_or(|next| {
+            block.bounds.end <= next.bounds.start + overlap && block.bounds.end < next.bounds.end
+        });
+
+        if fits_prev && fits_next {
+            self.blocks.insert(index, block);
+            self.calculate_beats();
+
+            Some(index)
         } else {
             None
         }
@@ -193,26 +648,91 @@ impl Track {
         }
     }
 
+    /// Returns the beats where the block at `block_index` overlaps the next block.
+    pub fn get_overlap(&self, block_index: usize) -> Range<usize> {
+        match self.blocks.get(block_index + 1) {
+            Some(next) => {
+                next.bounds.start..self.blocks[block_index].bounds.end.max(next.bounds.start)
+            }
+            None => 0..0,
+        }
+    }
+
+    /// Adds the blocks to `arrangement_index`, as the track at `track_index`. The volume, filter
+    /// and send aren't added, left out the track plays dry, which is what a bounce wants.
     pub fn compile_index(
         &self,
+        track_index: usize,
         arrangement_index: &mut ArrangementAudioSourceIndex,
         audio_blocks: &HashMap<AudioBlockID, AudioBlock>,
     ) {
-        for block in &self.blocks {
+        if arrangement_index.track_beats.len() <= track_index {
+            arrangement_index
+                .track_beats
+                .resize_with(track_index + 1, Vec::new);
+        }
+
+        for (block_index, block) in self.blocks.iter().enumerate() {
             let audio_block = &audio_blocks[&block.audio_block_id];
 
-            for play_cycle in 0..(block.bounds.end - block.bounds.start).saturating_sub(1)
-                / audio_block.len_beats
-                + 1
-            {
-                for relative_beat in 0..audio_block.true_len_beats {
+            let fade_in = if block_index > 0 {
+                self.get_overlap(block_index - 1)
+            } else {
+                0..0
+            };
+            let fade_out = self.get_overlap(block_index);
+            // the second part of a split plays everything from the split on, tails included
+            let split = self
+                .blocks
+                .get(block_index + 1)
+                .is_some_and(|next| next.continues(block));
+
+            // a choked block stops each repeat where the next one starts
+            let cycle_len = if audio_block.choke {
+                audio_block.len_beats.min(audio_block.true_len_beats)
+            } else {
+                audio_block.true_len_beats
+            };
+
+            // the repeats start `lead` beats before the block, the ones that are over by then
+            // are skipped
+            let lead = block.lead();
+            let first_cycle =
+                (lead + audio_block.len_beats).saturating_sub(cycle_len) / audio_block.len_beats;
+            let cycles = (block.bounds.len() + lead).saturating_sub(1) / audio_block.len_beats + 1;
+
+            for play_cycle in first_cycle..cycles {
+                for relative_beat in 0..cycle_len {
                     let cycle_offset = play_cycle * audio_block.len_beats;
 
-                    let beat = block.bounds.start + relative_beat + cycle_offset;
+                    if relative_beat + cycle_offset < lead {
+                        continue;
+                    }
+
+                    let beat = block.bounds.start + relative_beat + cycle_offset - lead;
+
+                    // once faded out, the tail of the block would only muddy the next one
+                    if (!fade_out.is_empty() || split) && beat >= block.bounds.end {
+                        continue;
+                    }
+
+                    let fade = if fade_in.contains(&beat) {
+                        Fade::new_in(&fade_in, beat)
+                    } else if fade_out.contains(&beat) {
+                        Fade::new_out(&fade_out, beat)
+                    } else {
+                        Fade::None
+                    };
 
                     let audio_source_index = AudioSourceIndex {
                         audio_source_id: audio_block.audio_id,
-                        beats_offset: relative_beat as f32 - audio_block.offset,
+                        beats_offset: relative_beat as f32
+                            - audio_block.offset
+                            - (block.offset + lead as f32),
+                        fade,
+                        track_index,
+                        time_stretch: audio_block.time_stretch,
+                        pitch: 2f64.powf(audio_block.pitch_semitones as f64 / 12.0),
                     };
 
                     arrangement_index
@@ -220,9 +740,14 @@ impl Track {
                         .entry(beat)
                         .or_insert(Vec::new())
                         .push(audio_source_index);
+                    arrangement_index.track_beats[track_index].push(beat);
                 }
             }
         }
+
+        let track_beats = &mut arrangement_index.track_beats[track_index];
+        track_beats.sort_unstable();
+        track_beats.dedup();
     }
 }
 
@@ -240,6 +765,9 @@ pub struct Block {
     pub bounds: Range<usize>,
     pub audio_block_id: AudioBlockID,
     pub format: AudioSourceFormat,
+    /// How far the source is shifted into this block only, in beats, on top of the offset of the
+    /// audio block. Lets blocks of the same audio block start at different points of the source.
+    pub offset: f32,
 }
 
 impl Block {
@@ -252,17 +780,382 @@ impl Block {
             bounds,
             audio_block_id,
             format,
+            offset: 0.0,
         }
     }
+
+    /// How many whole beats before the block its source starts, because of a negative offset.
+    pub fn lead(&self) -> usize {
+        (-self.offset).max(0.0) as usize
+    }
+
+    /// Whether the block carries on right where `previous` stops, like the parts of a split.
+    fn continues(&self, previous: &Block) -> bool {
+        self.bounds.start == previous.bounds.end
+            && self.audio_block_id == previous.audio_block_id
+            && self.offset == previous.offset - previous.bounds.len() as f32
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct AudioSourceIndex {
     pub audio_source_id: AudioSourceID,
+    /// Beats from the start of the source to the start of this beat, the block offset is in beats
+    /// too and simply subtracted.
     pub beats_offset: f32,
+    pub fade: Fade,
+    /// Which of the volumes of the index applies.
+    pub track_index: usize,
+    /// Play with [`AudioSource::get_stretched_sample`].
+    ///
+    /// [`AudioSource::get_stretched_sample`]: crate::audio::AudioSource::get_stretched_sample
+    pub time_stretch: bool,
+    /// What the pitch is multiplied by, apart from the tempo.
+    pub pitch: f64,
+}
+
+/// Gain ramp applied to an [`AudioSourceIndex`] over the course of its beat.
+///
+/// `from` and `to` are the progress through the crossfade, from 0 to 1, at the start and end of
+/// the beat.
+#[derive(Clone, Debug)]
+pub enum Fade {
+    None,
+    In { from: f32, to: f32 },
+    Out { from: f32, to: f32 },
+}
+
+impl Fade {
+    fn progress(overlap: &Range<usize>, beat: usize) -> (f32, f32) {
+        let len = overlap.len() as f32;
+        let relative_beat = (beat - overlap.start) as f32;
+
+        (relative_beat / len, (relative_beat + 1.0) / len)
+    }
+
+    pub fn new_in(overlap: &Range<usize>, beat: usize) -> Self {
+        let (from, to) = Self::progress(overlap, beat);
+        Fade::In { from, to }
+    }
+
+    pub fn new_out(overlap: &Range<usize>, beat: usize) -> Self {
+        let (from, to) = Self::progress(overlap, beat);
+        Fade::Out { from, to }
+    }
+
+    /// Equal-power gain at `beat_fraction` through the beat.
+    pub fn gain(&self, beat_fraction: f32) -> f32 {
+        match self {
+            Fade::None => 1.0,
+            Fade::In { from, to } => {
+                ((from + (to - from) * beat_fraction) * std::f32::consts::FRAC_PI_2).sin()
+            }
+            Fade::Out { from, to } => {
+                ((from + (to - from) * beat_fraction) * std::f32::consts::FRAC_PI_2).cos()
+            }
+        }
+    }
+}
+
+/// A point of an [`Automation`], `value` is reached at `beat`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AutomationPoint {
+    pub beat: f64,
+    pub value: f32,
+}
+
+/// A value changing over the arrangement, linear between the points and held before the first
+/// and after the last.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Automation {
+    /// Sorted by beat.
+    pub points: Vec<AutomationPoint>,
+}
+
+impl Automation {
+    /// The value at `beat`, or `default` if there are no points.
+    pub fn value_at(&self, beat: f64, default: f32) -> f32 {
+        let next = self.points.partition_point(|point| point.beat <= beat);
+
+        match (
+            next.checked_sub(1).map(|i| self.points[i]),
+            self.points.get(next),
+        ) {
+            (Some(prev), Some(next)) => {
+                let t = ((beat - prev.beat) / (next.beat - prev.beat)) as f32;
+
+                prev.value + (next.value - prev.value) * t
+            }
+            (Some(point), None) | (None, Some(&point)) => point.value,
+            (None, None) => default,
+        }
+    }
+
+    /// Returns the index of the new point.
+    pub fn add_point(&mut self, beat: f64, value: f32) -> usize {
+        let index = self.points.partition_point(|point| point.beat <= beat);
+        self.points.insert(index, AutomationPoint { beat, value });
+
+        index
+    }
+
+    /// Moves the point at `index`, it stays between its neighbours so the order is kept.
+    pub fn move_point(&mut self, index: usize, beat: f64, value: f32) {
+        let min = index.checked_sub(1).map_or(0.0, |i| self.points[i].beat);
+        let max = self
+            .points
+            .get(index + 1)
+            .map_or(f64::INFINITY, |point| point.beat);
+
+        self.points[index] = AutomationPoint {
+            beat: beat.clamp(min, max),
+            value,
+        };
+    }
+
+    pub fn remove_point(&mut self, index: usize) {
+        self.points.remove(index);
+    }
 }
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct ArrangementAudioSourceIndex {
     pub beats: HashMap<usize, Vec<AudioSourceIndex>>,
+    /// The volume of every track, by the `track_index` of the sources.
+    pub volume: Vec<Automation>,
+    /// The filter of every track, like `volume`.
+    pub filters: Vec<Option<TrackFilter>>,
+    /// The delay send of every track, like `volume`.
+    pub sends: Vec<f32>,
+    pub tempo: TempoMap,
+    /// Used by the metronome to accent the first beat of every bar.
+    pub beats_per_bar: usize,
+    /// Where the last block ends, playback can stop there.
+    pub end_beat: usize,
+    /// The beats every track has sources on, so a track can be replaced without going through
+    /// every beat.
+    pub track_beats: Vec<Vec<usize>>,
+}
+
+impl ArrangementAudioSourceIndex {
+    /// Swaps the sources and settings of one track for `track`. Ends up the same as compiling the
+    /// whole arrangement again, the sources of every beat stay in track order.
+    pub fn replace_track(&mut self, track: TrackAudioSourceIndex) {
+        let track_index = track.track_index;
+
+        if self.track_beats.len() <= track_index {
+            self.track_beats.resize_with(track_index + 1, Vec::new);
+        }
+
+        for beat in self.track_beats[track_index].drain(..) {
+            if let Some(sources) = self.beats.get_mut(&beat) {
+                sources.retain(|source| source.track_index != track_index);
+
+                if sources.is_empty() {
+                    self.beats.remove(&beat);
+                }
+            }
+        }
+
+        let mut track_beats = Vec::with_capacity(track.beats.len());
+
+        for (beat, sources) in track.beats {
+            let beat_sources = self.beats.entry(beat).or_default();
+            beat_sources.extend(sources);
+            // stable, the blocks of the track keep their order
+            beat_sources.sort_by_key(|source| source.track_index);
+
+            track_beats.push(beat);
+        }
+
+        track_beats.sort_unstable();
+        self.track_beats[track_index] = track_beats;
+
+        if self.volume.len() <= track_index {
+            self.volume.resize_with(track_index + 1, Default::default);
+            self.filters.resize(track_index + 1, None);
+            self.sends.resize(track_index + 1, 0.0);
+        }
+
+        self.volume[track_index] = track.volume;
+        self.filters[track_index] = track.filter;
+        self.sends[track_index] = track.send;
+        self.end_beat = track.end_beat;
+    }
+}
+
+/// Everything an [`ArrangementAudioSourceIndex`] holds for one track.
+pub struct TrackAudioSourceIndex {
+    pub track_index: usize,
+    pub beats: HashMap<usize, Vec<AudioSourceIndex>>,
+    pub volume: Automation,
+    pub filter: Option<TrackFilter>,
+    pub send: f32,
+    /// The end of the whole arrangement, it moves with the blocks of the track.
+    pub end_beat: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format() -> AudioSourceFormat {
+        AudioSourceFormat {
+            sample_rate: 1000,
+            len_frames: 2000,
+            channels: 1,
+            beats_per_second: 2.0,
+        }
+    }
+
+    fn block(bounds: Range<usize>, audio_block_id: usize) -> Block {
+        Block::new(bounds, AudioBlockID(audio_block_id), format())
+    }
+
+    #[test]
+    fn span_ends_with_the_block_that_ends_last() {
+        let mut track = Track::new();
+        assert_eq!(track.span(), None);
+
+        track.blocks = vec![block(2..10, 0), block(4..6, 1)];
+        track.calculate_beats();
+
+        assert_eq!(track.span(), Some(2..10));
+    }
+
+    #[test]
+    fn unfreeze_hands_back_the_bounce() {
+        let mut track = Track::new();
+        track.blocks = vec![block(0..4, 0), block(4..8, 1)];
+        track.calculate_beats();
+
+        track.freeze(block(0..8, 2));
+        assert!(track.frozen.is_some());
+
+        let bounce = track.unfreeze().unwrap();
+        assert_eq!(bounce.len(), 1);
+        assert_eq!(bounce[0].audio_block_id, AudioBlockID(2));
+        assert_eq!(track.blocks.len(), 2);
+        assert!(track.unfreeze().is_none());
+    }
+
+    #[test]
+    fn offsets_shift_the_source_in_beats() {
+        // two seconds at two beats a second
+        let mut audio_block = AudioBlock::new(AudioSourceID(7), format(), 2.0);
+        audio_block.offset = 1.5;
+        let audio_blocks = std::iter::once((AudioBlockID(0), audio_block)).collect();
+
+        let mut track = Track::new();
+        let mut shifted = block(8..12, 0);
+        shifted.offset = 0.25;
+        track.blocks = vec![shifted];
+        track.calculate_beats();
+
+        let mut index = ArrangementAudioSourceIndex::default();
+        track.compile_index(0, &mut index, &audio_blocks);
+
+        assert_eq!(index.track_beats[0], vec![8, 9, 10, 11]);
+
+        for beat in 8..12 {
+            let sources = &index.beats[&beat];
+
+            assert_eq!(sources.len(), 1);
+            assert_eq!(sources[0].audio_source_id, AudioSourceID(7));
+            assert_eq!(sources[0].beats_offset, (beat - 8) as f32 - 1.75);
+        }
+    }
+
+    #[test]
+    fn split_blocks_play_like_the_whole_block() {
+        // repeats every four beats, but rings on for two more
+        let mut audio_block = AudioBlock::new(AudioSourceID(0), format(), 2.0);
+        audio_block.true_len_beats = 6;
+        let audio_blocks: HashMap<_, _> = std::iter::once((AudioBlockID(0), audio_block)).collect();
+
+        let compile = |splits: &[usize]| {
+            let mut track = Track::new();
+            track.blocks = vec![block(0..8, 0)];
+            track.calculate_beats();
+
+            for beat in splits {
+                assert!(track.split_block(track.blocks.len() - 1, *beat));
+            }
+
+            let mut index = ArrangementAudioSourceIndex::default();
+            track.compile_index(0, &mut index, &audio_blocks);
+
+            summary(&index)
+        };
+
+        let whole = compile(&[]);
+
+        // inside a repeat, between two and in the tail of the first
+        assert_eq!(compile(&[2]), whole);
+        assert_eq!(compile(&[4]), whole);
+        assert_eq!(compile(&[2, 5, 7]), whole);
+    }
+
+    /// Everything the engine plays from, in an order that doesn't depend on how it was built.
+    fn summary(index: &ArrangementAudioSourceIndex) -> String {
+        let mut beats: Vec<_> = index.beats.iter().collect();
+        beats.sort_by_key(|(beat, _)| **beat);
+
+        format!(
+            "{:?} {:?} {:?} {:?} {:?} {}",
+            beats, index.track_beats, index.volume, index.filters, index.sends, index.end_beat,
+        )
+    }
+
+    #[test]
+    fn replaced_track_matches_a_full_compile() {
+        let audio_blocks: HashMap<_, _> = (0..2)
+            .map(|id| {
+                let audio_block = AudioBlock::new(AudioSourceID(id), format(), 2.0);
+                (AudioBlockID(id), audio_block)
+            })
+            .collect();
+
+        let mut arrangement = Arrangement::new();
+        arrangement.add_track();
+        arrangement.add_track();
+
+        {
+            let tracks = Arc::make_mut(&mut arrangement.tracks);
+            tracks[0].add_block(block(0..8, 0), 0);
+            tracks[1].add_block(block(2..6, 1), 0);
+            tracks[2].add_block(block(4..12, 0), 0);
+        }
+
+        let mut index = arrangement.compile_index(&audio_blocks);
+
+        // the middle track gets a crossfaded second block, a louder start and a send
+        {
+            let track = &mut Arc::make_mut(&mut arrangement.tracks)[1];
+            track.add_block(block(5..16, 0), 1);
+            track.volume.add_point(0.0, 1.5);
+            track.delay_send = 0.25;
+        }
+
+        assert_ne!(
+            summary(&index),
+            summary(&arrangement.compile_index(&audio_blocks))
+        );
+
+        index.replace_track(arrangement.compile_track_index(1, &audio_blocks));
+
+        assert_eq!(
+            summary(&index),
+            summary(&arrangement.compile_index(&audio_blocks))
+        );
+
+        // and emptying it again
+        Arc::make_mut(&mut arrangement.tracks)[1].clear();
+        index.replace_track(arrangement.compile_track_index(1, &audio_blocks));
+
+        assert_eq!(
+            summary(&index),
+            summary(&arrangement.compile_index(&audio_blocks))
+        );
+    }
 }
@@ -1,93 +1,502 @@
-use crate::{arrangement::*, audio_clip::AudioClip, commands::*};
+use crate::{
+    arrangement::*,
+    audio_clip::AudioClip,
+    commands::*,
+    delay::{Delay, DelaySettings},
+    filter::Biquad,
+    oscillator::{Oscillator, Waveform},
+};
 use cpal::traits::*;
 use druid::Target;
 use log::*;
 use std::{
     any::Any,
+    cell::Cell,
     collections::HashMap,
+    ops::Range,
     sync::{
-        mpsc::{channel, Receiver, Sender},
-        Arc,
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError, TryLockError,
     },
+    time::{Duration, Instant},
 };
 
 #[derive(Clone, Copy, Hash, PartialEq, Eq, Debug, druid::Data)]
 pub struct AudioSourceID(pub usize);
 
+pub const MIN_VOLUME_DB: f64 = -60.0;
+pub const MAX_VOLUME_DB: f64 = 14.0;
+
+/// Converts a linear gain to decibels, clamped to the volume range.
+pub fn linear_to_db(linear: f64) -> f64 {
+    (20.0 * linear.log10()).clamp(MIN_VOLUME_DB, MAX_VOLUME_DB)
+}
+
+/// Converts decibels to a linear gain, the bottom of the volume range is treated as silence.
+pub fn db_to_linear(db: f64) -> f64 {
+    if db <= MIN_VOLUME_DB {
+        0.0
+    } else {
+        10.0_f64.powf(db.min(MAX_VOLUME_DB) / 20.0)
+    }
+}
+
+/// Peak amplitude of the metronome click.
+const METRONOME_VOLUME: f32 = 0.3;
+const METRONOME_CLICK_MS: f32 = 20.0;
+/// The first beat of a bar gets a higher click than the rest.
+const METRONOME_BAR_FREQ: f32 = 1600.0;
+const METRONOME_BEAT_FREQ: f32 = 1000.0;
+
+/// How finely a `BeatClock` divides a beat, per sample of the sample rate.
+const BEAT_CLOCK_UNITS: f64 = 1_000_000.0;
+
+/// Counts beats frame by frame with an integer accumulator, the remainder of every beat is carried
+/// into the next, so a beat starts on the same frame no matter the sample rate or tempo, and
+/// rounding never adds up over time.
+struct BeatClock {
+    beat: u64,
+    /// How far into the beat the clock is, a beat is `sample_rate * BEAT_CLOCK_UNITS`.
+    accumulator: u64,
+}
+
+impl BeatClock {
+    fn at(position: f64, sample_rate: u32) -> Self {
+        let position = position.max(0.0);
+
+        Self {
+            beat: position.floor() as u64,
+            accumulator: (position.fract() * sample_rate as f64 * BEAT_CLOCK_UNITS) as u64,
+        }
+    }
+
+    fn step(beats_per_second: f64) -> u64 {
+        (beats_per_second * BEAT_CLOCK_UNITS).round() as u64
+    }
+
+    /// Returns the beat if the clock is on its first frame.
+    fn on_beat(&self, beats_per_second: f64) -> Option<u64> {
+        if self.accumulator < Self::step(beats_per_second) {
+            Some(self.beat)
+        } else {
+            None
+        }
+    }
+
+    /// Moves one frame ahead, returns the beat if a new one starts on that frame.
+    fn advance(&mut self, beats_per_second: f64, sample_rate: u32) -> Option<u64> {
+        let beat_len = sample_rate as u64 * BEAT_CLOCK_UNITS as u64;
+
+        self.accumulator += Self::step(beats_per_second);
+
+        if self.accumulator >= beat_len {
+            self.accumulator -= beat_len;
+            self.beat += 1;
+
+            Some(self.beat)
+        } else {
+            None
+        }
+    }
+}
+
+/// How often the load of the output callback is reported, with the highest load since the last
+/// report so short spikes show up.
+const LOAD_REPORT_MS: f32 = 250.0;
+
+/// How long a `SmoothedValue` takes to get most of the way to a new target, fast enough to feel
+/// immediate.
+const SMOOTHING_MS: f32 = 5.0;
+
+/// A gain that follows its target through a one pole lowpass, jumping straight to a new value
+/// steps the signal, and dragging a slider makes a string of steps heard as zipper noise.
+struct SmoothedValue {
+    value: f32,
+    /// How much of the way to the target one step goes.
+    coefficient: f32,
+}
+
+impl SmoothedValue {
+    /// Starts at `value`, stepped `steps_per_second` times.
+    fn new(value: f32, steps_per_second: u32) -> Self {
+        let steps = SMOOTHING_MS / 1000.0 * steps_per_second as f32;
+
+        Self {
+            value,
+            coefficient: 1.0 - (-1.0 / steps.max(1.0)).exp(),
+        }
+    }
+
+    /// Moves one step towards `target`, returns the new value.
+    fn next(&mut self, target: f32) -> f32 {
+        self.value += (target - self.value) * self.coefficient;
+
+        self.value
+    }
+}
+
+/// The sample for `channel` out of `channels` from a `frame` with its own channel count. Mono is
+/// copied to every channel and mixing down to mono averages, otherwise missing channels repeat the
+/// ones there are.
+pub fn remix_sample(frame: &[f32], channel: usize, channels: usize) -> f32 {
+    if channels == 1 && frame.len() > 1 {
+        frame.iter().sum::<f32>() / frame.len() as f32
+    } else {
+        frame[channel % frame.len()]
+    }
+}
+
+thread_local! {
+    static OUTPUT_THREAD: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether this is the thread of the output callback, where a source must not wait on a lock or
+/// read a file. Renders run on threads of their own and can.
+pub fn is_output_thread() -> bool {
+    OUTPUT_THREAD.with(Cell::get)
+}
+
+/// Marks this thread as the one of the output callback.
+pub(crate) fn set_output_thread() {
+    OUTPUT_THREAD.with(|output| output.set(true));
+}
+
+/// A short decaying sine burst, one frame per sample.
+fn metronome_click(sample_rate: u32, freq: f32) -> Vec<f32> {
+    let len = (METRONOME_CLICK_MS / 1000.0 * sample_rate as f32) as usize;
+
+    (0..len)
+        .map(|frame| {
+            let time = frame as f32 / sample_rate as f32;
+            // decays to about 1% by the end, so the cut is inaudible
+            let envelope = (-4.6 * frame as f32 / len as f32).exp();
+
+            (time * freq * 2.0 * std::f32::consts::PI).sin() * envelope * METRONOME_VOLUME
+        })
+        .collect()
+}
+
 pub enum Command {
     SetPlaying(bool),
     SetRecording(bool),
     SetPlayTime(f64),
     SetFeedback(bool),
+    SetStopAtEnd(bool),
     SetBeatsPerSecond(f64),
     SetVolume(f64),
+    SetDelay(DelaySettings),
+    AddOscillator(Waveform, f32),
+    SetAudioSource(AudioSourceID, Arc<dyn AudioSource + Send + Sync>),
+    AddAudioSource(Arc<dyn AudioSource + Send + Sync>),
+    /// Moves the sources into a map with more room, so adding one doesn't allocate.
+    GrowAudioSources(AudioSources),
+    /// Fills the map with every source, it has to have room for all of them.
+    DownloadAudioSources(AudioSources),
     RemoveAudioSource(AudioSourceID),
     GetAudioSourceClone(AudioSourceID),
     SetArrangementAudioSourceIndex(ArrangementAudioSourceIndex),
 }
 
+/// A recording as the engine hands it over, before it's cleaned up and added to the sources.
+pub struct RecordedClip {
+    clip: AudioClip,
+    /// See [`Recording::start_time`].
+    start_time: f64,
+}
+
+/// A finished recording, added to the sources of the engine.
+pub struct Recording {
+    pub id: AudioSourceID,
+    pub format: AudioSourceFormat,
+    /// The play time, in seconds, the first frame of the clip was played along with. That's a
+    /// round trip earlier than it came in, through the output, the air and back through the input.
+    pub start_time: f64,
+}
+
 pub enum CommandResponse {
-    SetRecording(Option<(AudioSourceID, AudioSourceFormat)>),
-    GetAudioSourceClone(Arc<dyn AudioSource + Send + Sync>),
+    SetRecording(Option<RecordedClip>),
+    AddOscillator(AudioSourceID, AudioSourceFormat),
+    /// The id of the source, or how many sources there are if there was no room for it.
+    AddAudioSource(Result<AudioSourceID, usize>),
+    /// The filled map, or how many sources there are if they didn't fit.
+    DownloadAudioSources(Result<AudioSources, usize>),
+    GetAudioSourceClone(Option<Arc<dyn AudioSource + Send + Sync>>),
 }
 
+/// Identifies a command sent to the engine, responses carry the id of the command they answer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RequestID(pub usize);
+
+/// How long to wait for the engine to answer before giving up, the engine reads its commands
+/// every callback so this is very generous.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often to look for a response while waiting for one.
+const RESPONSE_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// How many commands can wait for the engine, it reads all of them every callback.
+const COMMAND_QUEUE_LEN: usize = 1024;
+
+/// How long the engine waits before trying to start again, while there is no device it can use.
+const RESTART_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the engine thread drops what the output callback is done with.
+const COLLECT_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+pub enum AudioError {
+    /// The queues to the engine can't be used anymore.
+    Disconnected,
+    /// The engine didn't answer within [`RESPONSE_TIMEOUT`].
+    Timeout,
+    /// The engine answered with a response for a different kind of command.
+    WrongResponse,
+    /// The engine has fallen behind reading commands, the command was dropped.
+    QueueFull,
+    /// There is no source with the requested id.
+    NoSuchSource,
+    NoInputDevice,
+    NoOutputDevice,
+}
+
+impl std::fmt::Display for AudioError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AudioError::Disconnected => write!(f, "audio engine disconnected"),
+            AudioError::Timeout => write!(f, "audio engine didn't respond"),
+            AudioError::WrongResponse => write!(f, "audio engine sent the wrong response"),
+            AudioError::QueueFull => write!(f, "audio engine command queue is full"),
+            AudioError::NoSuchSource => write!(f, "audio source not found"),
+            AudioError::NoInputDevice => write!(f, "no input device found"),
+            AudioError::NoOutputDevice => write!(f, "no output device found"),
+        }
+    }
+}
+
+impl std::error::Error for AudioError {}
+
 #[derive(Clone, druid::Data)]
 pub struct AudioEngineHandle {
-    sender: std::sync::Arc<Sender<Command>>,
-    receiver: std::sync::Arc<Receiver<CommandResponse>>,
+    /// Lock free towards the engine, so the output callback never waits on the ui. The mutexes
+    /// are only there because the handle is cloned and the queues have a single end on each side.
+    sender: std::sync::Arc<Mutex<ringbuf::Producer<(RequestID, Command)>>>,
+    receiver: std::sync::Arc<Mutex<ringbuf::Consumer<(RequestID, CommandResponse)>>>,
+    next_request_id: std::sync::Arc<AtomicUsize>,
+    output_format: std::sync::Arc<OutputFormat>,
+    /// The last index sent to the engine.
+    arrangement_index: std::sync::Arc<Mutex<ArrangementAudioSourceIndex>>,
 }
 
 impl AudioEngineHandle {
+    fn send(&self, cmd: Command) -> Result<RequestID, AudioError> {
+        let request_id = RequestID(self.next_request_id.fetch_add(1, Ordering::Relaxed));
+
+        self.sender
+            .lock()
+            .map_err(|_| AudioError::Disconnected)?
+            .push((request_id, cmd))
+            .map_err(|_| AudioError::QueueFull)?;
+
+        Ok(request_id)
+    }
+
+    /// Sends a command that doesn't expect a response. If the engine is gone the command is
+    /// dropped, there is nothing the ui could do about it anyway.
+    fn notify(&self, cmd: Command) {
+        if let Err(e) = self.send(cmd) {
+            error!("{}", e);
+        }
+    }
+
+    /// Sends a command and waits for the response to it. Responses to earlier requests that timed
+    /// out are skipped.
+    fn request(&self, cmd: Command) -> Result<CommandResponse, AudioError> {
+        // held until the response is in, so requests from other threads can't take it
+        let mut receiver = self.receiver.lock().map_err(|_| AudioError::Disconnected)?;
+        let request_id = self.send(cmd)?;
+        let deadline = Instant::now() + RESPONSE_TIMEOUT;
+
+        loop {
+            match receiver.pop() {
+                Some((response_id, response)) if response_id == request_id => return Ok(response),
+                Some(_) => continue,
+                None if Instant::now() >= deadline => return Err(AudioError::Timeout),
+                None => std::thread::sleep(RESPONSE_POLL_INTERVAL),
+            }
+        }
+    }
+
     pub fn set_playing(&self, val: bool) {
-        self.sender.send(Command::SetPlaying(val)).unwrap();
+        self.notify(Command::SetPlaying(val));
     }
 
     pub fn set_play_time(&self, val: f64) {
-        self.sender.send(Command::SetPlayTime(val)).unwrap();
+        self.notify(Command::SetPlayTime(val));
     }
 
     pub fn start_recording(&self) {
-        self.sender.send(Command::SetRecording(true)).unwrap();
+        self.notify(Command::SetRecording(true));
     }
 
-    pub fn stop_recording(&self) -> Option<(AudioSourceID, AudioSourceFormat)> {
-        self.sender.send(Command::SetRecording(false)).unwrap();
+    /// Stops recording and adds the recorded clip to the sources.
+    pub fn stop_recording(&self) -> Result<Option<Recording>, AudioError> {
+        let recorded = match self.request(Command::SetRecording(false))? {
+            CommandResponse::SetRecording(recorded) => recorded,
+            _ => return Err(AudioError::WrongResponse),
+        };
 
-        match self.receiver.recv().unwrap() {
-            CommandResponse::SetRecording(v) => v,
-            CommandResponse::GetAudioSourceClone(_) => panic!("wrong response wtf"),
-        }
+        let RecordedClip {
+            mut clip,
+            start_time,
+        } = match recorded {
+            Some(recorded) => recorded,
+            None => return Ok(None),
+        };
+
+        // goes over the whole clip, so it's done here and not in the audio callback
+        clip.clean();
+
+        let format = clip.format();
+        let id = self.add_audio_source(Arc::new(clip))?;
+
+        Ok(Some(Recording {
+            id,
+            format,
+            start_time,
+        }))
     }
 
+    /// Plays the input through the output, as it comes in.
     pub fn set_feedback(&self, val: bool) {
-        self.sender.send(Command::SetFeedback(val)).unwrap();
+        self.notify(Command::SetFeedback(val));
+    }
+
+    pub fn set_stop_at_end(&self, val: bool) {
+        self.notify(Command::SetStopAtEnd(val));
     }
 
     pub fn set_volume(&self, volume: f64) {
-        self.sender.send(Command::SetVolume(volume)).unwrap();
+        self.notify(Command::SetVolume(volume));
+    }
+
+    pub fn set_delay(&self, delay: DelaySettings) {
+        self.notify(Command::SetDelay(delay));
     }
 
     pub fn set_beats_per_second(&self, beats_per_second: f64) {
-        self.sender
-            .send(Command::SetBeatsPerSecond(beats_per_second))
-            .unwrap();
+        self.notify(Command::SetBeatsPerSecond(beats_per_second));
     }
 
-    pub fn get_audio_source_clone(&self, audio_source_id: AudioSourceID) -> Arc<dyn AudioSource> {
-        self.sender
-            .send(Command::GetAudioSourceClone(audio_source_id))
-            .unwrap();
+    /// Shares the source with the engine, only the `Arc` is cloned so this doesn't get slower
+    /// with the length of the source. Editing the source replaces it with `set_audio_source`.
+    pub fn get_audio_source_clone(
+        &self,
+        audio_source_id: AudioSourceID,
+    ) -> Result<Arc<dyn AudioSource>, AudioError> {
+        match self.request(Command::GetAudioSourceClone(audio_source_id))? {
+            CommandResponse::GetAudioSourceClone(Some(v)) => Ok(v),
+            CommandResponse::GetAudioSourceClone(None) => Err(AudioError::NoSuchSource),
+            _ => Err(AudioError::WrongResponse),
+        }
+    }
 
-        match self.receiver.recv().unwrap() {
-            CommandResponse::SetRecording(_) => panic!("wrong response wtf"),
-            CommandResponse::GetAudioSourceClone(v) => v,
+    /// Adds an oscillator at the engine's sample rate and current tempo.
+    pub fn add_oscillator(
+        &self,
+        waveform: Waveform,
+        freq: f32,
+    ) -> Result<(AudioSourceID, AudioSourceFormat), AudioError> {
+        match self.request(Command::AddOscillator(waveform, freq))? {
+            CommandResponse::AddOscillator(id, format) => Ok((id, format)),
+            _ => Err(AudioError::WrongResponse),
         }
     }
 
+    /// Adds a source, it should already be at the sample rate and channel count of
+    /// [`output_format`](Self::output_format).
+    pub fn add_audio_source(
+        &self,
+        source: Arc<dyn AudioSource + Send + Sync>,
+    ) -> Result<AudioSourceID, AudioError> {
+        loop {
+            match self.request(Command::AddAudioSource(source.clone()))? {
+                CommandResponse::AddAudioSource(Ok(id)) => return Ok(id),
+                // the engine can't grow its map without allocating, it gets a bigger one from here
+                CommandResponse::AddAudioSource(Err(len)) => {
+                    let sources = HashMap::with_capacity(len * 2 + 16);
+                    self.send(Command::GrowAudioSources(sources))?;
+                }
+                _ => return Err(AudioError::WrongResponse),
+            }
+        }
+    }
+
+    pub fn remove_audio_source(&self, audio_source_id: AudioSourceID) {
+        self.notify(Command::RemoveAudioSource(audio_source_id));
+    }
+
+    /// Returns every source in the engine, used for saving and rendering.
+    pub fn download_audio_sources(&self) -> Result<AudioSources, AudioError> {
+        let mut len = 0;
+
+        // the engine only fills the map, it's allocated here with room for sources added meanwhile
+        loop {
+            let sources = HashMap::with_capacity(len * 2 + 16);
+
+            match self.request(Command::DownloadAudioSources(sources))? {
+                CommandResponse::DownloadAudioSources(Ok(sources)) => return Ok(sources),
+                CommandResponse::DownloadAudioSources(Err(needed)) => len = needed,
+                _ => return Err(AudioError::WrongResponse),
+            }
+        }
+    }
+
+    /// Returns the sample rate and channel count of the output, once the engine is running.
+    pub fn output_format(&self) -> Option<(u32, u32)> {
+        let sample_rate = self.output_format.sample_rate.load(Ordering::Relaxed);
+        let channels = self.output_format.channels.load(Ordering::Relaxed);
+
+        if sample_rate == 0 || channels == 0 {
+            None
+        } else {
+            Some((sample_rate, channels))
+        }
+    }
+
+    /// Replaces the source with `audio_source_id`, used when a source is edited.
+    pub fn set_audio_source(
+        &self,
+        audio_source_id: AudioSourceID,
+        source: Arc<dyn AudioSource + Send + Sync>,
+    ) {
+        self.notify(Command::SetAudioSource(audio_source_id, source));
+    }
+
     pub fn set_arrangement_index(&self, index: ArrangementAudioSourceIndex) {
-        self.sender
-            .send(Command::SetArrangementAudioSourceIndex(index))
-            .unwrap();
+        *self
+            .arrangement_index
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = index.clone();
+
+        self.notify(Command::SetArrangementAudioSourceIndex(index));
+    }
+
+    /// Replaces tracks of the index set with `set_arrangement_index`. They are swapped in here and
+    /// the whole index is sent, the engine can't take the time to do it.
+    pub fn replace_arrangement_tracks(
+        &self,
+        tracks: impl IntoIterator<Item = TrackAudioSourceIndex>,
+    ) {
+        let mut index = self
+            .arrangement_index
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        for track in tracks {
+            index.replace_track(track);
+        }
+
+        self.notify(Command::SetArrangementAudioSourceIndex(index.clone()));
     }
 }
 
@@ -99,10 +508,43 @@ pub struct AudioSourceFormat {
     pub beats_per_second: f64,
 }
 
+/// Anything the engine can play, new kinds of sources only need to implement this.
+///
+/// Sources are shared as `Arc`s between the engine and the ui and never mutated in place, an edit
+/// replaces the whole source with [`AudioEngineHandle::set_audio_source`].
 pub trait AudioSource: AudioSourceClone + Any {
     fn get_sample(&self, frame: u32, channel: u32, beats_per_second: f64) -> Option<f32>;
+
+    /// Like `get_sample`, but the pitch doesn't follow the tempo, it's the pitch the source has
+    /// at its own tempo multiplied by `pitch`. Only sources whose pitch follows the tempo need
+    /// this.
+    fn get_stretched_sample(
+        &self,
+        frame: u32,
+        channel: u32,
+        beats_per_second: f64,
+        _pitch: f64,
+    ) -> Option<f32> {
+        self.get_sample(frame, channel, beats_per_second)
+    }
+
     fn format(&self) -> AudioSourceFormat;
 
+    /// Returns a copy with the silence at either end cut off, if the source supports trimming.
+    fn trimmed(&self, _threshold: f32) -> Option<Arc<dyn AudioSource + Send + Sync>> {
+        None
+    }
+
+    /// Returns a copy scaled so the loudest sample is at full scale, if the source supports it.
+    fn normalized(&self) -> Option<Arc<dyn AudioSource + Send + Sync>> {
+        None
+    }
+
+    /// Returns a copy that plays backwards, if the source supports it.
+    fn reversed(&self) -> Option<Arc<dyn AudioSource + Send + Sync>> {
+        None
+    }
+
     fn widget(&self) -> Box<dyn druid::Widget<(Arc<dyn AudioSource>, crate::AudioBlock)>>;
 
     fn len_seconds(&self) -> f64 {
@@ -122,274 +564,1312 @@ impl<T: AudioSource + Send + Sync + Clone + 'static> AudioSourceClone for T {
     }
 }
 
+pub type AudioSources = HashMap<AudioSourceID, Arc<dyn AudioSource + Send + Sync + 'static>>;
+
+/// Downcasts a source to the concrete type `T`, if it is one.
+pub fn downcast_source<T: AudioSource>(source: &dyn AudioSource) -> Option<&T> {
+    (source as &dyn Any).downcast_ref::<T>()
+}
+
+/// Lens from what the widget of a source is given to the source as `S`, next to its audio block.
+/// The source is only replaced when it actually changed, every new source gets sent to the audio
+/// engine.
+///
+/// The widget of a source is only ever given that source, so another kind of source panics.
+pub fn source_lens<S: AudioSource + druid::Data>(
+) -> impl druid::Lens<(Arc<dyn AudioSource>, crate::AudioBlock), (S, crate::AudioBlock)> {
+    druid::lens::Map::new(
+        |data: &(Arc<dyn AudioSource>, crate::AudioBlock)| {
+            let source = downcast_source::<S>(&*data.0).expect("widget given another source");
+
+            (source.clone(), data.1.clone())
+        },
+        |data: &mut (Arc<dyn AudioSource>, crate::AudioBlock), val: (S, crate::AudioBlock)| {
+            if !downcast_source::<S>(&*data.0).is_some_and(|source| source.same(&val.0)) {
+                data.0 = Arc::new(val.0);
+            }
+
+            data.1 = val.1;
+        },
+    )
+}
+
+/// Filled in by the engine once the output stream is configured, zero until then.
+#[derive(Default)]
+struct OutputFormat {
+    sample_rate: AtomicU32,
+    channels: AtomicU32,
+}
+
+/// Mixes the arrangement sample by sample, running every track through its filter.
+///
+/// The filters need the samples before, so one mixer has to be used for the whole of a render,
+/// and the engine keeps one across buffers.
+#[derive(Default)]
+pub struct Mixer {
+    track_sums: Vec<f32>,
+    /// By track, then channel.
+    filters: Vec<Vec<Biquad>>,
+    pub delay_settings: DelaySettings,
+    delay: Delay,
+}
+
+impl Mixer {
+    pub fn new(delay_settings: DelaySettings) -> Self {
+        Self {
+            delay_settings,
+            ..Default::default()
+        }
+    }
+
+    /// Makes room for the delay at the output format, must be called before mixing at it.
+    pub fn prepare(&mut self, channels: u32, sample_rate: u32) {
+        self.delay.prepare(channels, sample_rate);
+    }
+
+    /// Mixes every source the arrangement plays at `position` beats on `channel`.
+    /// `beats_per_second` is the tempo at `position`.
+    pub fn mix(
+        &mut self,
+        arrangement_index: &ArrangementAudioSourceIndex,
+        sources: &AudioSources,
+        position: f64,
+        beats_per_second: f64,
+        channel: u32,
+        sample_rate: u32,
+    ) -> f32 {
+        let mut track_sums = std::mem::take(&mut self.track_sums);
+
+        sum_tracks(
+            arrangement_index,
+            sources,
+            position,
+            beats_per_second,
+            channel,
+            sample_rate,
+            &mut track_sums,
+        );
+        let sample = self.mix_tracks(
+            arrangement_index,
+            &track_sums,
+            beats_per_second,
+            channel,
+            sample_rate,
+        );
+
+        self.track_sums = track_sums;
+
+        sample
+    }
+
+    /// Runs the sums of every track through their filters and the delay, these carry over from
+    /// one frame to the next so the frames have to come in order.
+    fn mix_tracks(
+        &mut self,
+        arrangement_index: &ArrangementAudioSourceIndex,
+        track_sums: &[f32],
+        beats_per_second: f64,
+        channel: u32,
+        sample_rate: u32,
+    ) -> f32 {
+        let mut sample = 0.0;
+        let mut send = 0.0;
+
+        for (track_index, track_sum) in track_sums.iter().enumerate() {
+            // filtered even when silent, so the tail of the filter rings out
+            let track_sample = match arrangement_index
+                .filters
+                .get(track_index)
+                .copied()
+                .flatten()
+            {
+                Some(filter) => {
+                    if track_index >= self.filters.len() {
+                        self.filters.resize_with(track_index + 1, Vec::new);
+                    }
+
+                    let channels = &mut self.filters[track_index];
+
+                    if channel as usize >= channels.len() {
+                        channels.resize_with(channel as usize + 1, Biquad::default);
+                    }
+
+                    channels[channel as usize].process(filter, sample_rate, *track_sum)
+                }
+                None => *track_sum,
+            };
+
+            sample += track_sample;
+            send += track_sample
+                * arrangement_index
+                    .sends
+                    .get(track_index)
+                    .copied()
+                    .unwrap_or(0.0);
+        }
+
+        // fed even without sends, so the echoes die out after a send is turned off
+        sample
+            + self.delay.process(
+                self.delay_settings,
+                channel,
+                send,
+                beats_per_second,
+                sample_rate,
+            )
+    }
+}
+
+/// Sums the sources of every track at `position` beats on `channel` into `track_sums`, by the
+/// `track_index` of the sources. Only depends on `position`, so any frame can be summed on its
+/// own.
+fn sum_tracks(
+    arrangement_index: &ArrangementAudioSourceIndex,
+    sources: &AudioSources,
+    position: f64,
+    beats_per_second: f64,
+    channel: u32,
+    sample_rate: u32,
+    track_sums: &mut Vec<f32>,
+) {
+    let beat = position.floor();
+    let beat_frame = ((position - beat) * sample_rate as f64 / beats_per_second) as u32;
+
+    for track_sum in track_sums.iter_mut() {
+        *track_sum = 0.0;
+    }
+
+    if let Some(source_indices) = arrangement_index.beats.get(&(beat as usize)) {
+        for source_index in source_indices {
+            let offset =
+                (source_index.beats_offset as f64 * sample_rate as f64 / beats_per_second) as i64;
+
+            if beat_frame as i64 + offset < 0 {
+                continue;
+            }
+
+            if let Some(source_sample) =
+                sources
+                    .get(&source_index.audio_source_id)
+                    .and_then(|source| {
+                        let frame = beat_frame + offset as u32;
+
+                        if source_index.time_stretch {
+                            source.get_stretched_sample(
+                                frame,
+                                channel,
+                                beats_per_second,
+                                source_index.pitch,
+                            )
+                        } else if source_index.pitch != 1.0 {
+                            // the pitch still follows the tempo, only shifted
+                            let speed = beats_per_second / source.format().beats_per_second;
+
+                            source.get_stretched_sample(
+                                frame,
+                                channel,
+                                beats_per_second,
+                                source_index.pitch * speed,
+                            )
+                        } else {
+                            source.get_sample(frame, channel, beats_per_second)
+                        }
+                    })
+            {
+                let volume = arrangement_index
+                    .volume
+                    .get(source_index.track_index)
+                    .map_or(1.0, |volume| volume.value_at(position, 1.0));
+
+                if source_index.track_index >= track_sums.len() {
+                    track_sums.resize(source_index.track_index + 1, 0.0);
+                }
+
+                track_sums[source_index.track_index] +=
+                    source_sample * source_index.fade.gain(position.fract() as f32) * volume;
+            }
+        }
+    }
+}
+
+/// How many frames a thread sums at a time when rendering.
+const RENDER_CHUNK_FRAMES: usize = 8192;
+
+/// Renders `len_frames` frames with `mixer`, `at` gives the position and tempo of a frame.
+///
+/// Summing the sources is the slow part and every frame can be summed on its own, so that is
+/// spread over a thread per chunk of frames. The filters and the delay then go over the sums in
+/// order, which comes out the same as mixing frame by frame.
+fn render_frames(
+    arrangement_index: &ArrangementAudioSourceIndex,
+    sources: &AudioSources,
+    sample_rate: u32,
+    channels: u32,
+    len_frames: usize,
+    mut mixer: Mixer,
+    at: impl Fn(usize) -> (f64, f64) + Sync,
+) -> Vec<f32> {
+    let threads = std::thread::available_parallelism().map_or(1, |threads| threads.get());
+    let channels = channels.max(1) as usize;
+    // every track of a compiled index has an entry, even without sources
+    let tracks = arrangement_index.track_beats.len().max(1);
+    let frame_len = channels * tracks;
+
+    mixer.prepare(channels as u32, sample_rate);
+
+    let mut samples = Vec::with_capacity(len_frames * channels);
+    // the sums of a window of frames, by frame, then channel, then track
+    let mut sums = Vec::new();
+    let mut window_start = 0;
+
+    while window_start < len_frames {
+        let window_end = (window_start + RENDER_CHUNK_FRAMES * threads).min(len_frames);
+
+        sums.clear();
+        sums.resize((window_end - window_start) * frame_len, 0.0);
+
+        std::thread::scope(|scope| {
+            for (chunk_index, chunk) in sums.chunks_mut(RENDER_CHUNK_FRAMES * frame_len).enumerate()
+            {
+                let at = &at;
+
+                scope.spawn(move || {
+                    let first_frame = window_start + chunk_index * RENDER_CHUNK_FRAMES;
+                    let mut track_sums = Vec::with_capacity(tracks);
+
+                    for (frame_index, frame_sums) in chunk.chunks_mut(frame_len).enumerate() {
+                        let (position, beats_per_second) = at(first_frame + frame_index);
+
+                        for (channel, channel_sums) in frame_sums.chunks_mut(tracks).enumerate() {
+                            sum_tracks(
+                                arrangement_index,
+                                sources,
+                                position,
+                                beats_per_second,
+                                channel as u32,
+                                sample_rate,
+                                &mut track_sums,
+                            );
+
+                            let len = track_sums.len().min(tracks);
+                            channel_sums[..len].copy_from_slice(&track_sums[..len]);
+                        }
+                    }
+                });
+            }
+        });
+
+        for (frame_index, frame_sums) in sums.chunks(frame_len).enumerate() {
+            let (_, beats_per_second) = at(window_start + frame_index);
+
+            for (channel, channel_sums) in frame_sums.chunks(tracks).enumerate() {
+                samples.push(mixer.mix_tracks(
+                    arrangement_index,
+                    channel_sums,
+                    beats_per_second,
+                    channel as u32,
+                    sample_rate,
+                ));
+            }
+        }
+
+        window_start = window_end;
+    }
+
+    samples
+}
+
+/// Renders `len_seconds` of the arrangement without the engine, returns interleaved samples.
+pub fn render(
+    arrangement_index: &ArrangementAudioSourceIndex,
+    sources: &AudioSources,
+    sample_rate: u32,
+    channels: u32,
+    base_beats_per_second: f64,
+    len_seconds: f64,
+    delay: DelaySettings,
+) -> Vec<f32> {
+    let len_frames = (len_seconds * sample_rate as f64).ceil() as usize;
+    let tempo = &arrangement_index.tempo;
+
+    render_frames(
+        arrangement_index,
+        sources,
+        sample_rate,
+        channels,
+        len_frames,
+        Mixer::new(delay),
+        |frame| {
+            let position =
+                tempo.beat_at_time(frame as f64 / sample_rate as f64, base_beats_per_second);
+
+            (
+                position,
+                tempo.beats_per_second(position.floor() as usize, base_beats_per_second),
+            )
+        },
+    )
+}
+
+/// Renders `beats` of the arrangement at a constant tempo, ignoring the tempo map, returns
+/// interleaved samples.
+///
+/// The result played back at `beats_per_second` lines up with the beats it was rendered from,
+/// whatever the tempo map says.
+pub fn render_beats(
+    arrangement_index: &ArrangementAudioSourceIndex,
+    sources: &AudioSources,
+    sample_rate: u32,
+    channels: u32,
+    beats_per_second: f64,
+    beats: Range<usize>,
+) -> Vec<f32> {
+    let len_frames =
+        ((beats.end - beats.start) as f64 / beats_per_second * sample_rate as f64).ceil() as usize;
+
+    render_frames(
+        arrangement_index,
+        sources,
+        sample_rate,
+        channels,
+        len_frames,
+        Mixer::default(),
+        |frame| {
+            (
+                beats.start as f64 + frame as f64 / sample_rate as f64 * beats_per_second,
+                beats_per_second,
+            )
+        },
+    )
+}
+
+/// Something the output callback is done with. Freeing it could take a while, so it's handed to
+/// the engine thread to drop.
+// the values are never read, only held until they're dropped
+#[allow(dead_code)]
+enum Garbage {
+    Source(Arc<dyn AudioSource + Send + Sync>),
+    Sources(AudioSources),
+    Index(ArrangementAudioSourceIndex),
+}
+
 pub struct AudioEngine {
-    receiver: Receiver<Command>,
-    sender: Sender<CommandResponse>,
+    receiver: ringbuf::Consumer<(RequestID, Command)>,
+    sender: ringbuf::Producer<(RequestID, CommandResponse)>,
+    garbage: ringbuf::Producer<Garbage>,
+    /// Taken by the engine thread when it starts.
+    collector: Option<ringbuf::Consumer<Garbage>>,
     event_sink: druid::ExtEventSink,
     volume: f64,
     beats_per_second: f64,
     feedback: bool,
-    sources: HashMap<AudioSourceID, Box<dyn AudioSource + Send + Sync + 'static>>,
+    /// Stop playing past the end of the arrangement, recording keeps going.
+    stop_at_end: bool,
+    sources: AudioSources,
     next_audio_id: AudioSourceID,
+    output_format: Arc<OutputFormat>,
+    input_device_name: Option<String>,
+    output_device_name: Option<String>,
+    // kept here instead of in the output callback, so they outlive the streams when the device is
+    // lost and the engine starts again
+    arrangement_index: ArrangementAudioSourceIndex,
+    mixer: Mixer,
+    recording_clip: Option<AudioClip>,
+    /// The play time `recording_clip` starts at, see [`Recording::start_time`].
+    recording_start_time: f64,
+    /// A recording cut short by the loss of the device, handed over when the recording is stopped.
+    interrupted_recording: Option<RecordedClip>,
+}
+
+/// Tells the engine thread a stream failed, most likely because its device is gone.
+fn stream_failed(failed: &Arc<AtomicBool>) -> impl FnMut(cpal::StreamError) + Send + 'static {
+    let failed = failed.clone();
+    let thread = std::thread::current();
+
+    move |err| {
+        error!("{}", err);
+
+        failed.store(true, Ordering::Relaxed);
+        thread.unpark();
+    }
+}
+
+/// Finds the device called `name`, warns when there is a name but no such device.
+fn find_device(
+    devices: Result<impl Iterator<Item = cpal::Device>, cpal::DevicesError>,
+    name: &Option<String>,
+) -> Option<cpal::Device> {
+    let name = name.as_ref()?;
+    let device = devices
+        .ok()?
+        .find(|device| device.name().ok().as_ref() == Some(name));
+
+    if device.is_none() {
+        warn!("audio device {} not found, using the default", name);
+    }
+
+    device
 }
 
 impl AudioEngine {
     pub fn new(event_sink: druid::ExtEventSink) -> (Self, AudioEngineHandle) {
-        let (h_sender, e_receiver) = channel();
-        let (e_sender, h_receiver) = channel();
+        let (h_sender, e_receiver) = ringbuf::RingBuffer::new(COMMAND_QUEUE_LEN).split();
+        let (e_sender, h_receiver) = ringbuf::RingBuffer::new(COMMAND_QUEUE_LEN).split();
+        let (garbage, collector) = ringbuf::RingBuffer::new(COMMAND_QUEUE_LEN).split();
+        let output_format = Arc::new(OutputFormat::default());
 
         (
             Self {
                 event_sink,
                 volume: 0.5,
-                feedback: true,
+                // nothing is monitored until a track is armed
+                feedback: false,
+                stop_at_end: true,
                 beats_per_second: 120.0 / 60.0,
                 receiver: e_receiver,
                 sender: e_sender,
+                garbage,
+                collector: Some(collector),
                 sources: HashMap::new(),
                 next_audio_id: AudioSourceID(0),
+                output_format: output_format.clone(),
+                input_device_name: None,
+                output_device_name: None,
+                arrangement_index: ArrangementAudioSourceIndex::default(),
+                mixer: Mixer::default(),
+                recording_clip: None,
+                recording_start_time: 0.0,
+                interrupted_recording: None,
             },
             AudioEngineHandle {
-                sender: std::sync::Arc::new(h_sender),
-                receiver: std::sync::Arc::new(h_receiver),
+                sender: std::sync::Arc::new(Mutex::new(h_sender)),
+                receiver: std::sync::Arc::new(Mutex::new(h_receiver)),
+                next_request_id: std::sync::Arc::new(AtomicUsize::new(0)),
+                output_format,
+                arrangement_index: Default::default(),
             },
         )
     }
 
+    /// Uses the devices with these names instead of the default ones, if they exist.
+    pub fn with_devices(
+        mut self,
+        input_device_name: Option<String>,
+        output_device_name: Option<String>,
+    ) -> Self {
+        self.input_device_name = input_device_name;
+        self.output_device_name = output_device_name;
+        self
+    }
+
+    /// Runs the streams on a thread of their own. When a stream fails they are started again, on
+    /// the default devices if the chosen ones are gone, and without a device the engine waits
+    /// for one to appear.
     pub fn run(mut self) {
-        std::thread::spawn(|| -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-            let host = cpal::default_host();
+        std::thread::spawn(move || {
+            let event_sink = self.event_sink.clone();
+            let mut collector = self.collector.take().expect("the engine runs only once");
+            let engine = Arc::new(Mutex::new(self));
+            let failed = Arc::new(AtomicBool::new(false));
+            let mut started = false;
+            // the error is shown once, not on every attempt while waiting for a device
+            let mut waiting = false;
+
+            loop {
+                match Self::start_streams(&engine, &failed) {
+                    Ok(streams) => {
+                        if started {
+                            info!("audio engine restarted");
+
+                            event_sink
+                                .submit_command(
+                                    AUDIO_ENGINE_RESTARTED,
+                                    streams.0.is_some(),
+                                    Target::Global,
+                                )
+                                .ok();
+                        }
+
+                        started = true;
+                        waiting = false;
+
+                        // park may return early, only a failed stream ends the wait
+                        while !failed.load(Ordering::Relaxed) {
+                            std::thread::park_timeout(COLLECT_INTERVAL);
+
+                            while collector.pop().is_some() {}
+                        }
+
+                        drop(streams);
+                        failed.store(false, Ordering::Relaxed);
+
+                        let mut engine = engine.lock().unwrap_or_else(PoisonError::into_inner);
+
+                        if let Some(recording) = engine.take_recording() {
+                            engine.interrupted_recording = Some(recording);
+                        }
+
+                        warn!("audio device lost, starting again");
+
+                        event_sink
+                            .submit_command(
+                                AUDIO_ENGINE_ERROR,
+                                String::from("audio device lost"),
+                                Target::Global,
+                            )
+                            .ok();
+                    }
+                    Err(e) => {
+                        // the ui would otherwise never know the engine is down
+                        if !waiting {
+                            error!("audio engine stopped: {}", e);
+
+                            event_sink
+                                .submit_command(AUDIO_ENGINE_ERROR, e.to_string(), Target::Global)
+                                .ok();
+
+                            waiting = true;
+                        }
+
+                        std::thread::sleep(RESTART_INTERVAL);
+                    }
+                }
+            }
+        });
+    }
+
+    /// Hands something the output callback is done with to the engine thread, only if the queue
+    /// is full it's dropped right away.
+    fn discard(&mut self, garbage: Garbage) {
+        self.garbage.push(garbage).ok();
+    }
+
+    /// Takes the recording clip as it is, the handle cleans it up and adds it to the sources.
+    fn take_recording(&mut self) -> Option<RecordedClip> {
+        Some(RecordedClip {
+            clip: self.recording_clip.take()?,
+            start_time: self.recording_start_time,
+        })
+    }
 
-            let input_device = host
-                .default_input_device()
-                .expect("failed to get input device");
-            let output_device = host
-                .default_output_device()
-                .expect("failed to get output device");
+    /// Builds and plays the input stream, if there is an input, and the output stream. They run
+    /// until they are dropped, `failed` is set when either of them fails.
+    fn start_streams(
+        engine: &Arc<Mutex<Self>>,
+        failed: &Arc<AtomicBool>,
+    ) -> Result<(Option<cpal::Stream>, cpal::Stream), Box<dyn std::error::Error + Send + Sync>>
+    {
+        let mut this = engine.lock().unwrap_or_else(PoisonError::into_inner);
+        let host = cpal::default_host();
 
+        let input_device = find_device(host.input_devices(), &this.input_device_name)
+            .or_else(|| host.default_input_device());
+        let output_device = find_device(host.output_devices(), &this.output_device_name)
+            .or_else(|| host.default_output_device())
+            .ok_or(AudioError::NoOutputDevice)?;
+
+        if let Some(input_device) = &input_device {
             info!(
-                "Using default input device: {}, {:?}",
+                "Using input device: {}, {:?}",
                 input_device.name()?,
                 input_device.default_input_config()?
             );
-            info!(
-                "Using default output device: {}, {:?}",
-                output_device.name()?,
-                output_device.default_output_config()?
-            );
+        }
+        info!(
+            "Using output device: {}, {:?}",
+            output_device.name()?,
+            output_device.default_output_config()?
+        );
+
+        let config: cpal::StreamConfig = output_device.default_output_config()?.into();
 
-            let config: cpal::StreamConfig = output_device.default_output_config()?.into();
+        const LATENCY_MS: f32 = 20.0;
+        /// How long the playback fades out before and back in after a seek.
+        const SEEK_FADE_MS: f32 = 4.0;
 
-            const LATENCY_MS: f32 = 20.0;
+        let sample_rate = config.sample_rate.0;
+        let channels = config.channels as u32;
 
-            let sample_rate = config.sample_rate.0;
-            let channels = config.channels as u32;
-            let latency_frames = (LATENCY_MS / 1000.0) * sample_rate as f32;
-            let latency_samples = latency_frames as usize * channels as usize;
+        this.output_format
+            .sample_rate
+            .store(sample_rate, Ordering::Relaxed);
+        this.output_format
+            .channels
+            .store(channels, Ordering::Relaxed);
+        this.mixer.prepare(channels, sample_rate);
+        let latency_frames = (LATENCY_MS / 1000.0) * sample_rate as f32;
+        let latency_samples = latency_frames as usize * channels as usize;
+        let seek_fade_samples =
+            ((SEEK_FADE_MS / 1000.0 * sample_rate as f32) as u32).max(1) * channels;
 
-            let ring = ringbuf::RingBuffer::new(latency_samples * 2);
-            let (mut producer, mut consumer) = ring.split();
+        let ring = ringbuf::RingBuffer::new(latency_samples * 2);
+        let (mut producer, mut consumer) = ring.split();
 
-            for _ in 0..latency_samples {
-                producer.push(0.0).unwrap();
+        for _ in 0..latency_samples {
+            producer.push(0.0).unwrap();
+        }
+
+        let mut noise_level: f32 = 0.025;
+        let mut noise_sample = 0;
+        let mut channel = 0;
+        let mut play_sample: u32 = 0;
+        // jumping straight to a new time clicks, so while playing a seek fades out, jumps and
+        // fades back in, the ramp counts down over both fades and the jump happens halfway
+        let mut seek_ramp: u32 = 0;
+        let mut seek_target: u32 = 0;
+        let mut metronome = true;
+        let bar_click = metronome_click(sample_rate, METRONOME_BAR_FREQ);
+        let beat_click = metronome_click(sample_rate, METRONOME_BEAT_FREQ);
+        // the playing click, whether it's the bar click and the frame it started on
+        let mut click: Option<(bool, u32)> = None;
+        // cleared whenever playback jumps, it's set again from the new position
+        let mut beat_clock: Option<BeatClock> = None;
+        let mut wait_for_input = true;
+        let mut waiting_for_input = false;
+        let mut playing = false;
+        // how long the input takes from capture to the input callback, in microseconds
+        let input_delay = Arc::new(AtomicU32::new(0));
+        let input_delay_writer = input_delay.clone();
+        // the busiest callback since the last load report, and the frames played since
+        let mut peak_load: f64 = 0.0;
+        let mut load_frames: u32 = 0;
+        let load_report_frames = (LOAD_REPORT_MS / 1000.0 * sample_rate as f32) as u32;
+        // stepped every sample, not every frame
+        let mut volume = SmoothedValue::new(this.volume as f32, sample_rate * channels);
+
+        // without an input we can still play, only recording and feedback are lost
+        let input_stream = input_device.and_then(|input_device| {
+            // the input keeps its own channel count, but has to run at the output rate since the
+            // output reads it one sample at a time
+            let input_config = match input_device.default_input_config() {
+                Ok(input_config) => cpal::StreamConfig {
+                    channels: input_config.channels(),
+                    sample_rate: config.sample_rate,
+                    buffer_size: cpal::BufferSize::Default,
+                },
+                Err(e) => {
+                    error!("failed to get input config: {}", e);
+                    return None;
+                }
+            };
+
+            if input_config.channels as u32 != channels {
+                info!(
+                    "remixing input from {} to {} channels",
+                    input_config.channels, channels
+                );
             }
 
-            let mut noise_level: f32 = 0.025;
-            let mut noise_sample = 0;
-            let mut channel = 0;
-            let mut play_sample: u32 = 0;
-            let mut play_frame: u32 = 0;
-            let mut metronome = true;
-            let mut wait_for_input = true;
-            let mut waiting_for_input = false;
-            let mut playing = false;
-            let mut recording_clip: Option<AudioClip> = None;
-            let mut arrangement_index = ArrangementAudioSourceIndex::default();
-
-            let input_stream = input_device.build_input_stream(
-                &config,
-                move |data: &[f32], _: &cpal::InputCallbackInfo| {
+            let input_channels = input_config.channels.max(1) as usize;
+            let output_channels = channels as usize;
+            // the input may hand over a frame split between two callbacks
+            let mut frame = Vec::with_capacity(input_channels);
+
+            match input_device.build_input_stream(
+                &input_config,
+                move |data: &[f32], info: &cpal::InputCallbackInfo| {
+                    let timestamp = info.timestamp();
+
+                    if let Some(delay) = timestamp.callback.duration_since(&timestamp.capture) {
+                        input_delay_writer.store(delay.as_micros() as u32, Ordering::Relaxed);
+                    }
+
                     for sample in data {
-                        if let Err(_e) = producer.push(*sample) {
-                            //error!("output stream fell behind '{}', increase latency", e);
+                        frame.push(*sample);
+
+                        if frame.len() < input_channels {
+                            continue;
+                        }
+
+                        // only whole frames go in, a dropped sample would swap the channels of
+                        // everything after it
+                        if producer.remaining() >= output_channels {
+                            for channel in 0..output_channels {
+                                producer
+                                    .push(remix_sample(&frame, channel, output_channels))
+                                    .ok();
+                            }
                         }
+
+                        frame.clear();
                     }
                 },
-                |err| {
-                    error!("{}", err);
-                },
-            )?;
+                stream_failed(failed),
+            ) {
+                Ok(input_stream) => Some(input_stream),
+                Err(e) => {
+                    error!("failed to build input stream: {}", e);
+                    None
+                }
+            }
+        });
 
-            let output_stream = output_device.build_output_stream(
-                &config,
-                move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                    for sample in data {
-                        if let Ok(cmd) = self.receiver.try_recv() {
-                            match cmd {
-                                Command::SetPlaying(val) => playing = val,
-                                Command::SetRecording(val) => {
-                                    if val {
-                                        recording_clip =
-                                            Some(AudioClip::empty(AudioSourceFormat {
-                                                sample_rate,
-                                                channels,
-                                                len_frames: 0,
-                                                beats_per_second: self.beats_per_second,
-                                            }));
-
-                                        if wait_for_input {
-                                            waiting_for_input = true;
-                                        }
-
-                                        playing = true;
-                                    } else {
-                                        if let Some(mut recording_clip) =
-                                            std::mem::replace(&mut recording_clip, None)
-                                        {
-                                            recording_clip.clean();
-
-                                            let id = self.next_audio_id;
-                                            self.next_audio_id.0 += 1;
-
-                                            let format = recording_clip.format();
-
-                                            self.sources.insert(id, Box::new(recording_clip));
-
-                                            self.sender
-                                                .send(CommandResponse::SetRecording(Some((
-                                                    id, format,
-                                                ))))
-                                                .unwrap();
-                                        } else {
-                                            self.sender
-                                                .send(CommandResponse::SetRecording(None))
-                                                .unwrap();
-                                        }
-                                    }
-                                }
-                                Command::SetPlayTime(time) => {
-                                    play_sample =
-                                        (time * sample_rate as f64 * channels as f64) as u32;
-                                }
-                                Command::SetBeatsPerSecond(bps) => self.beats_per_second = bps,
-                                Command::SetFeedback(feedback) => self.feedback = feedback,
-                                Command::SetVolume(volume) => self.volume = volume,
-                                Command::RemoveAudioSource(audio_source_id) => {
-                                    self.sources.remove(&audio_source_id);
-                                }
-                                Command::GetAudioSourceClone(audio_source_id) => {
-                                    self.sender
-                                        .send(CommandResponse::GetAudioSourceClone(
-                                            self.sources[&audio_source_id].arc_clone(),
-                                        ))
-                                        .unwrap();
-                                }
-                                Command::SetArrangementAudioSourceIndex(index) => {
-                                    arrangement_index = index
+        let has_input = input_stream.is_some();
+
+        if !has_input {
+            warn!("{}, recording is disabled", AudioError::NoInputDevice);
+
+            this.feedback = false;
+            this.event_sink
+                .submit_command(AUDIO_ENGINE_NO_INPUT, (), Target::Global)
+                .ok();
+        }
+
+        let shared = engine.clone();
+
+        let output_stream = output_device.build_output_stream(
+            &config,
+            move |data: &mut [f32], info: &cpal::OutputCallbackInfo| {
+                set_output_thread();
+
+                // only the engine thread locks it otherwise, and only while the streams are down,
+                // should it still hold the lock the buffer is silent instead of waiting for it
+                let mut engine = match shared.try_lock() {
+                    Ok(engine) => engine,
+                    Err(TryLockError::Poisoned(e)) => e.into_inner(),
+                    Err(TryLockError::WouldBlock) => {
+                        data.iter_mut().for_each(|sample| *sample = 0.0);
+                        return;
+                    }
+                };
+                let engine = &mut *engine;
+                let callback_start = Instant::now();
+                let buffer_frames = data.len() as u32 / channels;
+
+                // from capture in the input to being heard from the output, in seconds, the input
+                // waits in the ring buffer on top of the delays of both devices
+                let timestamp = info.timestamp();
+                let output_delay = timestamp
+                    .playback
+                    .duration_since(&timestamp.callback)
+                    .unwrap_or_default();
+                let latency = if has_input {
+                    consumer.len() as f64 / channels as f64 / sample_rate as f64
+                        + output_delay.as_secs_f64()
+                        + input_delay.load(Ordering::Relaxed) as f64 / 1_000_000.0
+                } else {
+                    0.0
+                };
+
+                while let Some((request_id, cmd)) = engine.receiver.pop() {
+                    match cmd {
+                        Command::SetPlaying(val) => {
+                            // a seek that is still fading out would be lost otherwise
+                            if !val && seek_ramp > seek_fade_samples {
+                                play_sample = seek_target;
+                                beat_clock = None;
+                            }
+
+                            seek_ramp = 0;
+                            playing = val;
+                        }
+                        Command::SetRecording(val) => {
+                            if val && has_input {
+                                engine.recording_clip = Some(AudioClip::empty(AudioSourceFormat {
+                                    sample_rate,
+                                    channels,
+                                    len_frames: 0,
+                                    beats_per_second: engine.beats_per_second,
+                                }));
+
+                                if wait_for_input {
+                                    waiting_for_input = true;
                                 }
+
+                                playing = true;
+                            } else if val {
+                                playing = true;
+                            } else {
+                                // without a recording since the device was lost, the one it
+                                // cut short is handed over
+                                let recording = engine
+                                    .take_recording()
+                                    .or_else(|| engine.interrupted_recording.take());
+
+                                engine
+                                    .sender
+                                    .push((request_id, CommandResponse::SetRecording(recording)))
+                                    .ok();
                             }
                         }
+                        Command::SetPlayTime(time) => {
+                            // a jump lands on the first channel of a frame
+                            let target = (time * sample_rate as f64) as u32 * channels;
 
-                        match consumer.pop() {
-                            Some(s) => {
-                                if self.feedback {
-                                    *sample = s * self.volume as f32;
-                                } else {
-                                    *sample = 0.0;
+                            if !playing {
+                                play_sample = target;
+                                beat_clock = None;
+                            } else {
+                                seek_target = target;
+
+                                if seek_ramp == 0 {
+                                    seek_ramp = seek_fade_samples * 2;
+                                } else if seek_ramp <= seek_fade_samples {
+                                    // already fading back in, fade out again from the same gain
+                                    seek_ramp = seek_fade_samples * 2 - seek_ramp;
                                 }
                             }
-                            None => (), //error!("input stream fell behind, increase latency"),
                         }
+                        Command::SetBeatsPerSecond(bps) => engine.beats_per_second = bps,
+                        Command::SetFeedback(feedback) => engine.feedback = feedback,
+                        Command::SetStopAtEnd(stop_at_end) => engine.stop_at_end = stop_at_end,
+                        Command::SetVolume(volume) => engine.volume = volume,
+                        Command::SetDelay(delay) => engine.mixer.delay_settings = delay,
+                        Command::AddOscillator(waveform, freq) => {
+                            let id = engine.next_audio_id;
+                            engine.next_audio_id.0 += 1;
 
-                        channel += 1;
-                        channel = channel % channels;
+                            let oscillator = Oscillator::new(
+                                waveform,
+                                freq,
+                                sample_rate,
+                                channels,
+                                engine.beats_per_second,
+                            );
+                            let format = oscillator.format();
 
-                        if noise_sample > 0 {
-                            noise_sample -= 1;
-                            noise_level = noise_level.max(*sample);
+                            engine.sources.insert(id, Arc::new(oscillator));
 
-                            if noise_sample == 0 {
-                                info!("recorded noise level: {}", noise_level);
+                            engine
+                                .sender
+                                .push((request_id, CommandResponse::AddOscillator(id, format)))
+                                .ok();
+                        }
+                        Command::SetAudioSource(audio_source_id, source) => {
+                            if let Some(source) = engine.sources.insert(audio_source_id, source) {
+                                engine.discard(Garbage::Source(source));
                             }
                         }
+                        Command::AddAudioSource(source) => {
+                            // the handle keeps a clone, so a source without room isn't freed here
+                            let response = if engine.sources.len() < engine.sources.capacity() {
+                                let id = engine.next_audio_id;
+                                engine.next_audio_id.0 += 1;
 
-                        if let Some(recording_clip) = &mut recording_clip {
-                            if (channel % channels == 0
-                                && (sample.abs() > noise_level * 1.2 || !waiting_for_input))
-                                || recording_clip.len_samples() > 0
-                            {
-                                recording_clip.append_sample(*sample);
+                                engine.sources.insert(id, source);
+
+                                Ok(id)
+                            } else {
+                                Err(engine.sources.len())
+                            };
+
+                            engine
+                                .sender
+                                .push((request_id, CommandResponse::AddAudioSource(response)))
+                                .ok();
+                        }
+                        Command::GrowAudioSources(mut sources) => {
+                            if sources.capacity() > engine.sources.len() {
+                                sources.extend(engine.sources.drain());
+                                std::mem::swap(&mut engine.sources, &mut sources);
                             }
+
+                            engine.discard(Garbage::Sources(sources));
                         }
+                        Command::DownloadAudioSources(mut sources) => {
+                            // filling the map only allocates if it has no room for every source
+                            let response = if sources.capacity() >= engine.sources.len() {
+                                sources.extend(
+                                    engine
+                                        .sources
+                                        .iter()
+                                        .map(|(id, source)| (*id, source.clone())),
+                                );
 
-                        if playing {
-                            if recording_clip.is_some()
-                                && metronome
-                                && (play_frame as f64 / sample_rate as f64)
-                                    % (1.0 / self.beats_per_second)
-                                    < 0.01
-                            {
-                                *sample += 0.3;
+                                Ok(sources)
+                            } else {
+                                engine.discard(Garbage::Sources(sources));
+
+                                Err(engine.sources.len())
+                            };
+
+                            engine
+                                .sender
+                                .push((request_id, CommandResponse::DownloadAudioSources(response)))
+                                .ok();
+                        }
+                        Command::RemoveAudioSource(audio_source_id) => {
+                            if let Some(source) = engine.sources.remove(&audio_source_id) {
+                                engine.discard(Garbage::Source(source));
                             }
+                        }
+                        Command::GetAudioSourceClone(audio_source_id) => {
+                            engine
+                                .sender
+                                .push((
+                                    request_id,
+                                    CommandResponse::GetAudioSourceClone(
+                                        engine.sources.get(&audio_source_id).cloned(),
+                                    ),
+                                ))
+                                .ok();
+                        }
+                        Command::SetArrangementAudioSourceIndex(index) => {
+                            let index = std::mem::replace(&mut engine.arrangement_index, index);
+                            engine.discard(Garbage::Index(index));
+                        }
+                    }
+                }
+
+                for sample in data {
+                    // stepped even while nothing comes in, so it never lags behind
+                    let volume = volume.next(engine.volume as f32);
+
+                    // also silent without an input, recording doesn't depend on the feedback
+                    let input = consumer.pop().map_or(0.0, |s| s * volume);
+
+                    // don't leave stale data in the buffer
+                    *sample = if engine.feedback { input } else { 0.0 };
+
+                    let sample_channel = channel;
+                    channel += 1;
+                    channel = channel % channels;
 
-                            play_sample += 1;
+                    if noise_sample > 0 {
+                        noise_sample -= 1;
+                        noise_level = noise_level.max(input);
 
-                            play_frame = play_sample / channels;
+                        if noise_sample == 0 {
+                            info!("recorded noise level: {}", noise_level);
+                        }
+                    }
+
+                    if let Some(recording_clip) = &mut engine.recording_clip {
+                        // starting on the first channel keeps the clip aligned to whole frames
+                        if (sample_channel == 0
+                            && (input.abs() > noise_level * 1.2 || !waiting_for_input))
+                            || recording_clip.len_samples() > 0
+                        {
+                            if recording_clip.len_samples() == 0 {
+                                engine.recording_start_time =
+                                    play_sample as f64 / channels as f64 / sample_rate as f64
+                                        - latency;
+                            }
 
-                            let beat = (play_frame as f64 / sample_rate as f64
-                                * self.beats_per_second)
-                                .floor() as u32;
-                            let beat_frame = play_frame
-                                % (sample_rate as f64 / self.beats_per_second).floor() as u32;
+                            recording_clip.append_sample(input);
+                        }
+                    }
 
-                            if let Some(source_indices) =
-                                arrangement_index.beats.get(&(beat as usize))
+                    if playing {
+                        // taken before stepping, so every channel of a frame plays the same frame
+                        let play_frame = play_sample / channels;
+                        play_sample += 1;
+
+                        let mut gain = 1.0;
+
+                        if seek_ramp > 0 {
+                            seek_ramp -= 1;
+
+                            if seek_ramp == seek_fade_samples {
+                                play_sample = seek_target;
+                                beat_clock = None;
+                            }
+
+                            gain = if seek_ramp >= seek_fade_samples {
+                                (seek_ramp - seek_fade_samples) as f32
+                            } else {
+                                (seek_fade_samples - seek_ramp) as f32
+                            } / seek_fade_samples as f32;
+                        }
+
+                        let tempo = &engine.arrangement_index.tempo;
+
+                        let position = tempo.beat_at_time(
+                            play_frame as f64 / sample_rate as f64,
+                            engine.beats_per_second,
+                        );
+                        let beat = position.floor() as u32;
+                        let beats_per_second =
+                            tempo.beats_per_second(beat as usize, engine.beats_per_second);
+
+                        // a recording may well run past the last block, so it doesn't stop
+                        if engine.stop_at_end
+                            && engine.recording_clip.is_none()
+                            && engine.arrangement_index.end_beat > 0
+                            && position >= engine.arrangement_index.end_beat as f64
+                        {
+                            playing = false;
+                            click = None;
+
+                            engine
+                                .event_sink
+                                .submit_command(AUDIO_ENGINE_REACHED_END, (), Target::Global)
+                                .ok();
+
+                            continue;
+                        }
+
+                        // the clock only moves on the first sample of a frame, after a jump that's
+                        // the first one at the new position
+                        let started_beat = match &mut beat_clock {
+                            _ if sample_channel != 0 => None,
+                            Some(clock) => {
+                                let beats_per_second = tempo
+                                    .beats_per_second(clock.beat as usize, engine.beats_per_second);
+
+                                clock.advance(beats_per_second, sample_rate)
+                            }
+                            None => {
+                                let clock = BeatClock::at(position, sample_rate);
+                                let started_beat = clock.on_beat(beats_per_second);
+
+                                beat_clock = Some(clock);
+                                started_beat
+                            }
+                        };
+
+                        if let Some(started_beat) = started_beat {
+                            if engine.recording_clip.is_some() && metronome {
+                                let bar = (started_beat as usize)
+                                    .is_multiple_of(engine.arrangement_index.beats_per_bar.max(1));
+
+                                click = Some((bar, play_frame));
+                            }
+                        }
+
+                        if let Some((bar, start_frame)) = click {
+                            let samples = if bar { &bar_click } else { &beat_click };
+
+                            // a seek back before the start also ends the click
+                            match play_frame
+                                .checked_sub(start_frame)
+                                .and_then(|frame| samples.get(frame as usize))
                             {
-                                for source_index in source_indices {
-                                    let offset = (source_index.beats_offset as f64
-                                        * sample_rate as f64
-                                        / self.beats_per_second)
-                                        as i64;
-
-                                    if beat_frame as i64 + offset < 0 {
-                                        continue;
-                                    }
-
-                                    if let Some(source_sample) =
-                                        self.sources[&source_index.audio_source_id].get_sample(
-                                            beat_frame + offset as u32,
-                                            channel,
-                                            self.beats_per_second,
-                                        )
-                                    {
-                                        *sample += source_sample;
-                                    }
-                                }
+                                Some(click_sample) => *sample += click_sample * gain,
+                                None => click = None,
                             }
+                        }
+
+                        *sample += engine.mixer.mix(
+                            &engine.arrangement_index,
+                            &engine.sources,
+                            position,
+                            beats_per_second,
+                            sample_channel,
+                            sample_rate,
+                        ) * gain;
+
+                        // once per frame, not once per channel
+                        if sample_channel == 0 && play_frame % (sample_rate / 30) == 0 {
+                            engine
+                                .event_sink
+                                .submit_command(
+                                    ARRANGEMENT_UPDATE_PLAY_LINE,
+                                    play_frame as f64 / sample_rate as f64,
+                                    Target::Widget(crate::ARRANGEMENT_WIDGET_ID),
+                                )
+                                .ok();
+
+                            if let Some(recording_clip) = &engine.recording_clip {
+                                let frames = recording_clip.len_samples() / channels as usize;
 
-                            if play_frame % (sample_rate / 30) == 0 {
-                                self.event_sink
+                                engine
+                                    .event_sink
                                     .submit_command(
-                                        ARRANGEMENT_UPDATE_PLAY_LINE,
-                                        play_frame as f64 / sample_rate as f64,
-                                        Target::Widget(crate::ARRANGEMENT_WIDGET_ID),
+                                        AUDIO_ENGINE_RECORDED,
+                                        frames as f64 / sample_rate as f64,
+                                        Target::Global,
                                     )
-                                    .unwrap();
+                                    .ok();
                             }
                         }
                     }
-                },
-                |err| {
-                    error!("{}", err);
-                },
-            )?;
+                }
+
+                // how much of the time the buffer lasts it took to fill, past all of it the
+                // output drops out
+                if buffer_frames > 0 {
+                    let buffer_seconds = buffer_frames as f64 / sample_rate as f64;
+
+                    peak_load =
+                        peak_load.max(callback_start.elapsed().as_secs_f64() / buffer_seconds);
+                    load_frames += buffer_frames;
+
+                    if load_frames >= load_report_frames {
+                        engine
+                            .event_sink
+                            .submit_command(AUDIO_ENGINE_LOAD, peak_load, Target::Global)
+                            .ok();
+                        engine
+                            .event_sink
+                            .submit_command(AUDIO_ENGINE_LATENCY, latency, Target::Global)
+                            .ok();
+
+                        peak_load = 0.0;
+                        load_frames = 0;
+                    }
+                }
+            },
+            stream_failed(failed),
+        )?;
+
+        // the output callback locks the engine
+        drop(this);
 
+        if let Some(input_stream) = &input_stream {
             input_stream.play()?;
-            output_stream.play()?;
+        }
+        output_stream.play()?;
+
+        Ok((input_stream, output_stream))
+    }
+}
 
-            std::thread::park();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            Ok(())
-        });
+    #[test]
+    fn beat_clock_triggers_on_time() {
+        for &sample_rate in &[22_050, 44_100, 48_000, 96_000] {
+            for &beats_per_minute in &[60.0, 97.0, 120.0, 174.5] {
+                let beats_per_second = beats_per_minute / 60.0;
+                let beat_len = sample_rate as u64 * BEAT_CLOCK_UNITS as u64;
+                let step = BeatClock::step(beats_per_second);
+
+                let mut clock = BeatClock::at(0.0, sample_rate);
+                assert_eq!(clock.on_beat(beats_per_second), Some(0));
+
+                let frames = sample_rate as u64 * 60;
+                let mut triggers = 0;
+
+                for frame in 1..=frames {
+                    if let Some(beat) = clock.advance(beats_per_second, sample_rate) {
+                        triggers += 1;
+
+                        // the first frame at or past the exact start of the beat
+                        assert_eq!(beat, triggers);
+                        assert_eq!(frame, (beat * beat_len).div_ceil(step));
+                        assert_eq!(clock.on_beat(beats_per_second), Some(beat));
+                    } else {
+                        assert_eq!(clock.on_beat(beats_per_second), None);
+                    }
+                }
+
+                // a minute holds the tempo in beats, give or take the one in progress
+                assert!(
+                    (triggers as f64 - beats_per_minute).abs() <= 1.0,
+                    "{} beats at {} bpm and {} Hz",
+                    triggers,
+                    beats_per_minute,
+                    sample_rate,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn beat_clock_starts_mid_beat() {
+        let mut clock = BeatClock::at(2.5, 48_000);
+        assert_eq!(clock.on_beat(2.0), None);
+
+        // half a beat at two beats a second is a quarter of a second
+        let frames = (1..=48_000)
+            .find(|_| clock.advance(2.0, 48_000).is_some())
+            .unwrap();
+
+        assert_eq!(frames, 12_000);
+        assert_eq!(clock.beat, 3);
+    }
+
+    #[test]
+    fn remix_keeps_whole_frames() {
+        // mono to stereo copies, stereo to mono averages
+        assert_eq!(remix_sample(&[0.5], 0, 2), 0.5);
+        assert_eq!(remix_sample(&[0.5], 1, 2), 0.5);
+        assert_eq!(remix_sample(&[0.5, -0.25], 0, 1), 0.125);
+
+        // the same layout passes through, more outputs repeat the inputs
+        assert_eq!(remix_sample(&[0.5, -0.25], 1, 2), -0.25);
+        assert_eq!(remix_sample(&[0.5, -0.25], 2, 4), 0.5);
+        assert_eq!(remix_sample(&[0.5, -0.25], 3, 4), -0.25);
+    }
+
+    #[test]
+    fn parallel_render_matches_mixing_frame_by_frame() {
+        const SAMPLE_RATE: u32 = 8000;
+        const BEATS_PER_SECOND: f64 = 2.0;
+
+        let mut sources: AudioSources = HashMap::new();
+        let mut audio_blocks = HashMap::new();
+
+        for (id, waveform) in [Waveform::Sine, Waveform::Saw].iter().enumerate() {
+            let oscillator = Oscillator::new(*waveform, 220.0, SAMPLE_RATE, 2, BEATS_PER_SECOND);
+            let audio_block =
+                crate::AudioBlock::new(AudioSourceID(id), oscillator.format(), BEATS_PER_SECOND);
+
+            sources.insert(AudioSourceID(id), Arc::new(oscillator));
+            audio_blocks.insert(crate::AudioBlockID(id), audio_block);
+        }
+
+        let block = |bounds: Range<usize>, id: usize| {
+            Block::new(
+                bounds,
+                crate::AudioBlockID(id),
+                sources[&AudioSourceID(id)].format(),
+            )
+        };
+
+        // a filter and the delay carry over between frames, so they show frames out of order
+        let mut arrangement = Arrangement::new();
+        arrangement.add_track();
+        {
+            let tracks = Arc::make_mut(&mut arrangement.tracks);
+            tracks[0].add_block(block(0..12, 0), 0);
+            tracks[0].add_block(block(11..24, 1), 1);
+            tracks[0].delay_send = 0.5;
+            tracks[1].add_block(block(4..20, 1), 0);
+            tracks[1].filter = Some(crate::filter::TrackFilter {
+                kind: crate::filter::FilterKind::LowPass,
+                cutoff: 500.0,
+            });
+        }
+
+        let index = arrangement.compile_index(&audio_blocks);
+        // more frames than one chunk per thread, so the render takes several windows
+        let threads = std::thread::available_parallelism().map_or(1, |threads| threads.get());
+        let len_frames = RENDER_CHUNK_FRAMES * threads * 2 + 100;
+        let at = |frame: usize| {
+            (
+                frame as f64 / SAMPLE_RATE as f64 * BEATS_PER_SECOND,
+                BEATS_PER_SECOND,
+            )
+        };
+
+        let rendered = render_frames(
+            &index,
+            &sources,
+            SAMPLE_RATE,
+            2,
+            len_frames,
+            Mixer::new(DelaySettings::default()),
+            at,
+        );
+
+        let mut mixer = Mixer::new(DelaySettings::default());
+        mixer.prepare(2, SAMPLE_RATE);
+
+        let mixed: Vec<f32> = (0..len_frames)
+            .flat_map(|frame| {
+                let (position, beats_per_second) = at(frame);
+
+                (0..2)
+                    .map(|channel| {
+                        mixer.mix(
+                            &index,
+                            &sources,
+                            position,
+                            beats_per_second,
+                            channel,
+                            SAMPLE_RATE,
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        assert_eq!(rendered.len(), len_frames * 2);
+        assert!(rendered.iter().any(|sample| *sample != 0.0));
+        assert!(rendered == mixed);
     }
 }
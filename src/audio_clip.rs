@@ -1,41 +1,360 @@
-use crate::{audio::*, widgets};
+use crate::{audio::*, peak_cache::PeakCache, widgets};
 use druid::{widget::*, *};
-use std::sync::Arc;
+use std::{f64::consts::PI, sync::Arc};
+
+/// The tempos [`AudioClip::estimate_bpm`] picks from.
+const MIN_ESTIMATED_BPM: f64 = 60.0;
+const MAX_ESTIMATED_BPM: f64 = 200.0;
+/// How many times a second the onset envelope is sampled.
+const ONSET_RATE: f64 = 200.0;
+
+/// Length of the grains a stretched clip is made of. Longer smears transients, shorter makes low
+/// notes rough.
+const STRETCH_GRAIN_SECONDS: f64 = 0.04;
 
 #[derive(Clone, Data)]
 pub struct AudioClip {
     format: AudioSourceFormat,
     samples: Arc<Vec<f32>>,
+    peaks: Arc<PeakCache>,
 }
 
 impl AudioClip {
     pub fn new(samples: Vec<f32>, format: AudioSourceFormat) -> Self {
-        Self {
+        let mut clip = Self {
             format,
             samples: Arc::new(samples),
-        }
+            peaks: Arc::new(PeakCache::default()),
+        };
+
+        clip.rebuild_peaks();
+
+        clip
     }
 
     pub fn empty(format: AudioSourceFormat) -> Self {
         Self {
             format,
             samples: Arc::new(Vec::new()),
+            peaks: Arc::new(PeakCache::default()),
         }
     }
 
+    /// Rebuilds the cached waveform, must be called after the samples are changed.
+    pub fn rebuild_peaks(&mut self) {
+        self.peaks = Arc::new(PeakCache::new(&self.samples, self.format.channels));
+    }
+
     pub fn append_sample(&mut self, sample: f32) {
         Arc::make_mut(&mut self.samples).push(sample);
         self.format.len_frames = self.samples.len() as u32 / self.format.channels;
     }
 
+    /// The sample at a fractional `frame`, linearly interpolated, silent outside the clip.
+    fn sample_between(&self, frame: f64, channel: u32) -> f32 {
+        let channels = self.format.channels.max(1) as usize;
+        let before = frame.floor();
+        let t = (frame - before) as f32;
+
+        let at = |frame: f64| {
+            if frame < 0.0 || frame >= self.format.len_frames as f64 {
+                return 0.0;
+            }
+
+            self.samples
+                .get(frame as usize * channels + channel as usize)
+                .copied()
+                .unwrap_or(0.0)
+        };
+
+        at(before) * (1.0 - t) + at(before + 1.0) * t
+    }
+
+    /// Drops the samples of a partial frame at the end of the clip, then fades in the first and
+    /// out the last 10 ms so a recording doesn't click where it starts, ends or loops.
     pub fn clean(&mut self) {
-        let len = self.samples.len();
-        Arc::make_mut(&mut self.samples).truncate(len - len % self.format.channels as usize);
+        let channels = self.format.channels.max(1) as usize;
+        let samples = Arc::make_mut(&mut self.samples);
+        let len = samples.len();
+        samples.truncate(len - len % channels);
+
+        let len_frames = samples.len() / channels;
+        // short clips get both fades over half the clip each, instead of overlapping
+        let fade_frames = (self.format.sample_rate as usize / 100).min(len_frames / 2);
+
+        for frame in 0..fade_frames {
+            let gain = frame as f32 / fade_frames as f32;
+
+            for channel in 0..channels {
+                samples[frame * channels + channel] *= gain;
+                samples[(len_frames - frame - 1) * channels + channel] *= gain;
+            }
+        }
+
+        self.format.len_frames = len_frames as u32;
+
+        self.rebuild_peaks();
+    }
+
+    /// Cuts the frames before the first and after the last frame where any channel exceeds
+    /// `threshold`. Returns false and leaves the clip untouched if nothing does.
+    pub fn trim_silence(&mut self, threshold: f32) -> bool {
+        let channels = self.format.channels.max(1) as usize;
+        let loud = |frame: &[f32]| frame.iter().any(|sample| sample.abs() > threshold);
+
+        let start = match self.samples.chunks(channels).position(loud) {
+            Some(start) => start,
+            None => return false,
+        };
+        let end = self.samples.chunks(channels).rposition(loud).unwrap() + 1;
+
+        self.samples = Arc::new(self.samples[start * channels..end * channels].to_vec());
+        self.format.len_frames = (end - start) as u32;
+
+        self.rebuild_peaks();
+
+        true
+    }
+
+    /// Scales the clip so the loudest sample is at full scale. Returns false and leaves the clip
+    /// untouched if it's silent.
+    pub fn normalize(&mut self) -> bool {
+        let peak = self
+            .samples
+            .iter()
+            .fold(0.0_f32, |peak, sample| peak.max(sample.abs()));
+
+        if peak <= 0.0 {
+            return false;
+        }
+
+        for sample in Arc::make_mut(&mut self.samples) {
+            *sample /= peak;
+        }
+
+        self.rebuild_peaks();
+
+        true
+    }
+
+    /// Guesses the tempo of the clip, `None` if it's too short or has no rhythm to speak of.
+    ///
+    /// The rises in loudness are autocorrelated, the lag they repeat at best is the beat. Half
+    /// and double tempos repeat almost as well, so tempos far from the middle of the range count
+    /// for less. A clip that comes out within 2% of a whole number of beats, like a recorded
+    /// loop, is taken to be exactly that many beats long.
+    pub fn estimate_bpm(&self) -> Option<f64> {
+        let channels = self.format.channels.max(1) as usize;
+        let hop = (self.format.sample_rate as f64 / ONSET_RATE).max(1.0) as usize;
+
+        let energy = self
+            .samples
+            .chunks(hop * channels)
+            .map(|chunk| {
+                (chunk.iter().map(|sample| sample * sample).sum::<f32>() / chunk.len() as f32)
+                    .sqrt()
+            })
+            .collect::<Vec<_>>();
+
+        let mut flux = energy
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).max(0.0) as f64)
+            .collect::<Vec<_>>();
+        let mean = flux.iter().sum::<f64>() / flux.len().max(1) as f64;
+
+        for value in &mut flux {
+            *value -= mean;
+        }
+
+        let lag_at = |bpm: f64| 60.0 / bpm * ONSET_RATE;
+        let min_lag = lag_at(MAX_ESTIMATED_BPM).floor() as usize;
+        let max_lag = lag_at(MIN_ESTIMATED_BPM).ceil() as usize;
+
+        // a couple of beats at the slowest tempo, or there's nothing to compare
+        if flux.len() < max_lag * 2 {
+            return None;
+        }
+
+        let correlation = |lag: usize| {
+            flux.iter()
+                .zip(&flux[lag..])
+                .map(|(a, b)| a * b)
+                .sum::<f64>()
+                / (flux.len() - lag) as f64
+        };
+        let middle = (MIN_ESTIMATED_BPM * MAX_ESTIMATED_BPM).sqrt();
+        let weight = |lag: usize| {
+            let octaves = (60.0 / lag as f64 * ONSET_RATE / middle).log2();
+
+            (-octaves * octaves * 2.0).exp()
+        };
+
+        let correlations = (min_lag - 1..=max_lag + 1)
+            .map(correlation)
+            .collect::<Vec<_>>();
+        let (best, best_score) = (min_lag..=max_lag)
+            .map(|lag| (lag, correlations[lag - min_lag + 1] * weight(lag)))
+            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap())?;
+
+        if best_score <= 0.0 {
+            return None;
+        }
+
+        // a parabola through the neighbours finds the lag between the hops
+        let [before, at, after] = [
+            correlations[best - min_lag],
+            correlations[best - min_lag + 1],
+            correlations[best - min_lag + 2],
+        ];
+        let curvature = before - 2.0 * at + after;
+        let shift = if curvature < 0.0 {
+            (0.5 * (before - after) / curvature).clamp(-0.5, 0.5)
+        } else {
+            0.0
+        };
+
+        let bpm = 60.0 / (best as f64 + shift) * ONSET_RATE;
+        let beats = (self.len_seconds() * bpm / 60.0).round();
+        let whole_bpm = beats * 60.0 / self.len_seconds();
+
+        if beats >= 1.0 && (whole_bpm / bpm - 1.0).abs() < 0.02 {
+            Some(whole_bpm)
+        } else {
+            Some(bpm)
+        }
+    }
+
+    /// Sets the tempo the clip was made at, it plays slower or faster at any other tempo.
+    pub fn set_beats_per_second(&mut self, beats_per_second: f64) {
+        self.format.beats_per_second = beats_per_second;
+    }
+
+    /// Reverses the order of the frames, the channels in every frame keep their order.
+    pub fn reverse(&mut self) {
+        let channels = self.format.channels.max(1) as usize;
+
+        self.samples = Arc::new(
+            self.samples
+                .chunks(channels)
+                .rev()
+                .flatten()
+                .copied()
+                .collect(),
+        );
+
+        self.rebuild_peaks();
+    }
+
+    /// Returns a copy resampled and remixed to `sample_rate` and `channels`, used for clips
+    /// that didn't come from the engine.
+    pub fn converted(&self, sample_rate: u32, channels: u32) -> AudioClip {
+        let source_channels = self.format.channels.max(1) as usize;
+        let ratio = self.format.sample_rate as f64 / sample_rate as f64;
+        let len_frames = (self.format.len_frames as f64 / ratio).floor() as usize;
+
+        let frame_sample = |frame: usize, channel: usize| -> f32 {
+            if channels == 1 && source_channels > 1 {
+                // mix everything down for mono outputs
+                (0..source_channels)
+                    .map(|c| self.samples[frame * source_channels + c])
+                    .sum::<f32>()
+                    / source_channels as f32
+            } else {
+                // mono sources are copied to every channel, extra channels are dropped
+                self.samples[frame * source_channels + channel.min(source_channels - 1)]
+            }
+        };
+
+        let mut samples = Vec::with_capacity(len_frames * channels as usize);
+
+        for frame in 0..len_frames {
+            // linear interpolation is plenty for getting clips in at the right speed
+            let position = frame as f64 * ratio;
+            let index = position.floor() as usize;
+            let next = (index + 1).min(self.format.len_frames as usize - 1);
+            let fract = position.fract() as f32;
+
+            for channel in 0..channels as usize {
+                let a = frame_sample(index, channel);
+                let b = frame_sample(next, channel);
+
+                samples.push(a + (b - a) * fract);
+            }
+        }
+
+        AudioClip::new(
+            samples,
+            AudioSourceFormat {
+                sample_rate,
+                len_frames: len_frames as u32,
+                channels,
+                beats_per_second: self.format.beats_per_second,
+            },
+        )
+    }
+
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
     }
 
     pub fn len_samples(&self) -> usize {
         self.samples.len()
     }
+
+    fn window(
+        &self,
+        start_frame: u32,
+        len_frames: u32,
+        channel: u32,
+    ) -> impl Iterator<Item = f32> + '_ {
+        let channels = self.format.channels as usize;
+        let start = (start_frame as usize * channels + channel as usize).min(self.samples.len());
+        let end = ((start_frame + len_frames) as usize * channels).min(self.samples.len());
+
+        self.samples[start..end.max(start)]
+            .iter()
+            .step_by(channels)
+            .copied()
+    }
+
+    /// Returns the cached waveform level used for windows of `len_frames`, if the cache is up to
+    /// date.
+    fn peak_level(&self, len_frames: u32) -> Option<&crate::peak_cache::PeakLevel> {
+        if self.peaks.len_frames == self.format.len_frames {
+            self.peaks.level(len_frames)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the largest absolute amplitude of `channel` in the given window of frames.
+    pub fn window_peak(&self, start_frame: u32, len_frames: u32, channel: u32) -> f32 {
+        if let Some(level) = self.peak_level(len_frames) {
+            return level.peak(start_frame, len_frames, channel);
+        }
+
+        self.window(start_frame, len_frames, channel)
+            .fold(0.0, |peak, sample| peak.max(sample.abs()))
+    }
+
+    /// Returns the root mean square of `channel` in the given window of frames.
+    pub fn window_rms(&self, start_frame: u32, len_frames: u32, channel: u32) -> f32 {
+        if let Some(level) = self.peak_level(len_frames) {
+            return level.rms(start_frame, len_frames, channel);
+        }
+
+        let (sum, count) = self
+            .window(start_frame, len_frames, channel)
+            .fold((0.0, 0), |(sum, count), sample| {
+                (sum + sample * sample, count + 1)
+            });
+
+        if count == 0 {
+            0.0
+        } else {
+            (sum / count as f32).sqrt()
+        }
+    }
 }
 
 impl AudioSource for AudioClip {
@@ -49,29 +368,249 @@ impl AudioSource for AudioClip {
             .map(|x| *x)
     }
 
+    /// Overlap-add: the output is made of grains overlapping by half, each grain is a piece of the
+    /// clip played at `pitch` times its normal speed, taken from where the clip would be at the
+    /// start of the grain. Every frame is in two grains, faded with windows summing to one.
+    ///
+    /// The grains aren't aligned to the waveform like WSOLA would, that needs a search per grain
+    /// and the clip has nowhere to keep it, so sustained notes can sound a little phasey.
+    fn get_stretched_sample(
+        &self,
+        frame: u32,
+        channel: u32,
+        beats_per_second: f64,
+        pitch: f64,
+    ) -> Option<f32> {
+        let speed = beats_per_second / self.format.beats_per_second;
+
+        if (speed - 1.0).abs() < f64::EPSILON && (pitch - 1.0).abs() < f64::EPSILON {
+            return self.get_sample(frame, channel, beats_per_second);
+        }
+
+        if frame as f64 * speed >= self.format.len_frames as f64 {
+            return None;
+        }
+
+        // an even grain, or the windows wouldn't add up to one
+        let hop = ((STRETCH_GRAIN_SECONDS / 2.0 * self.format.sample_rate as f64) as i64).max(1);
+        let grain = hop * 2;
+        let frame = frame as i64;
+        let grain_index = frame / hop;
+
+        let mut sample = 0.0;
+
+        for grain_index in grain_index - 1..=grain_index {
+            let grain_start = grain_index * hop;
+            let in_grain = frame - grain_start;
+            let window = 0.5 - 0.5 * (2.0 * PI * in_grain as f64 / grain as f64).cos();
+            let source_frame = grain_start as f64 * speed + in_grain as f64 * pitch;
+
+            sample += self.sample_between(source_frame, channel) * window as f32;
+        }
+
+        Some(sample)
+    }
+
     fn format(&self) -> AudioSourceFormat {
         self.format.clone()
     }
 
+    fn trimmed(&self, threshold: f32) -> Option<Arc<dyn AudioSource + Send + Sync>> {
+        let mut clip = self.clone();
+
+        if clip.trim_silence(threshold) {
+            Some(Arc::new(clip))
+        } else {
+            None
+        }
+    }
+
+    fn normalized(&self) -> Option<Arc<dyn AudioSource + Send + Sync>> {
+        let mut clip = self.clone();
+
+        if clip.normalize() {
+            Some(Arc::new(clip))
+        } else {
+            None
+        }
+    }
+
+    fn reversed(&self) -> Option<Arc<dyn AudioSource + Send + Sync>> {
+        let mut clip = self.clone();
+        clip.reverse();
+
+        Some(Arc::new(clip))
+    }
+
     fn widget(&self) -> Box<dyn druid::Widget<(Arc<dyn AudioSource>, crate::AudioBlock)>> {
         Box::new(
             druid::widget::Flex::row()
                 .with_flex_child(widgets::audio_clip_editor::AudioClipEditor::new(), 1.0)
-                .lens(lens::Map::new(
-                    |data: &(Arc<dyn AudioSource>, crate::AudioBlock)| {
-                        // this is hell, but im also kinda proud of the solution, fuck me, why didn't i
-                        // just use a god dammed enum
-                        if (*data.0).type_id() == std::any::TypeId::of::<AudioClip>() {
-                            (unsafe { &*(&*data.0 as *const dyn AudioSource as *const Self) }.clone(), data.1.clone())
-                        } else {
-                            panic!("yeet");
-                        }
-                    },
-                    |data, val| {
-                        data.0 = Arc::new(val.0);
-                        data.1 = val.1;
-                    },
-                )),
+                .lens(source_lens::<Self>()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clip(samples: Vec<f32>, channels: u32) -> AudioClip {
+        let len_frames = samples.len() as u32 / channels;
+
+        AudioClip::new(
+            samples,
+            AudioSourceFormat {
+                sample_rate: 1000,
+                len_frames,
+                channels,
+                beats_per_second: 2.0,
+            },
         )
     }
+
+    #[test]
+    fn trim_silence_keeps_the_burst() {
+        let mut samples = vec![0.0; 2 * 300];
+        // the burst starts on the right channel and ends on the left one
+        samples[2 * 100 + 1] = 0.5;
+        samples[2 * 150] = -0.8;
+        samples[2 * 199] = 0.3;
+        // below the threshold, so it's silence
+        samples[2 * 250] = 0.01;
+
+        let mut clip = clip(samples.clone(), 2);
+
+        assert!(clip.trim_silence(0.02));
+        assert_eq!(clip.format.len_frames, 100);
+        assert_eq!(&clip.samples[..], &samples[2 * 100..2 * 200]);
+    }
+
+    #[test]
+    fn trim_silence_leaves_silence_alone() {
+        let mut clip = clip(vec![0.01; 200], 2);
+
+        assert!(!clip.trim_silence(0.02));
+        assert_eq!(clip.format.len_frames, 100);
+        assert_eq!(clip.samples.len(), 200);
+    }
+
+    #[test]
+    fn clean_drops_the_partial_frame() {
+        // left is positive and right negative, a shifted channel would flip the signs
+        let mut samples: Vec<f32> = (0..100).flat_map(|_| vec![1.0, -1.0]).collect();
+        samples.push(1.0);
+
+        let mut clip = clip(samples, 2);
+        clip.clean();
+
+        assert_eq!(clip.format.len_frames, 100);
+        assert_eq!(clip.samples().len(), 200);
+
+        for frame in clip.samples().chunks(2) {
+            assert!(frame[0] >= 0.0 && frame[1] <= 0.0);
+            assert_eq!(frame[0], -frame[1]);
+        }
+
+        assert_eq!(&clip.samples()[100..102], &[1.0, -1.0]);
+    }
+
+    #[test]
+    fn clean_fades_every_channel_of_a_frame_alike() {
+        for channels in 1..=2 {
+            let mut clip = clip(vec![1.0; 100 * channels as usize], channels);
+            clip.clean();
+
+            // at 1000 Hz the fades are 10 frames long
+            for (frame, samples) in clip.samples().chunks(channels as usize).enumerate() {
+                let gain = if frame < 10 {
+                    frame as f32 / 10.0
+                } else if frame >= 90 {
+                    (99 - frame) as f32 / 10.0
+                } else {
+                    1.0
+                };
+
+                assert!(
+                    samples.iter().all(|sample| *sample == gain),
+                    "frame {}",
+                    frame
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn clean_splits_a_short_clip_between_the_fades() {
+        let mut short = clip(vec![1.0; 5 * 2], 2);
+        short.clean();
+
+        assert_eq!(
+            short.samples(),
+            &[0.0, 0.0, 0.5, 0.5, 1.0, 1.0, 0.5, 0.5, 0.0, 0.0]
+        );
+
+        let mut single = clip(vec![0.5, -0.5], 2);
+        single.clean();
+
+        assert_eq!(single.format.len_frames, 1);
+        assert_eq!(single.samples(), &[0.5, -0.5]);
+    }
+
+    /// A mono click every beat at `bpm`, `seconds` long.
+    fn click_track(bpm: f64, seconds: f64) -> AudioClip {
+        let sample_rate = 8000;
+        let beat_frames = 60.0 / bpm * sample_rate as f64;
+        let samples: Vec<f32> = (0..(seconds * sample_rate as f64) as usize)
+            .map(|frame| {
+                let into_beat = frame as f64 % beat_frames;
+
+                // 5 ms of a decaying tone
+                if into_beat < 40.0 {
+                    (into_beat * 0.6).sin() as f32 * (1.0 - into_beat as f32 / 40.0)
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+
+        AudioClip::new(
+            samples,
+            AudioSourceFormat {
+                sample_rate,
+                len_frames: (seconds * sample_rate as f64) as u32,
+                channels: 1,
+                beats_per_second: 2.0,
+            },
+        )
+    }
+
+    #[test]
+    fn estimate_bpm_finds_the_clicks() {
+        for &bpm in &[90.0, 120.0, 140.0] {
+            // half a beat off a whole number of beats, so the estimate isn't snapped to the length
+            let estimate = click_track(bpm, 16.5 * 60.0 / bpm).estimate_bpm().unwrap();
+
+            assert!(
+                (estimate - bpm).abs() < 1.5,
+                "{} estimated as {}",
+                bpm,
+                estimate
+            );
+        }
+    }
+
+    #[test]
+    fn estimate_bpm_snaps_to_a_whole_loop() {
+        // 16 beats at 120 bpm
+        let estimate = click_track(120.0, 8.0).estimate_bpm().unwrap();
+
+        assert!((estimate - 120.0).abs() < 1e-9, "estimated as {}", estimate);
+    }
+
+    #[test]
+    fn estimate_bpm_needs_a_rhythm() {
+        assert_eq!(click_track(120.0, 1.0).estimate_bpm(), None);
+        assert_eq!(clip(vec![0.0; 8000], 1).estimate_bpm(), None);
+    }
 }
@@ -0,0 +1,159 @@
+//! The settings that survive between runs.
+//!
+//! Everything is optional, whatever is missing from the file keeps the value set by
+//! `settings::default`.
+
+use crate::{settings, wav};
+use druid::{Env, Size};
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+pub const DEFAULT_WINDOW_SIZE: (f64, f64) = (1000.0, 500.0);
+/// Share of the width the block list gets.
+pub const DEFAULT_BLOCK_LIST_SPLIT: f64 = 0.12;
+/// Share of the height the block editor gets, the arrangement gets the rest.
+pub const DEFAULT_EDITOR_SPLIT: f64 = 0.5;
+const MAX_RECENT_PROJECTS: usize = 10;
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub window_size: Option<(f64, f64)>,
+    pub scroll_speed: Option<f64>,
+    pub beat_size: Option<f64>,
+    pub beat_subdivision: Option<u64>,
+    pub track_height: Option<f64>,
+    /// How many beats adjacent blocks may overlap, there's no control for it in the window.
+    pub crossfade_beats: Option<u64>,
+    /// Name of the input device, the default device is used when it isn't found. Nothing in the
+    /// window picks a device, so it's only ever set by editing the file.
+    pub input_device: Option<String>,
+    /// Name of the output device, like `input_device`.
+    pub output_device: Option<String>,
+    /// The sample format of the last wav export.
+    pub export_format: Option<wav::SampleFormat>,
+    /// Whether the last import referenced the files instead of copying them.
+    pub reference_audio: Option<bool>,
+    /// Whether the last save collected the referenced files.
+    pub collect_files: Option<bool>,
+    /// The projects last opened or saved, most recent first.
+    pub recent_projects: Vec<PathBuf>,
+    /// Where the splitter between the block list and the rest of the window was left.
+    pub block_list_split: Option<f64>,
+    /// Where the splitter between the block editor and the arrangement was left.
+    pub editor_split: Option<f64>,
+}
+
+/// `musix/config.json` in the config directory of the platform.
+fn path() -> Option<PathBuf> {
+    let dir = if cfg!(target_os = "windows") {
+        std::env::var_os("APPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        std::env::var_os("HOME").map(|home| PathBuf::from(home).join("Library/Application Support"))
+    } else {
+        std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+    }?;
+
+    Some(dir.join("musix").join("config.json"))
+}
+
+impl Config {
+    /// Loads the config, a missing or broken file gives the defaults.
+    pub fn load() -> Self {
+        let path = match path() {
+            Some(path) => path,
+            None => return Self::default(),
+        };
+
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(_) => return Self::default(),
+        };
+
+        match serde_json::from_reader(BufReader::new(file)) {
+            Ok(config) => config,
+            Err(e) => {
+                log::warn!("ignoring broken config {}: {}", path.display(), e);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let path = path().ok_or("no config directory")?;
+
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        serde_json::to_writer_pretty(File::create(path)?, self)?;
+
+        Ok(())
+    }
+
+    pub fn window_size(&self) -> Size {
+        let (width, height) = self.window_size.unwrap_or(DEFAULT_WINDOW_SIZE);
+
+        // a size of nothing is as good as broken
+        if width >= 100.0 && height >= 100.0 {
+            Size::new(width, height)
+        } else {
+            DEFAULT_WINDOW_SIZE.into()
+        }
+    }
+
+    /// Where the block list and the block editor splitters were left, the defaults if they never
+    /// were.
+    pub fn splits(&self) -> (f64, f64) {
+        (
+            self.block_list_split.unwrap_or(DEFAULT_BLOCK_LIST_SPLIT),
+            self.editor_split.unwrap_or(DEFAULT_EDITOR_SPLIT),
+        )
+    }
+
+    /// Moves `path` to the front of the recent projects, dropping the oldest past the limit.
+    pub fn add_recent_project(&mut self, path: &Path) {
+        self.recent_projects.retain(|recent| recent != path);
+        self.recent_projects.insert(0, path.to_owned());
+        self.recent_projects.truncate(MAX_RECENT_PROJECTS);
+    }
+
+    /// Overrides the defaults in `env` with the stored settings.
+    pub fn apply(&self, env: &mut Env) {
+        if let Some(scroll_speed) = self.scroll_speed {
+            env.set(settings::ARRANGEMENT_SCROLL_SPEED, scroll_speed);
+        }
+
+        if let Some(beat_size) = self.beat_size {
+            env.set(settings::ARRANGEMENT_BEAT_SIZE, beat_size);
+        }
+
+        if let Some(beat_subdivision) = self.beat_subdivision {
+            env.set(settings::ARRANGEMENT_BEAT_SUBDIVISION, beat_subdivision);
+        }
+
+        if let Some(track_height) = self.track_height {
+            env.set(settings::ARRANGEMENT_TRACK_HEIGHT, track_height);
+        }
+
+        if let Some(crossfade_beats) = self.crossfade_beats {
+            env.set(settings::ARRANGEMENT_CROSSFADE_BEATS, crossfade_beats);
+        }
+    }
+
+    /// Stores the current settings from `env`.
+    pub fn capture(&mut self, env: &Env) {
+        self.scroll_speed = Some(env.get(settings::ARRANGEMENT_SCROLL_SPEED));
+        self.beat_size = Some(env.get(settings::ARRANGEMENT_BEAT_SIZE));
+        self.beat_subdivision = Some(env.get(settings::ARRANGEMENT_BEAT_SUBDIVISION));
+        self.track_height = Some(env.get(settings::ARRANGEMENT_TRACK_HEIGHT));
+        self.crossfade_beats = Some(env.get(settings::ARRANGEMENT_CROSSFADE_BEATS));
+    }
+}
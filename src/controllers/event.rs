@@ -1,12 +1,17 @@
 use druid::{widget::*, *};
 
+type EventHandler<T> = Box<dyn FnMut(&mut EventCtx, &Event, &mut T, &Env)>;
+
+/// Calls a closure with every event after the child has handled it.
 pub struct EventController<T> {
-    event_handler: fn(&mut EventCtx, &Event, &mut T, &Env),
+    event_handler: EventHandler<T>,
 }
 
 impl<T> EventController<T> {
-    pub fn new(event_handler: fn(&mut EventCtx, &Event, &mut T, &Env)) -> Self {
-        Self { event_handler }
+    pub fn new(event_handler: impl FnMut(&mut EventCtx, &Event, &mut T, &Env) + 'static) -> Self {
+        Self {
+            event_handler: Box::new(event_handler),
+        }
     }
 }
 
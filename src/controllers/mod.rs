@@ -1,2 +1,4 @@
 mod event;
+mod text_box;
 pub use event::*;
+pub use text_box::*;
@@ -0,0 +1,80 @@
+use crate::commands;
+use druid::{widget::*, *};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// How many text boxes have focus, counted by the boxes themselves since the window doesn't say
+/// when a removed widget had it.
+static FOCUSED_TEXT_BOXES: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether a text box has focus, hotkeys leave the keys to it while one does.
+pub fn text_box_has_focus() -> bool {
+    FOCUSED_TEXT_BOXES.load(Ordering::Relaxed) > 0
+}
+
+/// A text box that keeps the keys to itself while it has focus.
+pub fn text_box() -> ControllerHost<TextBox, TextBoxController> {
+    TextBox::new().controller(TextBoxController::default())
+}
+
+/// Keeps track of when the text box has focus, and gives it up on enter, escape or a click
+/// somewhere else.
+#[derive(Default)]
+pub struct TextBoxController {
+    focused: bool,
+}
+
+impl TextBoxController {
+    fn set_focused(&mut self, focused: bool) {
+        if focused && !self.focused {
+            FOCUSED_TEXT_BOXES.fetch_add(1, Ordering::Relaxed);
+        } else if !focused && self.focused {
+            FOCUSED_TEXT_BOXES.fetch_sub(1, Ordering::Relaxed);
+        }
+
+        self.focused = focused;
+    }
+}
+
+impl<T, W: Widget<T>> Controller<T, W> for TextBoxController {
+    fn event(&mut self, child: &mut W, ctx: &mut EventCtx, event: &Event, data: &mut T, env: &Env) {
+        child.event(ctx, event, data, env);
+
+        if !ctx.is_focused() {
+            return;
+        }
+
+        match event {
+            Event::KeyDown(key_event)
+                if key_event.key_code == KeyCode::Return
+                    || key_event.key_code == KeyCode::Escape =>
+            {
+                ctx.resign_focus();
+            }
+            Event::Command(cmd) if cmd.is(commands::GLOBAL_MOUSE_UP) && !ctx.is_hot() => {
+                ctx.resign_focus();
+            }
+            _ => (),
+        }
+    }
+
+    fn lifecycle(
+        &mut self,
+        child: &mut W,
+        ctx: &mut LifeCycleCtx,
+        event: &LifeCycle,
+        data: &T,
+        env: &Env,
+    ) {
+        if let LifeCycle::FocusChanged(focused) = event {
+            self.set_focused(*focused);
+        }
+
+        child.lifecycle(ctx, event, data, env);
+    }
+}
+
+impl Drop for TextBoxController {
+    fn drop(&mut self) {
+        self.set_focused(false);
+    }
+}
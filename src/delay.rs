@@ -0,0 +1,134 @@
+//! The echo every track can send to, shared by the whole arrangement.
+
+use druid::{Data, Lens};
+
+/// The longest delay the lines have room for.
+const MAX_DELAY_SECONDS: f64 = 4.0;
+/// Any more and the echoes would build up instead of dying out.
+pub const MAX_FEEDBACK: f32 = 0.95;
+
+#[derive(Clone, Copy, Debug, Data, Lens, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct DelaySettings {
+    /// Time between echoes, in beats so it follows the tempo.
+    pub beats: f64,
+    /// How much of every echo comes back as the next one.
+    pub feedback: f32,
+    /// Level of the echoes in the output.
+    pub wet: f32,
+}
+
+impl Default for DelaySettings {
+    fn default() -> Self {
+        Self {
+            beats: 0.75,
+            feedback: 0.35,
+            wet: 0.5,
+        }
+    }
+}
+
+/// One channel of the delay, a ring buffer holding the last `MAX_DELAY_SECONDS` of input.
+struct DelayLine {
+    buffer: Vec<f32>,
+    write: usize,
+}
+
+/// The delay lines of every channel, the echoes carry over from one sample to the next.
+#[derive(Default)]
+pub struct Delay {
+    lines: Vec<DelayLine>,
+    sample_rate: u32,
+}
+
+impl Delay {
+    /// Makes the lines for `channels` at `sample_rate`, so `process` never has to. The lines
+    /// there already keep their echoes unless the sample rate changes.
+    pub fn prepare(&mut self, channels: u32, sample_rate: u32) {
+        if self.sample_rate != sample_rate {
+            self.lines.clear();
+            self.sample_rate = sample_rate;
+        }
+
+        let len = (MAX_DELAY_SECONDS * sample_rate as f64) as usize;
+
+        while self.lines.len() < channels as usize {
+            self.lines.push(DelayLine {
+                buffer: vec![0.0; len],
+                write: 0,
+            });
+        }
+    }
+
+    /// Feeds `input` to the line of `channel`, returns the echo coming out of it. Without a line
+    /// prepared for the channel and sample rate there is no echo, allocating one here would stall
+    /// the audio thread.
+    pub fn process(
+        &mut self,
+        settings: DelaySettings,
+        channel: u32,
+        input: f32,
+        beats_per_second: f64,
+        sample_rate: u32,
+    ) -> f32 {
+        if self.sample_rate != sample_rate {
+            return 0.0;
+        }
+
+        let line = match self.lines.get_mut(channel as usize) {
+            Some(line) => line,
+            None => return 0.0,
+        };
+        let len = line.buffer.len();
+        let delay = ((settings.beats / beats_per_second * sample_rate as f64).round() as usize)
+            .clamp(1, len - 1);
+
+        let echo = line.buffer[(line.write + len - delay) % len];
+        line.buffer[line.write] = input + echo * settings.feedback.clamp(0.0, MAX_FEEDBACK);
+        line.write = (line.write + 1) % len;
+
+        echo * settings.wet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SETTINGS: DelaySettings = DelaySettings {
+        beats: 1.0,
+        feedback: 0.5,
+        wet: 1.0,
+    };
+
+    #[test]
+    fn unprepared_is_dry() {
+        let mut delay = Delay::default();
+
+        for _ in 0..2000 {
+            assert_eq!(delay.process(SETTINGS, 0, 1.0, 1.0, 1000), 0.0);
+        }
+    }
+
+    #[test]
+    fn echoes_after_the_delay() {
+        let mut delay = Delay::default();
+        delay.prepare(2, 1000);
+
+        // an impulse on the right channel, one beat at one beat a second is 1000 frames
+        let echoes: Vec<_> = (0..2500)
+            .map(|frame| {
+                let input = if frame == 0 { 1.0 } else { 0.0 };
+
+                delay.process(SETTINGS, 0, 0.0, 1.0, 1000);
+                delay.process(SETTINGS, 1, input, 1.0, 1000)
+            })
+            .collect();
+
+        assert_eq!(echoes[1000], 1.0);
+        assert_eq!(echoes[2000], 0.5);
+        assert_eq!(echoes.iter().filter(|echo| **echo != 0.0).count(), 2);
+
+        // another sample rate needs new lines
+        assert_eq!(delay.process(SETTINGS, 0, 1.0, 1.0, 2000), 0.0);
+    }
+}
@@ -1,38 +1,795 @@
-use crate::commands;
+use crate::{commands, config::Config, controllers, project};
 use druid::*;
-use std::sync::Arc;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-pub struct Deligate {}
+/// Imports the audio files in `paths`, directories are imported file by file. Files that aren't
+/// audio are skipped.
+fn import_paths(paths: &[PathBuf], data: &mut crate::AppState, beats_per_second: f64) {
+    let mut files = Vec::new();
 
-impl Default for Deligate {
-    fn default() -> Self {
-        Self {}
+    for path in paths {
+        if path.is_dir() {
+            match project::audio_files_in(path) {
+                Ok(dir_files) => files.extend(dir_files),
+                Err(e) => log::error!("failed to read {}: {}", path.display(), e),
+            }
+        } else if project::has_type(path, &project::WAV_FILE_TYPE) {
+            files.push(path.clone());
+        } else {
+            log::error!("{} is not an audio file", path.display());
+        }
+    }
+
+    let (imported, errors) = project::import_audio_files(&files, data, beats_per_second);
+
+    for (path, e) in &errors {
+        log::error!("failed to import {}: {}", path.display(), e);
+    }
+
+    log::info!("Imported {} of {} files", imported.len(), files.len());
+}
+
+/// Handles the hotkeys of the arrangement, returns true if `key_event` was used up.
+fn hotkey(
+    ctx: &mut DelegateCtx,
+    key_event: &KeyEvent,
+    data: &mut crate::AppState,
+    env: &Env,
+) -> bool {
+    let overlap = env.get(crate::settings::ARRANGEMENT_CROSSFADE_BEATS) as usize;
+
+    match key_event.key_code {
+        KeyCode::Space if !key_event.is_repeat => {
+            // the tempo is only in the env of the widgets
+            data.toggle_playing(data.beats_per_minute / 60.0);
+            true
+        }
+        KeyCode::ArrowLeft | KeyCode::ArrowRight => {
+            data.nudge_selected_block(key_event.key_code == KeyCode::ArrowRight, overlap)
+        }
+        KeyCode::ArrowUp | KeyCode::ArrowDown => {
+            data.move_selected_block(key_event.key_code == KeyCode::ArrowDown, overlap)
+        }
+        KeyCode::Delete | KeyCode::Backspace => data.remove_selected_blocks(),
+        KeyCode::KeyR if !key_event.is_repeat && data.selected_track.is_some() => {
+            data.toggle_armed_track(data.selected_track.unwrap());
+            true
+        }
+        KeyCode::KeyM => {
+            ctx.submit_command(
+                Command::new(commands::ARRANGEMENT_ADD_MARKER, ()),
+                Target::Widget(crate::ARRANGEMENT_WIDGET_ID),
+            );
+            true
+        }
+        _ => false,
+    }
+}
+
+pub struct Deligate {
+    config: Config,
+}
+
+impl Deligate {
+    pub fn new(config: Config) -> Self {
+        Self { config }
+    }
+
+    /// Puts `path` at the top of the recent projects and rebuilds the menu of `target` to show
+    /// it.
+    fn remember_project(&mut self, ctx: &mut DelegateCtx, target: Target, path: &Path) {
+        self.config.add_recent_project(path);
+
+        if let Target::Window(window) = target {
+            ctx.set_menu(
+                crate::make_menu::<crate::AppState>(&self.config.recent_projects),
+                window,
+            );
+        }
+    }
+
+    fn open_project(
+        &mut self,
+        ctx: &mut DelegateCtx,
+        target: Target,
+        path: &Path,
+        data: &mut crate::AppState,
+        beats_per_second: f64,
+    ) {
+        match project::open(path, data, beats_per_second) {
+            Ok(()) => {
+                log::info!("Opened {}", path.display());
+                self.remember_project(ctx, target, path);
+            }
+            Err(e) => log::error!("failed to open {}: {}", path.display(), e),
+        }
     }
 }
 
 impl druid::AppDelegate<crate::AppState> for Deligate {
-    fn command(
+    fn event(
+        &mut self,
+        ctx: &mut DelegateCtx,
+        _window_id: WindowId,
+        event: Event,
+        data: &mut crate::AppState,
+        env: &Env,
+    ) -> Option<Event> {
+        // while typing the keys are the text box's, undo included, and what's typed is recorded as
+        // one step once it lets go
+        let typing = controllers::text_box_has_focus();
+
+        match &event {
+            Event::WindowSize(size) => self.config.window_size = Some((size.width, size.height)),
+            // every new gesture starts a new step, so a drag undoes as a whole
+            Event::MouseDown(_) if !typing => {
+                data.history.record(&data.arrangement, &data.audio_blocks)
+            }
+            Event::KeyDown(key_event) if !typing => {
+                data.history.record(&data.arrangement, &data.audio_blocks);
+
+                // Cmd is Ctrl everywhere but on mac, the menu items handle the same keys when the
+                // platform gives them to the menu first
+                if HotKey::new(SysMods::Cmd, KeyCode::KeyZ).matches(key_event) {
+                    ctx.submit_command(druid::commands::UNDO, None);
+                    return None;
+                }
+
+                if HotKey::new(SysMods::CmdShift, KeyCode::KeyZ).matches(key_event) {
+                    ctx.submit_command(druid::commands::REDO, None);
+                    return None;
+                }
+
+                // key events only reach the focused widget, so the hotkeys are handled here
+                if hotkey(ctx, key_event, data, env) {
+                    return None;
+                }
+            }
+            _ => (),
+        }
+
+        Some(event)
+    }
+
+    fn window_removed(
         &mut self,
+        _id: WindowId,
+        data: &mut crate::AppState,
+        env: &Env,
         _ctx: &mut DelegateCtx,
-        _target: Target,
+    ) {
+        self.config.capture(env);
+        self.config.export_format = Some(data.export_format);
+        self.config.reference_audio = Some(data.reference_audio);
+        self.config.collect_files = Some(data.collect_files);
+
+        if let Err(e) = self.config.save() {
+            log::error!("failed to save config: {}", e);
+        }
+    }
+
+    fn command(
+        &mut self,
+        ctx: &mut DelegateCtx,
+        target: Target,
         cmd: &Command,
         data: &mut crate::AppState,
-        _env: &Env,
+        env: &Env,
     ) -> bool {
-        match cmd {
+        let handled = match cmd {
+            _ if cmd.is(druid::commands::UNDO) => {
+                if data
+                    .history
+                    .undo(&mut data.arrangement, &mut data.audio_blocks)
+                {
+                    log::info!("Undo");
+                }
+
+                false
+            }
+
+            _ if cmd.is(druid::commands::REDO) => {
+                if data
+                    .history
+                    .redo(&mut data.arrangement, &mut data.audio_blocks)
+                {
+                    log::info!("Redo");
+                }
+
+                false
+            }
+
+            _ if cmd.is(druid::commands::OPEN_FILE) => {
+                let path = cmd.get_unchecked(druid::commands::OPEN_FILE).path();
+                let beats_per_second = env.get(crate::settings::ARRANGEMENT_BEATS_PER_SECOND);
+
+                if path.is_dir() || project::has_type(path, &project::WAV_FILE_TYPE) {
+                    import_paths(&[path.to_owned()], data, beats_per_second);
+                } else if project::has_type(path, &project::MIDI_FILE_TYPE) {
+                    match project::import_midi(path, data, beats_per_second) {
+                        Ok(tracks) => {
+                            data.log_step(format!("Imported {} MIDI Tracks", tracks));
+                        }
+                        Err(e) => log::error!("failed to import {}: {}", path.display(), e),
+                    }
+                } else if !project::has_type(path, &project::PROJECT_FILE_TYPE) {
+                    log::error!("{} is neither an audio file nor a project", path.display());
+                } else {
+                    self.open_project(ctx, target, path, data, beats_per_second);
+                }
+
+                false
+            }
+
+            _ if cmd.is(commands::OPEN_PROJECT) => {
+                let path = cmd.get_unchecked(commands::OPEN_PROJECT);
+
+                self.open_project(
+                    ctx,
+                    target,
+                    path,
+                    data,
+                    env.get(crate::settings::ARRANGEMENT_BEATS_PER_SECOND),
+                );
+
+                false
+            }
+
+            _ if cmd.is(commands::SET_BLOCK_LIST_SPLIT) => {
+                self.config.block_list_split =
+                    Some(*cmd.get_unchecked(commands::SET_BLOCK_LIST_SPLIT));
+
+                false
+            }
+
+            _ if cmd.is(commands::SET_EDITOR_SPLIT) => {
+                self.config.editor_split = Some(*cmd.get_unchecked(commands::SET_EDITOR_SPLIT));
+
+                false
+            }
+
+            _ if cmd.is(commands::EXPORT_WAV_AS) => {
+                data.export_format = *cmd.get_unchecked(commands::EXPORT_WAV_AS);
+
+                // the panel belongs to a window, the menu sends its commands to the window it's in
+                if let Target::Window(window) = target {
+                    let options = FileDialogOptions::new()
+                        .allowed_types(vec![project::WAV_FILE_TYPE])
+                        .default_type(project::WAV_FILE_TYPE);
+
+                    ctx.submit_command(druid::commands::SHOW_SAVE_PANEL.with(options), window);
+                }
+
+                false
+            }
+
+            _ if cmd.is(commands::IMPORT_AUDIO_AS) => {
+                data.reference_audio = *cmd.get_unchecked(commands::IMPORT_AUDIO_AS);
+
+                if let Target::Window(window) = target {
+                    let options = FileDialogOptions::new()
+                        .allowed_types(vec![project::WAV_FILE_TYPE])
+                        .default_type(project::WAV_FILE_TYPE)
+                        .multi_selection();
+
+                    ctx.submit_command(druid::commands::SHOW_OPEN_PANEL.with(options), window);
+                }
+
+                false
+            }
+
+            _ if cmd.is(commands::SAVE_PROJECT_AS) => {
+                data.collect_files = *cmd.get_unchecked(commands::SAVE_PROJECT_AS);
+
+                if let Target::Window(window) = target {
+                    let options = FileDialogOptions::new()
+                        .allowed_types(vec![project::PROJECT_FILE_TYPE])
+                        .default_type(project::PROJECT_FILE_TYPE);
+
+                    ctx.submit_command(druid::commands::SHOW_SAVE_PANEL.with(options), window);
+                }
+
+                false
+            }
+
+            _ if cmd.is(druid::commands::SAVE_FILE) => {
+                if let Some(file_info) = cmd.get_unchecked(druid::commands::SAVE_FILE) {
+                    let path = file_info.path();
+
+                    if project::has_type(path, &project::WAV_FILE_TYPE) {
+                        match project::export_wav(
+                            path,
+                            data,
+                            env.get(crate::settings::ARRANGEMENT_BEATS_PER_SECOND),
+                        ) {
+                            Ok(()) => log::info!("Exported {}", path.display()),
+                            Err(e) => log::error!("failed to export {}: {}", path.display(), e),
+                        }
+                    } else if project::has_type(path, &project::MIDI_FILE_TYPE) {
+                        match project::export_midi(
+                            path,
+                            data,
+                            env.get(crate::settings::ARRANGEMENT_BEATS_PER_SECOND),
+                        ) {
+                            Ok(skipped) => {
+                                if skipped > 0 {
+                                    log::warn!(
+                                        "left out {} blocks that aren't midi clips or samplers",
+                                        skipped
+                                    );
+                                }
+
+                                log::info!("Exported {}", path.display());
+                            }
+                            Err(e) => log::error!("failed to export {}: {}", path.display(), e),
+                        }
+                    } else {
+                        let path = project::with_extension(path, &project::PROJECT_FILE_TYPE);
+
+                        match project::save(&path, data) {
+                            Ok(()) => {
+                                log::info!("Saved {}", path.display());
+                                self.remember_project(ctx, target, &path);
+                            }
+                            Err(e) => log::error!("failed to save {}: {}", path.display(), e),
+                        }
+                    }
+                }
+
+                false
+            }
+
             _ if cmd.is(commands::ARRANGEMENT_ADD_TRACK) => {
                 data.arrangement.add_track();
 
-                log::info!("Added Track");
+                data.log_step("Added Track");
 
                 false
             }
 
             _ if cmd.is(commands::ARRANGEMENT_REMOVE_TRACK) => {
-                let index = cmd.get_unchecked(commands::ARRANGEMENT_REMOVE_TRACK);
-                data.arrangement.remove_track(*index);
+                let index = *cmd.get_unchecked(commands::ARRANGEMENT_REMOVE_TRACK);
+
+                // removing a track with blocks on it has to be confirmed in the top bar
+                if data.arrangement.tracks[index].blocks.is_empty() {
+                    data.arrangement.remove_track(index);
+                    data.removed_track = None;
+                    data.selected_block = None;
+                    data.selected_blocks = Arc::new(Vec::new());
+                    data.selected_track = None;
+                    data.set_armed_track(None);
+
+                    data.log_step(format!("Removed Track {}", index + 1));
+                } else {
+                    data.removed_track = Some(index);
+                }
+
+                false
+            }
+
+            _ if cmd.is(commands::ARRANGEMENT_CLEAR_TRACK) => {
+                let index = *cmd.get_unchecked(commands::ARRANGEMENT_CLEAR_TRACK);
+
+                if Arc::make_mut(&mut data.arrangement.tracks)[index].clear() {
+                    data.selected_block = None;
+                    data.selected_blocks = Arc::new(Vec::new());
+
+                    data.log_step(format!("Cleared Track {}", index + 1));
+                }
+
+                false
+            }
+
+            _ if cmd.is(commands::ARRANGEMENT_CLEAR) => {
+                if data.arrangement.clear() {
+                    data.selected_block = None;
+                    data.selected_blocks = Arc::new(Vec::new());
+
+                    data.log_step("Cleared Arrangement");
+                }
+
+                false
+            }
+
+            _ if cmd.is(commands::ARRANGEMENT_BOUNCE_TRACK) => {
+                let index = *cmd.get_unchecked(commands::ARRANGEMENT_BOUNCE_TRACK);
+
+                match data.bounce_track(
+                    index,
+                    env.get(crate::settings::ARRANGEMENT_BEATS_PER_SECOND),
+                ) {
+                    Ok(Some(_)) => data.log_step(format!("Bounced Track {}", index + 1)),
+                    Ok(None) => (),
+                    Err(e) => log::error!("failed to bounce track {}: {}", index, e),
+                }
+
+                false
+            }
+
+            _ if cmd.is(commands::ARRANGEMENT_UNFREEZE_TRACK) => {
+                let index = *cmd.get_unchecked(commands::ARRANGEMENT_UNFREEZE_TRACK);
+
+                if data.unfreeze_track(index) {
+                    data.log_step(format!("Unfroze Track {}", index + 1));
+                }
+
+                false
+            }
+
+            _ if cmd.is(commands::ARRANGEMENT_ARM_TRACK) => {
+                let index = *cmd.get_unchecked(commands::ARRANGEMENT_ARM_TRACK);
+
+                data.toggle_armed_track(index);
+
+                false
+            }
+
+            _ if cmd.is(commands::ARRANGEMENT_SET_TRACK_SEND) => {
+                let (index, send) = *cmd.get_unchecked(commands::ARRANGEMENT_SET_TRACK_SEND);
+
+                Arc::make_mut(&mut data.arrangement.tracks)[index].delay_send = send;
+
+                data.log_step(format!("Set Delay Send of Track {}", index + 1));
+
+                false
+            }
+
+            _ if cmd.is(commands::ARRANGEMENT_SET_TRACK_FILTER) => {
+                let (index, filter) = *cmd.get_unchecked(commands::ARRANGEMENT_SET_TRACK_FILTER);
 
-                log::info!("Removed Track {}", index);
+                Arc::make_mut(&mut data.arrangement.tracks)[index].filter = filter;
+
+                data.log_step(match filter {
+                    Some(_) => format!("Set Filter of Track {}", index + 1),
+                    None => format!("Removed Filter of Track {}", index + 1),
+                });
+
+                false
+            }
+
+            _ if cmd.is(commands::AUDIO_ENGINE_ERROR) => {
+                let error = cmd.get_unchecked(commands::AUDIO_ENGINE_ERROR);
+
+                data.audio_error = Some(error.clone());
+
+                false
+            }
+
+            _ if cmd.is(commands::AUDIO_ENGINE_RESTARTED) => {
+                data.audio_error = None;
+                data.has_input = *cmd.get_unchecked(commands::AUDIO_ENGINE_RESTARTED);
+
+                // the new streams start out stopped, a recording cut short is placed like any other
+                if data.playing || data.recording {
+                    data.pause_playing(env.get(crate::settings::ARRANGEMENT_BEATS_PER_SECOND));
+                }
+
+                data.audio_engine_handle.set_play_time(data.play_time);
+                data.update_monitoring();
+
+                log::info!("Audio engine restarted");
+
+                false
+            }
+
+            _ if cmd.is(commands::AUDIO_ENGINE_RECORDED) => {
+                // a late message from a recording that already stopped would restart the counter
+                if data.recording {
+                    data.recorded_time = *cmd.get_unchecked(commands::AUDIO_ENGINE_RECORDED);
+                }
+
+                false
+            }
+
+            _ if cmd.is(commands::AUDIO_ENGINE_LOAD) => {
+                data.audio_load = *cmd.get_unchecked(commands::AUDIO_ENGINE_LOAD);
+
+                false
+            }
+
+            _ if cmd.is(commands::AUDIO_ENGINE_LATENCY) => {
+                data.latency = *cmd.get_unchecked(commands::AUDIO_ENGINE_LATENCY);
+
+                false
+            }
+
+            _ if cmd.is(commands::AUDIO_ENGINE_REACHED_END) => {
+                // back to the start, playing again from the end would stop right away
+                if data.playing {
+                    data.stop_playing(env.get(crate::settings::ARRANGEMENT_BEATS_PER_SECOND));
+
+                    ctx.submit_command(
+                        Command::new(commands::ARRANGEMENT_UPDATE_PLAY_LINE, 0.0),
+                        Target::Widget(crate::ARRANGEMENT_WIDGET_ID),
+                    );
+                }
+
+                false
+            }
+
+            _ if cmd.is(commands::AUDIO_ENGINE_NO_INPUT) => {
+                data.has_input = false;
+                data.feedback = false;
+
+                false
+            }
+
+            _ if cmd.is(commands::TOGGLE_AUTOMATION) => {
+                data.show_automation = !data.show_automation;
+
+                false
+            }
+
+            _ if cmd.is(commands::TOGGLE_EFFECTS_PANEL) => {
+                data.show_effects = !data.show_effects;
+
+                false
+            }
+
+            _ if cmd.is(commands::TOGGLE_HISTORY_PANEL) => {
+                data.show_history = !data.show_history;
+
+                false
+            }
+
+            _ if cmd.is(commands::ADD_OSCILLATOR) => {
+                match data
+                    .audio_engine_handle
+                    .add_oscillator(crate::oscillator::Waveform::Sine, 440.0)
+                {
+                    Ok((id, format)) => {
+                        let audio_block_id = data.add_audio_block(
+                            id,
+                            format,
+                            env.get(crate::settings::ARRANGEMENT_BEATS_PER_SECOND),
+                        );
+                        data.set_audio_block_name(audio_block_id, "Oscillator");
+
+                        log::info!("Added Oscillator");
+                    }
+                    Err(e) => log::error!("failed to add oscillator: {}", e),
+                }
+
+                false
+            }
+
+            _ if cmd.is(commands::ADD_MIDI_CLIP) => {
+                let (sample_rate, channels) = match data.audio_engine_handle.output_format() {
+                    Some(format) => format,
+                    None => {
+                        log::error!("failed to add midi clip: audio engine isn't running");
+                        return false;
+                    }
+                };
+                let beats_per_second = env.get(crate::settings::ARRANGEMENT_BEATS_PER_SECOND);
+
+                let midi_clip = crate::midi_clip::MidiClip::new(
+                    crate::oscillator::Waveform::Saw,
+                    Vec::new(),
+                    crate::midi_clip::DEFAULT_LEN_BEATS,
+                    sample_rate,
+                    channels,
+                    beats_per_second,
+                );
+                let format = crate::audio::AudioSource::format(&midi_clip);
+
+                match data
+                    .audio_engine_handle
+                    .add_audio_source(Arc::new(midi_clip))
+                {
+                    Ok(audio_id) => {
+                        let audio_block_id =
+                            data.add_audio_block(audio_id, format, beats_per_second);
+                        data.set_audio_block_name(audio_block_id, "MIDI Clip");
+
+                        log::info!("Added MIDI Clip");
+                    }
+                    Err(e) => log::error!("failed to add midi clip: {}", e),
+                }
+
+                false
+            }
+
+            _ if cmd.is(commands::ADD_NOISE) => {
+                let (sample_rate, channels) = match data.audio_engine_handle.output_format() {
+                    Some(format) => format,
+                    None => {
+                        log::error!("failed to add noise: audio engine isn't running");
+                        return false;
+                    }
+                };
+                let beats_per_second = env.get(crate::settings::ARRANGEMENT_BEATS_PER_SECOND);
+                // a new seed every time, so two noise sources don't play the same noise
+                let seed = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|time| time.as_nanos() as u64)
+                    .unwrap_or_default();
+
+                let noise = crate::noise::Noise::new(
+                    crate::noise::NoiseKind::White,
+                    0.3,
+                    seed,
+                    sample_rate,
+                    channels,
+                    beats_per_second,
+                );
+                let format = crate::audio::AudioSource::format(&noise);
+
+                match data.audio_engine_handle.add_audio_source(Arc::new(noise)) {
+                    Ok(audio_id) => {
+                        let audio_block_id =
+                            data.add_audio_block(audio_id, format, beats_per_second);
+                        data.set_audio_block_name(audio_block_id, "Noise");
+
+                        log::info!("Added Noise");
+                    }
+                    Err(e) => log::error!("failed to add noise: {}", e),
+                }
+
+                false
+            }
+
+            _ if cmd.is(commands::ADD_SAMPLER) => {
+                let id = match data.selected_audio_block {
+                    Some(id) => id,
+                    None => return false,
+                };
+                let audio_block = &data.audio_blocks[&id];
+                let name = format!("{} Sampler", audio_block.name);
+
+                let sampler = data
+                    .audio_engine_handle
+                    .get_audio_source_clone(audio_block.audio_id)
+                    .ok()
+                    .and_then(|source| {
+                        crate::audio::downcast_source::<crate::audio_clip::AudioClip>(&*source)
+                            .map(|clip| crate::sampler::Sampler::new(clip.clone(), 1.0))
+                    });
+
+                let sampler = match sampler {
+                    Some(sampler) => sampler,
+                    None => {
+                        log::error!("only clips can be made into a sampler");
+                        return false;
+                    }
+                };
+
+                let format = crate::audio::AudioSource::format(&sampler);
+
+                match data.audio_engine_handle.add_audio_source(Arc::new(sampler)) {
+                    Ok(audio_id) => {
+                        let audio_block_id = data.add_audio_block(
+                            audio_id,
+                            format,
+                            env.get(crate::settings::ARRANGEMENT_BEATS_PER_SECOND),
+                        );
+                        // one trigger every beat to start with
+                        Arc::make_mut(&mut data.audio_blocks)
+                            .get_mut(&audio_block_id)
+                            .unwrap()
+                            .len_beats = 1;
+                        data.set_audio_block_name(audio_block_id, name);
+
+                        log::info!("Added Sampler");
+                    }
+                    Err(e) => log::error!("failed to add sampler: {}", e),
+                }
+
+                false
+            }
+
+            _ if cmd.is(commands::ARRANGEMENT_QUANTIZE_BLOCK) => {
+                let (track_index, block_index, division) =
+                    *cmd.get_unchecked(commands::ARRANGEMENT_QUANTIZE_BLOCK);
+
+                let track = &mut Arc::make_mut(&mut data.arrangement.tracks)[track_index];
+
+                if track.quantize_block(
+                    block_index,
+                    division.max(1),
+                    env.get(crate::settings::ARRANGEMENT_CROSSFADE_BEATS) as usize,
+                ) {
+                    data.log_step(format!("Quantized Block on Track {}", track_index + 1));
+                }
+
+                false
+            }
+
+            _ if cmd.is(commands::ARRANGEMENT_REMOVE_BLOCK) => {
+                let (track_index, block_index) =
+                    *cmd.get_unchecked(commands::ARRANGEMENT_REMOVE_BLOCK);
+
+                Arc::make_mut(&mut data.arrangement.tracks)[track_index]
+                    .remove_block_index(block_index);
+                data.log_step(format!("Removed Block on Track {}", track_index + 1));
+
+                false
+            }
+
+            _ if cmd.is(commands::ARRANGEMENT_REMOVE_SELECTED_BLOCKS) => {
+                data.remove_selected_blocks();
+
+                false
+            }
+
+            _ if cmd.is(commands::ARRANGEMENT_COLOR_SELECTED_BLOCKS) => {
+                let color = cmd.get_unchecked(commands::ARRANGEMENT_COLOR_SELECTED_BLOCKS);
+
+                if data.color_selected_blocks(color.clone()) {
+                    data.log_step(format!(
+                        "Changed Color of {} Blocks",
+                        data.selected_blocks.len()
+                    ));
+                }
+
+                false
+            }
+
+            _ if cmd.is(commands::ARRANGEMENT_DUPLICATE_BLOCK) => {
+                let (track_index, block_index) =
+                    *cmd.get_unchecked(commands::ARRANGEMENT_DUPLICATE_BLOCK);
+
+                let track = &mut Arc::make_mut(&mut data.arrangement.tracks)[track_index];
+
+                if track
+                    .duplicate_block(
+                        block_index,
+                        env.get(crate::settings::ARRANGEMENT_CROSSFADE_BEATS) as usize,
+                    )
+                    .is_some()
+                {
+                    data.log_step(format!("Duplicated Block on Track {}", track_index + 1));
+                } else {
+                    log::info!("no room to duplicate the block");
+                }
+
+                false
+            }
+
+            _ if cmd.is(commands::ARRANGEMENT_SPLIT_BLOCK) => {
+                let (track_index, block_index, beat) =
+                    *cmd.get_unchecked(commands::ARRANGEMENT_SPLIT_BLOCK);
+
+                if data.split_block(track_index, block_index, beat) {
+                    data.log_step(format!("Split Block on Track {}", track_index + 1));
+                }
+
+                false
+            }
+
+            _ if cmd.is(commands::ARRANGEMENT_NORMALIZE_BLOCK) => {
+                let (track_index, block_index) =
+                    *cmd.get_unchecked(commands::ARRANGEMENT_NORMALIZE_BLOCK);
+
+                match data.edit_block_source(track_index, block_index, "Normalized", |source| {
+                    source.normalized()
+                }) {
+                    Ok(true) => {
+                        data.log_step(format!("Normalized Block on Track {}", track_index + 1))
+                    }
+                    Ok(false) => log::info!("the block can't be normalized"),
+                    Err(e) => log::error!("failed to normalize block: {}", e),
+                }
+
+                false
+            }
+
+            _ if cmd.is(commands::ARRANGEMENT_REVERSE_BLOCK) => {
+                let (track_index, block_index) =
+                    *cmd.get_unchecked(commands::ARRANGEMENT_REVERSE_BLOCK);
+
+                match data.edit_block_source(track_index, block_index, "Reversed", |source| {
+                    source.reversed()
+                }) {
+                    Ok(true) => {
+                        data.log_step(format!("Reversed Block on Track {}", track_index + 1))
+                    }
+                    Ok(false) => log::info!("the block can't be reversed"),
+                    Err(e) => log::error!("failed to reverse block: {}", e),
+                }
 
                 false
             }
@@ -41,6 +798,18 @@ impl druid::AppDelegate<crate::AppState> for Deligate {
                 let time = cmd.get_unchecked(commands::AUDIO_ENGINE_SET_PLAY_TIME);
 
                 data.audio_engine_handle.set_play_time(*time);
+                data.play_time = *time;
+
+                false
+            }
+
+            _ if cmd.is(commands::SET_AUDIO_BLOCK_COLOR) => {
+                let (id, color) = cmd.get_unchecked(commands::SET_AUDIO_BLOCK_COLOR);
+
+                // picking the same color again doesn't make a step
+                if data.set_audio_block_color(*id, color.clone()) {
+                    data.log_step(format!("Changed Color of {}", data.audio_blocks[id].name));
+                }
 
                 false
             }
@@ -51,10 +820,60 @@ impl druid::AppDelegate<crate::AppState> for Deligate {
                 data.selected_audio_block = Some(*id);
                 let audio_blocks = &data.audio_blocks[id];
 
-                data.selected_audio_source_clone = Some(
-                    data.audio_engine_handle
-                        .get_audio_source_clone(audio_blocks.audio_id),
-                );
+                data.selected_audio_source_clone = match data
+                    .audio_engine_handle
+                    .get_audio_source_clone(audio_blocks.audio_id)
+                {
+                    Ok(source) => Some(source),
+                    Err(e) => {
+                        log::error!("failed to get audio source: {}", e);
+                        None
+                    }
+                };
+
+                false
+            }
+
+            _ if cmd.is(commands::TRIM_AUDIO_BLOCK) => {
+                let id = *cmd.get_unchecked(commands::TRIM_AUDIO_BLOCK);
+                let threshold = env.get(crate::settings::AUDIO_CLIP_TRIM_THRESHOLD) as f32;
+                let name = data.audio_blocks[&id].name.clone();
+
+                match data.edit_audio_block_source(
+                    id,
+                    "Trimmed",
+                    env.get(crate::settings::ARRANGEMENT_BEATS_PER_SECOND),
+                    |source| source.trimmed(threshold),
+                ) {
+                    Ok(true) => data.log_step(format!("Trimmed {}", name)),
+                    Ok(false) => log::info!("nothing to trim from {}", name),
+                    Err(e) => log::error!("failed to trim audio source: {}", e),
+                }
+
+                false
+            }
+
+            _ if cmd.is(commands::ESTIMATE_AUDIO_BLOCK_TEMPO) => {
+                let id = *cmd.get_unchecked(commands::ESTIMATE_AUDIO_BLOCK_TEMPO);
+                let audio_id = data.audio_blocks[&id].audio_id;
+
+                match data.audio_engine_handle.get_audio_source_clone(audio_id) {
+                    Ok(source) => {
+                        let estimated =
+                            crate::audio::downcast_source::<crate::audio_clip::AudioClip>(&*source)
+                                .and_then(|clip| clip.estimate_bpm());
+
+                        match estimated {
+                            Some(beats_per_minute) => {
+                                log::info!("Estimated {} bpm for {:?}", beats_per_minute, id);
+
+                                data.estimated_tempo = Some((id, beats_per_minute));
+                            }
+                            None => log::warn!("couldn't estimate the tempo of {:?}", id),
+                        }
+                    }
+                    Err(e) => log::error!("failed to get audio source: {}", e),
+                }
 
                 false
             }
@@ -62,19 +881,18 @@ impl druid::AppDelegate<crate::AppState> for Deligate {
             _ if cmd.is(commands::REMOVE_AUDIO_BLOCK) => {
                 let id = cmd.get_unchecked(commands::REMOVE_AUDIO_BLOCK);
 
-                if data.selected_audio_block == Some(*id) {
-                    data.selected_audio_block = None;
-                    data.selected_audio_source_clone = None;
-                }
+                let name = data.remove_audio_block(*id);
 
-                Arc::make_mut(&mut data.shown_audio_blocks).retain(|x| x != id);
-                Arc::make_mut(&mut data.audio_blocks).remove(id);
-                data.arrangement.remove_audio_block(*id);
+                data.log_step(format!("Removed {}", name.unwrap_or_default()));
 
                 false
             }
 
             _ => true,
-        }
+        };
+
+        data.history.record(&data.arrangement, &data.audio_blocks);
+
+        handled
     }
 }
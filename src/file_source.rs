@@ -0,0 +1,335 @@
+//! Audio played straight from a wav file, read as it's needed instead of loaded up front.
+
+use crate::{audio::*, wav, AudioBlock};
+use druid::{widget::*, *};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{mpsc::SyncSender, Arc, Mutex, PoisonError, TryLockError},
+};
+
+/// How many frames of the file are read at a time.
+const CHUNK_FRAMES: u32 = 1 << 15;
+/// How many chunks are kept in memory, the one used least recently goes first.
+const CACHED_CHUNKS: usize = 8;
+/// Read ahead requests past this many waiting are dropped, the chunk is asked for again.
+const PREFETCH_QUEUE_LEN: usize = 4;
+
+/// The chunks of the file in memory.
+#[derive(Default)]
+struct ChunkCache {
+    /// The index of the chunk, its samples and when it was last used, counted in `uses`.
+    chunks: Vec<(u32, Vec<f32>, u64)>,
+    uses: u64,
+    /// The chunk last asked to be read ahead, so it's only asked for once until it's read.
+    requested: Option<u32>,
+}
+
+impl ChunkCache {
+    fn contains(&self, index: u32) -> bool {
+        self.chunks.iter().any(|chunk| chunk.0 == index)
+    }
+
+    fn get(&mut self, index: u32) -> Option<&[f32]> {
+        self.uses += 1;
+        let uses = self.uses;
+
+        self.chunks
+            .iter_mut()
+            .find(|chunk| chunk.0 == index)
+            .map(|chunk| {
+                chunk.2 = uses;
+                &chunk.1[..]
+            })
+    }
+
+    fn insert(&mut self, index: u32, samples: Vec<f32>) {
+        if self.contains(index) {
+            return;
+        }
+
+        if self.chunks.len() >= CACHED_CHUNKS {
+            if let Some(oldest) = (0..self.chunks.len()).min_by_key(|i| self.chunks[*i].2) {
+                self.chunks.swap_remove(oldest);
+            }
+        }
+
+        self.uses += 1;
+        self.chunks.push((index, samples, self.uses));
+    }
+}
+
+/// What every copy of the source shares, with the thread reading ahead.
+struct Shared {
+    reader: Mutex<wav::WavReader>,
+    cache: Mutex<ChunkCache>,
+}
+
+impl Shared {
+    /// A chunk that can't be read is empty, and plays as silence.
+    fn read_chunk(&self, index: u32) -> Vec<f32> {
+        let mut reader = self.reader.lock().unwrap_or_else(PoisonError::into_inner);
+
+        reader
+            .read_frames(index * CHUNK_FRAMES, CHUNK_FRAMES)
+            .unwrap_or_else(|e| {
+                log::error!("failed to read audio file: {}", e);
+                Vec::new()
+            })
+    }
+}
+
+/// A wav file played without holding its samples, only the chunks around where it's playing are
+/// kept in memory.
+///
+/// The file is resampled and remixed to the output format as it's played. While playing the next
+/// chunk is read ahead on a thread of its own, the output callback never waits for it: a chunk
+/// that isn't in memory yet, like right after seeking, plays as silence until it's been read.
+/// Renders read what's missing themselves.
+#[derive(Clone)]
+pub struct FileSource {
+    path: PathBuf,
+    format: AudioSourceFormat,
+    file_format: wav::WavFormat,
+    file_len_frames: u32,
+    shared: Arc<Shared>,
+    /// The thread reading ahead stops once every copy of the source is gone.
+    prefetch: SyncSender<u32>,
+}
+
+impl FileSource {
+    pub fn open(
+        path: &Path,
+        sample_rate: u32,
+        channels: u32,
+        beats_per_second: f64,
+    ) -> io::Result<Self> {
+        let reader = wav::WavReader::open(path)?;
+        let file_format = reader.format();
+        let file_len_frames = reader.len_frames();
+        let ratio = file_format.sample_rate as f64 / sample_rate as f64;
+
+        let shared = Arc::new(Shared {
+            reader: Mutex::new(reader),
+            cache: Mutex::new(ChunkCache::default()),
+        });
+
+        // the start is where playback starts most often
+        let first = shared.read_chunk(0);
+        shared
+            .cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(0, first);
+
+        let (prefetch, requests) = std::sync::mpsc::sync_channel::<u32>(PREFETCH_QUEUE_LEN);
+        let reading = shared.clone();
+
+        std::thread::spawn(move || {
+            for index in requests {
+                let cached = reading
+                    .cache
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .contains(index);
+
+                // read without holding the cache, the audio thread keeps using it meanwhile
+                if !cached {
+                    let samples = reading.read_chunk(index);
+                    let mut cache = reading.cache.lock().unwrap_or_else(PoisonError::into_inner);
+
+                    cache.insert(index, samples);
+
+                    if cache.requested == Some(index) {
+                        cache.requested = None;
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            path: path.to_owned(),
+            format: AudioSourceFormat {
+                sample_rate,
+                len_frames: (file_len_frames as f64 / ratio).floor() as u32,
+                channels,
+                beats_per_second,
+            },
+            file_format,
+            file_len_frames,
+            shared,
+            prefetch,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The sample of the file at `file_frame`, remixed for `channel` of the output.
+    fn file_sample(&self, file_frame: u32, channel: u32) -> Option<f32> {
+        if file_frame >= self.file_len_frames {
+            return None;
+        }
+
+        let index = file_frame / CHUNK_FRAMES;
+        let file_channels = self.file_format.channels as usize;
+        let at = (file_frame % CHUNK_FRAMES) as usize * file_channels;
+
+        let output_thread = is_output_thread();
+
+        let mut cache = if output_thread {
+            match self.shared.cache.try_lock() {
+                Ok(cache) => cache,
+                Err(TryLockError::Poisoned(e)) => e.into_inner(),
+                // the thread reading ahead is putting a chunk in
+                Err(TryLockError::WouldBlock) => return Some(0.0),
+            }
+        } else {
+            self.shared
+                .cache
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+        };
+
+        if !cache.contains(index) {
+            if output_thread {
+                self.request(&mut cache, index);
+                return Some(0.0);
+            }
+
+            // like the thread reading ahead, don't hold the cache while reading
+            drop(cache);
+            let samples = self.shared.read_chunk(index);
+            cache = self
+                .shared
+                .cache
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            cache.insert(index, samples);
+        }
+
+        // halfway through a chunk is early enough to have the next one by its end
+        if file_frame % CHUNK_FRAMES >= CHUNK_FRAMES / 2 && !cache.contains(index + 1) {
+            self.request(&mut cache, index + 1);
+        }
+
+        cache
+            .get(index)?
+            .get(at..at + file_channels)
+            .map(|frame| remix_sample(frame, channel as usize, self.format.channels as usize))
+    }
+
+    /// Asks the thread reading ahead for the chunk at `index`, if it isn't asked for already.
+    fn request(&self, cache: &mut ChunkCache, index: u32) {
+        if cache.requested != Some(index) && self.prefetch.try_send(index).is_ok() {
+            cache.requested = Some(index);
+        }
+    }
+}
+
+impl AudioSource for FileSource {
+    fn get_sample(&self, frame: u32, channel: u32, beats_per_second: f64) -> Option<f32> {
+        // like a clip it plays faster at a faster tempo
+        let frame = frame as f64 * (beats_per_second / self.format.beats_per_second);
+
+        if frame >= self.format.len_frames as f64 {
+            return None;
+        }
+
+        let position = frame * self.file_format.sample_rate as f64 / self.format.sample_rate as f64;
+        let index = position.floor() as u32;
+        let fract = position.fract() as f32;
+
+        let a = self.file_sample(index, channel)?;
+        let b = self.file_sample(index + 1, channel).unwrap_or(a);
+
+        Some(a + (b - a) * fract)
+    }
+
+    fn format(&self) -> AudioSourceFormat {
+        self.format.clone()
+    }
+
+    fn widget(&self) -> Box<dyn druid::Widget<(Arc<dyn AudioSource>, AudioBlock)>> {
+        Box::new(
+            Label::new(|data: &(Arc<dyn AudioSource>, AudioBlock), _env: &Env| {
+                match downcast_source::<Self>(&*data.0) {
+                    Some(source) => format!(
+                        "Played from {}, {:.1} seconds",
+                        source.path.display(),
+                        source.len_seconds()
+                    ),
+                    None => String::new(),
+                }
+            })
+            .align_left(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A file three chunks long, each sample is its frame over the length.
+    fn ramp_file(name: &str) -> (PathBuf, Vec<f32>) {
+        let frames = CHUNK_FRAMES * 3;
+        let samples = (0..frames)
+            .map(|i| i as f32 / frames as f32)
+            .collect::<Vec<_>>();
+        let path = std::env::temp_dir().join(format!("musix-{}-{}.wav", name, std::process::id()));
+        let format = wav::WavFormat {
+            sample_rate: 1000,
+            channels: 1,
+        };
+
+        wav::write(&path, format, &samples, wav::SampleFormat::Float32).unwrap();
+        (path, samples)
+    }
+
+    #[test]
+    fn renders_read_missing_chunks() {
+        let (path, samples) = ramp_file("render");
+        let source = FileSource::open(&path, 1000, 1, 2.0).unwrap();
+
+        for &frame in &[0, CHUNK_FRAMES * 2 + 7, CHUNK_FRAMES + 3] {
+            assert_eq!(
+                source.get_sample(frame, 0, 2.0),
+                Some(samples[frame as usize])
+            );
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn output_thread_plays_missing_chunks_as_silence() {
+        let (path, samples) = ramp_file("output");
+        let source = FileSource::open(&path, 1000, 1, 2.0).unwrap();
+        let frame = CHUNK_FRAMES * 2 + 7;
+
+        let read = std::thread::spawn(move || {
+            set_output_thread();
+
+            let first = source.get_sample(frame, 0, 2.0);
+
+            // the thread reading ahead has it after a while
+            let mut later = first;
+            for _ in 0..1000 {
+                later = source.get_sample(frame, 0, 2.0);
+                if later != Some(0.0) {
+                    break;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+
+            (first, later)
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(read, (Some(0.0), Some(samples[frame as usize])));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,80 @@
+//! The filter a track can run its mix through.
+
+use druid::Data;
+use std::f64::consts::PI;
+
+const MIN_CUTOFF: f64 = 20.0;
+
+/// Butterworth, no resonance peak at the cutoff.
+const Q: f64 = std::f64::consts::FRAC_1_SQRT_2;
+
+#[derive(Clone, Copy, Debug, Data, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum FilterKind {
+    LowPass,
+    HighPass,
+}
+
+#[derive(Clone, Copy, Debug, Data, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TrackFilter {
+    pub kind: FilterKind,
+    /// In Hz.
+    pub cutoff: f64,
+}
+
+impl TrackFilter {
+    /// Normalized biquad coefficients, `[b0, b1, b2, a1, a2]`, from the audio eq cookbook.
+    fn coefficients(&self, sample_rate: u32) -> [f64; 5] {
+        // past nyquist the filter blows up
+        let cutoff = self.cutoff.clamp(MIN_CUTOFF, sample_rate as f64 * 0.45);
+        let w0 = 2.0 * PI * cutoff / sample_rate as f64;
+        let cos = w0.cos();
+        let alpha = w0.sin() / (2.0 * Q);
+        let a0 = 1.0 + alpha;
+
+        let (b0, b1, b2) = match self.kind {
+            FilterKind::LowPass => ((1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0),
+            FilterKind::HighPass => ((1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0),
+        };
+
+        [
+            b0 / a0,
+            b1 / a0,
+            b2 / a0,
+            -2.0 * cos / a0,
+            (1.0 - alpha) / a0,
+        ]
+    }
+}
+
+/// A biquad for one channel, the state carries over from one sample to the next.
+#[derive(Default)]
+pub struct Biquad {
+    /// The settings and sample rate the coefficients were made for.
+    made_for: Option<(TrackFilter, u32)>,
+    coefficients: [f64; 5],
+    x1: f64,
+    x2: f64,
+    y1: f64,
+    y2: f64,
+}
+
+impl Biquad {
+    pub fn process(&mut self, filter: TrackFilter, sample_rate: u32, x: f32) -> f32 {
+        // changing the settings keeps the state, so moving the cutoff doesn't click
+        if self.made_for != Some((filter, sample_rate)) {
+            self.coefficients = filter.coefficients(sample_rate);
+            self.made_for = Some((filter, sample_rate));
+        }
+
+        let [b0, b1, b2, a1, a2] = self.coefficients;
+        let x = x as f64;
+        let y = b0 * x + b1 * self.x1 + b2 * self.x2 - a1 * self.y1 - a2 * self.y2;
+
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+
+        y as f32
+    }
+}
@@ -0,0 +1,331 @@
+use crate::{arrangement::Arrangement, AudioBlock, AudioBlockID};
+use druid::{Color, Data};
+use std::{collections::HashMap, sync::Arc};
+
+/// What the arrangement looked like after a change, and what the change was.
+#[derive(Clone)]
+pub struct Step {
+    pub arrangement: Arrangement,
+    /// Colors of the audio blocks, as rgba.
+    colors: Arc<HashMap<AudioBlockID, u32>>,
+    pub label: String,
+}
+
+impl Step {
+    fn new(
+        arrangement: &Arrangement,
+        audio_blocks: &HashMap<AudioBlockID, AudioBlock>,
+        label: String,
+    ) -> Self {
+        Self {
+            arrangement: arrangement.clone(),
+            colors: Arc::new(
+                audio_blocks
+                    .iter()
+                    .map(|(id, audio_block)| (*id, audio_block.color.as_rgba_u32()))
+                    .collect(),
+            ),
+            label,
+        }
+    }
+
+    /// Blocks that didn't exist when the step was made are left out, adding a block isn't a
+    /// change on its own.
+    fn same(
+        &self,
+        arrangement: &Arrangement,
+        audio_blocks: &HashMap<AudioBlockID, AudioBlock>,
+    ) -> bool {
+        self.arrangement.same(arrangement)
+            && audio_blocks
+                .iter()
+                .all(|(id, audio_block)| match self.colors.get(id) {
+                    Some(color) => *color == audio_block.color.as_rgba_u32(),
+                    None => true,
+                })
+    }
+}
+
+/// Undo and redo for the arrangement.
+///
+/// The arrangement only consists of `Arc`s, so every step is a cheap snapshot of the whole thing
+/// rather than a list of operations. The steps form a line, undo and redo just move along it.
+///
+/// Of the audio blocks only the colors are part of the history, blocks using one that has since
+/// been removed are dropped when a step is restored.
+#[derive(Clone)]
+pub struct History {
+    steps: Arc<Vec<Step>>,
+    position: usize,
+    /// Describes the change that is being made, used by the next step.
+    label: Option<String>,
+    /// Bumped whenever the steps change, so the ui knows when to rebuild the list.
+    version: usize,
+    /// While a group is open nothing is recorded, the whole group becomes one step.
+    grouping: bool,
+}
+
+impl Data for History {
+    fn same(&self, other: &Self) -> bool {
+        self.version == other.version && self.position == other.position
+    }
+}
+
+impl History {
+    pub fn new(
+        arrangement: &Arrangement,
+        audio_blocks: &HashMap<AudioBlockID, AudioBlock>,
+    ) -> Self {
+        Self {
+            steps: Arc::new(vec![Step::new(
+                arrangement,
+                audio_blocks,
+                "Start".to_owned(),
+            )]),
+            position: 0,
+            label: None,
+            version: 0,
+            grouping: false,
+        }
+    }
+
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+
+    /// Index of the step the arrangement is at.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn version(&self) -> usize {
+        self.version
+    }
+
+    /// Describes the change being made, the next step gets this label. When a step is made of
+    /// several changes the first one names it.
+    pub fn label(&mut self, label: impl Into<String>) {
+        if self.label.is_none() {
+            self.label = Some(label.into());
+        }
+    }
+
+    /// Starts a group of changes, like all the moves of a drag. Until `commit_group` every record
+    /// is ignored.
+    pub fn begin_group(&mut self) {
+        self.grouping = true;
+    }
+
+    /// Ends the group and makes one step of it, does nothing if no group is open.
+    pub fn commit_group(
+        &mut self,
+        arrangement: &Arrangement,
+        audio_blocks: &HashMap<AudioBlockID, AudioBlock>,
+    ) {
+        if self.grouping {
+            self.grouping = false;
+            self.record(arrangement, audio_blocks);
+        }
+    }
+
+    /// Makes a step of everything that changed since the last step, if anything did.
+    pub fn record(
+        &mut self,
+        arrangement: &Arrangement,
+        audio_blocks: &HashMap<AudioBlockID, AudioBlock>,
+    ) {
+        if self.grouping {
+            return;
+        }
+
+        let label = self.label.take();
+
+        if self.steps[self.position].same(arrangement, audio_blocks) {
+            return;
+        }
+
+        let steps = Arc::make_mut(&mut self.steps);
+        steps.truncate(self.position + 1);
+        steps.push(Step::new(
+            arrangement,
+            audio_blocks,
+            label.unwrap_or_else(|| "Edit".to_owned()),
+        ));
+
+        self.position += 1;
+        self.version += 1;
+    }
+
+    pub fn undo(
+        &mut self,
+        arrangement: &mut Arrangement,
+        audio_blocks: &mut Arc<HashMap<AudioBlockID, AudioBlock>>,
+    ) -> bool {
+        self.commit_group(arrangement, audio_blocks);
+        self.record(arrangement, audio_blocks);
+
+        if self.position > 0 {
+            self.restore(self.position - 1, arrangement, audio_blocks);
+
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn redo(
+        &mut self,
+        arrangement: &mut Arrangement,
+        audio_blocks: &mut Arc<HashMap<AudioBlockID, AudioBlock>>,
+    ) -> bool {
+        self.commit_group(arrangement, audio_blocks);
+        self.record(arrangement, audio_blocks);
+
+        if self.position + 1 < self.steps.len() {
+            self.restore(self.position + 1, arrangement, audio_blocks);
+
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Jumps to the step at `position`, the steps after it can still be redone.
+    pub fn revert_to(
+        &mut self,
+        position: usize,
+        arrangement: &mut Arrangement,
+        audio_blocks: &mut Arc<HashMap<AudioBlockID, AudioBlock>>,
+    ) -> bool {
+        self.commit_group(arrangement, audio_blocks);
+        self.record(arrangement, audio_blocks);
+
+        if position < self.steps.len() && position != self.position {
+            self.restore(position, arrangement, audio_blocks);
+
+            true
+        } else {
+            false
+        }
+    }
+
+    fn restore(
+        &mut self,
+        position: usize,
+        arrangement: &mut Arrangement,
+        audio_blocks: &mut Arc<HashMap<AudioBlockID, AudioBlock>>,
+    ) {
+        let step = &self.steps[position];
+
+        let mut restored = step.arrangement.clone();
+        restored.retain_audio_blocks(audio_blocks);
+
+        let changed_colors: Vec<_> = step
+            .colors
+            .iter()
+            .filter(|(id, color)| match audio_blocks.get(id) {
+                Some(audio_block) => audio_block.color.as_rgba_u32() != **color,
+                None => false,
+            })
+            .map(|(id, color)| (*id, *color))
+            .collect();
+
+        if !changed_colors.is_empty() {
+            let audio_blocks = Arc::make_mut(audio_blocks);
+
+            for (id, color) in changed_colors {
+                if let Some(audio_block) = audio_blocks.get_mut(&id) {
+                    audio_block.color = Color::from_rgba32_u32(color);
+                }
+            }
+        }
+
+        // keep the step and the arrangement the same, or the next record would see a change
+        if !restored.same(&step.arrangement) {
+            Arc::make_mut(&mut self.steps)[position].arrangement = restored.clone();
+        }
+
+        *arrangement = restored;
+        self.position = position;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn group_is_one_step() {
+        let mut arrangement = Arrangement::new();
+        let mut audio_blocks = Arc::new(HashMap::new());
+        let mut history = History::new(&arrangement, &audio_blocks);
+
+        history.begin_group();
+
+        for _ in 0..3 {
+            history.label("Add Tracks");
+            arrangement.add_track();
+            history.record(&arrangement, &audio_blocks);
+        }
+
+        assert_eq!(history.steps().len(), 1);
+
+        history.commit_group(&arrangement, &audio_blocks);
+
+        assert_eq!(history.steps().len(), 2);
+        assert_eq!(history.position(), 1);
+        assert_eq!(history.steps()[1].label, "Add Tracks");
+
+        assert!(history.undo(&mut arrangement, &mut audio_blocks));
+        assert_eq!(arrangement.tracks.len(), 1);
+
+        assert!(history.redo(&mut arrangement, &mut audio_blocks));
+        assert_eq!(arrangement.tracks.len(), 4);
+    }
+
+    #[test]
+    fn undo_restores_color() {
+        let mut arrangement = Arrangement::new();
+        let format = crate::audio::AudioSourceFormat {
+            sample_rate: 44100,
+            len_frames: 44100,
+            channels: 1,
+            beats_per_second: 2.0,
+        };
+        let id = AudioBlockID(0);
+        let audio_block = AudioBlock::new(crate::audio::AudioSourceID(0), format, 2.0);
+        let color = audio_block.color.as_rgba_u32();
+        let mut audio_blocks = Arc::new(HashMap::new());
+        Arc::make_mut(&mut audio_blocks).insert(id, audio_block);
+        let mut history = History::new(&arrangement, &audio_blocks);
+
+        Arc::make_mut(&mut audio_blocks).get_mut(&id).unwrap().color = Color::rgb(0.2, 0.7, 0.2);
+        history.record(&arrangement, &audio_blocks);
+
+        assert_eq!(history.steps().len(), 2);
+
+        assert!(history.undo(&mut arrangement, &mut audio_blocks));
+        assert_eq!(audio_blocks[&id].color.as_rgba_u32(), color);
+
+        assert!(history.redo(&mut arrangement, &mut audio_blocks));
+        assert_ne!(audio_blocks[&id].color.as_rgba_u32(), color);
+    }
+
+    #[test]
+    fn commit_without_group_records_nothing() {
+        let mut arrangement = Arrangement::new();
+        let audio_blocks = HashMap::new();
+        let mut history = History::new(&arrangement, &audio_blocks);
+
+        arrangement.add_track();
+        history.commit_group(&arrangement, &audio_blocks);
+
+        assert_eq!(history.steps().len(), 1);
+
+        history.record(&arrangement, &audio_blocks);
+        history.record(&arrangement, &audio_blocks);
+
+        assert_eq!(history.steps().len(), 2);
+        assert_eq!(history.steps()[1].label, "Edit");
+    }
+}
@@ -4,13 +4,28 @@ use std::{collections::HashMap, sync::Arc};
 mod arrangement;
 mod audio;
 mod audio_clip;
+mod config;
 mod controllers;
+mod delay;
 mod deligate;
+mod file_source;
+mod filter;
+mod history;
+mod midi;
+mod midi_clip;
+mod noise;
+mod oscillator;
+mod peak_cache;
+mod project;
+mod sampler;
+mod tap_tempo;
+mod wav;
 mod widgets;
 
-use widgets::arrangement::*;
+use widgets::{arrangement::*, minimap::MinimapWidget, piano_roll, MaxBox, Splitter};
 
 pub const ARRANGEMENT_WIDGET_ID: WidgetId = WidgetId::reserved(0);
+pub const MINIMAP_WIDGET_ID: WidgetId = WidgetId::reserved(1);
 
 mod commands {
     use druid::MouseEvent;
@@ -27,10 +42,110 @@ mod commands {
     pub const REMOVE_AUDIO_BLOCK: Selector<super::AudioBlockID> =
         Selector::new("global.remove-audio-block");
 
+    pub const TRIM_AUDIO_BLOCK: Selector<super::AudioBlockID> =
+        Selector::new("global.trim-audio-block");
+
+    /// Guesses the tempo of a recorded block, the result has to be confirmed in the top bar.
+    pub const ESTIMATE_AUDIO_BLOCK_TEMPO: Selector<super::AudioBlockID> =
+        Selector::new("global.estimate-audio-block-tempo");
+
+    pub const SET_AUDIO_BLOCK_COLOR: Selector<(super::AudioBlockID, druid::Color)> =
+        Selector::new("global.set-audio-block-color");
+
+    /// Sent by the audio engine when it stops because of an error, or loses its device.
+    pub const AUDIO_ENGINE_ERROR: Selector<String> = Selector::new("audio-engine.error");
+    /// Sent by the audio engine when it's running again after an error, with whether there is an
+    /// input.
+    pub const AUDIO_ENGINE_RESTARTED: Selector<bool> = Selector::new("audio-engine.restarted");
+
+    /// Sent by the audio engine when playback stops at the end of the arrangement.
+    pub const AUDIO_ENGINE_REACHED_END: Selector<()> = Selector::new("audio-engine.reached-end");
+    /// Sent by the audio engine while recording, with how many seconds the clip holds so far.
+    pub const AUDIO_ENGINE_RECORDED: Selector<f64> = Selector::new("audio-engine.recorded");
+
+    /// Sent by the audio engine a few times a second, with how long the output callback takes as
+    /// a share of the time its buffer lasts.
+    pub const AUDIO_ENGINE_LOAD: Selector<f64> = Selector::new("audio-engine.load");
+    /// Sent by the audio engine with the load, the seconds from the input to the output and back.
+    pub const AUDIO_ENGINE_LATENCY: Selector<f64> = Selector::new("audio-engine.latency");
+
+    /// Sent by the audio engine when there is no input device, recording is unavailable.
+    pub const AUDIO_ENGINE_NO_INPUT: Selector<()> = Selector::new("audio-engine.no-input");
+
+    /// Imports audio files, copied into the project or referenced where they are, as picked.
+    pub const IMPORT_AUDIO_AS: Selector<bool> = Selector::new("global.import-audio-as");
+    /// Saves the project, with the referenced files collected next to it or not, as picked.
+    pub const SAVE_PROJECT_AS: Selector<bool> = Selector::new("global.save-project-as");
+
+    /// Opens a project from the recent projects in the file menu.
+    pub const OPEN_PROJECT: Selector<std::path::PathBuf> = Selector::new("global.open-project");
+
+    /// Sets the sample format of wav exports and asks where to export to.
+    pub const EXPORT_WAV_AS: Selector<crate::wav::SampleFormat> =
+        Selector::new("global.export-wav-as");
+
+    pub const ADD_OSCILLATOR: Selector<()> = Selector::new("global.add-oscillator");
+    pub const ADD_MIDI_CLIP: Selector<()> = Selector::new("global.add-midi-clip");
+    pub const ADD_NOISE: Selector<()> = Selector::new("global.add-noise");
+    /// Adds a sampler playing the clip of the selected audio block.
+    pub const ADD_SAMPLER: Selector<()> = Selector::new("global.add-sampler");
+    pub const TOGGLE_HISTORY_PANEL: Selector<()> = Selector::new("global.toggle-history-panel");
+    pub const TOGGLE_AUTOMATION: Selector<()> = Selector::new("global.toggle-automation");
+    pub const TOGGLE_EFFECTS_PANEL: Selector<()> = Selector::new("global.toggle-effects-panel");
+
     pub const ARRANGEMENT_ADD_TRACK: Selector<()> = Selector::new("arrangement.add-track");
+    pub const ARRANGEMENT_ADD_MARKER: Selector<()> = Selector::new("arrangement.add-marker");
     pub const ARRANGEMENT_REMOVE_TRACK: Selector<usize> = Selector::new("arrangement.remove-track");
+    /// Sent by the splitters when they are dragged, with the new split, so it can be stored.
+    pub const SET_BLOCK_LIST_SPLIT: Selector<f64> = Selector::new("global.set-block-list-split");
+    pub const SET_EDITOR_SPLIT: Selector<f64> = Selector::new("global.set-editor-split");
+
+    /// Removes the blocks of a track, keeping the track.
+    pub const ARRANGEMENT_CLEAR_TRACK: Selector<usize> = Selector::new("arrangement.clear-track");
+    /// Removes the blocks of every track.
+    pub const ARRANGEMENT_CLEAR: Selector<()> = Selector::new("arrangement.clear");
+    pub const ARRANGEMENT_BOUNCE_TRACK: Selector<usize> = Selector::new("arrangement.bounce-track");
+    pub const ARRANGEMENT_UNFREEZE_TRACK: Selector<usize> =
+        Selector::new("arrangement.unfreeze-track");
+    /// Arms a track for recording, or disarms it if it already is.
+    pub const ARRANGEMENT_ARM_TRACK: Selector<usize> = Selector::new("arrangement.arm-track");
+    /// Sets how much of a track goes to the delay.
+    pub const ARRANGEMENT_SET_TRACK_SEND: Selector<(usize, f32)> =
+        Selector::new("arrangement.set-track-send");
+    /// Sets the filter of a track, `None` turns it off.
+    pub const ARRANGEMENT_SET_TRACK_FILTER: Selector<(usize, Option<crate::filter::TrackFilter>)> =
+        Selector::new("arrangement.set-track-filter");
+    /// The block actions from the context menu, the payload is the track and block index.
+    pub const ARRANGEMENT_REMOVE_BLOCK: Selector<(usize, usize)> =
+        Selector::new("arrangement.remove-block");
+    pub const ARRANGEMENT_DUPLICATE_BLOCK: Selector<(usize, usize)> =
+        Selector::new("arrangement.duplicate-block");
+    pub const ARRANGEMENT_NORMALIZE_BLOCK: Selector<(usize, usize)> =
+        Selector::new("arrangement.normalize-block");
+    pub const ARRANGEMENT_REVERSE_BLOCK: Selector<(usize, usize)> =
+        Selector::new("arrangement.reverse-block");
+    /// Splits a block, the payload is the track and block index and the beat to split at.
+    pub const ARRANGEMENT_SPLIT_BLOCK: Selector<(usize, usize, usize)> =
+        Selector::new("arrangement.split-block");
+    /// Quantizes a block, the payload is the track and block index and the division in beats.
+    pub const ARRANGEMENT_QUANTIZE_BLOCK: Selector<(usize, usize, usize)> =
+        Selector::new("arrangement.quantize-block");
+    /// Removes every block picked with the rubber band.
+    pub const ARRANGEMENT_REMOVE_SELECTED_BLOCKS: Selector<()> =
+        Selector::new("arrangement.remove-selected-blocks");
+    /// Colors the audio blocks of every block picked with the rubber band.
+    pub const ARRANGEMENT_COLOR_SELECTED_BLOCKS: Selector<druid::Color> =
+        Selector::new("arrangement.color-selected-blocks");
     pub const ARRANGEMENT_UPDATE_PLAY_LINE: Selector<f64> =
         Selector::new("arrangement.update-play-line");
+    /// Scrolls the arrangement so the beat is at the left edge.
+    pub const ARRANGEMENT_SCROLL_TO: Selector<f64> = Selector::new("arrangement.scroll-to");
+    /// The beats in view in the arrangement, the first one and how many there are.
+    pub const MINIMAP_SET_VIEW: Selector<(f64, f64)> = Selector::new("minimap.set-view");
+    /// Where playback is within the selected block, in beats from its start, `None` when it isn't
+    /// playing.
+    pub const AUDIO_CLIP_EDITOR_UPDATE_PLAY_LINE: Selector<Option<f64>> =
+        Selector::new("audio-clip-editor.update-play-line");
 }
 
 mod settings {
@@ -38,14 +153,31 @@ mod settings {
 
     pub const ARRANGEMENT_SCROLL_SPEED: Key<f64> = Key::new("arrangement.scroll-speed");
     pub const ARRANGEMENT_BEAT_SIZE: Key<f64> = Key::new("arrangement.beat-size");
+    /// How many parts the grid splits every beat into, 3 for triplets, 4 for sixteenths.
+    pub const ARRANGEMENT_BEAT_SUBDIVISION: Key<u64> = Key::new("arrangement.beat-subdivision");
     pub const ARRANGEMENT_TRACK_HEIGHT: Key<f64> = Key::new("arrangement.track-height");
     pub const ARRANGEMENT_BEATS_PER_SECOND: Key<f64> = Key::new("arrangement.beats-per-second");
+    pub const ARRANGEMENT_RULER_HEIGHT: Key<f64> = Key::new("arrangement.ruler-height");
+    pub const ARRANGEMENT_MARKER_LANE_HEIGHT: Key<f64> = Key::new("arrangement.marker-lane-height");
+    pub const ARRANGEMENT_SCROLLBAR_HEIGHT: Key<f64> = Key::new("arrangement.scrollbar-height");
+    /// How many beats adjacent blocks are allowed to overlap, the overlap is crossfaded.
+    pub const ARRANGEMENT_CROSSFADE_BEATS: Key<u64> = Key::new("arrangement.crossfade-beats");
+    /// The swing of the arrangement, see [`crate::arrangement::Arrangement::swing`].
+    pub const ARRANGEMENT_SWING: Key<f64> = Key::new("arrangement.swing");
+    pub const AUDIO_CLIP_TRIM_THRESHOLD: Key<f64> = Key::new("audio-clip.trim-threshold");
 
     pub fn default(env: &mut druid::Env) {
         env.set(ARRANGEMENT_SCROLL_SPEED, 0.1);
         env.set(ARRANGEMENT_BEAT_SIZE, 40.0);
+        env.set(ARRANGEMENT_BEAT_SUBDIVISION, 4u64);
         env.set(ARRANGEMENT_TRACK_HEIGHT, 30.0);
         env.set(ARRANGEMENT_BEATS_PER_SECOND, 120.0 / 60.0);
+        env.set(ARRANGEMENT_RULER_HEIGHT, 20.0);
+        env.set(ARRANGEMENT_MARKER_LANE_HEIGHT, 18.0);
+        env.set(ARRANGEMENT_SCROLLBAR_HEIGHT, 8.0);
+        env.set(ARRANGEMENT_CROSSFADE_BEATS, 0u64);
+        env.set(ARRANGEMENT_SWING, 0.0);
+        env.set(AUDIO_CLIP_TRIM_THRESHOLD, 0.02);
     }
 }
 
@@ -58,12 +190,31 @@ mod theme {
     pub const ARRANGEMENT_BEAT_LINE_WIDTH: Key<f64> = Key::new("arrangement.beat-line-width");
     pub const ARRANGEMENT_BEAT_LINE_COLOR: Key<Color> = Key::new("arrangement.beat-line-color");
     pub const ARRANGEMENT_TACT_LINE_COLOR: Key<Color> = Key::new("arrangement.tact-line-color");
+    pub const ARRANGEMENT_SUBDIVISION_LINE_COLOR: Key<Color> =
+        Key::new("arrangement.subdivision-line-color");
     pub const ARRANGEMENT_PLAY_LINE_WIDTH: Key<f64> = Key::new("arrangement.play-line-width");
     pub const ARRANGEMENT_PLAY_LINE_COLOR: Key<Color> = Key::new("arrangement.play-line-color");
+    pub const ARRANGEMENT_RULER_COLOR: Key<Color> = Key::new("arrangement.ruler-color");
+    pub const ARRANGEMENT_TEMPO_MARKER_COLOR: Key<Color> =
+        Key::new("arrangement.tempo-marker-color");
+    pub const ARRANGEMENT_MARKER_COLOR: Key<Color> = Key::new("arrangement.marker-color");
+    pub const ARRANGEMENT_SCROLLBAR_COLOR: Key<Color> = Key::new("arrangement.scrollbar-color");
+    pub const ARRANGEMENT_SELECTED_BLOCK_COLOR: Key<Color> =
+        Key::new("arrangement.selected-block-color");
+    pub const ARRANGEMENT_AUTOMATION_COLOR: Key<Color> = Key::new("arrangement.automation-color");
+    pub const ARRANGEMENT_RUBBER_BAND_COLOR: Key<Color> = Key::new("arrangement.rubber-band-color");
+    pub const ARRANGEMENT_ARMED_TRACK_COLOR: Key<Color> = Key::new("arrangement.armed-track-color");
+
+    pub const ERROR_TEXT_COLOR: Key<Color> = Key::new("general.error-text-color");
+    pub const HISTORY_CURRENT_STEP_COLOR: Key<Color> = Key::new("history.current-step-color");
 
     pub const AUDIO_CLIP_EDITOR_RESOLUTION: Key<f64> = Key::new("audio-clip-editor.resolution");
     pub const AUDIO_CLIP_EDITOR_SCALE: Key<f64> = Key::new("audio-clip-editor.scale");
     pub const AUDIO_CLIP_EDITOR_BAR_COLOR: Key<Color> = Key::new("audio-clip-editor.bar-color");
+    pub const AUDIO_CLIP_EDITOR_RMS_COLOR: Key<Color> = Key::new("audio-clip-editor.rms-color");
+
+    pub const PIANO_ROLL_BLACK_KEY_COLOR: Key<Color> = Key::new("piano-roll.black-key-color");
+    pub const PIANO_ROLL_NOTE_COLOR: Key<Color> = Key::new("piano-roll.note-color");
 
     pub fn default(env: &mut druid::Env) {
         env.set(BORDER_COLOR, Color::WHITE);
@@ -72,12 +223,37 @@ mod theme {
         env.set(ARRANGEMENT_BEAT_LINE_WIDTH, 1.0);
         env.set(ARRANGEMENT_BEAT_LINE_COLOR, Color::rgb(0.2, 0.2, 0.2));
         env.set(ARRANGEMENT_TACT_LINE_COLOR, Color::rgb(0.4, 0.4, 0.4));
+        env.set(
+            ARRANGEMENT_SUBDIVISION_LINE_COLOR,
+            Color::rgb(0.12, 0.12, 0.12),
+        );
         env.set(ARRANGEMENT_PLAY_LINE_WIDTH, 3.5);
         env.set(ARRANGEMENT_PLAY_LINE_COLOR, Color::rgb(0.5, 0.5, 0.5));
+        env.set(ARRANGEMENT_RULER_COLOR, Color::rgb(0.1, 0.1, 0.11));
+        env.set(ARRANGEMENT_TEMPO_MARKER_COLOR, Color::rgb(0.9, 0.6, 0.2));
+        env.set(ARRANGEMENT_MARKER_COLOR, Color::rgb(0.3, 0.7, 0.9));
+        env.set(ARRANGEMENT_SCROLLBAR_COLOR, Color::rgb(0.35, 0.35, 0.37));
+        env.set(
+            ARRANGEMENT_SELECTED_BLOCK_COLOR,
+            Color::rgba(1.0, 1.0, 1.0, 0.08),
+        );
+        env.set(ARRANGEMENT_AUTOMATION_COLOR, Color::rgb(0.9, 0.8, 0.3));
+        env.set(ARRANGEMENT_RUBBER_BAND_COLOR, Color::rgb(0.6, 0.6, 0.65));
+        env.set(
+            ARRANGEMENT_ARMED_TRACK_COLOR,
+            Color::rgba(0.9, 0.2, 0.2, 0.1),
+        );
+
+        env.set(ERROR_TEXT_COLOR, Color::rgb(0.9, 0.3, 0.3));
+        env.set(HISTORY_CURRENT_STEP_COLOR, Color::rgb(0.25, 0.25, 0.3));
 
         env.set(AUDIO_CLIP_EDITOR_RESOLUTION, 1.0 / 80.0);
         env.set(AUDIO_CLIP_EDITOR_SCALE, 200.0);
-        env.set(AUDIO_CLIP_EDITOR_BAR_COLOR, Color::rgb(0.6, 0.6, 0.6));
+        env.set(AUDIO_CLIP_EDITOR_BAR_COLOR, Color::rgb(0.4, 0.4, 0.4));
+        env.set(AUDIO_CLIP_EDITOR_RMS_COLOR, Color::rgb(0.7, 0.7, 0.7));
+
+        env.set(PIANO_ROLL_BLACK_KEY_COLOR, Color::rgb(0.08, 0.08, 0.085));
+        env.set(PIANO_ROLL_NOTE_COLOR, Color::rgb(0.3, 0.7, 0.9));
 
         env.set(
             druid::theme::WINDOW_BACKGROUND_COLOR,
@@ -93,10 +269,21 @@ pub struct AudioBlockID(pub usize);
 pub struct AudioBlock {
     audio_id: audio::AudioSourceID,
     format: audio::AudioSourceFormat,
+    /// How far the source is shifted into every block playing it, in beats, positive starts it
+    /// later. Blocks can shift it further with their own offset.
     offset: f32,
     len_beats: usize,
     true_len_beats: usize,
     color: Color,
+    /// Shown on the blocks in the arrangement.
+    name: String,
+    /// Every repeat cuts off the one before, instead of letting it ring out.
+    choke: bool,
+    /// Played at another tempo than the source was made at, the source is stretched instead of
+    /// sped up or slowed down, so it keeps its pitch.
+    time_stretch: bool,
+    /// Transposes the source without changing its length.
+    pitch_semitones: i32,
 }
 
 impl AudioBlock {
@@ -116,8 +303,34 @@ impl AudioBlock {
             len_beats: true_len_beats,
             true_len_beats,
             color: Color::rgb(0.7, 0.2, 0.2),
+            name: format!("Source {}", audio_id.0),
+            choke: false,
+            time_stretch: false,
+            pitch_semitones: 0,
         }
     }
+
+    /// How many times the audio block plays in a block with `bounds`, every play is `len_beats`
+    /// long.
+    pub fn loops(&self, bounds: &std::ops::Range<usize>) -> f64 {
+        bounds.len() as f64 / self.len_beats.max(1) as f64
+    }
+
+    /// Updates the format after the source changed length, a block that played the whole source
+    /// keeps doing so.
+    pub fn set_format(&mut self, format: audio::AudioSourceFormat, beats_per_second: f64) {
+        let true_len_beats = (format.len_frames as f64 / format.sample_rate as f64
+            * beats_per_second)
+            .ceil()
+            .max(1.0) as usize;
+
+        if self.len_beats == self.true_len_beats {
+            self.len_beats = true_len_beats;
+        }
+
+        self.format = format;
+        self.true_len_beats = true_len_beats;
+    }
 }
 
 #[derive(Clone, Data, Lens)]
@@ -128,19 +341,816 @@ pub struct AppState {
     pub listed_audio_blocks: Arc<Vec<AudioBlockID>>,
     pub selected_audio_block: Option<AudioBlockID>,
     pub selected_audio_source_clone: Option<Arc<dyn audio::AudioSource>>,
+    /// The block selected in the arrangement, as the track index and the start beat of the block.
+    pub selected_block: Option<(usize, usize)>,
+    /// The blocks picked with the rubber band, like `selected_block`. Only one of the two is set.
+    pub selected_blocks: Arc<Vec<(usize, usize)>>,
+    /// The track last clicked in the arrangement.
+    pub selected_track: Option<usize>,
+    /// The track recordings are put on, at the beat they were started at. Set with
+    /// [`AppState::set_armed_track`], the input is only monitored while a track is armed.
+    pub armed_track: Option<usize>,
+    pub renamed_marker: Option<usize>,
+    /// A track waiting for the removal to be confirmed.
+    pub removed_track: Option<usize>,
+    /// A block and the tempo guessed for it, waiting to be confirmed.
+    pub estimated_tempo: Option<(AudioBlockID, f64)>,
     pub next_audio_block_id: AudioBlockID,
     pub playing: bool,
     pub recording: bool,
+    /// Monitor the input of the armed track.
     pub feedback: bool,
+    /// Playback stops by itself after the last block.
+    pub stop_at_end: bool,
     pub audio_engine_handle: audio::AudioEngineHandle,
     pub volume: f64,
     pub beats_per_minute: f64,
+    pub tap_tempo: tap_tempo::TapTempo,
+    pub audio_error: Option<String>,
+    pub has_input: bool,
+    /// Where playback is, in seconds.
+    pub play_time: f64,
+    /// Where playback was when the recording started, in seconds.
+    pub record_start_time: f64,
+    /// How long the recording in progress is, in seconds.
+    pub recorded_time: f64,
+    /// The load of the audio thread, from `commands::AUDIO_ENGINE_LOAD`.
+    pub audio_load: f64,
+    /// The round trip latency of the audio engine, from `commands::AUDIO_ENGINE_LATENCY`.
+    pub latency: f64,
+    pub history: history::History,
+    pub show_history: bool,
+    /// The tracks show their volume automation instead of editing blocks with the mouse.
+    pub show_automation: bool,
+    pub delay: delay::DelaySettings,
+    pub show_effects: bool,
+    /// How the samples of exported wav files are stored.
+    pub export_format: wav::SampleFormat,
+    /// Imported audio is played from the files instead of loaded into the project.
+    pub reference_audio: bool,
+    /// Saving copies the referenced files into the project, which then refers to the copies.
+    pub collect_files: bool,
+}
+
+impl AppState {
+    pub fn start_playing(&mut self) {
+        self.playing = true;
+        self.audio_engine_handle.set_playing(true);
+
+        let arrangement_index = self.arrangement.compile_index(&self.audio_blocks);
+
+        self.audio_engine_handle
+            .set_arrangement_index(arrangement_index);
+    }
+
+    /// Sends the edits made since `old` to the engine, so they are heard while playing. Only the
+    /// tracks that changed are compiled again, unless the change is to the whole arrangement.
+    pub fn sync_arrangement_index(&self, old: &AppState) {
+        if self.arrangement.same(&old.arrangement) && self.audio_blocks.same(&old.audio_blocks) {
+            return;
+        }
+
+        let tracks = &self.arrangement.tracks;
+        let old_tracks = &old.arrangement.tracks;
+
+        if tracks.len() != old_tracks.len()
+            || !self
+                .arrangement
+                .tempo_changes
+                .same(&old.arrangement.tempo_changes)
+            || self.arrangement.beats != old.arrangement.beats
+        {
+            self.audio_engine_handle
+                .set_arrangement_index(self.arrangement.compile_index(&self.audio_blocks));
+
+            return;
+        }
+
+        let changed = tracks
+            .iter()
+            .zip(old_tracks.iter())
+            .enumerate()
+            .filter(|(_, (track, old_track))| {
+                // editing a block, like its length, changes every track it's on
+                let audio_block_changed = !self.audio_blocks.same(&old.audio_blocks)
+                    && track.blocks.iter().any(|block| {
+                        let id = &block.audio_block_id;
+
+                        match (old.audio_blocks.get(id), self.audio_blocks.get(id)) {
+                            (Some(old_block), Some(new_block)) => !old_block.same(new_block),
+                            _ => true,
+                        }
+                    });
+
+                track != old_track || audio_block_changed
+            })
+            .map(|(track_index, _)| {
+                self.arrangement
+                    .compile_track_index(track_index, &self.audio_blocks)
+            })
+            .collect::<Vec<_>>();
+
+        if !changed.is_empty() {
+            self.audio_engine_handle.replace_arrangement_tracks(changed);
+        }
+    }
+
+    pub fn start_recording(&mut self) {
+        self.recording = true;
+        self.record_start_time = self.play_time;
+        self.recorded_time = 0.0;
+        self.playing = true;
+        self.audio_engine_handle.start_recording();
+
+        let arrangement_index = self.arrangement.compile_index(&self.audio_blocks);
+
+        self.audio_engine_handle
+            .set_arrangement_index(arrangement_index);
+    }
+
+    /// Stops playback and keeps the play time, if a recording was in progress the recorded clip
+    /// is added to the block list, and to the armed track if there is one.
+    pub fn pause_playing(&mut self, beats_per_second: f64) {
+        self.playing = false;
+        self.recording = false;
+        self.recorded_time = 0.0;
+        self.audio_engine_handle.set_playing(false);
+
+        match self.audio_engine_handle.stop_recording() {
+            Ok(Some(recording)) => {
+                log::info!("{:?}", recording.format);
+
+                let audio_block_id =
+                    self.add_audio_block(recording.id, recording.format.clone(), beats_per_second);
+
+                if let Some(track_index) = self.armed_track {
+                    self.place_recording(
+                        track_index,
+                        audio_block_id,
+                        recording.format,
+                        recording.start_time,
+                        beats_per_second,
+                    );
+                }
+            }
+            Ok(None) => (),
+            Err(e) => log::error!("failed to stop recording: {}", e),
+        }
+    }
+
+    /// Puts the recorded `audio_block_id` on the track at `track_index`, from the beat the
+    /// recording started at. Left in the block list only if it doesn't fit there.
+    ///
+    /// The block is offset so the clip plays from `clip_start_time`, the time it was played along
+    /// with, instead of the time it came in a round trip later.
+    fn place_recording(
+        &mut self,
+        track_index: usize,
+        audio_block_id: AudioBlockID,
+        format: audio::AudioSourceFormat,
+        clip_start_time: f64,
+        beats_per_second: f64,
+    ) {
+        let tempo_map = self.arrangement.tempo_map();
+        let record_start_beat = tempo_map.beat_at_time(self.record_start_time, beats_per_second);
+        let clip_start_beat = tempo_map.beat_at_time(clip_start_time, beats_per_second);
+        let start = record_start_beat.min(clip_start_beat).max(0.0).floor() as usize;
+        let offset = (clip_start_beat - start as f64) as f32;
+        let len_beats = self.audio_blocks[&audio_block_id].len_beats.max(1);
+
+        let placed = Arc::make_mut(&mut self.arrangement.tracks)
+            .get_mut(track_index)
+            .and_then(|track| {
+                track.add_block(
+                    arrangement::Block {
+                        offset,
+                        ..arrangement::Block::new(start..start + len_beats, audio_block_id, format)
+                    },
+                    0,
+                )
+            });
+
+        if placed.is_some() {
+            self.selected_block = Some((track_index, start));
+            self.selected_blocks = Arc::new(Vec::new());
+            self.log_step(format!("Recorded on Track {}", track_index + 1));
+        } else {
+            log::warn!(
+                "the recording doesn't fit on track {}, it's only in the block list",
+                track_index + 1
+            );
+        }
+    }
+
+    /// Arms the track at `track_index` for recording, disarming any other. Disarms it if it
+    /// already was.
+    pub fn toggle_armed_track(&mut self, track_index: usize) {
+        if self.armed_track == Some(track_index) {
+            self.set_armed_track(None);
+        } else {
+            self.set_armed_track(Some(track_index));
+        }
+    }
+
+    /// Arms `armed_track` and monitors the input only while a track is armed and feedback is on.
+    pub fn set_armed_track(&mut self, armed_track: Option<usize>) {
+        self.armed_track = armed_track;
+        self.update_monitoring();
+    }
+
+    pub fn update_monitoring(&self) {
+        self.audio_engine_handle
+            .set_feedback(self.feedback && self.armed_track.is_some());
+    }
+
+    /// Adds a block for the audio source `id` and shows it in the block list.
+    pub fn add_audio_block(
+        &mut self,
+        id: audio::AudioSourceID,
+        format: audio::AudioSourceFormat,
+        beats_per_second: f64,
+    ) -> AudioBlockID {
+        self.add_audio_blocks(std::iter::once((id, format)), beats_per_second)[0]
+    }
+
+    /// Adds a block for each audio source, the block list is only updated once.
+    pub fn add_audio_blocks(
+        &mut self,
+        sources: impl IntoIterator<Item = (audio::AudioSourceID, audio::AudioSourceFormat)>,
+        beats_per_second: f64,
+    ) -> Vec<AudioBlockID> {
+        let audio_blocks = Arc::make_mut(&mut self.audio_blocks);
+        let mut audio_block_ids = Vec::new();
+
+        for (id, format) in sources {
+            let audio_block_id = self.next_audio_block_id;
+            self.next_audio_block_id.0 += 1;
+
+            audio_blocks.insert(
+                audio_block_id,
+                AudioBlock::new(id, format, beats_per_second),
+            );
+            audio_block_ids.push(audio_block_id);
+        }
+
+        Arc::make_mut(&mut self.shown_audio_blocks).extend(&audio_block_ids);
+
+        audio_block_ids
+    }
+
+    /// Adds a copy of the audio block `id` under `name`, with `audio_id` as its source.
+    fn copy_audio_block(
+        &mut self,
+        id: AudioBlockID,
+        audio_id: audio::AudioSourceID,
+        name: String,
+    ) -> AudioBlockID {
+        let copy_id = self.next_audio_block_id;
+        self.next_audio_block_id.0 += 1;
+
+        let mut copy = self.audio_blocks[&id].clone();
+        copy.audio_id = audio_id;
+        copy.name = name;
+
+        Arc::make_mut(&mut self.audio_blocks).insert(copy_id, copy);
+        Arc::make_mut(&mut self.shown_audio_blocks).push(copy_id);
+
+        copy_id
+    }
+
+    /// Cuts the block at `block_index` in two at `beat`. The second part keeps the audio block,
+    /// its own offset is shifted so it carries on where the first part stops.
+    pub fn split_block(&mut self, track_index: usize, block_index: usize, beat: usize) -> bool {
+        let block = &self.arrangement.tracks[track_index].blocks[block_index];
+
+        if beat <= block.bounds.start || beat >= block.bounds.end {
+            return false;
+        }
+
+        Arc::make_mut(&mut self.arrangement.tracks)[track_index].split_block(block_index, beat)
+    }
+
+    /// Replaces the source of the block at `block_index` with an edited copy, `edit` returns
+    /// `None` if the source can't be edited that way. The copy gets its own audio block, so the
+    /// other blocks using the source are left alone and the change can be undone.
+    pub fn edit_block_source(
+        &mut self,
+        track_index: usize,
+        block_index: usize,
+        suffix: &str,
+        edit: impl FnOnce(&dyn audio::AudioSource) -> Option<Arc<dyn audio::AudioSource + Send + Sync>>,
+    ) -> Result<bool, audio::AudioError> {
+        let id = self.arrangement.tracks[track_index].blocks[block_index].audio_block_id;
+
+        let (copy_id, _) = match self.copy_with_edited_source(id, suffix, edit)? {
+            Some(copy) => copy,
+            None => return Ok(false),
+        };
+
+        Arc::make_mut(&mut self.arrangement.tracks)[track_index].blocks[block_index]
+            .audio_block_id = copy_id;
+
+        Ok(true)
+    }
+
+    /// Like [`edit_block_source`](Self::edit_block_source), but for every block playing the audio
+    /// block `id`. The copy is selected, it can have another length than the original.
+    pub fn edit_audio_block_source(
+        &mut self,
+        id: AudioBlockID,
+        suffix: &str,
+        beats_per_second: f64,
+        edit: impl FnOnce(&dyn audio::AudioSource) -> Option<Arc<dyn audio::AudioSource + Send + Sync>>,
+    ) -> Result<bool, audio::AudioError> {
+        let (copy_id, source) = match self.copy_with_edited_source(id, suffix, edit)? {
+            Some(copy) => copy,
+            None => return Ok(false),
+        };
+
+        Arc::make_mut(&mut self.audio_blocks)
+            .get_mut(&copy_id)
+            .unwrap()
+            .set_format(source.format(), beats_per_second);
+
+        for track in Arc::make_mut(&mut self.arrangement.tracks) {
+            for block in &mut track.blocks {
+                if block.audio_block_id == id {
+                    block.audio_block_id = copy_id;
+                }
+            }
+        }
+
+        self.selected_audio_block = Some(copy_id);
+        self.selected_audio_source_clone = Some(source);
+
+        Ok(true)
+    }
+
+    /// Adds a copy of the audio block `id` that plays an edited copy of its source, returns the
+    /// copy and the edited source, or `None` if the source can't be edited that way. The source
+    /// itself is left alone.
+    fn copy_with_edited_source(
+        &mut self,
+        id: AudioBlockID,
+        suffix: &str,
+        edit: impl FnOnce(&dyn audio::AudioSource) -> Option<Arc<dyn audio::AudioSource + Send + Sync>>,
+    ) -> Result<Option<(AudioBlockID, Arc<dyn audio::AudioSource + Send + Sync>)>, audio::AudioError>
+    {
+        let audio_block = &self.audio_blocks[&id];
+
+        let source = self
+            .audio_engine_handle
+            .get_audio_source_clone(audio_block.audio_id)?;
+        let edited = match edit(&*source) {
+            Some(edited) => edited,
+            None => return Ok(None),
+        };
+
+        let name = format!("{} {}", audio_block.name, suffix);
+        let audio_id = self.audio_engine_handle.add_audio_source(edited.clone())?;
+
+        Ok(Some((self.copy_audio_block(id, audio_id, name), edited)))
+    }
+
+    /// Logs a change to the arrangement and uses it as the label of the history step.
+    pub fn log_step(&mut self, label: impl Into<String>) {
+        let label = label.into();
+
+        log::info!("{}", label);
+        self.history.label(label);
+    }
+
+    /// Returns false if the block already had the color.
+    pub fn set_audio_block_color(&mut self, id: AudioBlockID, color: Color) -> bool {
+        match Arc::make_mut(&mut self.audio_blocks).get_mut(&id) {
+            Some(audio_block) if audio_block.color.as_rgba_u32() != color.as_rgba_u32() => {
+                audio_block.color = color;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn set_audio_block_name(&mut self, id: AudioBlockID, name: impl Into<String>) {
+        if let Some(audio_block) = Arc::make_mut(&mut self.audio_blocks).get_mut(&id) {
+            audio_block.name = name.into();
+        }
+    }
+
+    /// Removes the audio block from the block list and every block playing it, returns its name.
+    /// Its source stays in the engine, so undoing brings it back.
+    pub fn remove_audio_block(&mut self, id: AudioBlockID) -> Option<String> {
+        if self.selected_audio_block == Some(id) {
+            self.selected_audio_block = None;
+            self.selected_audio_source_clone = None;
+        }
+
+        Arc::make_mut(&mut self.shown_audio_blocks).retain(|x| *x != id);
+        let name = Arc::make_mut(&mut self.audio_blocks)
+            .remove(&id)
+            .map(|audio_block| audio_block.name);
+        self.arrangement.remove_audio_block(id);
+
+        name
+    }
+
+    /// Puts back the blocks of a frozen track, returns false if it wasn't frozen. The bounce goes
+    /// from the block list unless it was placed somewhere else too.
+    pub fn unfreeze_track(&mut self, track_index: usize) -> bool {
+        let bounce = match Arc::make_mut(&mut self.arrangement.tracks)[track_index].unfreeze() {
+            Some(bounce) => bounce,
+            None => return false,
+        };
+
+        for block in bounce {
+            if !self.arrangement.uses_audio_block(block.audio_block_id) {
+                self.remove_audio_block(block.audio_block_id);
+            }
+        }
+
+        true
+    }
+
+    /// Renders the track at `track_index` into a single clip and replaces its blocks with one
+    /// block playing it, the old blocks are kept so the track can be unfrozen.
+    pub fn bounce_track(
+        &mut self,
+        track_index: usize,
+        beats_per_second: f64,
+    ) -> Result<Option<AudioBlockID>, audio::AudioError> {
+        let track = &self.arrangement.tracks[track_index];
+
+        let span = match track.span() {
+            Some(span) if track.frozen.is_none() => span,
+            _ => return Ok(None),
+        };
+
+        let (sample_rate, channels) = self
+            .audio_engine_handle
+            .output_format()
+            .ok_or(audio::AudioError::NoOutputDevice)?;
+
+        let mut index = arrangement::ArrangementAudioSourceIndex::default();
+        // without the volume, filter and send of the track, they still apply to the frozen track
+        track.compile_index(track_index, &mut index, &self.audio_blocks);
+
+        let sources = self.audio_engine_handle.download_audio_sources()?;
+        let samples = audio::render_beats(
+            &index,
+            &sources,
+            sample_rate,
+            channels,
+            beats_per_second,
+            span.clone(),
+        );
+
+        let format = audio::AudioSourceFormat {
+            sample_rate,
+            len_frames: samples.len() as u32 / channels,
+            channels,
+            beats_per_second,
+        };
+        let id =
+            self.audio_engine_handle
+                .add_audio_source(Arc::new(audio_clip::AudioClip::new(
+                    samples,
+                    format.clone(),
+                )))?;
+        let audio_block_id = self.add_audio_block(id, format.clone(), beats_per_second);
+        self.set_audio_block_name(audio_block_id, format!("Track {} Bounce", track_index + 1));
+
+        Arc::make_mut(&mut self.arrangement.tracks)[track_index].freeze(arrangement::Block::new(
+            span,
+            audio_block_id,
+            format,
+        ));
+
+        Ok(Some(audio_block_id))
+    }
+
+    /// Like `pause_playing`, but also goes back to the start.
+    pub fn stop_playing(&mut self, beats_per_second: f64) {
+        self.pause_playing(beats_per_second);
+
+        self.play_time = 0.0;
+        self.audio_engine_handle.set_play_time(0.0);
+    }
+
+    /// How many times the selected block plays `audio_block_id`, `None` if it plays another one.
+    pub fn selected_block_loops(&self, audio_block_id: AudioBlockID) -> Option<f64> {
+        let (track_index, block_index) = self.selected_block_index_of(audio_block_id)?;
+        let block = &self.arrangement.tracks[track_index].blocks[block_index];
+
+        Some(self.audio_blocks[&audio_block_id].loops(&block.bounds))
+    }
+
+    /// The offset of the audio block `audio_block_id` plus the own offset of the selected block,
+    /// if that plays it.
+    fn selected_block_offset(&self, audio_block_id: AudioBlockID) -> f32 {
+        let offset = self.audio_blocks[&audio_block_id].offset;
+
+        match self.selected_block_index_of(audio_block_id) {
+            Some((track_index, block_index)) => {
+                offset + self.arrangement.tracks[track_index].blocks[block_index].offset
+            }
+            None => offset,
+        }
+    }
+
+    /// Makes the selected block play its audio block `loops` times, returns false if it doesn't
+    /// change or doesn't fit.
+    pub fn set_selected_block_loops(&mut self, loops: f64) -> bool {
+        let (track_index, block_index) = match self.selected_block_index() {
+            Some(index) => index,
+            None => return false,
+        };
+
+        let track = &mut Arc::make_mut(&mut self.arrangement.tracks)[track_index];
+        let len_beats = self.audio_blocks[&track.blocks[block_index].audio_block_id].len_beats;
+
+        // a lens has no env to get the crossfade from, so the block never overlaps the next one
+        if track.set_block_loops(block_index, len_beats.max(1), loops, 0) {
+            self.log_step(format!("Set Block to {} Loops", format_loops(loops)));
+
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Track and block index of the selected block, if it plays `audio_block_id`.
+    fn selected_block_index_of(&self, audio_block_id: AudioBlockID) -> Option<(usize, usize)> {
+        let (track_index, block_index) = self.selected_block_index()?;
+        let block = &self.arrangement.tracks[track_index].blocks[block_index];
+
+        (block.audio_block_id == audio_block_id).then_some((track_index, block_index))
+    }
+
+    /// Track and block index of the selected block, if it still exists.
+    fn selected_block_index(&self) -> Option<(usize, usize)> {
+        let (track_index, start) = self.selected_block?;
+        let block_index = self
+            .arrangement
+            .tracks
+            .get(track_index)?
+            .blocks
+            .iter()
+            .position(|block| block.bounds.start == start)?;
+
+        Some((track_index, block_index))
+    }
+
+    /// Track and block index of every block picked with the rubber band that still exists, or of
+    /// the selected block if none are.
+    fn selected_block_indices(&self) -> Vec<(usize, usize)> {
+        if self.selected_blocks.is_empty() {
+            return self.selected_block_index().into_iter().collect();
+        }
+
+        self.selected_blocks
+            .iter()
+            .filter_map(|&(track_index, start)| {
+                let block_index = self
+                    .arrangement
+                    .tracks
+                    .get(track_index)?
+                    .blocks
+                    .iter()
+                    .position(|block| block.bounds.start == start)?;
+
+                Some((track_index, block_index))
+            })
+            .collect()
+    }
+
+    /// Selects the blocks at `indices`, as a rubber band selection if there was one.
+    fn reselect_blocks(&mut self, indices: &[(usize, usize)]) {
+        let selection = indices
+            .iter()
+            .map(|&(track_index, block_index)| {
+                let start = self.arrangement.tracks[track_index].blocks[block_index]
+                    .bounds
+                    .start;
+
+                (track_index, start)
+            })
+            .collect::<Vec<_>>();
+
+        if self.selected_blocks.is_empty() {
+            self.selected_block = selection.first().copied();
+        } else {
+            self.selected_blocks = Arc::new(selection);
+        }
+    }
+
+    /// Moves the selected blocks one beat, returns false if any of them has no room.
+    pub fn nudge_selected_block(&mut self, right: bool, overlap: usize) -> bool {
+        let mut indices = self.selected_block_indices();
+
+        // the blocks in front go first, making room for the ones behind them
+        indices.sort();
+
+        if right {
+            indices.reverse();
+        }
+
+        let tracks = self.arrangement.tracks.clone();
+
+        for &(track_index, block_index) in &indices {
+            let track = &mut Arc::make_mut(&mut self.arrangement.tracks)[track_index];
+
+            if !track.nudge_block(block_index, right, overlap) {
+                self.arrangement.tracks = tracks;
+                return false;
+            }
+        }
+
+        if indices.is_empty() {
+            return false;
+        }
+
+        self.reselect_blocks(&indices);
+        self.log_step(if indices.len() == 1 {
+            "Nudged Block".to_owned()
+        } else {
+            format!("Nudged {} Blocks", indices.len())
+        });
+
+        true
+    }
+
+    /// Moves the selected blocks to the track below, or above if `down` isn't set. Returns false
+    /// if there's no such track or any of the blocks doesn't fit on it.
+    pub fn move_selected_block(&mut self, down: bool, overlap: usize) -> bool {
+        let mut indices = self.selected_block_indices();
+
+        // the tracks in front go first, emptying the track the next ones move to, and the last
+        // block of a track goes first, so removing it doesn't shift the others
+        indices.sort_by_key(|&(track_index, block_index)| {
+            (
+                if down {
+                    usize::MAX - track_index
+                } else {
+                    track_index
+                },
+                usize::MAX - block_index,
+            )
+        });
+
+        let tracks = self.arrangement.tracks.clone();
+        let mut moved = Vec::new();
+
+        for &(track_index, block_index) in &indices {
+            let target = match (down, track_index) {
+                (false, 0) => None,
+                (false, _) => Some(track_index - 1),
+                (true, _) => Some(track_index + 1),
+            };
+            let start = self.arrangement.tracks[track_index].blocks[block_index]
+                .bounds
+                .start;
+
+            let fits = target.is_some_and(|target| {
+                self.arrangement
+                    .move_block_to_track(track_index, block_index, target, overlap)
+                    .is_some()
+            });
+
+            if !fits {
+                self.arrangement.tracks = tracks;
+                return false;
+            }
+
+            moved.push((target.unwrap(), start));
+        }
+
+        if moved.is_empty() {
+            return false;
+        }
+
+        if self.selected_blocks.is_empty() {
+            self.selected_block = Some(moved[0]);
+            self.log_step(format!("Moved Block to Track {}", moved[0].0 + 1));
+        } else {
+            self.log_step(format!("Moved {} Blocks", moved.len()));
+            self.selected_blocks = Arc::new(moved);
+        }
+
+        true
+    }
+
+    /// Removes the selected blocks, returns false if there were none.
+    pub fn remove_selected_blocks(&mut self) -> bool {
+        let mut indices = self.selected_block_indices();
+
+        // from the back, so removing a block doesn't shift the others
+        indices.sort();
+        indices.reverse();
+
+        for &(track_index, block_index) in &indices {
+            Arc::make_mut(&mut self.arrangement.tracks)[track_index]
+                .remove_block_index(block_index);
+        }
+
+        self.selected_block = None;
+        self.selected_blocks = Arc::new(Vec::new());
+
+        match indices.len() {
+            0 => return false,
+            1 => self.log_step(format!("Removed Block on Track {}", indices[0].0 + 1)),
+            len => self.log_step(format!("Removed {} Blocks", len)),
+        }
+
+        true
+    }
+
+    /// Colors the audio blocks of the selected blocks, returns false if none changed.
+    pub fn color_selected_blocks(&mut self, color: Color) -> bool {
+        let mut ids = self
+            .selected_block_indices()
+            .into_iter()
+            .map(|(track_index, block_index)| {
+                self.arrangement.tracks[track_index].blocks[block_index].audio_block_id
+            })
+            .collect::<Vec<_>>();
+        ids.sort_by_key(|id| id.0);
+        ids.dedup();
+
+        let mut changed = false;
+
+        for id in ids {
+            changed |= self.set_audio_block_color(id, color.clone());
+        }
+
+        changed
+    }
+
+    /// Sets the project tempo to `beats_per_minute`, the tempo estimated for the clip of
+    /// `audio_block_id`. The clip is marked as made at that tempo too, so it keeps playing at its
+    /// own speed, now in time with the beats.
+    pub fn set_tempo_from_block(&mut self, audio_block_id: AudioBlockID, beats_per_minute: f64) {
+        let audio_id = match self.audio_blocks.get(&audio_block_id) {
+            Some(audio_block) => audio_block.audio_id,
+            None => return,
+        };
+        let beats_per_second = beats_per_minute / 60.0;
+
+        let source = match self.audio_engine_handle.get_audio_source_clone(audio_id) {
+            Ok(source) => source,
+            Err(e) => {
+                log::error!("failed to get audio source: {}", e);
+                return;
+            }
+        };
+
+        if let Some(clip) = audio::downcast_source::<audio_clip::AudioClip>(&*source) {
+            let mut clip = clip.clone();
+            clip.set_beats_per_second(beats_per_second);
+            let format = audio::AudioSource::format(&clip);
+
+            self.audio_engine_handle
+                .set_audio_source(audio_id, Arc::new(clip));
+
+            // every block built from the source changes length
+            for audio_block in Arc::make_mut(&mut self.audio_blocks).values_mut() {
+                if audio_block.audio_id == audio_id {
+                    audio_block.set_format(format.clone(), beats_per_second);
+                }
+            }
+
+            if self
+                .selected_audio_block
+                .is_some_and(|selected| self.audio_blocks[&selected].audio_id == audio_id)
+            {
+                self.selected_audio_source_clone = self
+                    .audio_engine_handle
+                    .get_audio_source_clone(audio_id)
+                    .ok();
+            }
+        }
+
+        self.beats_per_minute = (beats_per_minute * 10.0).round() / 10.0;
+        self.audio_engine_handle
+            .set_beats_per_second(self.beats_per_minute / 60.0);
+
+        self.log_step(format!("Set Tempo to {} bpm", self.beats_per_minute));
+    }
+
+    pub fn toggle_playing(&mut self, beats_per_second: f64) {
+        if self.playing {
+            self.pause_playing(beats_per_second);
+        } else {
+            self.start_playing();
+        }
+    }
 }
 
 fn create_block_list() -> impl Widget<AppState> {
+    // as wide as the splitter leaves it
     Scroll::new(List::new(|| {
         Flex::column()
-            .fix_size(120.0, 80.0)
+            .fix_height(80.0)
+            .expand_width()
             .background(Painter::new(
                 |ctx, data: &(Arc<HashMap<AudioBlockID, AudioBlock>>, AudioBlockID), _| {
                     let rect = Rect::from_origin_size((0.0, 0.0), ctx.size());
@@ -166,10 +1176,19 @@ fn create_block_list() -> impl Widget<AppState> {
                         // on right click, offer option to remove block
                         Event::MouseDown(mouse_event) if mouse_event.button.is_right() => {
                             let menu = ContextMenu::<AppState>::new(
-                                MenuDesc::empty().append(MenuItem::new(
-                                    LocalizedString::new("Remove"),
-                                    Command::new(commands::REMOVE_AUDIO_BLOCK, data.1),
-                                )),
+                                MenuDesc::empty()
+                                    .append(MenuItem::new(
+                                        LocalizedString::new("Trim Silence"),
+                                        Command::new(commands::TRIM_AUDIO_BLOCK, data.1),
+                                    ))
+                                    .append(MenuItem::new(
+                                        LocalizedString::new("Estimate Tempo"),
+                                        Command::new(commands::ESTIMATE_AUDIO_BLOCK_TEMPO, data.1),
+                                    ))
+                                    .append(MenuItem::new(
+                                        LocalizedString::new("Remove"),
+                                        Command::new(commands::REMOVE_AUDIO_BLOCK, data.1),
+                                    )),
                                 mouse_event.window_pos,
                             );
 
@@ -181,8 +1200,8 @@ fn create_block_list() -> impl Widget<AppState> {
                 },
             ))
     }))
+    .vertical()
     .expand_height()
-    .fix_width(120.0)
     .border(theme::BORDER_COLOR, theme::BORDER_WIDTH)
     .rounded(5.0)
     .lens(lens::Id.map(
@@ -191,6 +1210,98 @@ fn create_block_list() -> impl Widget<AppState> {
     ))
 }
 
+/// A loop count without trailing zeros, `4` or `2.5`.
+pub fn format_loops(loops: f64) -> String {
+    ((loops * 100.0).round() / 100.0).to_string()
+}
+
+/// The audio block `selected` out of the state.
+fn audio_block_lens(selected: AudioBlockID) -> impl Lens<AppState, AudioBlock> {
+    AppState::audio_blocks.map(
+        move |data: &Arc<HashMap<AudioBlockID, AudioBlock>>| data[&selected].clone(),
+        move |data, val| {
+            Arc::make_mut(data).insert(selected, val);
+        },
+    )
+}
+
+/// The clone of the selected source and its audio block, for the source editors.
+///
+/// The offset shown includes the own offset of the selected block in the arrangement, if it plays
+/// the audio block, and changing it only moves that block. Without one the shared offset of the
+/// audio block is edited.
+fn selected_source_lens() -> impl Lens<AppState, (Arc<dyn audio::AudioSource>, AudioBlock)> {
+    lens::Map::new(
+        |data: &AppState| {
+            let selected = data.selected_audio_block.unwrap();
+            let mut audio_block = data.audio_blocks[&selected].clone();
+            audio_block.offset = data.selected_block_offset(selected);
+
+            (
+                data.selected_audio_source_clone.clone().unwrap(),
+                audio_block,
+            )
+        },
+        |data: &mut AppState, (source, mut audio_block): (_, AudioBlock)| {
+            let selected = data.selected_audio_block.unwrap();
+            let audio_id = data.audio_blocks[&selected].audio_id;
+
+            // the editor edits a clone, so changes have to be sent back to the engine
+            if !data
+                .selected_audio_source_clone
+                .as_ref()
+                .is_some_and(|clone| Arc::ptr_eq(clone, &source))
+            {
+                data.audio_engine_handle
+                    .set_audio_source(audio_id, source.arc_clone());
+            }
+
+            data.selected_audio_source_clone = Some(source);
+
+            if let Some((track_index, block_index)) = data.selected_block_index_of(selected) {
+                let shift = audio_block.offset - data.selected_block_offset(selected);
+                audio_block.offset = data.audio_blocks[&selected].offset;
+
+                // the lens always writes back, only an actual change should touch the arrangement
+                if shift != 0.0 {
+                    Arc::make_mut(&mut data.arrangement.tracks)[track_index].blocks[block_index]
+                        .offset += shift;
+                }
+            }
+
+            *Arc::make_mut(&mut data.audio_blocks)
+                .get_mut(&selected)
+                .unwrap() = audio_block;
+        },
+    )
+}
+
+/// How many times the selected block in the arrangement plays the audio block `selected`, typing
+/// a count resizes the block to it.
+fn create_loops_row(selected: AudioBlockID) -> impl Widget<AppState> {
+    Flex::row()
+        .with_child(Label::new(move |data: &AppState, _env: &Env| {
+            match data.audio_blocks.get(&selected) {
+                Some(audio_block) if data.selected_block_loops(selected).is_some() => {
+                    format!("Loops of {} beats", audio_block.len_beats)
+                }
+                _ => "Loops".to_owned(),
+            }
+        }))
+        .with_child(
+            Parse::new(controllers::text_box())
+                .lens(lens::Map::new(
+                    move |data: &AppState| data.selected_block_loops(selected),
+                    |data: &mut AppState, val: Option<f64>| {
+                        if let Some(loops) = val.filter(|loops| *loops > 0.0) {
+                            data.set_selected_block_loops(loops);
+                        }
+                    },
+                ))
+                .fix_width(50.0),
+        )
+}
+
 fn create_block_menu(selected: AudioBlockID) -> impl Widget<AppState> {
     const NUM_COLORS: u32 = 30;
 
@@ -206,106 +1317,295 @@ fn create_block_menu(selected: AudioBlockID) -> impl Widget<AppState> {
                 ctx.fill(rect, &color);
             })
             .fix_size(30.0, 20.0)
-            .on_click(move |ctx, data: &mut AudioBlock, _env| {
-                data.color = cloned_color.clone();
-                ctx.window().invalidate();
+            .on_click(move |ctx, _data: &mut AudioBlock, _env| {
+                // goes through the delegate so the change ends up in the history
+                ctx.submit_command(
+                    Command::new(
+                        commands::SET_AUDIO_BLOCK_COLOR,
+                        (selected, cloned_color.clone()),
+                    ),
+                    None,
+                );
             }),
         );
         block_color_pick.add_spacer(2.0);
     }
 
-    Scroll::new(block_color_pick)
-        .vertical()
-        .border(theme::BORDER_COLOR, theme::BORDER_WIDTH)
-        .rounded(5.0)
+    let properties = Flex::column()
+        .with_child(
+            controllers::text_box()
+                .fix_width(120.0)
+                .lens(AudioBlock::name),
+        )
+        .with_spacer(5.0)
+        .with_child(
+            Flex::row()
+                .with_child(Label::new("Offset"))
+                .with_child(
+                    Parse::new(controllers::text_box())
+                        .lens(lens::Map::new(
+                            |offset: &f32| Some((*offset as f64 * 100.0).round() / 100.0),
+                            |offset, val: Option<f64>| {
+                                if let Some(val) = val {
+                                    *offset = val as f32;
+                                }
+                            },
+                        ))
+                        .fix_width(50.0)
+                        .lens(AudioBlock::offset),
+                )
+                .with_child(Label::new("beats")),
+        )
+        .with_spacer(5.0)
+        .with_child(Checkbox::new("Keep Pitch").lens(AudioBlock::time_stretch))
+        .with_spacer(5.0)
+        .with_child(
+            Flex::row()
+                .with_child(Label::new(|data: &AudioBlock, _env: &Env| {
+                    format!("Pitch {:+}", data.pitch_semitones)
+                }))
+                .with_child(
+                    Stepper::new()
+                        .with_range(-24.0, 24.0)
+                        .with_step(1.0)
+                        .lens(lens::Map::new(
+                            |pitch: &i32| *pitch as f64,
+                            |pitch, val: f64| *pitch = val.round() as i32,
+                        ))
+                        .lens(AudioBlock::pitch_semitones),
+                ),
+        )
+        .lens(audio_block_lens(selected));
+
+    Flex::column()
+        .with_child(properties)
+        .with_spacer(5.0)
+        .with_child(create_loops_row(selected))
+        .with_spacer(5.0)
+        .with_flex_child(
+            Scroll::new(block_color_pick)
+                .vertical()
+                .border(theme::BORDER_COLOR, theme::BORDER_WIDTH)
+                .rounded(5.0)
+                .lens(audio_block_lens(selected)),
+            1.0,
+        )
         .align_left()
-        .lens(AppState::audio_blocks.map(
-            move |data: &Arc<HashMap<AudioBlockID, AudioBlock>>| data[&selected].clone(),
-            move |data, val| {
-                Arc::make_mut(data).insert(selected, val);
-            },
-        ))
+}
+
+/// A blinking indicator and the length of the recording, empty while not recording.
+fn format_recorded_time(data: &AppState, _env: &Env) -> String {
+    if !data.recording {
+        return String::new();
+    }
+
+    // nothing is captured until the input gets louder than the noise
+    if data.recorded_time == 0.0 {
+        return "REC  waiting for input".to_owned();
+    }
+
+    let tenths = (data.recorded_time * 10.0) as usize;
+    let dot = if tenths % 10 < 5 { "\u{25cf}" } else { " " };
+
+    format!(
+        "{} REC  {:02}:{:02}.{}",
+        dot,
+        tenths / 600,
+        tenths / 10 % 60,
+        tenths % 10
+    )
+}
+
+/// Formats the play time as `bar.beat.ticks` and `mm:ss.t`, bars and beats count from one.
+fn format_play_time(data: &AppState, env: &Env) -> String {
+    const TICKS_PER_BEAT: f64 = 100.0;
+
+    let beats_per_bar = data.arrangement.beats.max(1);
+    let beat = data
+        .arrangement
+        .tempo_map()
+        .beat_at_time(
+            data.play_time,
+            env.get(settings::ARRANGEMENT_BEATS_PER_SECOND),
+        )
+        .max(0.0);
+    let whole_beat = beat.floor() as usize;
+    let ticks = ((beat.fract() * TICKS_PER_BEAT) as usize).min(TICKS_PER_BEAT as usize - 1);
+
+    let tenths = (data.play_time.max(0.0) * 10.0) as usize;
+
+    format!(
+        "{}.{}.{:02}  {:02}:{:02}.{}",
+        whole_beat / beats_per_bar + 1,
+        whole_beat % beats_per_bar + 1,
+        ticks,
+        tenths / 600,
+        tenths / 10 % 60,
+        tenths % 10,
+    )
+}
+
+/// Sums up the tempo and position of the play line, the selected block and the state of the
+/// audio engine.
+fn format_status(data: &AppState, env: &Env) -> String {
+    let beats_per_bar = data.arrangement.beats.max(1);
+    let beats_per_second = env.get(settings::ARRANGEMENT_BEATS_PER_SECOND);
+    let tempo_map = data.arrangement.tempo_map();
+    let beat = tempo_map
+        .beat_at_time(data.play_time, beats_per_second)
+        .max(0.0) as usize;
+    // the tempo where the play line is, the tempo track may have changed it from the base
+    let beats_per_minute = tempo_map.beats_per_second(beat, beats_per_second) * 60.0;
+
+    let selected = match data.selected_audio_block {
+        Some(id) => {
+            let block = &data.audio_blocks[&id];
+
+            format!(
+                "{}, block {}, source {}",
+                block.name, id.0, block.audio_id.0
+            )
+        }
+        None => String::from("No block selected"),
+    };
+
+    let output = match data.audio_engine_handle.output_format() {
+        Some((sample_rate, channels)) => format!("{} Hz, {} channels", sample_rate, channels),
+        None => String::from("No output"),
+    };
+
+    format!(
+        "{:.1} bpm    {}:{}    {}    {}    Latency {:.0} ms    Load {:.0}%",
+        beats_per_minute,
+        beat / beats_per_bar + 1,
+        beat % beats_per_bar + 1,
+        selected,
+        output,
+        data.latency * 1000.0,
+        data.audio_load * 100.0,
+    )
+}
+
+fn create_status_bar() -> impl Widget<AppState> {
+    Label::new(format_status).align_left().padding((5.0, 0.0))
 }
 
 fn create_top_bar() -> impl Widget<AppState> {
     Flex::row()
         .with_child(ViewSwitcher::new(
-            |data: &AppState, _| data.playing,
-            |selector, _, _| match selector {
-                true => Box::new(
-                    Button::new("Stop").on_click(|_ctx, data: &mut AppState, env| {
-                        data.playing = false;
-                        data.audio_engine_handle.set_playing(false);
-
-                        if let Some((id, format)) = data.audio_engine_handle.stop_recording() {
-                            log::info!("{:?}", format);
-
-                            Arc::make_mut(&mut data.audio_blocks).insert(
-                                data.next_audio_block_id,
-                                AudioBlock::new(
-                                    id,
-                                    format,
-                                    env.get(settings::ARRANGEMENT_BEATS_PER_SECOND),
-                                ),
-                            );
-                            Arc::make_mut(&mut data.shown_audio_blocks)
-                                .push(data.next_audio_block_id);
-                            data.next_audio_block_id.0 += 1;
-                        }
-                    }),
-                ),
-                false => Box::new(
-                    Flex::row()
-                        .with_child(Button::new("Play").on_click(
-                            |_ctx, data: &mut AppState, _env| {
-                                data.playing = true;
-                                data.audio_engine_handle.set_playing(true);
+            // paused when stopped somewhere past the start
+            |data: &AppState, _| (data.playing, data.play_time > 0.0, data.has_input),
+            |selector, _, _| {
+                let stop = Button::new("Stop").on_click(|ctx, data: &mut AppState, env| {
+                    data.stop_playing(env.get(settings::ARRANGEMENT_BEATS_PER_SECOND));
 
-                                let arrangement_index =
-                                    data.arrangement.compile_index(&data.audio_blocks);
+                    ctx.submit_command(
+                        Command::new(commands::ARRANGEMENT_UPDATE_PLAY_LINE, 0.0),
+                        Target::Widget(ARRANGEMENT_WIDGET_ID),
+                    );
+                });
 
-                                data.audio_engine_handle
-                                    .set_arrangement_index(arrangement_index);
-                            },
-                        ))
-                        .with_child(Button::new("Record").on_click(
+                match selector {
+                    (true, _, _) => Box::new(
+                        Flex::row()
+                            .with_child(Button::new("Pause").on_click(
+                                |_ctx, data: &mut AppState, env| {
+                                    data.pause_playing(
+                                        env.get(settings::ARRANGEMENT_BEATS_PER_SECOND),
+                                    );
+                                },
+                            ))
+                            .with_child(stop),
+                    ),
+                    (false, paused, has_input) => {
+                        let mut row = Flex::row().with_child(Button::new("Play").on_click(
                             |_ctx, data: &mut AppState, _env| {
-                                data.recording = true;
-                                data.playing = true;
-                                data.audio_engine_handle.start_recording();
+                                data.start_playing();
+                            },
+                        ));
 
-                                let arrangement_index =
-                                    data.arrangement.compile_index(&data.audio_blocks);
+                        // recording needs an input device
+                        if *has_input {
+                            row.add_child(Button::new("Record").on_click(
+                                |_ctx, data: &mut AppState, _env| {
+                                    data.start_recording();
+                                },
+                            ));
+                        }
 
-                                data.audio_engine_handle
-                                    .set_arrangement_index(arrangement_index);
-                            },
-                        )),
-                ),
+                        // stopping while paused only goes back to the start
+                        if *paused {
+                            row.add_child(stop);
+                        }
+
+                        Box::new(row)
+                    }
+                }
             },
         ))
+        .with_spacer(10.0)
+        // fixed width, so the bar doesn't shift around as the digits change
+        .with_child(Label::new(format_play_time).fix_width(140.0))
+        .with_child(
+            Label::new(format_recorded_time)
+                .with_text_color(theme::ERROR_TEXT_COLOR)
+                .fix_width(170.0),
+        )
         .with_spacer(5.0)
         .with_child(Checkbox::new("Feedback").lens(lens::Id.map(
             |data: &AppState| data.feedback,
             |data, val| {
                 data.feedback = val;
-                data.audio_engine_handle.set_feedback(data.feedback);
+                data.update_monitoring();
             },
         )))
-        .with_spacer(15.0)
-        .with_child(Label::new("Volume"))
-        .with_child(Slider::new().with_range(0.0, 5.0).lens(lens::Map::new(
-            |data: &AppState| data.volume,
+        .with_spacer(5.0)
+        .with_child(Checkbox::new("Stop at End").lens(lens::Id.map(
+            |data: &AppState| data.stop_at_end,
             |data, val| {
-                data.volume = val;
-                data.audio_engine_handle.set_volume(data.volume);
+                data.stop_at_end = val;
+                data.audio_engine_handle.set_stop_at_end(data.stop_at_end);
             },
         )))
         .with_spacer(15.0)
+        .with_child(Label::new("Volume"))
+        .with_child(
+            Slider::new()
+                .with_range(audio::MIN_VOLUME_DB, audio::MAX_VOLUME_DB)
+                .lens(lens::Map::new(
+                    |data: &AppState| audio::linear_to_db(data.volume),
+                    |data, val| {
+                        data.volume = audio::db_to_linear(val);
+                        data.audio_engine_handle.set_volume(data.volume);
+                    },
+                )),
+        )
+        .with_child(
+            Parse::new(controllers::text_box())
+                .lens(lens::Map::new(
+                    |data: &AppState| {
+                        Some((audio::linear_to_db(data.volume) * 10.0).round() / 10.0)
+                    },
+                    |data, val: Option<f64>| {
+                        if let Some(db) = val {
+                            let volume = audio::db_to_linear(db);
+
+                            if (volume - data.volume).abs() > f64::EPSILON {
+                                data.volume = volume;
+                                data.audio_engine_handle.set_volume(data.volume);
+                            }
+                        }
+                    },
+                ))
+                .fix_width(45.0),
+        )
+        .with_child(Label::new("dB"))
+        .with_spacer(15.0)
         .with_child(Label::new("bpm"))
         .with_child(
             TextBox::new()
                 .with_placeholder("0")
+                .controller(controllers::TextBoxController::default())
                 .lens(lens::Map::new(
                     |data: &AppState| {
                         if data.beats_per_minute == 0.0 {
@@ -326,82 +1626,373 @@ fn create_top_bar() -> impl Widget<AppState> {
                 ))
                 .fix_width(35.0),
         )
+        .with_spacer(5.0)
+        .with_child(
+            Button::new(
+                |data: &AppState, _env: &Env| match data.tap_tempo.beats_per_minute {
+                    Some(beats_per_minute) => format!("Tap ({:.1})", beats_per_minute),
+                    None => "Tap".to_owned(),
+                },
+            )
+            .on_click(|_ctx, data: &mut AppState, _env| {
+                if let Some(beats_per_minute) = data.tap_tempo.tap(std::time::Instant::now()) {
+                    data.beats_per_minute = (beats_per_minute * 10.0).round() / 10.0;
+                    data.audio_engine_handle
+                        .set_beats_per_second(data.beats_per_minute / 60.0);
+                }
+            }),
+        )
+        .with_spacer(15.0)
+        .with_child(Label::new(|data: &AppState, _env: &Env| {
+            format!("Swing {}%", (data.arrangement.swing * 100.0).round())
+        }))
+        .with_child(
+            Stepper::new()
+                .with_range(0.0, 66.0)
+                .with_step(6.0)
+                .lens(lens::Map::new(
+                    |data: &AppState| (data.arrangement.swing * 100.0).round(),
+                    |data: &mut AppState, val: f64| {
+                        // the getter rounds, a swing between the steps isn't a change
+                        if val.round() != (data.arrangement.swing * 100.0).round() {
+                            data.arrangement.swing =
+                                (val / 100.0).clamp(0.0, arrangement::MAX_SWING);
+                            data.log_step(format!("Set Swing to {}%", val.round()));
+                        }
+                    },
+                )),
+        )
+        .with_spacer(15.0)
+        .with_child(ViewSwitcher::new(
+            |data: &AppState, _| data.renamed_marker,
+            |selector, _, _| match selector {
+                Some(index) => {
+                    let index = *index;
+
+                    Box::new(
+                        Flex::row()
+                            .with_child(Label::new("Marker"))
+                            .with_child(
+                                controllers::text_box()
+                                    .lens(lens::Map::new(
+                                        move |data: &AppState| {
+                                            data.arrangement
+                                                .markers
+                                                .get(index)
+                                                .map(|marker| marker.label.clone())
+                                                .unwrap_or_default()
+                                        },
+                                        move |data, val| {
+                                            if data
+                                                .arrangement
+                                                .markers
+                                                .get(index)
+                                                .map(|marker| &marker.label)
+                                                != Some(&val)
+                                            {
+                                                if let Some(marker) =
+                                                    Arc::make_mut(&mut data.arrangement.markers)
+                                                        .get_mut(index)
+                                                {
+                                                    marker.label = val;
+                                                }
+                                            }
+                                        },
+                                    ))
+                                    .fix_width(100.0),
+                            )
+                            .with_child(Button::new("Done").on_click(
+                                |_ctx, data: &mut AppState, _env| {
+                                    data.renamed_marker = None;
+                                },
+                            )),
+                    )
+                }
+                None => Box::new(Flex::row()),
+            },
+        ))
+        .with_spacer(15.0)
+        .with_child(ViewSwitcher::new(
+            |data: &AppState, _| data.removed_track,
+            |selector, _, _| match selector {
+                Some(index) => {
+                    let index = *index;
+
+                    Box::new(
+                        Flex::row()
+                            .with_child(Label::new(move |data: &AppState, _env: &Env| {
+                                let blocks = data
+                                    .arrangement
+                                    .tracks
+                                    .get(index)
+                                    .map_or(0, |track| track.blocks.len());
+
+                                format!("Remove Track {} and its {} blocks?", index + 1, blocks)
+                            }))
+                            .with_child(Button::new("Remove").on_click(
+                                move |_ctx, data: &mut AppState, _env| {
+                                    if index < data.arrangement.tracks.len() {
+                                        data.arrangement.remove_track(index);
+                                        data.selected_block = None;
+                                        data.selected_blocks = Arc::new(Vec::new());
+                                        data.selected_track = None;
+                                        data.set_armed_track(None);
+
+                                        data.log_step(format!("Removed Track {}", index + 1));
+                                    }
+
+                                    data.removed_track = None;
+                                },
+                            ))
+                            .with_child(Button::new("Cancel").on_click(
+                                |_ctx, data: &mut AppState, _env| {
+                                    data.removed_track = None;
+                                },
+                            )),
+                    )
+                }
+                None => Box::new(Flex::row()),
+            },
+        ))
+        .with_spacer(15.0)
+        .with_child(ViewSwitcher::new(
+            |data: &AppState, _| data.estimated_tempo,
+            |selector, _, _| match selector {
+                Some((audio_block_id, beats_per_minute)) => {
+                    let (audio_block_id, beats_per_minute) = (*audio_block_id, *beats_per_minute);
+
+                    Box::new(
+                        Flex::row()
+                            .with_child(Label::new(format!(
+                                "Set the tempo to {:.1} bpm?",
+                                beats_per_minute
+                            )))
+                            .with_child(Button::new("Set").on_click(
+                                move |_ctx, data: &mut AppState, _env| {
+                                    data.set_tempo_from_block(audio_block_id, beats_per_minute);
+                                    data.estimated_tempo = None;
+                                },
+                            ))
+                            .with_child(Button::new("Cancel").on_click(
+                                |_ctx, data: &mut AppState, _env| {
+                                    data.estimated_tempo = None;
+                                },
+                            )),
+                    )
+                }
+                None => Box::new(Flex::row()),
+            },
+        ))
+        .with_spacer(15.0)
+        .with_child(
+            Label::new(|data: &AppState, _env: &Env| data.audio_error.clone().unwrap_or_default())
+                .with_text_color(theme::ERROR_TEXT_COLOR),
+        )
         .align_left()
 }
 
-fn create_menu() -> impl druid::Widget<AppState> {
+/// The settings of the delay every track can send to.
+fn create_effects_panel() -> impl Widget<AppState> {
+    ViewSwitcher::new(
+        |data: &AppState, _| data.show_effects,
+        |show_effects, _, _| {
+            if !*show_effects {
+                return Box::new(Flex::column());
+            }
+
+            Box::new(
+                Flex::column()
+                    .cross_axis_alignment(CrossAxisAlignment::Start)
+                    .with_child(Label::new("Delay"))
+                    .with_spacer(5.0)
+                    .with_child(
+                        Flex::row()
+                            .with_child(Label::new(|data: &delay::DelaySettings, _env: &Env| {
+                                format!("Time {} beats", data.beats)
+                            }))
+                            .with_child(
+                                Stepper::new()
+                                    .with_range(0.25, 4.0)
+                                    .with_step(0.25)
+                                    .lens(delay::DelaySettings::beats),
+                            ),
+                    )
+                    .with_child(Label::new("Feedback"))
+                    .with_child(
+                        Slider::new()
+                            .with_range(0.0, delay::MAX_FEEDBACK as f64)
+                            .lens(lens::Map::new(
+                                |feedback: &f32| *feedback as f64,
+                                |feedback, val: f64| *feedback = val as f32,
+                            ))
+                            .lens(delay::DelaySettings::feedback),
+                    )
+                    .with_child(Label::new("Wet"))
+                    .with_child(
+                        Slider::new()
+                            .lens(lens::Map::new(
+                                |wet: &f32| *wet as f64,
+                                |wet, val: f64| *wet = val as f32,
+                            ))
+                            .lens(delay::DelaySettings::wet),
+                    )
+                    .lens(lens::Map::new(
+                        |data: &AppState| data.delay,
+                        |data: &mut AppState, val: delay::DelaySettings| {
+                            // every change goes to the engine, so only send actual changes
+                            if !data.delay.same(&val) {
+                                data.delay = val;
+                                data.audio_engine_handle.set_delay(val);
+                            }
+                        },
+                    ))
+                    .padding(5.0)
+                    .expand_height()
+                    .fix_width(150.0)
+                    .border(theme::BORDER_COLOR, theme::BORDER_WIDTH)
+                    .rounded(5.0),
+            )
+        },
+    )
+}
+
+/// Lists the history steps, clicking one jumps to it.
+fn create_history_panel() -> impl Widget<AppState> {
+    ViewSwitcher::new(
+        |data: &AppState, _| (data.show_history, data.history.version()),
+        |(show_history, _), data, _| {
+            if !*show_history {
+                return Box::new(Flex::column());
+            }
+
+            let mut steps = Flex::column();
+
+            for (index, step) in data.history.steps().iter().enumerate() {
+                steps.add_child(
+                    Label::new(step.label.clone())
+                        .padding((4.0, 2.0))
+                        .expand_width()
+                        .background(Painter::new(move |ctx, data: &AppState, env| {
+                            let rect = ctx.size().to_rect().to_rounded_rect(3.0);
+
+                            if data.history.position() == index {
+                                ctx.fill(rect, &env.get(theme::HISTORY_CURRENT_STEP_COLOR));
+                            }
+                        }))
+                        .on_click(move |_ctx, data: &mut AppState, _env| {
+                            if data.history.revert_to(
+                                index,
+                                &mut data.arrangement,
+                                &mut data.audio_blocks,
+                            ) {
+                                log::info!("Reverted to step {}", index);
+                            }
+                        }),
+                );
+            }
+
+            Box::new(
+                Scroll::new(steps)
+                    .vertical()
+                    .expand_height()
+                    .fix_width(150.0)
+                    .border(theme::BORDER_COLOR, theme::BORDER_WIDTH)
+                    .rounded(5.0),
+            )
+        },
+    )
+}
+
+fn create_menu(block_list_split: f64, editor_split: f64) -> impl druid::Widget<AppState> {
+    let editor = MaxBox::new(ViewSwitcher::new(
+        |data: &AppState, _| data.selected_audio_block,
+        |selector, data, _| match selector {
+            Some(selected) => {
+                let mut row = Flex::row().with_child(create_block_menu(*selected));
+
+                if let Some(source) = &data.selected_audio_source_clone {
+                    let editor = EnvScope::new(
+                        |env, data: &AppState| {
+                            env.set(piano_roll::BEATS_PER_BAR, data.arrangement.beats as u64)
+                        },
+                        source.widget().lens(selected_source_lens()),
+                    );
+
+                    row.add_flex_child(editor, 1.0);
+                }
+
+                Box::new(row)
+            }
+            None => Box::new(Flex::row().align_left()),
+        },
+    ))
+    // the source editors need some room to be usable
+    .min_height(120.0)
+    .border(theme::BORDER_COLOR, theme::BORDER_WIDTH)
+    .rounded(5.0);
+
+    let arrangement = Flex::column()
+        .with_child(
+            MinimapWidget::new()
+                .with_id(MINIMAP_WIDGET_ID)
+                .fix_height(30.0)
+                .border(theme::BORDER_COLOR, theme::BORDER_WIDTH)
+                .rounded(5.0),
+        )
+        .with_spacer(5.0)
+        .with_flex_child(
+            ArrangementWidget::new()
+                .border(theme::BORDER_COLOR, theme::BORDER_WIDTH)
+                .rounded(5.0)
+                .with_id(ARRANGEMENT_WIDGET_ID),
+            1.0,
+        );
+
+    // the splitters only remember where they were left through the config
+    let main = Splitter::columns(
+        create_block_list(),
+        Splitter::rows(editor, arrangement)
+            .split(editor_split)
+            .min_size(120.0)
+            .on_moved(|ctx, split| {
+                ctx.submit_command(commands::SET_EDITOR_SPLIT.with(split), None)
+            }),
+    )
+    .split(block_list_split)
+    .min_size(80.0)
+    .on_moved(|ctx, split| ctx.submit_command(commands::SET_BLOCK_LIST_SPLIT.with(split), None));
+
     Flex::column()
         .with_child(create_top_bar())
         .with_flex_child(
-            Flex::row().with_child(create_block_list()).with_flex_child(
-                Flex::column()
-                    .with_flex_child(
-                        ViewSwitcher::new(
-                            |data: &AppState, _| data.selected_audio_block,
-                            |selector, data, _| match selector {
-                                Some(selected) => {
-                                    let mut row =
-                                        Flex::row().with_child(create_block_menu(*selected));
-
-                                    if let Some(source) = &data.selected_audio_source_clone {
-                                        row.add_flex_child(
-                                            source.widget().lens(lens::Map::new(
-                                                move |data: &AppState| {
-                                                    (
-                                                        data.selected_audio_source_clone
-                                                            .clone()
-                                                            .unwrap(),
-                                                        data.audio_blocks
-                                                            [&data.selected_audio_block.unwrap()]
-                                                            .clone(),
-                                                    )
-                                                },
-                                                |data, val| {
-                                                    data.selected_audio_source_clone = Some(val.0);
-                                                    *Arc::make_mut(&mut data.audio_blocks)
-                                                        .get_mut(
-                                                            &data.selected_audio_block.unwrap(),
-                                                        )
-                                                        .unwrap() = val.1;
-                                                },
-                                            )),
-                                            1.0,
-                                        );
-                                    }
-
-                                    Box::new(row)
-                                }
-                                None => Box::new(Flex::row().align_left()),
-                            },
-                        )
-                        .border(theme::BORDER_COLOR, theme::BORDER_WIDTH)
-                        .rounded(5.0),
-                        1.2,
-                    )
-                    .with_flex_child(
-                        ArrangementWidget::new()
-                            .border(theme::BORDER_COLOR, theme::BORDER_WIDTH)
-                            .rounded(5.0)
-                            .with_id(ARRANGEMENT_WIDGET_ID),
-                        1.0,
-                    ),
-                1.0,
-            ),
+            Flex::row()
+                .with_flex_child(main, 1.0)
+                .with_child(create_effects_panel())
+                .with_child(create_history_panel()),
             1.0,
         )
+        .with_child(create_status_bar())
         .controller(GlobalController)
         .env_scope(|env, data: &AppState| {
             env.set(
                 settings::ARRANGEMENT_BEATS_PER_SECOND,
                 data.beats_per_minute / 60.0,
             );
+            env.set(settings::ARRANGEMENT_SWING, data.arrangement.swing);
         })
 }
 
 struct GlobalController;
 
-impl<T, W: Widget<T>> Controller<T, W> for GlobalController {
-    fn event(&mut self, child: &mut W, ctx: &mut EventCtx, event: &Event, data: &mut T, env: &Env) {
+impl<W: Widget<AppState>> Controller<AppState, W> for GlobalController {
+    fn event(
+        &mut self,
+        child: &mut W,
+        ctx: &mut EventCtx,
+        event: &Event,
+        data: &mut AppState,
+        env: &Env,
+    ) {
         match event {
             Event::MouseUp(mouse_event) => {
                 ctx.submit_command(
@@ -414,52 +2005,280 @@ impl<T, W: Widget<T>> Controller<T, W> for GlobalController {
 
         child.event(ctx, event, data, env);
     }
+
+    fn update(
+        &mut self,
+        child: &mut W,
+        ctx: &mut UpdateCtx,
+        old_data: &AppState,
+        data: &AppState,
+        env: &Env,
+    ) {
+        // starting to play compiles the whole arrangement anyway
+        if data.playing && old_data.playing {
+            data.sync_arrangement_index(old_data);
+        }
+
+        child.update(ctx, old_data, data, env);
+    }
 }
 
-fn make_menu<T: Data>() -> MenuDesc<T> {
-    MenuDesc::empty()
-        .append(druid::platform_menus::win::file::default())
+/// Lists the projects that still exist, opening them straight away.
+fn make_recent_projects_menu<T: Data>(recent_projects: &[std::path::PathBuf]) -> MenuDesc<T> {
+    let mut menu = MenuDesc::new(LocalizedString::new("Open Recent"));
+    let mut empty = true;
+
+    for path in recent_projects.iter().filter(|path| path.is_file()) {
+        menu = menu.append(MenuItem::new(
+            LocalizedString::new("musix-recent-project")
+                .with_placeholder(path.display().to_string()),
+            commands::OPEN_PROJECT.with(path.clone()),
+        ));
+        empty = false;
+    }
+
+    if empty {
+        menu = menu.append(
+            MenuItem::new(LocalizedString::new("No Recent Projects"), Selector::NOOP).disabled(),
+        );
+    }
+
+    menu
+}
+
+fn make_file_menu<T: Data>(recent_projects: &[std::path::PathBuf]) -> MenuDesc<T> {
+    let project_options = FileDialogOptions::new()
+        .allowed_types(vec![project::PROJECT_FILE_TYPE])
+        .default_type(project::PROJECT_FILE_TYPE);
+    let wav_options = FileDialogOptions::new()
+        .allowed_types(vec![project::WAV_FILE_TYPE])
+        .default_type(project::WAV_FILE_TYPE);
+    let midi_options = FileDialogOptions::new()
+        .allowed_types(vec![project::MIDI_FILE_TYPE])
+        .default_type(project::MIDI_FILE_TYPE);
+    // druid only reports the first of several selected files, so a whole folder can be imported
+    // as well
+    let folder_options = FileDialogOptions::new().select_directories();
+
+    // the panels report back with OPEN_FILE and SAVE_FILE, the delegate tells them apart by the
+    // extension of the chosen file
+    let menu = MenuDesc::new(LocalizedString::new("common-menu-file-menu"))
         .append(
-            MenuDesc::new(LocalizedString::new("Track")).append(MenuItem::new(
-                LocalizedString::new("Add Track"),
-                commands::ARRANGEMENT_ADD_TRACK,
-            )),
+            MenuItem::new(
+                LocalizedString::new("Open Project..."),
+                druid::commands::SHOW_OPEN_PANEL.with(project_options.clone()),
+            )
+            .hotkey(SysMods::Cmd, "o"),
+        )
+        .append(make_recent_projects_menu(recent_projects))
+        .append(
+            MenuItem::new(
+                LocalizedString::new("Save Project..."),
+                druid::commands::SHOW_SAVE_PANEL.with(project_options),
+            )
+            .hotkey(SysMods::Cmd, "s"),
+        )
+        // the plain save does whatever was picked here last, like the import and export below
+        .append(
+            MenuDesc::new(LocalizedString::new("Save Project As"))
+                .append(MenuItem::new(
+                    LocalizedString::new("Referencing Files..."),
+                    commands::SAVE_PROJECT_AS.with(false),
+                ))
+                .append(MenuItem::new(
+                    LocalizedString::new("Collecting Files..."),
+                    commands::SAVE_PROJECT_AS.with(true),
+                )),
+        )
+        .append_separator()
+        .append(
+            MenuItem::new(
+                LocalizedString::new("Import Audio..."),
+                druid::commands::SHOW_OPEN_PANEL.with(wav_options.clone().multi_selection()),
+            )
+            .hotkey(SysMods::Cmd, "i"),
+        )
+        .append(
+            MenuDesc::new(LocalizedString::new("Import Audio As"))
+                .append(MenuItem::new(
+                    LocalizedString::new("Copies..."),
+                    commands::IMPORT_AUDIO_AS.with(false),
+                ))
+                .append(MenuItem::new(
+                    LocalizedString::new("References..."),
+                    commands::IMPORT_AUDIO_AS.with(true),
+                )),
+        )
+        .append(MenuItem::new(
+            LocalizedString::new("Import Folder..."),
+            druid::commands::SHOW_OPEN_PANEL.with(folder_options),
+        ))
+        .append(MenuItem::new(
+            LocalizedString::new("Import MIDI..."),
+            druid::commands::SHOW_OPEN_PANEL.with(midi_options.clone()),
+        ))
+        .append(
+            MenuItem::new(
+                LocalizedString::new("Export Wav..."),
+                druid::commands::SHOW_SAVE_PANEL.with(wav_options),
+            )
+            .hotkey(SysMods::Cmd, "e"),
+        )
+        // the plain export uses whatever was picked here last
+        .append(
+            MenuDesc::new(LocalizedString::new("Export Wav As"))
+                .append(MenuItem::new(
+                    LocalizedString::new("16 Bit..."),
+                    commands::EXPORT_WAV_AS.with(wav::SampleFormat::Int16),
+                ))
+                .append(MenuItem::new(
+                    LocalizedString::new("24 Bit..."),
+                    commands::EXPORT_WAV_AS.with(wav::SampleFormat::Int24),
+                ))
+                .append(MenuItem::new(
+                    LocalizedString::new("32 Bit Float..."),
+                    commands::EXPORT_WAV_AS.with(wav::SampleFormat::Float32),
+                )),
+        )
+        .append(MenuItem::new(
+            LocalizedString::new("Export MIDI..."),
+            druid::commands::SHOW_SAVE_PANEL.with(midi_options),
+        ));
+
+    // quitting lives in the application menu on mac
+    #[cfg(not(target_os = "macos"))]
+    let menu = menu
+        .append_separator()
+        .append(platform_menus::win::file::exit());
+
+    menu
+}
+
+/// The menu bar, the delegate builds it again when the recent projects change.
+fn make_menu<T: Data>(recent_projects: &[std::path::PathBuf]) -> MenuDesc<T> {
+    let menu = MenuDesc::empty();
+
+    #[cfg(target_os = "macos")]
+    let menu = menu.append(platform_menus::mac::application::default());
+
+    menu.append(make_file_menu(recent_projects))
+        .append(
+            MenuDesc::new(LocalizedString::new("common-menu-edit-menu"))
+                .append(platform_menus::common::undo())
+                .append(platform_menus::common::redo())
+                .append_separator()
+                .append(MenuItem::new(
+                    LocalizedString::new("Show History"),
+                    commands::TOGGLE_HISTORY_PANEL,
+                ))
+                .append(MenuItem::new(
+                    LocalizedString::new("Show Effects"),
+                    commands::TOGGLE_EFFECTS_PANEL,
+                )),
+        )
+        .append(
+            MenuDesc::new(LocalizedString::new("Track"))
+                .append(MenuItem::new(
+                    LocalizedString::new("Add Track"),
+                    commands::ARRANGEMENT_ADD_TRACK,
+                ))
+                .append(MenuItem::new(
+                    LocalizedString::new("Clear Arrangement"),
+                    commands::ARRANGEMENT_CLEAR,
+                ))
+                .append_separator()
+                .append(MenuItem::new(
+                    LocalizedString::new("Edit Volume Automation"),
+                    commands::TOGGLE_AUTOMATION,
+                )),
+        )
+        .append(
+            MenuDesc::new(LocalizedString::new("Source"))
+                .append(MenuItem::new(
+                    LocalizedString::new("Add Oscillator"),
+                    commands::ADD_OSCILLATOR,
+                ))
+                .append(MenuItem::new(
+                    LocalizedString::new("Add MIDI Clip"),
+                    commands::ADD_MIDI_CLIP,
+                ))
+                .append(MenuItem::new(
+                    LocalizedString::new("Add Noise"),
+                    commands::ADD_NOISE,
+                ))
+                .append(MenuItem::new(
+                    LocalizedString::new("Add Sampler from Selected"),
+                    commands::ADD_SAMPLER,
+                )),
         )
 }
 
 fn main() {
     simple_logger::init().unwrap();
 
-    let window_desc = druid::WindowDesc::new(create_menu)
-        .window_size((1000.0, 500.0))
-        .menu(make_menu())
+    let config = config::Config::load();
+
+    let (block_list_split, editor_split) = config.splits();
+    let window_desc = druid::WindowDesc::new(move || create_menu(block_list_split, editor_split))
+        .window_size(config.window_size())
+        .menu(make_menu(&config.recent_projects))
         .title("Musix");
 
+    let env_config = config.clone();
     let launcher = druid::AppLauncher::with_window(window_desc)
-        .configure_env(|env, _| {
+        .configure_env(move |env, _| {
             theme::default(env);
             settings::default(env);
+            env_config.apply(env);
         })
-        .delegate(deligate::Deligate::default());
+        .delegate(deligate::Deligate::new(config.clone()));
 
     let (audio_engine, audio_engine_handle) =
         audio::AudioEngine::new(launcher.get_external_handle());
-    audio_engine.run();
+    audio_engine
+        .with_devices(config.input_device, config.output_device)
+        .run();
+
+    let arrangement = arrangement::Arrangement::new();
 
     let app_data = AppState {
-        arrangement: arrangement::Arrangement::new(),
+        history: history::History::new(&arrangement, &HashMap::new()),
+        show_history: false,
+        show_automation: false,
+        delay: Default::default(),
+        show_effects: false,
+        arrangement,
         audio_blocks: Arc::new(HashMap::new()),
         shown_audio_blocks: Arc::new(Vec::new()),
         listed_audio_blocks: Arc::new(Vec::new()),
         selected_audio_block: None,
         selected_audio_source_clone: None,
+        selected_block: None,
+        selected_blocks: Arc::new(Vec::new()),
+        selected_track: None,
+        armed_track: None,
+        renamed_marker: None,
+        removed_track: None,
+        estimated_tempo: None,
         next_audio_block_id: AudioBlockID(0),
         playing: false,
         recording: false,
         feedback: true,
+        stop_at_end: true,
         audio_engine_handle,
         volume: 2.5,
         beats_per_minute: 120.0,
+        tap_tempo: tap_tempo::TapTempo::default(),
+        audio_error: None,
+        has_input: true,
+        play_time: 0.0,
+        record_start_time: 0.0,
+        recorded_time: 0.0,
+        audio_load: 0.0,
+        latency: 0.0,
+        export_format: config.export_format.unwrap_or_default(),
+        reference_audio: config.reference_audio.unwrap_or(false),
+        collect_files: config.collect_files.unwrap_or(false),
     };
 
     launcher.launch(app_data).expect("launch failed");
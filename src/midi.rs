@@ -0,0 +1,429 @@
+//! Just enough of the standard midi file format to get notes in and out of musix.
+//!
+//! Only the notes and the first tempo are read, everything else is skipped. Files timed in smpte
+//! frames instead of ticks per quarter note aren't supported. Written files always use
+//! [`TICKS_PER_BEAT`], so reading one back gets the notes within a tick of where they were.
+
+use crate::midi_clip::Note;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+/// Microseconds per quarter note when the file doesn't set a tempo, 120 bpm.
+const DEFAULT_TEMPO: u32 = 500_000;
+
+pub struct MidiFile {
+    /// The first tempo of the file.
+    pub beats_per_minute: f64,
+    pub tracks: Vec<MidiTrack>,
+}
+
+#[derive(Default)]
+pub struct MidiTrack {
+    pub name: Option<String>,
+    /// In beats, a beat being a quarter note.
+    pub notes: Vec<Note>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+/// Reads through the bytes of a track chunk.
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    place: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn byte(&mut self) -> io::Result<u8> {
+        let byte = *self
+            .bytes
+            .get(self.place)
+            .ok_or_else(|| invalid("track ends in the middle of an event"))?;
+        self.place += 1;
+
+        Ok(byte)
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.place + len > self.bytes.len() {
+            return Err(invalid("track ends in the middle of an event"));
+        }
+
+        let taken = &self.bytes[self.place..self.place + len];
+        self.place += len;
+
+        Ok(taken)
+    }
+
+    /// A variable length quantity, seven bits per byte with the top bit set on all but the last.
+    fn var_len(&mut self) -> io::Result<u32> {
+        let mut value = 0u32;
+
+        for _ in 0..4 {
+            let byte = self.byte()?;
+            value = (value << 7) | (byte & 0x7f) as u32;
+
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(invalid("variable length quantity is too long"))
+    }
+}
+
+/// Reads a midi file. Events other than notes are counted and logged, not kept.
+pub fn read(path: impl AsRef<Path>) -> io::Result<MidiFile> {
+    let mut bytes = Vec::new();
+    BufReader::new(File::open(path)?).read_to_end(&mut bytes)?;
+
+    if bytes.len() < 14 || &bytes[0..4] != b"MThd" {
+        return Err(invalid("not a midi file"));
+    }
+
+    let header_len = u32_at(&bytes, 4) as usize;
+    let division = u16_at(&bytes, 12);
+
+    if division & 0x8000 != 0 {
+        return Err(invalid("midi files timed in smpte frames aren't supported"));
+    }
+
+    let ticks_per_beat = division.max(1) as f64;
+
+    let mut tempo = None;
+    let mut tracks = Vec::new();
+    let mut dropped = HashMap::new();
+    let mut place = 8 + header_len;
+
+    while place + 8 <= bytes.len() {
+        let id = &bytes[place..place + 4];
+        let len = u32_at(&bytes, place + 4) as usize;
+        let start = place + 8;
+        let end = (start + len).min(bytes.len());
+
+        if id == b"MTrk" {
+            let mut cursor = Cursor {
+                bytes: &bytes[start..end],
+                place: 0,
+            };
+
+            tracks.push(read_track(
+                &mut cursor,
+                ticks_per_beat,
+                &mut tempo,
+                &mut dropped,
+            )?);
+        }
+
+        place = start + len;
+    }
+
+    let mut dropped = dropped.into_iter().collect::<Vec<_>>();
+    dropped.sort();
+
+    for (kind, count) in dropped {
+        log::warn!("skipped {} {} events", count, kind);
+    }
+
+    Ok(MidiFile {
+        beats_per_minute: 60_000_000.0 / tempo.unwrap_or(DEFAULT_TEMPO) as f64,
+        tracks,
+    })
+}
+
+fn read_track(
+    cursor: &mut Cursor,
+    ticks_per_beat: f64,
+    tempo: &mut Option<u32>,
+    dropped: &mut HashMap<&'static str, usize>,
+) -> io::Result<MidiTrack> {
+    let mut track = MidiTrack::default();
+    // start tick of every sounding note, by channel and pitch
+    let mut sounding = HashMap::new();
+    let mut tick = 0u64;
+    let mut running_status = None;
+
+    let end_note = |notes: &mut Vec<Note>, pitch: u8, start: u64, end: u64| {
+        if end > start {
+            notes.push(Note {
+                pitch,
+                start: start as f64 / ticks_per_beat,
+                len: (end - start) as f64 / ticks_per_beat,
+            });
+        }
+    };
+
+    while cursor.place < cursor.bytes.len() {
+        tick += cursor.var_len()? as u64;
+
+        let mut status = cursor.byte()?;
+
+        if status < 0x80 {
+            // running status, the byte was already the first data byte
+            status = running_status.ok_or_else(|| invalid("data byte without a status"))?;
+            cursor.place -= 1;
+        }
+
+        match status {
+            0xff => {
+                let kind = cursor.byte()?;
+                let len = cursor.var_len()? as usize;
+                let data = cursor.take(len)?;
+
+                match kind {
+                    0x03 if track.name.is_none() => {
+                        track.name = Some(String::from_utf8_lossy(data).into_owned());
+                    }
+                    0x51 if len == 3 && tempo.is_none() => {
+                        *tempo = Some(u32::from_be_bytes([0, data[0], data[1], data[2]]));
+                    }
+                    0x2f => break,
+                    _ => (),
+                }
+            }
+            0xf0 | 0xf7 => {
+                let len = cursor.var_len()? as usize;
+                cursor.take(len)?;
+
+                *dropped.entry("sysex").or_insert(0) += 1;
+            }
+            0x80..=0xef => {
+                running_status = Some(status);
+
+                let channel = status & 0x0f;
+
+                match status & 0xf0 {
+                    0x80 | 0x90 => {
+                        let pitch = cursor.byte()? & 0x7f;
+                        let velocity = cursor.byte()?;
+
+                        let started = sounding.remove(&(channel, pitch));
+
+                        if let Some(start) = started {
+                            end_note(&mut track.notes, pitch, start, tick);
+                        }
+
+                        // a note on with no velocity is a note off
+                        if status & 0xf0 == 0x90 && velocity > 0 {
+                            sounding.insert((channel, pitch), tick);
+                        }
+                    }
+                    0xc0 | 0xd0 => {
+                        cursor.byte()?;
+
+                        *dropped.entry(channel_event_name(status)).or_insert(0) += 1;
+                    }
+                    _ => {
+                        cursor.take(2)?;
+
+                        *dropped.entry(channel_event_name(status)).or_insert(0) += 1;
+                    }
+                }
+            }
+            _ => return Err(invalid("unknown event")),
+        }
+    }
+
+    // notes never let go of end with the track
+    for ((_, pitch), start) in sounding {
+        end_note(&mut track.notes, pitch, start, tick);
+    }
+
+    track.notes.sort_by(|a, b| a.start.total_cmp(&b.start));
+
+    Ok(track)
+}
+
+fn channel_event_name(status: u8) -> &'static str {
+    match status & 0xf0 {
+        0xa0 => "aftertouch",
+        0xb0 => "control change",
+        0xc0 => "program change",
+        0xd0 => "channel pressure",
+        _ => "pitch bend",
+    }
+}
+
+/// How finely written files divide a beat.
+pub const TICKS_PER_BEAT: u16 = 480;
+
+/// Velocity of every written note, musix doesn't have velocities of its own.
+const VELOCITY: u8 = 100;
+
+fn write_var_len(bytes: &mut Vec<u8>, value: u32) {
+    let mut groups = vec![(value & 0x7f) as u8];
+    let mut value = value >> 7;
+
+    while value > 0 {
+        groups.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+
+    bytes.extend(groups.iter().rev());
+}
+
+fn to_ticks(beat: f64) -> u32 {
+    (beat * TICKS_PER_BEAT as f64).round().max(0.0) as u32
+}
+
+/// Turns events at absolute ticks into a track chunk.
+fn write_track(file: &mut Vec<u8>, mut events: Vec<(u32, Vec<u8>)>) {
+    // stable, so a note off stays before a note on at the same tick
+    events.sort_by_key(|(tick, _)| *tick);
+
+    let mut bytes = Vec::new();
+    let mut tick = 0;
+
+    for (event_tick, event) in events {
+        write_var_len(&mut bytes, event_tick - tick);
+        bytes.extend(event);
+        tick = event_tick;
+    }
+
+    // end of track
+    bytes.extend([0, 0xff, 0x2f, 0]);
+
+    file.extend(b"MTrk");
+    file.extend((bytes.len() as u32).to_be_bytes());
+    file.extend(bytes);
+}
+
+/// Writes a midi file with a tempo track followed by `tracks`, every note on channel 1.
+/// `tempo_changes` are in beats and beats per minute, the first should be at beat 0.
+pub fn write(
+    path: impl AsRef<Path>,
+    tempo_changes: &[(f64, f64)],
+    tracks: &[MidiTrack],
+) -> io::Result<()> {
+    let mut file = Vec::new();
+
+    file.extend(b"MThd");
+    file.extend(6u32.to_be_bytes());
+    file.extend(1u16.to_be_bytes());
+    file.extend((tracks.len() as u16 + 1).to_be_bytes());
+    file.extend(TICKS_PER_BEAT.to_be_bytes());
+
+    let tempo_events = tempo_changes
+        .iter()
+        .map(|(beat, beats_per_minute)| {
+            let tempo = (60_000_000.0 / beats_per_minute) as u32;
+            let [_, a, b, c] = tempo.to_be_bytes();
+
+            (to_ticks(*beat), vec![0xff, 0x51, 3, a, b, c])
+        })
+        .collect();
+    write_track(&mut file, tempo_events);
+
+    for track in tracks {
+        let mut events = Vec::new();
+
+        if let Some(name) = &track.name {
+            let mut event = vec![0xff, 0x03];
+            write_var_len(&mut event, name.len() as u32);
+            event.extend(name.as_bytes());
+
+            events.push((0, event));
+        }
+
+        // notes shorter than a tick would have their off before their on
+        let notes = track
+            .notes
+            .iter()
+            .filter(|note| to_ticks(note.end()) > to_ticks(note.start));
+
+        // the offs go first, so a note ending where the next one starts lets go before it
+        for note in notes.clone() {
+            events.push((to_ticks(note.end()), vec![0x80, note.pitch & 0x7f, 0]));
+        }
+
+        for note in notes {
+            events.push((
+                to_ticks(note.start),
+                vec![0x90, note.pitch & 0x7f, VELOCITY],
+            ));
+        }
+
+        write_track(&mut file, events);
+    }
+
+    BufWriter::new(File::create(path)?).write_all(&file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(pitch: u8, start: f64, len: f64) -> Note {
+        Note { pitch, start, len }
+    }
+
+    fn sorted(mut notes: Vec<Note>) -> Vec<Note> {
+        notes.sort_by(|a, b| a.start.total_cmp(&b.start).then(a.pitch.cmp(&b.pitch)));
+        notes
+    }
+
+    #[test]
+    fn written_notes_read_back() {
+        let notes = vec![
+            // a chord, with a note held over the next one
+            note(60, 0.0, 1.0),
+            note(64, 0.0, 1.0),
+            note(67, 0.0, 2.5),
+            // the same pitch again right as it lets go
+            note(60, 1.0, 0.5),
+            // starts and lengths between ticks
+            note(72, 1.0 / 7.0, 2.0 / 3.0),
+            note(48, 3.3, 0.123),
+            note(67, 4.05, 1.0 / 3.0),
+        ];
+        let path = std::env::temp_dir().join(format!("musix-midi-{}.mid", std::process::id()));
+        let track = MidiTrack {
+            name: Some("Lead".to_owned()),
+            notes: notes.clone(),
+        };
+
+        write(&path, &[(0.0, 90.0)], &[track]).unwrap();
+        let file = read(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert!((file.beats_per_minute - 90.0).abs() < 0.001);
+        // the tempo track comes first
+        assert_eq!(file.tracks.len(), 2);
+        assert!(file.tracks[0].notes.is_empty());
+        assert_eq!(file.tracks[1].name.as_deref(), Some("Lead"));
+
+        let read_notes = sorted(file.tracks[1].notes.clone());
+        assert_eq!(read_notes.len(), notes.len());
+
+        let tick = 1.0 / TICKS_PER_BEAT as f64;
+
+        for (read, written) in read_notes.iter().zip(sorted(notes)) {
+            assert_eq!(read.pitch, written.pitch);
+            assert!(
+                (read.start - written.start).abs() <= tick,
+                "{} started at {}",
+                written.start,
+                read.start
+            );
+            assert!(
+                (read.len - written.len).abs() <= tick,
+                "{} long came back {} long",
+                written.len,
+                read.len
+            );
+        }
+    }
+}
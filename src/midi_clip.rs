@@ -0,0 +1,123 @@
+use crate::{audio::*, oscillator::Waveform, widgets::piano_roll::PianoRoll};
+use druid::{widget::*, *};
+use std::sync::Arc;
+
+/// How long a new clip is, four bars of four.
+pub const DEFAULT_LEN_BEATS: usize = 16;
+
+/// Peak amplitude of a single note, low enough that a few chords don't clip.
+const NOTE_AMPLITUDE: f32 = 0.15;
+/// Fade at both ends of every note, so they start and stop without clicking.
+const NOTE_FADE_SECONDS: f64 = 0.005;
+
+/// A note of a clip, `pitch` is the midi note number.
+#[derive(Clone, Copy, Debug, Data, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Note {
+    pub pitch: u8,
+    /// In beats from the start of the clip.
+    pub start: f64,
+    /// In beats.
+    pub len: f64,
+}
+
+impl Note {
+    pub fn end(&self) -> f64 {
+        self.start + self.len
+    }
+
+    pub fn freq(&self) -> f64 {
+        440.0 * 2.0_f64.powf((self.pitch as f64 - 69.0) / 12.0)
+    }
+}
+
+/// Notes played by a simple synth, every note gets its own voice.
+///
+/// The notes are in beats, so unlike the oscillator the clip follows the tempo while the pitch
+/// stays the same.
+#[derive(Clone, Data, Lens)]
+pub struct MidiClip {
+    format: AudioSourceFormat,
+    pub waveform: Waveform,
+    pub notes: Arc<Vec<Note>>,
+}
+
+impl MidiClip {
+    pub fn new(
+        waveform: Waveform,
+        notes: Vec<Note>,
+        len_beats: usize,
+        sample_rate: u32,
+        channels: u32,
+        beats_per_second: f64,
+    ) -> Self {
+        Self {
+            format: AudioSourceFormat {
+                sample_rate,
+                len_frames: (len_beats as f64 / beats_per_second * sample_rate as f64) as u32,
+                channels,
+                beats_per_second,
+            },
+            waveform,
+            notes: Arc::new(notes),
+        }
+    }
+
+    /// Length of the clip in beats, at the tempo it was created with.
+    pub fn len_beats(&self) -> usize {
+        (self.format.len_frames as f64 / self.format.sample_rate as f64
+            * self.format.beats_per_second)
+            .round() as usize
+    }
+}
+
+impl AudioSource for MidiClip {
+    fn get_sample(&self, frame: u32, _channel: u32, beats_per_second: f64) -> Option<f32> {
+        let sample_rate = self.format.sample_rate as f64;
+        let seconds = frame as f64 / sample_rate;
+        let beat = seconds * beats_per_second;
+
+        if beat >= self.len_beats() as f64 {
+            return None;
+        }
+
+        let mut sample = 0.0;
+
+        for note in self.notes.iter() {
+            if beat < note.start || beat >= note.end() {
+                continue;
+            }
+
+            let since_start = (beat - note.start) / beats_per_second;
+            let until_end = (note.end() - beat) / beats_per_second;
+            let envelope = (since_start.min(until_end) / NOTE_FADE_SECONDS).min(1.0) as f32;
+
+            let phase = (since_start * note.freq()).fract() as f32;
+
+            sample += self.waveform.sample(phase) * envelope * NOTE_AMPLITUDE;
+        }
+
+        Some(sample)
+    }
+
+    fn format(&self) -> AudioSourceFormat {
+        self.format.clone()
+    }
+
+    fn widget(&self) -> Box<dyn druid::Widget<(Arc<dyn AudioSource>, crate::AudioBlock)>> {
+        Box::new(
+            Flex::column()
+                .with_child(
+                    RadioGroup::new(vec![
+                        ("Sine", Waveform::Sine),
+                        ("Square", Waveform::Square),
+                        ("Saw", Waveform::Saw),
+                    ])
+                    .lens(MidiClip::waveform)
+                    .align_left(),
+                )
+                .with_flex_child(PianoRoll::new(), 1.0)
+                .lens(lens!((MidiClip, crate::AudioBlock), 0))
+                .lens(source_lens::<MidiClip>()),
+        )
+    }
+}
@@ -0,0 +1,111 @@
+use crate::audio::*;
+use druid::{widget::*, *};
+use std::sync::Arc;
+
+/// How many octave rows pink noise is summed from, each changes half as often as the one before.
+const PINK_ROWS: u32 = 12;
+
+#[derive(Clone, Copy, Debug, Data, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum NoiseKind {
+    White,
+    /// Equal power per octave, sounds softer than white noise.
+    Pink,
+}
+
+/// Generated noise, lasting one bar at the tempo it was created with.
+///
+/// Every sample is a hash of the frame, the channel and the seed, so the source has no state and
+/// plays, and renders, the same noise every time.
+#[derive(Clone, Data, Lens)]
+pub struct Noise {
+    format: AudioSourceFormat,
+    pub kind: NoiseKind,
+    /// Peak amplitude, from 0 to 1.
+    pub level: f32,
+    pub seed: u64,
+}
+
+/// Splitmix64, a cheap hash with well mixed bits.
+fn hash(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+
+    x ^ (x >> 31)
+}
+
+/// A random value from -1 to 1 for `index`.
+fn white(seed: u64, index: u64) -> f32 {
+    (hash(seed ^ hash(index)) >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+}
+
+impl Noise {
+    pub fn new(
+        kind: NoiseKind,
+        level: f32,
+        seed: u64,
+        sample_rate: u32,
+        channels: u32,
+        beats_per_second: f64,
+    ) -> Self {
+        Self {
+            format: AudioSourceFormat {
+                sample_rate,
+                len_frames: (4.0 / beats_per_second * sample_rate as f64) as u32,
+                channels,
+                beats_per_second,
+            },
+            kind,
+            level,
+            seed,
+        }
+    }
+}
+
+impl AudioSource for Noise {
+    fn get_sample(&self, frame: u32, channel: u32, _beats_per_second: f64) -> Option<f32> {
+        // every channel gets its own noise
+        let seed = hash(self.seed ^ channel as u64);
+
+        let sample = match self.kind {
+            NoiseKind::White => white(seed, frame as u64),
+            NoiseKind::Pink => {
+                // Voss-McCartney, the rows hold their value for longer and longer stretches
+                let sum: f32 = (0..PINK_ROWS)
+                    .map(|row| white(seed.wrapping_add(row as u64), (frame >> row) as u64))
+                    .sum();
+
+                sum / PINK_ROWS as f32 * 2.0
+            }
+        };
+
+        Some((sample * self.level).clamp(-1.0, 1.0))
+    }
+
+    fn format(&self) -> AudioSourceFormat {
+        self.format.clone()
+    }
+
+    fn widget(&self) -> Box<dyn druid::Widget<(Arc<dyn AudioSource>, crate::AudioBlock)>> {
+        Box::new(
+            Flex::row()
+                .with_child(
+                    RadioGroup::new(vec![("White", NoiseKind::White), ("Pink", NoiseKind::Pink)])
+                        .lens(Noise::kind),
+                )
+                .with_spacer(15.0)
+                .with_child(Label::new("Level"))
+                .with_child(
+                    Slider::new()
+                        .lens(lens::Map::new(
+                            |level: &f32| *level as f64,
+                            |level, val: f64| *level = val as f32,
+                        ))
+                        .lens(Noise::level),
+                )
+                .align_left()
+                .lens(lens!((Noise, crate::AudioBlock), 0))
+                .lens(source_lens::<Noise>()),
+        )
+    }
+}
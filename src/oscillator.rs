@@ -0,0 +1,121 @@
+use crate::audio::*;
+use druid::{widget::*, *};
+use std::{f32::consts::PI, sync::Arc};
+
+pub const MIN_FREQ: f64 = 20.0;
+pub const MAX_FREQ: f64 = 20000.0;
+
+/// Peak amplitude of the generated tone, keeps it from drowning out recorded clips.
+const AMPLITUDE: f32 = 0.3;
+
+#[derive(Clone, Copy, Debug, Data, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Waveform {
+    Sine,
+    Square,
+    Saw,
+}
+
+impl Waveform {
+    /// One period of the wave at full scale, `phase` going from 0 to 1.
+    pub fn sample(self, phase: f32) -> f32 {
+        match self {
+            Waveform::Sine => (phase * 2.0 * PI).sin(),
+            Waveform::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Saw => phase * 2.0 - 1.0,
+        }
+    }
+}
+
+/// A generated tone, lasting one bar at the tempo it was created with.
+#[derive(Clone, Data, Lens)]
+pub struct Oscillator {
+    format: AudioSourceFormat,
+    pub waveform: Waveform,
+    pub freq: f32,
+}
+
+impl Oscillator {
+    pub fn new(
+        waveform: Waveform,
+        freq: f32,
+        sample_rate: u32,
+        channels: u32,
+        beats_per_second: f64,
+    ) -> Self {
+        Self {
+            format: AudioSourceFormat {
+                sample_rate,
+                len_frames: (4.0 / beats_per_second * sample_rate as f64) as u32,
+                channels,
+                beats_per_second,
+            },
+            waveform,
+            freq,
+        }
+    }
+}
+
+impl AudioSource for Oscillator {
+    fn get_sample(&self, frame: u32, _channel: u32, _beats_per_second: f64) -> Option<f32> {
+        // the pitch doesn't follow the tempo, so unlike clips the frame isn't rescaled
+        let phase =
+            (frame as f64 * self.freq as f64 / self.format.sample_rate as f64).fract() as f32;
+
+        Some(self.waveform.sample(phase) * AMPLITUDE)
+    }
+
+    fn format(&self) -> AudioSourceFormat {
+        self.format.clone()
+    }
+
+    fn widget(&self) -> Box<dyn druid::Widget<(Arc<dyn AudioSource>, crate::AudioBlock)>> {
+        Box::new(
+            Flex::row()
+                .with_child(
+                    RadioGroup::new(vec![
+                        ("Sine", Waveform::Sine),
+                        ("Square", Waveform::Square),
+                        ("Saw", Waveform::Saw),
+                    ])
+                    .lens(Oscillator::waveform),
+                )
+                .with_spacer(15.0)
+                .with_child(Label::new("Frequency"))
+                .with_child(
+                    // logarithmic, so the low end isn't squeezed into the first few pixels
+                    Slider::new()
+                        .with_range(MIN_FREQ.log10(), MAX_FREQ.log10())
+                        .lens(lens::Map::new(
+                            |freq: &f32| (*freq as f64).log10(),
+                            |freq, val: f64| *freq = 10.0_f64.powf(val) as f32,
+                        ))
+                        .lens(Oscillator::freq),
+                )
+                .with_child(
+                    Parse::new(crate::controllers::text_box())
+                        .lens(lens::Map::new(
+                            |freq: &f32| Some((*freq as f64 * 10.0).round() / 10.0),
+                            |freq, val: Option<f64>| {
+                                if let Some(val) =
+                                    val.filter(|val| (MIN_FREQ..=MAX_FREQ).contains(val))
+                                {
+                                    *freq = val as f32;
+                                }
+                            },
+                        ))
+                        .fix_width(60.0)
+                        .lens(Oscillator::freq),
+                )
+                .with_child(Label::new("Hz"))
+                .align_left()
+                .lens(lens!((Oscillator, crate::AudioBlock), 0))
+                .lens(source_lens::<Oscillator>()),
+        )
+    }
+}
@@ -0,0 +1,249 @@
+/// Frames per bin of each level in a [`PeakCache`], from finest to coarsest.
+/// Every level must be a multiple of the one before it.
+const LEVELS: [u32; 4] = [64, 256, 1024, 4096];
+
+#[derive(Clone, Copy, Debug)]
+pub struct PeakBin {
+    pub min: f32,
+    pub max: f32,
+    pub mean_square: f32,
+}
+
+impl Default for PeakBin {
+    fn default() -> Self {
+        Self {
+            min: f32::MAX,
+            max: f32::MIN,
+            mean_square: 0.0,
+        }
+    }
+}
+
+impl PeakBin {
+    pub fn peak(&self) -> f32 {
+        self.min.abs().max(self.max.abs())
+    }
+}
+
+pub struct PeakLevel {
+    pub frames_per_bin: u32,
+    /// One list of bins per channel.
+    channels: Vec<Vec<PeakBin>>,
+}
+
+impl PeakLevel {
+    fn bins(&self, start_frame: u32, len_frames: u32, channel: u32) -> &[PeakBin] {
+        let bins = &self.channels[channel as usize];
+        let start = ((start_frame / self.frames_per_bin) as usize).min(bins.len());
+        let end = (((start_frame + len_frames) as f64 / self.frames_per_bin as f64).ceil()
+            as usize)
+            .min(bins.len());
+
+        &bins[start..end.max(start)]
+    }
+
+    pub fn peak(&self, start_frame: u32, len_frames: u32, channel: u32) -> f32 {
+        self.bins(start_frame, len_frames, channel)
+            .iter()
+            .fold(0.0, |peak, bin| peak.max(bin.peak()))
+    }
+
+    pub fn rms(&self, start_frame: u32, len_frames: u32, channel: u32) -> f32 {
+        let bins = self.bins(start_frame, len_frames, channel);
+
+        if bins.is_empty() {
+            0.0
+        } else {
+            (bins.iter().map(|bin| bin.mean_square).sum::<f32>() / bins.len() as f32).sqrt()
+        }
+    }
+}
+
+/// Precomputed min/max/rms of an interleaved buffer at a few resolutions, so waveforms can be
+/// drawn without scanning every sample.
+#[derive(Default)]
+pub struct PeakCache {
+    pub len_frames: u32,
+    levels: Vec<PeakLevel>,
+}
+
+impl PeakCache {
+    pub fn new(samples: &[f32], channels: u32) -> Self {
+        let channel_count = channels.max(1) as usize;
+        let len_frames = (samples.len() / channel_count) as u32;
+
+        let mut levels: Vec<PeakLevel> = Vec::with_capacity(LEVELS.len());
+
+        for frames_per_bin in LEVELS.iter().copied() {
+            let level = match levels.last() {
+                Some(finer) => Self::merge(finer, frames_per_bin),
+                None => Self::scan(samples, channel_count, frames_per_bin),
+            };
+
+            levels.push(level);
+        }
+
+        Self { len_frames, levels }
+    }
+
+    fn scan(samples: &[f32], channels: usize, frames_per_bin: u32) -> PeakLevel {
+        let bin_samples = frames_per_bin as usize * channels;
+
+        let channels = (0..channels)
+            .map(|channel| {
+                samples
+                    .chunks(bin_samples)
+                    .map(|chunk| {
+                        let mut bin = PeakBin::default();
+                        let mut count = 0;
+
+                        for sample in chunk.iter().skip(channel).step_by(channels) {
+                            bin.min = bin.min.min(*sample);
+                            bin.max = bin.max.max(*sample);
+                            bin.mean_square += sample * sample;
+                            count += 1;
+                        }
+
+                        if count > 0 {
+                            bin.mean_square /= count as f32;
+                        }
+
+                        bin
+                    })
+                    .collect()
+            })
+            .collect();
+
+        PeakLevel {
+            frames_per_bin,
+            channels,
+        }
+    }
+
+    fn merge(finer: &PeakLevel, frames_per_bin: u32) -> PeakLevel {
+        let ratio = (frames_per_bin / finer.frames_per_bin) as usize;
+
+        let channels = finer
+            .channels
+            .iter()
+            .map(|bins| {
+                bins.chunks(ratio)
+                    .map(|chunk| {
+                        let mut bin = chunk.iter().fold(PeakBin::default(), |bin, finer| PeakBin {
+                            min: bin.min.min(finer.min),
+                            max: bin.max.max(finer.max),
+                            mean_square: bin.mean_square + finer.mean_square,
+                        });
+
+                        bin.mean_square /= chunk.len() as f32;
+
+                        bin
+                    })
+                    .collect()
+            })
+            .collect();
+
+        PeakLevel {
+            frames_per_bin,
+            channels,
+        }
+    }
+
+    /// Returns the coarsest level that still has at least one bin per window of `len_frames`.
+    pub fn level(&self, len_frames: u32) -> Option<&PeakLevel> {
+        self.levels
+            .iter()
+            .rev()
+            .find(|level| level.frames_per_bin <= len_frames)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A noisy stereo signal that doesn't end on a bin boundary.
+    fn signal(len_frames: usize) -> Vec<f32> {
+        let mut state = 0x2545_f491_u32;
+
+        (0..len_frames * 2)
+            .map(|i| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+
+                let noise = state as f32 / u32::MAX as f32 * 2.0 - 1.0;
+                noise * (i as f32 / 3000.0).sin()
+            })
+            .collect()
+    }
+
+    fn brute_force(samples: &[f32], start: u32, len: u32, channel: u32) -> (f32, f32) {
+        let frames = samples
+            .chunks(2)
+            .skip(start as usize)
+            .take(len as usize)
+            .map(|frame| frame[channel as usize]);
+
+        let peak = frames
+            .clone()
+            .fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        let mean_square = frames.map(|sample| sample * sample).sum::<f32>() / len as f32;
+
+        (peak, mean_square.sqrt())
+    }
+
+    #[test]
+    fn levels_match_a_full_scan() {
+        let samples = signal(20_000);
+        let cache = PeakCache::new(&samples, 2);
+
+        assert_eq!(cache.len_frames, 20_000);
+
+        for frames_per_bin in LEVELS.iter().copied() {
+            let level = cache.level(frames_per_bin).unwrap();
+            assert_eq!(level.frames_per_bin, frames_per_bin);
+
+            for channel in 0..2 {
+                for bins in 1..4 {
+                    let len = frames_per_bin * bins;
+                    let mut start = 0;
+
+                    while start + len <= cache.len_frames {
+                        let (peak, rms) = brute_force(&samples, start, len, channel);
+
+                        assert_eq!(level.peak(start, len, channel), peak);
+                        assert!((level.rms(start, len, channel) - rms).abs() < 1e-4);
+
+                        start += len;
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn partial_last_bin_keeps_its_peak() {
+        let mut samples = vec![0.0; 2 * 100];
+        samples[2 * 99 + 1] = -0.75;
+
+        let cache = PeakCache::new(&samples, 2);
+
+        for frames_per_bin in LEVELS.iter().copied() {
+            let level = cache.level(frames_per_bin).unwrap();
+
+            assert_eq!(level.peak(0, 100, 0), 0.0);
+            assert_eq!(level.peak(0, 100, 1), 0.75);
+        }
+    }
+
+    #[test]
+    fn level_picks_the_coarsest_that_fits() {
+        let cache = PeakCache::new(&signal(100), 2);
+
+        assert!(cache.level(10).is_none());
+        assert_eq!(cache.level(64).unwrap().frames_per_bin, 64);
+        assert_eq!(cache.level(1000).unwrap().frames_per_bin, 256);
+        assert_eq!(cache.level(1 << 20).unwrap().frames_per_bin, 4096);
+    }
+}
@@ -0,0 +1,916 @@
+//! Saving and loading projects, and getting audio in and out as wav files.
+//!
+//! A project is a json file describing the arrangement and blocks, the recorded clips are stored
+//! as wav files in a directory next to it. Referenced files are stored as their paths, unless
+//! they are collected into that directory on save.
+
+use crate::{
+    arrangement::{Arrangement, Block, Track, MAX_SWING},
+    audio::{self, AudioSource, AudioSourceFormat, AudioSourceID},
+    audio_clip::AudioClip,
+    delay::DelaySettings,
+    file_source::FileSource,
+    filter::TrackFilter,
+    history::History,
+    midi,
+    midi_clip::{MidiClip, Note},
+    noise::{Noise, NoiseKind},
+    oscillator::{Oscillator, Waveform},
+    sampler::Sampler,
+    wav, AppState, AudioBlock, AudioBlockID,
+};
+use druid::{Color, FileSpec};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+pub const PROJECT_FILE_TYPE: FileSpec = FileSpec::new("Musix Project", &["musix"]);
+pub const WAV_FILE_TYPE: FileSpec = FileSpec::new("Wav Audio", &["wav"]);
+pub const MIDI_FILE_TYPE: FileSpec = FileSpec::new("Midi File", &["mid", "midi"]);
+
+const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct ProjectFile {
+    version: u32,
+    beats_per_minute: f64,
+    volume: f64,
+    tracks: Vec<Vec<BlockFile>>,
+    /// The blocks of each frozen track from before it was bounced.
+    #[serde(default)]
+    frozen_tracks: Vec<Option<Vec<BlockFile>>>,
+    /// The volume automation of each track, as beats and values.
+    #[serde(default)]
+    volume_automation: Vec<Vec<(f64, f32)>>,
+    #[serde(default)]
+    filters: Vec<Option<TrackFilter>>,
+    #[serde(default)]
+    delay_sends: Vec<f32>,
+    #[serde(default)]
+    delay: DelaySettings,
+    #[serde(default)]
+    swing: f64,
+    tempo_changes: Vec<(usize, f64)>,
+    markers: Vec<(usize, String)>,
+    audio_blocks: Vec<AudioBlockFile>,
+    sources: Vec<SourceFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BlockFile {
+    start: usize,
+    end: usize,
+    audio_block: usize,
+    #[serde(default)]
+    offset: f32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AudioBlockFile {
+    id: usize,
+    source: usize,
+    offset: f32,
+    len_beats: usize,
+    color: (f64, f64, f64, f64),
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    choke: bool,
+    #[serde(default)]
+    time_stretch: bool,
+    #[serde(default)]
+    pitch_semitones: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SourceFile {
+    id: usize,
+    beats_per_second: f64,
+    #[serde(flatten)]
+    kind: SourceKind,
+}
+
+/// The serialization boundary for the different kinds of [`AudioSource`].
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind")]
+enum SourceKind {
+    Clip {
+        file: String,
+    },
+    Oscillator {
+        waveform: Waveform,
+        freq: f32,
+    },
+    Sampler {
+        file: String,
+        velocity: f32,
+    },
+    Midi {
+        waveform: Waveform,
+        len_beats: usize,
+        notes: Vec<Note>,
+    },
+    Noise {
+        /// Not `kind`, that's the tag.
+        noise: NoiseKind,
+        level: f32,
+        seed: u64,
+    },
+    /// Played from the file, absolute or relative to the project.
+    File {
+        path: String,
+    },
+}
+
+/// Returns `path` with `extension`, unless it already has it.
+pub fn with_extension(path: &Path, file_type: &FileSpec) -> PathBuf {
+    let extension = file_type.extensions[0];
+
+    if path.extension() == Some(extension.as_ref()) {
+        path.to_owned()
+    } else {
+        let mut path = path.as_os_str().to_owned();
+        path.push(".");
+        path.push(extension);
+        path.into()
+    }
+}
+
+pub fn has_type(path: &Path, file_type: &FileSpec) -> bool {
+    match path.extension() {
+        Some(extension) => file_type
+            .extensions
+            .iter()
+            .any(|e| extension.eq_ignore_ascii_case(e)),
+        None => false,
+    }
+}
+
+/// The directory the clips of the project at `path` are stored in.
+fn sources_dir(path: &Path) -> PathBuf {
+    path.with_extension("sources")
+}
+
+pub fn save(path: &Path, data: &AppState) -> Result<(), Box<dyn Error>> {
+    let sources = data.audio_engine_handle.download_audio_sources()?;
+    let dir = sources_dir(path);
+    let dir_name = dir
+        .file_name()
+        .ok_or("invalid project path")?
+        .to_string_lossy()
+        .into_owned();
+
+    let mut source_files = Vec::new();
+    let mut saved = HashSet::new();
+
+    for audio_block in data.audio_blocks.values() {
+        if !saved.insert(audio_block.audio_id) {
+            continue;
+        }
+
+        let source = sources
+            .get(&audio_block.audio_id)
+            .ok_or_else(|| format!("audio source {} is missing", audio_block.audio_id.0))?;
+        let beats_per_second = source.format().beats_per_second;
+
+        let kind = if let Some(clip) = audio::downcast_source::<AudioClip>(&**source) {
+            SourceKind::Clip {
+                file: write_clip(&dir, &dir_name, audio_block.audio_id, clip)?,
+            }
+        } else if let Some(oscillator) = audio::downcast_source::<Oscillator>(&**source) {
+            SourceKind::Oscillator {
+                waveform: oscillator.waveform,
+                freq: oscillator.freq,
+            }
+        } else if let Some(sampler) = audio::downcast_source::<Sampler>(&**source) {
+            SourceKind::Sampler {
+                file: write_clip(&dir, &dir_name, audio_block.audio_id, &sampler.clip)?,
+                velocity: sampler.velocity,
+            }
+        } else if let Some(midi_clip) = audio::downcast_source::<MidiClip>(&**source) {
+            SourceKind::Midi {
+                waveform: midi_clip.waveform,
+                len_beats: midi_clip.len_beats(),
+                notes: midi_clip.notes.to_vec(),
+            }
+        } else if let Some(noise) = audio::downcast_source::<Noise>(&**source) {
+            SourceKind::Noise {
+                noise: noise.kind,
+                level: noise.level,
+                seed: noise.seed,
+            }
+        } else if let Some(file_source) = audio::downcast_source::<FileSource>(&**source) {
+            let path = if data.collect_files {
+                collect_file(&dir, &dir_name, audio_block.audio_id, file_source.path())?
+            } else {
+                file_source.path().to_string_lossy().into_owned()
+            };
+
+            SourceKind::File { path }
+        } else {
+            return Err("can't save this kind of audio source".into());
+        };
+
+        source_files.push(SourceFile {
+            id: audio_block.audio_id.0,
+            beats_per_second,
+            kind,
+        });
+    }
+
+    let mut audio_blocks = data
+        .audio_blocks
+        .iter()
+        .map(|(id, audio_block)| AudioBlockFile {
+            id: id.0,
+            source: audio_block.audio_id.0,
+            offset: audio_block.offset,
+            len_beats: audio_block.len_beats,
+            color: audio_block.color.as_rgba(),
+            name: Some(audio_block.name.clone()),
+            choke: audio_block.choke,
+            time_stretch: audio_block.time_stretch,
+            pitch_semitones: audio_block.pitch_semitones,
+        })
+        .collect::<Vec<_>>();
+    audio_blocks.sort_by_key(|audio_block| audio_block.id);
+
+    let project = ProjectFile {
+        version: VERSION,
+        beats_per_minute: data.beats_per_minute,
+        volume: data.volume,
+        tracks: data
+            .arrangement
+            .tracks
+            .iter()
+            .map(|track| block_files(&track.blocks))
+            .collect(),
+        frozen_tracks: data
+            .arrangement
+            .tracks
+            .iter()
+            .map(|track| track.frozen.as_deref().map(block_files))
+            .collect(),
+        volume_automation: data
+            .arrangement
+            .tracks
+            .iter()
+            .map(|track| {
+                track
+                    .volume
+                    .points
+                    .iter()
+                    .map(|point| (point.beat, point.value))
+                    .collect()
+            })
+            .collect(),
+        filters: data
+            .arrangement
+            .tracks
+            .iter()
+            .map(|track| track.filter)
+            .collect(),
+        delay_sends: data
+            .arrangement
+            .tracks
+            .iter()
+            .map(|track| track.delay_send)
+            .collect(),
+        delay: data.delay,
+        swing: data.arrangement.swing,
+        tempo_changes: data
+            .arrangement
+            .tempo_changes
+            .iter()
+            .map(|change| (change.beat, change.beats_per_minute))
+            .collect(),
+        markers: data
+            .arrangement
+            .markers
+            .iter()
+            .map(|marker| (marker.beat, marker.label.clone()))
+            .collect(),
+        audio_blocks,
+        sources: source_files,
+    };
+
+    serde_json::to_writer_pretty(BufWriter::new(File::create(path)?), &project)?;
+
+    Ok(())
+}
+
+/// Replaces the current project with the one at `path`. If anything fails the current project is
+/// left as it was.
+pub fn open(path: &Path, data: &mut AppState, beats_per_second: f64) -> Result<(), Box<dyn Error>> {
+    let project: ProjectFile = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+
+    if project.version > VERSION {
+        return Err("the project was saved by a newer version".into());
+    }
+
+    let (sample_rate, channels) = data
+        .audio_engine_handle
+        .output_format()
+        .ok_or("audio engine isn't running")?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+    // load everything before touching the engine, so a missing file doesn't leave half a project
+    let mut loaded: Vec<(usize, Arc<dyn AudioSource + Send + Sync>)> = Vec::new();
+
+    for source in &project.sources {
+        let loaded_source: Arc<dyn AudioSource + Send + Sync> = match &source.kind {
+            SourceKind::Clip { file } => {
+                let (format, samples) = wav::read(dir.join(file))?;
+
+                Arc::new(
+                    clip_from_wav(format, samples, source.beats_per_second)
+                        .converted(sample_rate, channels),
+                )
+            }
+            SourceKind::Oscillator { waveform, freq } => Arc::new(Oscillator::new(
+                *waveform,
+                *freq,
+                sample_rate,
+                channels,
+                source.beats_per_second,
+            )),
+            SourceKind::Sampler { file, velocity } => {
+                let (format, samples) = wav::read(dir.join(file))?;
+
+                Arc::new(Sampler::new(
+                    clip_from_wav(format, samples, source.beats_per_second)
+                        .converted(sample_rate, channels),
+                    *velocity,
+                ))
+            }
+            SourceKind::Midi {
+                waveform,
+                len_beats,
+                notes,
+            } => Arc::new(MidiClip::new(
+                *waveform,
+                notes.clone(),
+                (*len_beats).max(1),
+                sample_rate,
+                channels,
+                source.beats_per_second,
+            )),
+            SourceKind::Noise { noise, level, seed } => Arc::new(Noise::new(
+                *noise,
+                *level,
+                *seed,
+                sample_rate,
+                channels,
+                source.beats_per_second,
+            )),
+            SourceKind::File { path } => Arc::new(FileSource::open(
+                &dir.join(path),
+                sample_rate,
+                channels,
+                source.beats_per_second,
+            )?),
+        };
+
+        loaded.push((source.id, loaded_source));
+    }
+
+    let mut source_ids = HashMap::new();
+    let mut formats = HashMap::new();
+
+    for (id, source) in loaded {
+        formats.insert(id, source.format());
+        source_ids.insert(id, data.audio_engine_handle.add_audio_source(source)?);
+    }
+
+    let old_sources = data
+        .audio_blocks
+        .values()
+        .map(|audio_block| audio_block.audio_id)
+        .collect::<HashSet<_>>();
+
+    for audio_source_id in old_sources {
+        data.audio_engine_handle
+            .remove_audio_source(audio_source_id);
+    }
+
+    let mut audio_blocks = HashMap::new();
+
+    for audio_block in &project.audio_blocks {
+        let (id, format) = match (
+            source_ids.get(&audio_block.source),
+            formats.get(&audio_block.source),
+        ) {
+            (Some(id), Some(format)) => (*id, format.clone()),
+            _ => continue,
+        };
+
+        let mut new_audio_block = AudioBlock::new(id, format, beats_per_second);
+
+        new_audio_block.offset = audio_block.offset;
+        new_audio_block.len_beats = audio_block.len_beats.max(1);
+        new_audio_block.choke = audio_block.choke;
+        new_audio_block.time_stretch = audio_block.time_stretch;
+        new_audio_block.pitch_semitones = audio_block.pitch_semitones.clamp(-24, 24);
+        let (r, g, b, a) = audio_block.color;
+        new_audio_block.color = Color::rgba(r, g, b, a);
+
+        if let Some(name) = &audio_block.name {
+            new_audio_block.name = name.clone();
+        }
+
+        audio_blocks.insert(AudioBlockID(audio_block.id), new_audio_block);
+    }
+
+    let mut arrangement = Arrangement::new();
+
+    arrangement.tracks = Arc::new(
+        project
+            .tracks
+            .iter()
+            .enumerate()
+            .map(|(index, blocks)| {
+                let mut track = Track::new();
+
+                track.blocks = blocks_from_files(blocks, &audio_blocks);
+                track.frozen = project
+                    .frozen_tracks
+                    .get(index)
+                    .and_then(Option::as_ref)
+                    .map(|blocks| blocks_from_files(blocks, &audio_blocks));
+
+                for (beat, value) in project.volume_automation.get(index).into_iter().flatten() {
+                    track.volume.add_point(*beat, value.clamp(0.0, 1.0));
+                }
+
+                track.filter = project.filters.get(index).copied().flatten();
+                track.delay_send = project
+                    .delay_sends
+                    .get(index)
+                    .map_or(0.0, |send| send.clamp(0.0, 1.0));
+                track.calculate_beats();
+                track
+            })
+            .collect(),
+    );
+
+    for (beat, beats_per_minute) in &project.tempo_changes {
+        arrangement.add_tempo_change(*beat, *beats_per_minute);
+    }
+
+    arrangement.swing = project.swing.clamp(0.0, MAX_SWING);
+
+    for (beat, label) in &project.markers {
+        arrangement.add_marker(*beat, label.clone());
+    }
+
+    let mut shown_audio_blocks = audio_blocks.keys().copied().collect::<Vec<_>>();
+    shown_audio_blocks.sort_by_key(|id| id.0);
+
+    data.next_audio_block_id = AudioBlockID(shown_audio_blocks.last().map_or(0, |id| id.0 + 1));
+    data.history = History::new(&arrangement, &audio_blocks);
+    data.arrangement = arrangement;
+    data.audio_blocks = Arc::new(audio_blocks);
+    data.shown_audio_blocks = Arc::new(shown_audio_blocks);
+    data.selected_audio_block = None;
+    data.selected_block = None;
+    data.selected_blocks = Arc::new(Vec::new());
+    data.selected_track = None;
+    data.set_armed_track(None);
+    data.selected_audio_source_clone = None;
+    data.renamed_marker = None;
+    data.removed_track = None;
+
+    data.beats_per_minute = project.beats_per_minute;
+    data.audio_engine_handle
+        .set_beats_per_second(data.beats_per_minute / 60.0);
+    data.volume = project.volume;
+    data.audio_engine_handle.set_volume(data.volume);
+    data.delay = project.delay;
+    data.audio_engine_handle.set_delay(data.delay);
+
+    Ok(())
+}
+
+fn block_files(blocks: &[Block]) -> Vec<BlockFile> {
+    blocks
+        .iter()
+        .map(|block| BlockFile {
+            start: block.bounds.start,
+            end: block.bounds.end,
+            audio_block: block.audio_block_id.0,
+            offset: block.offset,
+        })
+        .collect()
+}
+
+/// Blocks whose audio block is missing are dropped.
+fn blocks_from_files(
+    blocks: &[BlockFile],
+    audio_blocks: &HashMap<AudioBlockID, AudioBlock>,
+) -> Vec<Block> {
+    let mut blocks = blocks
+        .iter()
+        .filter_map(|block| {
+            let id = AudioBlockID(block.audio_block);
+
+            audio_blocks.get(&id).map(|audio_block| Block {
+                offset: block.offset,
+                ..Block::new(block.start..block.end, id, audio_block.format.clone())
+            })
+        })
+        .collect::<Vec<_>>();
+
+    blocks.sort_by_key(|block| block.bounds.start);
+    blocks
+}
+
+/// Writes the samples of `clip` to the sources directory, returns the file relative to the
+/// project.
+fn write_clip(
+    dir: &Path,
+    dir_name: &str,
+    id: AudioSourceID,
+    clip: &AudioClip,
+) -> Result<String, Box<dyn Error>> {
+    std::fs::create_dir_all(dir)?;
+
+    let file = format!("{}.wav", id.0);
+    let format = clip.format();
+
+    wav::write(
+        dir.join(&file),
+        wav::WavFormat {
+            sample_rate: format.sample_rate,
+            channels: format.channels,
+        },
+        clip.samples(),
+        // clips are kept as they were recorded
+        wav::SampleFormat::Float32,
+    )?;
+
+    Ok(format!("{}/{}", dir_name, file))
+}
+
+/// Copies the file played by a file source to the sources directory, returns the copy relative
+/// to the project.
+fn collect_file(
+    dir: &Path,
+    dir_name: &str,
+    id: AudioSourceID,
+    path: &Path,
+) -> Result<String, Box<dyn Error>> {
+    std::fs::create_dir_all(dir)?;
+
+    // not named like the clips, a clip and a file source never share an id but may share a file
+    let file = format!("{}-file.wav", id.0);
+    let copy = dir.join(&file);
+
+    // saving again over the collected project would copy the file onto itself
+    if std::fs::canonicalize(path).ok() != std::fs::canonicalize(&copy).ok() {
+        std::fs::copy(path, &copy)?;
+    }
+
+    Ok(format!("{}/{}", dir_name, file))
+}
+
+fn clip_from_wav(format: wav::WavFormat, samples: Vec<f32>, beats_per_second: f64) -> AudioClip {
+    let len_frames = samples.len() as u32 / format.channels;
+
+    AudioClip::new(
+        samples,
+        AudioSourceFormat {
+            sample_rate: format.sample_rate,
+            len_frames,
+            channels: format.channels,
+            beats_per_second,
+        },
+    )
+}
+
+/// Loads a wav file as a clip in the output format of the engine.
+fn load_audio_file(
+    path: &Path,
+    sample_rate: u32,
+    channels: u32,
+    beats_per_second: f64,
+) -> Result<AudioClip, Box<dyn Error>> {
+    let (format, samples) = wav::read(path)?;
+    let clip = clip_from_wav(format, samples, beats_per_second).converted(sample_rate, channels);
+
+    if clip.format().len_frames == 0 {
+        return Err("the file is empty".into());
+    }
+
+    Ok(clip)
+}
+
+/// Every wav file directly in `dir`, sorted by name.
+pub fn audio_files_in(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut paths = std::fs::read_dir(dir)?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.is_file() && has_type(path, &WAV_FILE_TYPE))
+        .collect::<Vec<_>>();
+
+    paths.sort();
+    Ok(paths)
+}
+
+/// A file that couldn't be imported, and why.
+pub type ImportError = (PathBuf, Box<dyn Error>);
+
+/// Loads wav files as new clips and adds a block for each, a file that fails doesn't stop the
+/// rest. With `reference_audio` set the files are played from where they are instead of being
+/// loaded. Returns the new blocks and the files that failed.
+pub fn import_audio_files(
+    paths: &[PathBuf],
+    data: &mut AppState,
+    beats_per_second: f64,
+) -> (Vec<AudioBlockID>, Vec<ImportError>) {
+    let mut sources = Vec::new();
+    let mut names = Vec::new();
+    let mut errors = Vec::new();
+
+    let (sample_rate, channels) = match data.audio_engine_handle.output_format() {
+        Some(format) => format,
+        None => {
+            let errors = paths
+                .iter()
+                .map(|path| (path.clone(), "audio engine isn't running".into()))
+                .collect();
+
+            return (Vec::new(), errors);
+        }
+    };
+
+    for path in paths {
+        let source: Result<Arc<dyn AudioSource + Send + Sync>, Box<dyn Error>> =
+            if data.reference_audio {
+                // the path is stored in the project, it has to work from anywhere
+                let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+
+                FileSource::open(&path, sample_rate, channels, beats_per_second)
+                    .map(|source| Arc::new(source) as _)
+                    .map_err(Into::into)
+            } else {
+                load_audio_file(path, sample_rate, channels, beats_per_second)
+                    .map(|clip| Arc::new(clip) as _)
+            };
+
+        let imported = source.and_then(|source| {
+            if source.format().len_frames == 0 {
+                return Err("the file is empty".into());
+            }
+
+            let format = source.format();
+            let id: AudioSourceID = data.audio_engine_handle.add_audio_source(source)?;
+
+            Ok((id, format))
+        });
+
+        match imported {
+            Ok(source) => {
+                sources.push(source);
+                names.push(
+                    path.file_stem()
+                        .map(|stem| stem.to_string_lossy().into_owned()),
+                );
+            }
+            Err(e) => errors.push((path.clone(), e)),
+        }
+    }
+
+    let audio_block_ids = data.add_audio_blocks(sources, beats_per_second);
+
+    for (id, name) in audio_block_ids.iter().zip(names) {
+        if let Some(name) = name {
+            data.set_audio_block_name(*id, name);
+        }
+    }
+
+    (audio_block_ids, errors)
+}
+
+/// Adds a midi clip for every track of the midi file at `path` that has notes, each on a new track
+/// of the arrangement. Returns how many tracks were added.
+///
+/// The notes keep their place in beats, so a file at a different tempo plays at the tempo of the
+/// project.
+pub fn import_midi(
+    path: &Path,
+    data: &mut AppState,
+    beats_per_second: f64,
+) -> Result<usize, Box<dyn Error>> {
+    let (sample_rate, channels) = data
+        .audio_engine_handle
+        .output_format()
+        .ok_or("audio engine isn't running")?;
+
+    let file = midi::read(path)?;
+
+    if (file.beats_per_minute - beats_per_second * 60.0).abs() > 0.01 {
+        log::info!(
+            "{} is at {:.1} bpm, its notes are placed at the tempo of the project",
+            path.display(),
+            file.beats_per_minute
+        );
+    }
+
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let beats_per_bar = data.arrangement.beats.max(1);
+    let mut added = 0;
+
+    for (index, track) in file.tracks.into_iter().enumerate() {
+        if track.notes.is_empty() {
+            continue;
+        }
+
+        let end = track
+            .notes
+            .iter()
+            .map(|note| note.end())
+            .fold(0.0, f64::max);
+
+        // whole bars, so the clip loops in time
+        let len_beats = ((end / beats_per_bar as f64).ceil() as usize).max(1) * beats_per_bar;
+
+        let midi_clip = MidiClip::new(
+            Waveform::Saw,
+            track.notes,
+            len_beats,
+            sample_rate,
+            channels,
+            beats_per_second,
+        );
+        let format = midi_clip.format();
+        let id = data
+            .audio_engine_handle
+            .add_audio_source(Arc::new(midi_clip))?;
+
+        let audio_block_id = data.add_audio_block(id, format.clone(), beats_per_second);
+        let name = match track.name {
+            Some(name) if !name.trim().is_empty() => name,
+            _ => format!("{} {}", stem, index + 1),
+        };
+        data.set_audio_block_name(audio_block_id, name);
+
+        data.arrangement.add_track();
+        let tracks = Arc::make_mut(&mut data.arrangement.tracks);
+        let last = tracks.len() - 1;
+        tracks[last].add_block(Block::new(0..len_beats, audio_block_id, format), 0);
+
+        added += 1;
+    }
+
+    Ok(added)
+}
+
+/// The pitch samplers are exported at, the bass drum of general midi.
+const SAMPLER_PITCH: u8 = 36;
+
+/// Writes the notes of the midi clips and the triggers of the samplers in the arrangement to a
+/// midi file, one midi track per track. Returns how many blocks were left out because notes can't
+/// be made of them.
+pub fn export_midi(
+    path: &Path,
+    data: &AppState,
+    beats_per_second: f64,
+) -> Result<usize, Box<dyn Error>> {
+    let sources = data.audio_engine_handle.download_audio_sources()?;
+    let mut skipped = 0;
+    let mut tracks = Vec::new();
+
+    for (track_index, track) in data.arrangement.tracks.iter().enumerate() {
+        let mut notes = Vec::new();
+
+        for block in &track.blocks {
+            let audio_block = data
+                .audio_blocks
+                .get(&block.audio_block_id)
+                .ok_or_else(|| format!("audio block {} is missing", block.audio_block_id.0))?;
+            let source = sources
+                .get(&audio_block.audio_id)
+                .ok_or_else(|| format!("audio source {} is missing", audio_block.audio_id.0))?;
+
+            // the notes of one play of the source, in beats from where the play starts
+            let played: Vec<Note> =
+                if let Some(midi_clip) = audio::downcast_source::<MidiClip>(&**source) {
+                    midi_clip.notes.to_vec()
+                } else if audio::downcast_source::<Sampler>(&**source).is_some() {
+                    let len = if audio_block.choke {
+                        audio_block.len_beats.min(audio_block.true_len_beats)
+                    } else {
+                        audio_block.true_len_beats
+                    };
+
+                    vec![Note {
+                        pitch: SAMPLER_PITCH,
+                        start: 0.0,
+                        len: len as f64,
+                    }]
+                } else {
+                    skipped += 1;
+                    continue;
+                };
+
+            // the same repeats as the index the engine plays from
+            let cycles = (block.bounds.len() + block.lead()).saturating_sub(1)
+                / audio_block.len_beats.max(1)
+                + 1;
+
+            for cycle in 0..cycles {
+                let cycle_start = (block.bounds.start + cycle * audio_block.len_beats) as f64
+                    + audio_block.offset as f64
+                    + block.offset as f64;
+
+                for note in &played {
+                    let start = cycle_start + note.start;
+                    let end = (cycle_start + note.end()).min(block.bounds.end as f64);
+
+                    if start >= block.bounds.start as f64 && start < end {
+                        notes.push(Note {
+                            pitch: note.pitch,
+                            start,
+                            len: end - start,
+                        });
+                    }
+                }
+            }
+        }
+
+        if !notes.is_empty() {
+            notes.sort_by(|a, b| a.start.total_cmp(&b.start));
+
+            tracks.push(midi::MidiTrack {
+                name: Some(format!("Track {}", track_index + 1)),
+                notes,
+            });
+        }
+    }
+
+    if tracks.is_empty() {
+        return Err("there are no midi clips or samplers in the arrangement".into());
+    }
+
+    let mut tempo_changes = vec![(0.0, beats_per_second * 60.0)];
+    tempo_changes.extend(
+        data.arrangement
+            .tempo_changes
+            .iter()
+            .map(|change| (change.beat as f64, change.beats_per_minute)),
+    );
+
+    midi::write(path, &tempo_changes, &tracks)?;
+
+    Ok(skipped)
+}
+
+/// Renders the whole arrangement to a wav file at the output format of the engine, with the
+/// samples stored as `data.export_format`.
+pub fn export_wav(
+    path: &Path,
+    data: &AppState,
+    beats_per_second: f64,
+) -> Result<(), Box<dyn Error>> {
+    let (sample_rate, channels) = data
+        .audio_engine_handle
+        .output_format()
+        .ok_or("audio engine isn't running")?;
+
+    let sources = data.audio_engine_handle.download_audio_sources()?;
+    let arrangement_index = data.arrangement.compile_index(&data.audio_blocks);
+    let len_seconds = data
+        .arrangement
+        .tempo_map()
+        .time_at_beat(data.arrangement.end_beat() as f64, beats_per_second);
+
+    let samples = audio::render(
+        &arrangement_index,
+        &sources,
+        sample_rate,
+        channels,
+        beats_per_second,
+        len_seconds,
+        data.delay,
+    );
+
+    wav::write(
+        path,
+        wav::WavFormat {
+            sample_rate,
+            channels,
+        },
+        &samples,
+        data.export_format,
+    )?;
+
+    Ok(())
+}
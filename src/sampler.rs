@@ -0,0 +1,94 @@
+use crate::{audio::*, audio_clip::AudioClip, widgets, AudioBlock};
+use druid::{widget::*, *};
+use std::sync::Arc;
+
+/// A clip played from the start on every trigger, for building drum patterns out of one shots.
+///
+/// The triggers come from the block repeating the audio block every `len_beats`, so unlike a
+/// clip the repeat isn't tied to the length of the sample. Whether a trigger cuts off the one
+/// before is the `choke` of the audio block.
+#[derive(Clone, Data, Lens)]
+pub struct Sampler {
+    pub clip: AudioClip,
+    /// Gain of every trigger, from 0 to 1.
+    pub velocity: f32,
+}
+
+impl Sampler {
+    pub fn new(clip: AudioClip, velocity: f32) -> Self {
+        Self { clip, velocity }
+    }
+}
+
+impl AudioSource for Sampler {
+    fn get_sample(&self, frame: u32, channel: u32, beats_per_second: f64) -> Option<f32> {
+        self.clip
+            .get_sample(frame, channel, beats_per_second)
+            .map(|sample| sample * self.velocity)
+    }
+
+    fn get_stretched_sample(
+        &self,
+        frame: u32,
+        channel: u32,
+        beats_per_second: f64,
+        pitch: f64,
+    ) -> Option<f32> {
+        self.clip
+            .get_stretched_sample(frame, channel, beats_per_second, pitch)
+            .map(|sample| sample * self.velocity)
+    }
+
+    fn format(&self) -> AudioSourceFormat {
+        self.clip.format()
+    }
+
+    fn widget(&self) -> Box<dyn Widget<(Arc<dyn AudioSource>, AudioBlock)>> {
+        Box::new(
+            Flex::column()
+                .with_flex_child(
+                    widgets::audio_clip_editor::AudioClipEditor::new().lens(lens::Map::new(
+                        |data: &(Sampler, AudioBlock)| (data.0.clip.clone(), data.1.clone()),
+                        |data, val: (AudioClip, AudioBlock)| data.1 = val.1,
+                    )),
+                    1.0,
+                )
+                .with_child(
+                    Flex::row()
+                        .with_child(Label::new("Velocity"))
+                        .with_child(
+                            Slider::new()
+                                .lens(lens::Map::new(
+                                    |velocity: &f32| *velocity as f64,
+                                    |velocity, val: f64| *velocity = val as f32,
+                                ))
+                                .lens(Sampler::velocity)
+                                .lens(lens!((Sampler, AudioBlock), 0)),
+                        )
+                        .with_spacer(15.0)
+                        .with_child(Label::new(|data: &(Sampler, AudioBlock), _env: &Env| {
+                            format!("Every {} beats", data.1.len_beats)
+                        }))
+                        .with_child(
+                            Stepper::new()
+                                .with_range(1.0, 64.0)
+                                .with_step(1.0)
+                                .lens(lens::Map::new(
+                                    |len_beats: &usize| *len_beats as f64,
+                                    |len_beats, val: f64| *len_beats = val.max(1.0) as usize,
+                                ))
+                                .lens(AudioBlock::len_beats)
+                                .lens(lens!((Sampler, AudioBlock), 1)),
+                        )
+                        .with_spacer(15.0)
+                        .with_child(
+                            Checkbox::new("Choke")
+                                .lens(AudioBlock::choke)
+                                .lens(lens!((Sampler, AudioBlock), 1)),
+                        )
+                        .align_left(),
+                )
+                .lens(source_lens::<Sampler>()),
+        )
+    }
+}
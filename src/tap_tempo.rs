@@ -0,0 +1,43 @@
+use druid::Data;
+use std::time::{Duration, Instant};
+
+/// How many taps are averaged when estimating the tempo.
+const MAX_TAPS: usize = 8;
+/// If no tap happens for this long, the next tap starts a new estimate.
+const TAP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Estimates a tempo from the average interval between the last few taps.
+#[derive(Clone, Data, Default)]
+pub struct TapTempo {
+    #[data(ignore)]
+    taps: Vec<Instant>,
+    pub beats_per_minute: Option<f64>,
+}
+
+impl TapTempo {
+    /// Registers a tap at `now` and returns the new estimate, if there are enough taps to make one.
+    pub fn tap(&mut self, now: Instant) -> Option<f64> {
+        if let Some(last) = self.taps.last() {
+            if now.duration_since(*last) > TAP_TIMEOUT {
+                self.taps.clear();
+            }
+        }
+
+        self.taps.push(now);
+
+        if self.taps.len() > MAX_TAPS {
+            self.taps.remove(0);
+        }
+
+        self.beats_per_minute = if self.taps.len() >= 2 {
+            let span = self.taps[self.taps.len() - 1].duration_since(self.taps[0]);
+            let interval = span.as_secs_f64() / (self.taps.len() - 1) as f64;
+
+            Some(60.0 / interval)
+        } else {
+            None
+        };
+
+        self.beats_per_minute
+    }
+}
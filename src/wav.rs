@@ -0,0 +1,381 @@
+//! Just enough of the wav format to get audio in and out of musix.
+//!
+//! Reading supports integer pcm of 8 to 32 bits and 32 or 64 bit floats, all at once or a few
+//! frames at a time, writing produces 16 or 24 bit integer pcm, dithered, or 32 bit floats.
+
+use druid::Data;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+const FORMAT_PCM: u16 = 1;
+const FORMAT_FLOAT: u16 = 3;
+const FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+#[derive(Clone, Copy, Debug)]
+pub struct WavFormat {
+    pub sample_rate: u32,
+    pub channels: u32,
+}
+
+/// How written samples are stored.
+#[derive(Clone, Copy, Debug, Default, Data, PartialEq, Serialize, Deserialize)]
+pub enum SampleFormat {
+    Int16,
+    #[default]
+    Int24,
+    Float32,
+}
+
+impl SampleFormat {
+    fn tag(self) -> u16 {
+        match self {
+            Self::Int16 | Self::Int24 => FORMAT_PCM,
+            Self::Float32 => FORMAT_FLOAT,
+        }
+    }
+
+    fn bits(self) -> u16 {
+        match self {
+            Self::Int16 => 16,
+            Self::Int24 => 24,
+            Self::Float32 => 32,
+        }
+    }
+}
+
+/// Tpdf dither, noise spanning one step either way added before rounding, so the rounding error
+/// turns into a steady hiss instead of distortion following the signal.
+struct Dither {
+    state: u64,
+}
+
+impl Dither {
+    fn new() -> Self {
+        // a fixed seed, exporting twice gives the same file
+        Self {
+            state: 0x9e37_79b9_7f4a_7c15,
+        }
+    }
+
+    /// Xorshift64, a uniform value from -0.5 to 0.5.
+    fn uniform(&mut self) -> f64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+
+        (self.state >> 11) as f64 / (1u64 << 53) as f64 - 0.5
+    }
+
+    /// Rounds `sample` to an integer of `bits`, with the sum of two uniform values as the
+    /// triangular noise.
+    fn quantize(&mut self, sample: f32, bits: u16) -> i32 {
+        let scale = (1i64 << (bits - 1)) as f64;
+        let dither = self.uniform() + self.uniform();
+
+        (sample as f64 * scale + dither)
+            .round()
+            .clamp(-scale, scale - 1.0) as i32
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+/// How the samples of a file are stored, from its fmt chunk.
+#[derive(Clone, Copy, Debug)]
+struct Encoding {
+    tag: u16,
+    bits: u16,
+}
+
+impl Encoding {
+    /// Checks the encoding is one that can be read.
+    fn new(tag: u16, bits: u16) -> io::Result<Self> {
+        match (tag, bits) {
+            (FORMAT_PCM, 8 | 16 | 24 | 32) | (FORMAT_FLOAT, 32 | 64) => Ok(Self { tag, bits }),
+            _ => Err(invalid("unsupported wav sample format")),
+        }
+    }
+
+    fn bytes(self) -> usize {
+        self.bits as usize / 8
+    }
+
+    fn decode(self, data: &[u8]) -> Vec<f32> {
+        match (self.tag, self.bits) {
+            (FORMAT_PCM, 8) => data.iter().map(|b| (*b as f32 - 128.0) / 128.0).collect(),
+            (FORMAT_PCM, 16) => data
+                .chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0)
+                .collect(),
+            (FORMAT_PCM, 24) => data
+                .chunks_exact(3)
+                .map(|b| i32::from_le_bytes([0, b[0], b[1], b[2]]) as f32 / 2147483648.0)
+                .collect(),
+            (FORMAT_PCM, _) => data
+                .chunks_exact(4)
+                .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2147483648.0)
+                .collect(),
+            (_, 32) => data
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+            _ => data
+                .chunks_exact(8)
+                .map(|b| {
+                    f64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]) as f32
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The format and encoding from the bytes of a fmt chunk.
+fn parse_format(format: &[u8]) -> io::Result<(WavFormat, Encoding)> {
+    let mut tag = u16_at(format, 0);
+    let channels = u16_at(format, 2) as u32;
+    let sample_rate = u32_at(format, 4);
+    let bits = u16_at(format, 14);
+
+    if tag == FORMAT_EXTENSIBLE && format.len() >= 26 {
+        // the first two bytes of the sub format guid are the actual format tag
+        tag = u16_at(format, 24);
+    }
+
+    if channels == 0 || sample_rate == 0 {
+        return Err(invalid("wav file has no channels or sample rate"));
+    }
+
+    Ok((
+        WavFormat {
+            sample_rate,
+            channels,
+        },
+        Encoding::new(tag, bits)?,
+    ))
+}
+
+/// Reads a wav file, returns its format and the interleaved samples.
+pub fn read(path: impl AsRef<Path>) -> io::Result<(WavFormat, Vec<f32>)> {
+    let mut bytes = Vec::new();
+    BufReader::new(File::open(path)?).read_to_end(&mut bytes)?;
+
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err(invalid("not a wav file"));
+    }
+
+    let mut format = None;
+    let mut data = None;
+    let mut place = 12;
+
+    while place + 8 <= bytes.len() {
+        let id = &bytes[place..place + 4];
+        let len = u32_at(&bytes, place + 4) as usize;
+        let start = place + 8;
+        // some writers leave the size of the last chunk unset, clamp it to the file
+        let end = (start + len).min(bytes.len());
+
+        match id {
+            b"fmt " if end - start >= 16 => format = Some(&bytes[start..end]),
+            b"data" => data = Some(&bytes[start..end]),
+            _ => (),
+        }
+
+        // chunks are padded to an even length
+        place = start + len + len % 2;
+    }
+
+    let format = format.ok_or_else(|| invalid("missing fmt chunk"))?;
+    let data = data.ok_or_else(|| invalid("missing data chunk"))?;
+    let (format, encoding) = parse_format(format)?;
+
+    Ok((format, encoding.decode(data)))
+}
+
+/// A wav file read a few frames at a time, instead of all at once like [`read`].
+pub struct WavReader {
+    file: File,
+    format: WavFormat,
+    encoding: Encoding,
+    /// Where in the file the samples start.
+    data_start: u64,
+    len_frames: u32,
+}
+
+impl WavReader {
+    /// Reads the chunks before the samples, the samples are left in the file.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+
+        let mut header = [0; 12];
+        file.read_exact(&mut header)
+            .map_err(|_| invalid("not a wav file"))?;
+
+        if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+            return Err(invalid("not a wav file"));
+        }
+
+        let mut format = None;
+        let mut data = None;
+        let mut place = 12;
+
+        while place + 8 <= file_len {
+            let mut chunk = [0; 8];
+            file.seek(SeekFrom::Start(place))?;
+            file.read_exact(&mut chunk)?;
+
+            let len = u32_at(&chunk, 4) as u64;
+            let start = place + 8;
+            // the same clamp as `read`
+            let end = (start + len).min(file_len);
+
+            match &chunk[0..4] {
+                b"fmt " if end - start >= 16 => {
+                    let mut bytes = vec![0; (end - start) as usize];
+                    file.read_exact(&mut bytes)?;
+                    format = Some(parse_format(&bytes)?);
+                }
+                b"data" => data = Some((start, end - start)),
+                _ => (),
+            }
+
+            place = start + len + len % 2;
+        }
+
+        let (format, encoding) = format.ok_or_else(|| invalid("missing fmt chunk"))?;
+        let (data_start, data_len) = data.ok_or_else(|| invalid("missing data chunk"))?;
+        let frame_bytes = (encoding.bytes() * format.channels as usize) as u64;
+
+        Ok(Self {
+            file,
+            format,
+            encoding,
+            data_start,
+            len_frames: (data_len / frame_bytes) as u32,
+        })
+    }
+
+    pub fn format(&self) -> WavFormat {
+        self.format
+    }
+
+    pub fn len_frames(&self) -> u32 {
+        self.len_frames
+    }
+
+    /// Reads the interleaved samples of up to `frames` frames from `start`, fewer where the file
+    /// ends.
+    pub fn read_frames(&mut self, start: u32, frames: u32) -> io::Result<Vec<f32>> {
+        let frames = frames.min(self.len_frames.saturating_sub(start));
+        let frame_bytes = self.encoding.bytes() * self.format.channels as usize;
+
+        let mut bytes = vec![0; frames as usize * frame_bytes];
+        self.file.seek(SeekFrom::Start(
+            self.data_start + start as u64 * frame_bytes as u64,
+        ))?;
+        self.file.read_exact(&mut bytes)?;
+
+        Ok(self.encoding.decode(&bytes))
+    }
+}
+
+/// Writes interleaved samples as a wav file, integer formats are dithered.
+pub fn write(
+    path: impl AsRef<Path>,
+    format: WavFormat,
+    samples: &[f32],
+    sample_format: SampleFormat,
+) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+
+    let bytes = sample_format.bits() as u32 / 8;
+    let data_len = samples.len() as u32 * bytes;
+    let block_align = format.channels * bytes;
+
+    file.write_all(b"RIFF")?;
+    file.write_all(&(36 + data_len + data_len % 2).to_le_bytes())?;
+    file.write_all(b"WAVE")?;
+
+    file.write_all(b"fmt ")?;
+    file.write_all(&16u32.to_le_bytes())?;
+    file.write_all(&sample_format.tag().to_le_bytes())?;
+    file.write_all(&(format.channels as u16).to_le_bytes())?;
+    file.write_all(&format.sample_rate.to_le_bytes())?;
+    file.write_all(&(format.sample_rate * block_align).to_le_bytes())?;
+    file.write_all(&(block_align as u16).to_le_bytes())?;
+    file.write_all(&sample_format.bits().to_le_bytes())?;
+
+    file.write_all(b"data")?;
+    file.write_all(&data_len.to_le_bytes())?;
+
+    let mut dither = Dither::new();
+
+    for sample in samples {
+        match sample_format {
+            SampleFormat::Int16 => {
+                file.write_all(&(dither.quantize(*sample, 16) as i16).to_le_bytes())?
+            }
+            SampleFormat::Int24 => {
+                file.write_all(&dither.quantize(*sample, 24).to_le_bytes()[..3])?
+            }
+            SampleFormat::Float32 => file.write_all(&sample.to_le_bytes())?,
+        }
+    }
+
+    // the data chunk is padded to an even length, 24 bit mono can end up odd
+    if data_len % 2 == 1 {
+        file.write_all(&[0])?;
+    }
+
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dither_averages_to_the_sample() {
+        let mut dither = Dither::new();
+
+        // values between the steps of an 8 bit file, which rounding alone would lose
+        for &steps in &[0.3, -0.3, 0.5, 2.75, -41.1] {
+            let sample = steps as f32 / 128.0;
+            let count = 100_000;
+            let sum: i64 = (0..count).map(|_| dither.quantize(sample, 8) as i64).sum();
+            let mean = sum as f64 / count as f64;
+
+            assert!(
+                (mean - sample as f64 * 128.0).abs() < 0.01,
+                "{} steps averaged to {}",
+                steps,
+                mean
+            );
+        }
+    }
+
+    #[test]
+    fn dither_stays_in_range() {
+        let mut dither = Dither::new();
+
+        for _ in 0..1000 {
+            assert!((-0.5..0.5).contains(&dither.uniform()));
+            assert_eq!(dither.quantize(1.0, 16), i16::MAX as i32);
+            assert_eq!(dither.quantize(-1.5, 16), i16::MIN as i32);
+        }
+    }
+}
@@ -1,11 +1,66 @@
-use crate::{arrangement::*, commands, settings, theme, AppState};
+use crate::{
+    arrangement::*,
+    commands,
+    filter::{FilterKind, TrackFilter},
+    settings, theme, widgets, AppState, AudioBlockID,
+};
 use druid::{widget::*, *};
 use std::sync::Arc;
 
+/// How far from a tempo marker, in pixels, the marker can be grabbed.
+const TEMPO_MARKER_GRAB_DISTANCE: f64 = 6.0;
+/// How wide the clickable flag of a marker is.
+const MARKER_FLAG_WIDTH: f64 = 60.0;
+/// Room past the last block, so there is somewhere to put the next one.
+pub const CONTENT_PADDING_BEATS: usize = 16;
+/// Blocks narrower than this, in pixels, don't get a name drawn on them.
+const MIN_NAMED_BLOCK_WIDTH: f64 = 30.0;
+/// The scrollbar thumb never gets narrower than this.
+const MIN_SCROLLBAR_THUMB_WIDTH: f64 = 20.0;
+/// The colors offered in the context menu of a block, as hues.
+const BLOCK_MENU_COLORS: &[(&str, f64)] = &[
+    ("Red", 0.0),
+    ("Orange", 45.0),
+    ("Yellow", 90.0),
+    ("Green", 135.0),
+    ("Cyan", 180.0),
+    ("Blue", 240.0),
+    ("Purple", 285.0),
+    ("Pink", 330.0),
+];
+/// The cutoffs offered in the filter menu of a track, in Hz.
+const FILTER_MENU_CUTOFFS: &[f64] = &[100.0, 200.0, 500.0, 1000.0, 2000.0, 5000.0, 10000.0];
+/// The delay sends offered in the menu of a track.
+const SEND_MENU_LEVELS: &[(&str, f32)] = &[
+    ("Off", 0.0),
+    ("25%", 0.25),
+    ("50%", 0.5),
+    ("75%", 0.75),
+    ("100%", 1.0),
+];
+/// Beat lines closer together than this are left out, leaving only the bar lines.
+const MIN_BEAT_LINE_SPACING: f64 = 8.0;
+/// Bar lines closer together than this are thinned out to every second bar, every fourth and so
+/// on.
+const MIN_BAR_LINE_SPACING: f64 = 12.0;
+/// How far the mouse may move between press and release for it to still count as a click.
+const CLICK_THRESHOLD: f64 = 4.0;
+/// How far from an automation point, in pixels, it can be grabbed.
+const AUTOMATION_GRAB_DISTANCE: f64 = 6.0;
+/// Room above and below the automation line, so the points at the ends stay visible.
+const AUTOMATION_MARGIN: f64 = 4.0;
+
 pub struct ArrangementWidget {
     children: Vec<WidgetPod<AppState, TrackWidget>>,
     scroll: Vec2,
     play_line: f64,
+    dragged_tempo_change: Option<usize>,
+    /// Where on the scrollbar thumb it was grabbed, while it's dragged.
+    dragged_scrollbar: Option<f64>,
+    /// The corners of the rubber band, from where the drag started to the mouse.
+    rubber_band: Option<(Point, Point)>,
+    /// The beats in view last sent to the minimap.
+    reported_view: Option<(f64, f64)>,
 }
 
 impl ArrangementWidget {
@@ -14,9 +69,167 @@ impl ArrangementWidget {
             children: Vec::new(),
             scroll: Vec2::new(0.0, 0.0),
             play_line: 0.0,
+            dragged_tempo_change: None,
+            dragged_scrollbar: None,
+            rubber_band: None,
+            reported_view: None,
+        }
+    }
+
+    /// Tells the minimap which beats are in view, if that changed since it was last told.
+    fn report_view(&mut self, ctx: &mut EventCtx, env: &Env) {
+        let beat_size = env.get(settings::ARRANGEMENT_BEAT_SIZE);
+        let view = (self.scroll.x / beat_size, ctx.size().width / beat_size);
+
+        if self.reported_view != Some(view) {
+            self.reported_view = Some(view);
+
+            ctx.submit_command(
+                Command::new(commands::MINIMAP_SET_VIEW, view),
+                Target::Widget(crate::MINIMAP_WIDGET_ID),
+            );
+        }
+    }
+
+    /// The range `scroll.x` can be in, from a beat before the start to the end of the content.
+    fn scroll_range(arrangement: &Arrangement, width: f64, env: &Env) -> (f64, f64) {
+        let beat_size = env.get(settings::ARRANGEMENT_BEAT_SIZE);
+        let content_width = (arrangement.end_beat() + CONTENT_PADDING_BEATS) as f64 * beat_size;
+
+        let min = -beat_size;
+        (min, (content_width - width).max(min))
+    }
+
+    fn clamp_scroll(&mut self, arrangement: &Arrangement, width: f64, env: &Env) {
+        let (min, max) = Self::scroll_range(arrangement, width, env);
+
+        self.scroll.x = self.scroll.x.clamp(min, max);
+    }
+
+    /// The x position and width of the scrollbar thumb.
+    fn scrollbar_thumb(&self, arrangement: &Arrangement, width: f64, env: &Env) -> (f64, f64) {
+        let (min, max) = Self::scroll_range(arrangement, width, env);
+        let range = max - min;
+
+        let thumb_width = (width * width / (range + width))
+            .max(MIN_SCROLLBAR_THUMB_WIDTH)
+            .min(width);
+        let thumb_x = if range > 0.0 {
+            (self.scroll.x - min) / range * (width - thumb_width)
+        } else {
+            0.0
+        };
+
+        (thumb_x, thumb_width)
+    }
+
+    /// Scrolls so the thumb starts at `thumb_x`.
+    fn scroll_to_thumb(&mut self, arrangement: &Arrangement, thumb_x: f64, width: f64, env: &Env) {
+        let (min, max) = Self::scroll_range(arrangement, width, env);
+        let (_, thumb_width) = self.scrollbar_thumb(arrangement, width, env);
+
+        if width > thumb_width {
+            self.scroll.x = min + thumb_x / (width - thumb_width) * (max - min);
+        }
+
+        self.clamp_scroll(arrangement, width, env);
+    }
+
+    fn beat_at(&self, x: f64, env: &Env) -> usize {
+        ((x + self.scroll.x) / env.get(settings::ARRANGEMENT_BEAT_SIZE))
+            .round()
+            .max(0.0) as usize
+    }
+
+    fn tempo_change_at(&self, arrangement: &Arrangement, x: f64, env: &Env) -> Option<usize> {
+        let beat = self.beat_at(x, env);
+        let beat_x = beat as f64 * env.get(settings::ARRANGEMENT_BEAT_SIZE) - self.scroll.x;
+
+        if (beat_x - x).abs() <= TEMPO_MARKER_GRAB_DISTANCE {
+            arrangement.get_tempo_change(beat)
+        } else {
+            None
         }
     }
 
+    fn marker_at(&self, arrangement: &Arrangement, x: f64, env: &Env) -> Option<usize> {
+        let beat_size = env.get(settings::ARRANGEMENT_BEAT_SIZE);
+
+        arrangement.markers.iter().rposition(|marker| {
+            let marker_x = marker.beat as f64 * beat_size - self.scroll.x;
+
+            x >= marker_x - TEMPO_MARKER_GRAB_DISTANCE && x <= marker_x + MARKER_FLAG_WIDTH
+        })
+    }
+
+    fn header_height(env: &Env) -> f64 {
+        env.get(settings::ARRANGEMENT_MARKER_LANE_HEIGHT)
+            + env.get(settings::ARRANGEMENT_RULER_HEIGHT)
+    }
+
+    /// Moves the play line to `beat` and tells the audio engine to play from there.
+    fn seek(&mut self, ctx: &mut EventCtx, data: &AppState, beat: f64, env: &Env) {
+        self.play_line = beat;
+        ctx.submit_command(
+            Command::new(
+                commands::AUDIO_ENGINE_SET_PLAY_TIME,
+                data.arrangement
+                    .tempo_map()
+                    .time_at_beat(beat, env.get(settings::ARRANGEMENT_BEATS_PER_SECOND)),
+            ),
+            Target::Global,
+        );
+        ctx.request_paint();
+    }
+
+    /// Where the play line is within the selected audio block, if one of its blocks is playing.
+    fn selected_block_play_line(&self, data: &AppState) -> Option<f64> {
+        if !data.playing || self.play_line < 0.0 {
+            return None;
+        }
+
+        let id = data.selected_audio_block?;
+        let len_beats = data.audio_blocks.get(&id)?.len_beats.max(1);
+        let beat = self.play_line.floor() as usize;
+
+        let block = data
+            .arrangement
+            .tracks
+            .iter()
+            .flat_map(|track| &track.blocks)
+            .find(|block| block.audio_block_id == id && block.bounds.contains(&beat))?;
+
+        // blocks longer than the audio block repeat it
+        Some((self.play_line - block.bounds.start as f64) % len_beats as f64)
+    }
+
+    /// Every block `rect` touches, as the track index and start beat of the block.
+    fn blocks_in(&self, rect: Rect, arrangement: &Arrangement, env: &Env) -> Vec<(usize, usize)> {
+        let beat_size = env.get(settings::ARRANGEMENT_BEAT_SIZE);
+        let mut blocks = Vec::new();
+
+        for (track_index, (child, track)) in
+            self.children.iter().zip(&*arrangement.tracks).enumerate()
+        {
+            let track_rect = child.layout_rect();
+
+            if track_rect.y1 <= rect.y0 || track_rect.y0 >= rect.y1 {
+                continue;
+            }
+
+            for block in &track.blocks {
+                let start = track_rect.x0 + block.bounds.start as f64 * beat_size;
+                let end = track_rect.x0 + block.bounds.end as f64 * beat_size;
+
+                if start < rect.x1 && end > rect.x0 {
+                    blocks.push((track_index, block.bounds.start));
+                }
+            }
+        }
+
+        blocks
+    }
+
     pub fn update_children(&mut self, arrangement: &Arrangement) -> bool {
         let changed = self.children.len() != arrangement.tracks.len();
 
@@ -32,15 +245,124 @@ impl ArrangementWidget {
 
         changed
     }
-}
 
-impl Widget<AppState> for ArrangementWidget {
-    fn event(&mut self, ctx: &mut EventCtx, event: &Event, data: &mut AppState, env: &Env) {
-        for child in &mut self.children {
-            child.event(ctx, event, data, env);
+    fn handle_event(&mut self, ctx: &mut EventCtx, event: &Event, data: &mut AppState, env: &Env) {
+        let mouse_y = match event {
+            Event::MouseDown(mouse_event)
+            | Event::MouseUp(mouse_event)
+            | Event::MouseMove(mouse_event)
+            | Event::Wheel(mouse_event) => Some(mouse_event.pos.y),
+            _ => None,
+        };
+        let in_header = mouse_y.is_some_and(|y| y < Self::header_height(env));
+        let in_marker_lane =
+            mouse_y.is_some_and(|y| y < env.get(settings::ARRANGEMENT_MARKER_LANE_HEIGHT));
+        let in_ruler = in_header && !in_marker_lane;
+        let in_scrollbar = mouse_y.is_some_and(|y| {
+            y >= ctx.size().height - env.get(settings::ARRANGEMENT_SCROLLBAR_HEIGHT)
+        });
+
+        let width = ctx.size().width;
+
+        match event {
+            Event::MouseDown(mouse_event) if in_scrollbar && mouse_event.button.is_left() => {
+                let (thumb_x, thumb_width) = self.scrollbar_thumb(&data.arrangement, width, env);
+                let x = mouse_event.pos.x;
+
+                // clicking next to the thumb jumps there, grabbing it by the middle
+                let grab = if x >= thumb_x && x <= thumb_x + thumb_width {
+                    x - thumb_x
+                } else {
+                    thumb_width / 2.0
+                };
+
+                self.dragged_scrollbar = Some(grab);
+                self.scroll_to_thumb(&data.arrangement, x - grab, width, env);
+                ctx.set_active(true);
+                ctx.request_layout();
+                return;
+            }
+
+            Event::MouseMove(mouse_event) if self.dragged_scrollbar.is_some() => {
+                let grab = self.dragged_scrollbar.unwrap();
+
+                self.scroll_to_thumb(&data.arrangement, mouse_event.pos.x - grab, width, env);
+                ctx.request_layout();
+                return;
+            }
+
+            Event::MouseUp(_) if self.dragged_scrollbar.is_some() => {
+                self.dragged_scrollbar = None;
+                ctx.set_active(false);
+                return;
+            }
+
+            // dragging with ctrl held picks every block the rubber band touches, on any track
+            Event::MouseDown(mouse_event)
+                if !in_header && mouse_event.button.is_left() && mouse_event.mods.ctrl =>
+            {
+                self.rubber_band = Some((mouse_event.pos, mouse_event.pos));
+                data.selected_block = None;
+                data.selected_blocks = Arc::new(Vec::new());
+                ctx.set_active(true);
+                ctx.request_paint();
+                return;
+            }
+
+            Event::MouseMove(mouse_event) if self.rubber_band.is_some() => {
+                let (start, _) = self.rubber_band.unwrap();
+                let blocks = self.blocks_in(
+                    Rect::from_points(start, mouse_event.pos),
+                    &data.arrangement,
+                    env,
+                );
+
+                if *data.selected_blocks != blocks {
+                    data.selected_blocks = Arc::new(blocks);
+                }
+
+                self.rubber_band = Some((start, mouse_event.pos));
+                ctx.request_paint();
+                return;
+            }
+
+            Event::MouseUp(_) if self.rubber_band.is_some() => {
+                self.rubber_band = None;
+                ctx.set_active(false);
+                ctx.request_paint();
+                return;
+            }
+
+            _ => (),
+        }
+
+        if !in_header && !in_scrollbar && self.dragged_tempo_change.is_none() {
+            for child in &mut self.children {
+                child.event(ctx, event, data, env);
+            }
         }
 
         match event {
+            // scrolling over a tempo marker changes its tempo
+            Event::Wheel(mouse_event)
+                if in_ruler
+                    && self
+                        .tempo_change_at(&data.arrangement, mouse_event.pos.x, env)
+                        .is_some() =>
+            {
+                let index = self
+                    .tempo_change_at(&data.arrangement, mouse_event.pos.x, env)
+                    .unwrap();
+                let change = data.arrangement.tempo_changes[index].clone();
+                let beats_per_minute = (change.beats_per_minute
+                    - mouse_event.wheel_delta.y.signum())
+                .clamp(1.0, 999.0);
+
+                data.arrangement
+                    .add_tempo_change(change.beat, beats_per_minute);
+                data.history.label("Changed Tempo");
+            }
+
             Event::Wheel(mouse_event) => {
                 let scroll_speed = env.get(settings::ARRANGEMENT_SCROLL_SPEED);
 
@@ -55,32 +377,116 @@ impl Widget<AppState> for ArrangementWidget {
                 } else {
                     self.scroll.x += mouse_event.wheel_delta.y * scroll_speed;
 
-                    self.scroll.x = self.scroll.x.max(-env.get(settings::ARRANGEMENT_BEAT_SIZE));
+                    self.clamp_scroll(&data.arrangement, width, env);
                 }
 
                 ctx.request_layout();
             }
 
+            Event::MouseDown(mouse_event) if in_marker_lane && mouse_event.button.is_left() => {
+                if let Some(index) = self.marker_at(&data.arrangement, mouse_event.pos.x, env) {
+                    if mouse_event.mods.shift {
+                        data.arrangement.remove_marker(index);
+                        data.history.label("Removed Marker");
+
+                        if data.renamed_marker == Some(index) {
+                            data.renamed_marker = None;
+                        }
+                    } else if mouse_event.count == 2 {
+                        data.renamed_marker = Some(index);
+                    } else {
+                        let beat = data.arrangement.markers[index].beat as f64;
+                        self.seek(ctx, data, beat, env);
+                    }
+                }
+            }
+
+            Event::Command(cmd) if cmd.is(commands::ARRANGEMENT_SCROLL_TO) => {
+                let beat = cmd.get_unchecked(commands::ARRANGEMENT_SCROLL_TO);
+
+                self.scroll.x = beat * env.get(settings::ARRANGEMENT_BEAT_SIZE);
+                self.clamp_scroll(&data.arrangement, width, env);
+                ctx.request_layout();
+            }
+
+            Event::Command(cmd) if cmd.is(commands::ARRANGEMENT_ADD_MARKER) => {
+                let beat = self.play_line.floor() as usize;
+                let label = format!("Marker {}", data.arrangement.markers.len() + 1);
+
+                data.arrangement.add_marker(beat, label);
+                data.history.label("Added Marker");
+            }
+
+            Event::MouseDown(mouse_event) if in_ruler && mouse_event.button.is_left() => {
+                if let Some(index) = self.tempo_change_at(&data.arrangement, mouse_event.pos.x, env)
+                {
+                    if mouse_event.mods.shift {
+                        data.arrangement.remove_tempo_change(index);
+                        data.history.label("Removed Tempo Change");
+                    } else {
+                        self.dragged_tempo_change = Some(index);
+                        data.history.begin_group();
+                    }
+                } else if mouse_event.count == 2 {
+                    // double clicking the ruler adds a tempo change, starting at the tempo
+                    // already playing at that beat
+                    let beat = self.beat_at(mouse_event.pos.x, env);
+                    let beats_per_second = data
+                        .arrangement
+                        .tempo_map()
+                        .beats_per_second(beat, env.get(settings::ARRANGEMENT_BEATS_PER_SECOND));
+
+                    data.arrangement
+                        .add_tempo_change(beat, beats_per_second * 60.0);
+                    data.history.label("Added Tempo Change");
+                }
+            }
+
+            Event::MouseMove(mouse_event) if self.dragged_tempo_change.is_some() => {
+                let index = self.dragged_tempo_change.unwrap();
+                let beat = self.beat_at(mouse_event.pos.x, env);
+
+                if data.arrangement.move_tempo_change(index, beat) {
+                    data.history.label("Moved Tempo Change");
+                }
+            }
+
+            Event::MouseUp(mouse_event) if mouse_event.button.is_left() => {
+                self.dragged_tempo_change = None;
+                data.history
+                    .commit_group(&data.arrangement, &data.audio_blocks);
+            }
+
+            Event::Command(cmd) if cmd.is(commands::GLOBAL_MOUSE_UP) => {
+                self.dragged_tempo_change = None;
+                data.history
+                    .commit_group(&data.arrangement, &data.audio_blocks);
+            }
+
             Event::MouseDown(mouse_event) if mouse_event.button.is_middle() => {
                 let beat_size = env.get(settings::ARRANGEMENT_BEAT_SIZE);
-                let mut time = (mouse_event.pos.x + self.scroll.x) / beat_size;
-                time = time.max(0.0);
+                let mut beat = (mouse_event.pos.x + self.scroll.x) / beat_size;
+                beat = beat.max(0.0);
 
-                self.play_line = time;
-                ctx.submit_command(
-                    Command::new(
-                        commands::AUDIO_ENGINE_SET_PLAY_TIME,
-                        time / env.get(settings::ARRANGEMENT_BEATS_PER_SECOND),
-                    ),
-                    Target::Global,
-                );
-                ctx.request_paint();
+                self.seek(ctx, data, beat, env);
             }
 
             Event::Command(cmd) if cmd.is(commands::ARRANGEMENT_UPDATE_PLAY_LINE) => {
                 let place = cmd.get_unchecked(commands::ARRANGEMENT_UPDATE_PLAY_LINE);
 
-                self.play_line = *place * env.get(settings::ARRANGEMENT_BEATS_PER_SECOND);
+                data.play_time = *place;
+                self.play_line = data
+                    .arrangement
+                    .tempo_map()
+                    .beat_at_time(*place, env.get(settings::ARRANGEMENT_BEATS_PER_SECOND));
+
+                ctx.submit_command(
+                    Command::new(
+                        commands::AUDIO_CLIP_EDITOR_UPDATE_PLAY_LINE,
+                        self.selected_block_play_line(data),
+                    ),
+                    Target::Global,
+                );
 
                 ctx.request_paint();
             }
@@ -88,6 +494,15 @@ impl Widget<AppState> for ArrangementWidget {
             _ => (),
         }
     }
+}
+
+impl Widget<AppState> for ArrangementWidget {
+    fn event(&mut self, ctx: &mut EventCtx, event: &Event, data: &mut AppState, env: &Env) {
+        self.handle_event(ctx, event, data, env);
+
+        // whatever the event was, the minimap follows the scroll
+        self.report_view(ctx, env);
+    }
 
     fn lifecycle(&mut self, ctx: &mut LifeCycleCtx, event: &LifeCycle, data: &AppState, env: &Env) {
         if let LifeCycle::WidgetAdded = event {
@@ -121,7 +536,10 @@ impl Widget<AppState> for ArrangementWidget {
         data: &AppState,
         env: &Env,
     ) -> Size {
-        let mut size = Size::new(bc.max().width, 0.0);
+        // the content may have gotten shorter, or the viewport wider
+        self.clamp_scroll(&data.arrangement, bc.max().width, env);
+
+        let mut size = Size::new(bc.max().width, Self::header_height(env));
 
         for child in &mut self.children {
             let mut max = bc.max();
@@ -151,10 +569,13 @@ impl Widget<AppState> for ArrangementWidget {
             ctx.with_save(|ctx| {
                 ctx.transform(Affine::translate(Vec2::new(-self.scroll.x, 0.0)));
 
-                let mut beat = 0.0;
-                let mut beat_num = 0;
                 let beat_size = env.get(settings::ARRANGEMENT_BEAT_SIZE);
                 let beat_line_width = env.get(theme::ARRANGEMENT_BEAT_LINE_WIDTH);
+                let step = beat_line_step(beat_size, arrangement.beats);
+
+                // from the first line in view, the ones scrolled past aren't drawn
+                let mut beat_num = (self.scroll.x / beat_size).max(0.0) as usize / step * step;
+                let mut beat = beat_num as f64 * beat_size;
 
                 while beat <= ctx.size().width + self.scroll.x {
                     let rect = Rect::from_origin_size(
@@ -162,7 +583,7 @@ impl Widget<AppState> for ArrangementWidget {
                         (beat_line_width, ctx.size().height),
                     );
 
-                    let color = if beat_num % arrangement.beats == 0 {
+                    let color = if beat_num.is_multiple_of(arrangement.beats) {
                         env.get(theme::ARRANGEMENT_TACT_LINE_COLOR)
                     } else {
                         env.get(theme::ARRANGEMENT_BEAT_LINE_COLOR)
@@ -170,8 +591,12 @@ impl Widget<AppState> for ArrangementWidget {
 
                     ctx.fill(rect, &color);
 
-                    beat += beat_size;
-                    beat_num += 1;
+                    if step == 1 {
+                        widgets::draw_subdivisions(ctx, beat, beat_size, ctx.size().height, env);
+                    }
+
+                    beat_num += step;
+                    beat = beat_num as f64 * beat_size;
                 }
             });
 
@@ -179,6 +604,73 @@ impl Widget<AppState> for ArrangementWidget {
                 self.children[i].paint(ctx, data, env);
             }
 
+            // draw the header on top of the tracks, so tracks scrolled beneath it are hidden
+            let marker_lane_height = env.get(settings::ARRANGEMENT_MARKER_LANE_HEIGHT);
+            let ruler_height = env.get(settings::ARRANGEMENT_RULER_HEIGHT);
+            let header =
+                Rect::from_origin_size((0.0, 0.0), (ctx.size().width, Self::header_height(env)));
+            ctx.fill(header, &env.get(theme::ARRANGEMENT_RULER_COLOR));
+
+            ctx.with_save(|ctx| {
+                ctx.transform(Affine::translate(Vec2::new(-self.scroll.x, 0.0)));
+
+                let beat_size = env.get(settings::ARRANGEMENT_BEAT_SIZE);
+
+                // draw markers as flags
+                let color = env.get(theme::ARRANGEMENT_MARKER_COLOR);
+
+                for marker in &*arrangement.markers {
+                    let x = marker.beat as f64 * beat_size;
+
+                    let pole = Rect::from_origin_size((x - 1.0, 0.0), (2.0, marker_lane_height));
+                    ctx.fill(pole, &color);
+
+                    ctx.with_save(|ctx| {
+                        let flag = Rect::from_origin_size(
+                            (x, 0.0),
+                            (MARKER_FLAG_WIDTH, marker_lane_height),
+                        );
+                        ctx.clip(flag);
+
+                        widgets::draw_text(
+                            ctx,
+                            &marker.label,
+                            (x + 4.0, marker_lane_height - 5.0),
+                            11.0,
+                            &color,
+                            env,
+                        );
+                    });
+                }
+
+                // draw tempo changes
+                let color = env.get(theme::ARRANGEMENT_TEMPO_MARKER_COLOR);
+
+                for tempo_change in &*arrangement.tempo_changes {
+                    let x = tempo_change.beat as f64 * beat_size;
+
+                    let rect =
+                        Rect::from_origin_size((x - 1.0, marker_lane_height), (2.0, ruler_height));
+                    ctx.fill(rect, &color);
+
+                    widgets::draw_text(
+                        ctx,
+                        &tempo_change.beats_per_minute.to_string(),
+                        (x + 4.0, marker_lane_height + ruler_height - 6.0),
+                        10.0,
+                        &color,
+                        env,
+                    );
+                }
+            });
+
+            if let Some((start, end)) = self.rubber_band {
+                let rect = Rect::from_points(start, end);
+
+                ctx.fill(rect, &env.get(theme::ARRANGEMENT_SELECTED_BLOCK_COLOR));
+                ctx.stroke(rect, &env.get(theme::ARRANGEMENT_RUBBER_BAND_COLOR), 1.0);
+            }
+
             ctx.with_save(|ctx| {
                 ctx.transform(Affine::translate(Vec2::new(-self.scroll.x, 0.0)));
 
@@ -191,6 +683,23 @@ impl Widget<AppState> for ArrangementWidget {
 
                 ctx.fill(rect, &env.get(theme::ARRANGEMENT_PLAY_LINE_COLOR));
             });
+
+            let size = ctx.size();
+            let scrollbar_height = env.get(settings::ARRANGEMENT_SCROLLBAR_HEIGHT);
+            let (thumb_x, thumb_width) = self.scrollbar_thumb(arrangement, size.width, env);
+
+            let track = Rect::from_origin_size(
+                (0.0, size.height - scrollbar_height),
+                (size.width, scrollbar_height),
+            );
+            ctx.fill(track, &env.get(theme::ARRANGEMENT_RULER_COLOR));
+
+            let thumb = Rect::from_origin_size(
+                (thumb_x, size.height - scrollbar_height),
+                (thumb_width, scrollbar_height),
+            )
+            .to_rounded_rect(scrollbar_height / 2.0);
+            ctx.fill(thumb, &env.get(theme::ARRANGEMENT_SCROLLBAR_COLOR));
         });
     }
 }
@@ -201,9 +710,92 @@ pub enum Selection {
     None(usize),
 }
 
+/// How many beats apart the grid lines are drawn with beats `beat_size` wide. Every beat while
+/// they are far enough apart, then every bar, then every second bar, every fourth and so on.
+fn beat_line_step(beat_size: f64, beats_per_bar: usize) -> usize {
+    if beat_size >= MIN_BEAT_LINE_SPACING {
+        return 1;
+    }
+
+    let mut step = beats_per_bar.max(1);
+
+    while (step as f64) * beat_size < MIN_BAR_LINE_SPACING {
+        step *= 2;
+    }
+
+    step
+}
+
+/// The filter submenu of the track at `track_index`, `current` is checked.
+fn filter_menu(track_index: usize, current: Option<TrackFilter>) -> MenuDesc<AppState> {
+    let mut menu = MenuDesc::new(LocalizedString::new("Filter")).append(
+        MenuItem::new(
+            LocalizedString::new("Off"),
+            Command::new(commands::ARRANGEMENT_SET_TRACK_FILTER, (track_index, None)),
+        )
+        .selected_if(|| current.is_none()),
+    );
+
+    for (name, kind) in &[
+        ("Low Pass", FilterKind::LowPass),
+        ("High Pass", FilterKind::HighPass),
+    ] {
+        let mut cutoffs = MenuDesc::new(LocalizedString::new(name));
+
+        for cutoff in FILTER_MENU_CUTOFFS {
+            let filter = TrackFilter {
+                kind: *kind,
+                cutoff: *cutoff,
+            };
+            let label = if *cutoff >= 1000.0 {
+                format!("{} kHz", cutoff / 1000.0)
+            } else {
+                format!("{} Hz", cutoff)
+            };
+
+            cutoffs = cutoffs.append(
+                MenuItem::new(
+                    LocalizedString::new("filter-cutoff").with_placeholder(label),
+                    Command::new(
+                        commands::ARRANGEMENT_SET_TRACK_FILTER,
+                        (track_index, Some(filter)),
+                    ),
+                )
+                .selected_if(|| current == Some(filter)),
+            );
+        }
+
+        menu = menu.append(cutoffs);
+    }
+
+    menu
+}
+
+/// The delay send submenu of the track at `track_index`, `current` is checked.
+fn send_menu(track_index: usize, current: f32) -> MenuDesc<AppState> {
+    let mut menu = MenuDesc::new(LocalizedString::new("Delay Send"));
+
+    for (name, send) in SEND_MENU_LEVELS {
+        menu = menu.append(
+            MenuItem::new(
+                LocalizedString::new("delay-send").with_placeholder(*name),
+                Command::new(commands::ARRANGEMENT_SET_TRACK_SEND, (track_index, *send)),
+            )
+            .selected_if(|| current == *send),
+        );
+    }
+
+    menu
+}
+
 pub struct TrackWidget {
     idx: usize,
     selection: Option<Selection>,
+    /// Where the mouse was pressed and the audio block under it, until the mouse moves far
+    /// enough to make it a drag.
+    click: Option<(Point, Option<AudioBlockID>)>,
+    /// Index of the automation point being dragged.
+    dragged_point: Option<usize>,
 }
 
 impl TrackWidget {
@@ -211,6 +803,74 @@ impl TrackWidget {
         Self {
             idx,
             selection: None,
+            click: None,
+            dragged_point: None,
+        }
+    }
+
+    fn automation_y(value: f32, height: f64) -> f64 {
+        AUTOMATION_MARGIN + (1.0 - value as f64) * (height - AUTOMATION_MARGIN * 2.0)
+    }
+
+    fn automation_value(y: f64, height: f64) -> f32 {
+        (1.0 - (y - AUTOMATION_MARGIN) / (height - AUTOMATION_MARGIN * 2.0)).clamp(0.0, 1.0) as f32
+    }
+
+    /// Where the mouse puts an automation point, snapped to the beat subdivision.
+    fn automation_point(pos: Point, height: f64, env: &Env) -> (f64, f32) {
+        let beat_size = env.get(settings::ARRANGEMENT_BEAT_SIZE);
+        let beat = widgets::snap_to_subdivision(pos.x / beat_size, env).max(0.0);
+
+        (beat, Self::automation_value(pos.y, height))
+    }
+
+    fn automation_point_at(track: &Track, pos: Point, height: f64, env: &Env) -> Option<usize> {
+        let beat_size = env.get(settings::ARRANGEMENT_BEAT_SIZE);
+
+        track.volume.points.iter().position(|point| {
+            let point_pos = Point::new(
+                point.beat * beat_size,
+                Self::automation_y(point.value, height),
+            );
+
+            point_pos.distance(pos) <= AUTOMATION_GRAB_DISTANCE
+        })
+    }
+
+    fn paint_automation(ctx: &mut PaintCtx, track: &Track, env: &Env) {
+        let size = ctx.size();
+        let beat_size = env.get(settings::ARRANGEMENT_BEAT_SIZE);
+        let color = env.get(theme::ARRANGEMENT_AUTOMATION_COLOR);
+
+        let points = &track.volume.points;
+        let mut path = kurbo::BezPath::new();
+
+        path.move_to((
+            0.0,
+            Self::automation_y(track.volume.value_at(0.0, 1.0), size.height),
+        ));
+
+        for point in points {
+            path.line_to((
+                point.beat * beat_size,
+                Self::automation_y(point.value, size.height),
+            ));
+        }
+
+        path.line_to((
+            size.width,
+            Self::automation_y(points.last().map_or(1.0, |point| point.value), size.height),
+        ));
+
+        ctx.stroke(path, &color, 1.5);
+
+        for point in points {
+            let center = (
+                point.beat * beat_size,
+                Self::automation_y(point.value, size.height),
+            );
+
+            ctx.fill(kurbo::Circle::new(center, 3.0), &color);
         }
     }
 }
@@ -220,28 +880,237 @@ impl Widget<AppState> for TrackWidget {
         let track = &data.arrangement.tracks[self.idx];
 
         match event {
+            Event::MouseDown(mouse_event)
+                if mouse_event.button.is_left() && data.show_automation =>
+            {
+                let height = ctx.size().height;
+                let (beat, value) = Self::automation_point(mouse_event.pos, height, env);
+                let track = &mut Arc::make_mut(&mut data.arrangement.tracks)[self.idx];
+
+                let index = match Self::automation_point_at(track, mouse_event.pos, height, env) {
+                    Some(index) => {
+                        data.history.label("Moved Automation Point");
+                        index
+                    }
+                    None => {
+                        data.history.label("Added Automation Point");
+                        track.volume.add_point(beat, value)
+                    }
+                };
+
+                self.dragged_point = Some(index);
+                data.history.begin_group();
+            }
+
+            Event::MouseDown(mouse_event)
+                if mouse_event.button.is_right()
+                    && data.show_automation
+                    && Self::automation_point_at(
+                        track,
+                        mouse_event.pos,
+                        ctx.size().height,
+                        env,
+                    )
+                    .is_some() =>
+            {
+                let height = ctx.size().height;
+                let track = &mut Arc::make_mut(&mut data.arrangement.tracks)[self.idx];
+
+                if let Some(index) = Self::automation_point_at(track, mouse_event.pos, height, env)
+                {
+                    track.volume.remove_point(index);
+                    data.history.label("Removed Automation Point");
+                }
+            }
+
+            Event::MouseMove(mouse_event) if self.dragged_point.is_some() => {
+                let (beat, value) = Self::automation_point(mouse_event.pos, ctx.size().height, env);
+                let track = &mut Arc::make_mut(&mut data.arrangement.tracks)[self.idx];
+
+                if let Some(index) = self.dragged_point {
+                    track.volume.move_point(index, beat, value);
+                }
+            }
+
             Event::MouseDown(mouse_event) if mouse_event.button.is_left() => {
+                data.selected_track = Some(self.idx);
+
                 if mouse_event.mods.shift {
                     let beat_size = env.get(settings::ARRANGEMENT_BEAT_SIZE);
                     let beat = (mouse_event.pos.x / beat_size).round() as usize;
 
-                    Arc::make_mut(&mut data.arrangement.tracks)[self.idx].remove_block(beat);
+                    if Arc::make_mut(&mut data.arrangement.tracks)[self.idx].remove_block(beat) {
+                        data.history.label("Removed Block");
+                    }
                 } else {
                     let beat_size = env.get(settings::ARRANGEMENT_BEAT_SIZE);
                     let beat = (mouse_event.pos.x / beat_size).round() as usize;
 
                     self.selection = track.get_selection(beat);
+
+                    let clicked_beat = (mouse_event.pos.x / beat_size).floor() as usize;
+                    let clicked_block = track.get_block(clicked_beat);
+                    data.selected_block = clicked_block.map(|block| (self.idx, block.bounds.start));
+                    data.selected_blocks = Arc::new(Vec::new());
+                    self.click = Some((
+                        mouse_event.pos,
+                        clicked_block.map(|block| block.audio_block_id),
+                    ));
+
+                    // every bit of the drag would be its own step otherwise
+                    if self.selection.is_some() {
+                        data.history.begin_group();
+                    }
                 }
             }
 
             Event::Command(cmd) if cmd.is(commands::GLOBAL_MOUSE_UP) => {
+                // a click on a block opens it in the editor
+                if let Some((_, Some(audio_block_id))) = self.click.take() {
+                    ctx.submit_command(
+                        Command::new(commands::SELECT_AUDIO_BLOCK, audio_block_id),
+                        None,
+                    );
+                }
+
                 self.selection = None;
+                self.dragged_point = None;
+                data.history
+                    .commit_group(&data.arrangement, &data.audio_blocks);
             }
 
             Event::MouseDown(mouse_event) if mouse_event.button.is_right() => {
+                let beat_size = env.get(settings::ARRANGEMENT_BEAT_SIZE);
+                let beat = (mouse_event.pos.x / beat_size).floor() as usize;
+
+                let mut menu = MenuDesc::<AppState>::empty();
+
+                // a block gets its own menu, empty space the menu of the track
+                if let Some(block_index) = track.get_block_index(beat) {
+                    let block = &track.blocks[block_index];
+                    let split_beat = (mouse_event.pos.x / beat_size).round() as usize;
+                    let index = (self.idx, block_index);
+                    // removing and coloring a block of the rubber band selection does all of them
+                    let in_selection = data.selected_blocks.len() > 1
+                        && data
+                            .selected_blocks
+                            .contains(&(self.idx, block.bounds.start));
+
+                    let mut color_menu = MenuDesc::new(LocalizedString::new("Color"));
+
+                    for (name, hue) in BLOCK_MENU_COLORS {
+                        let color = Color::hlc(*hue, 70.0, 127.0);
+
+                        color_menu = color_menu.append(MenuItem::new(
+                            LocalizedString::new(name),
+                            if in_selection {
+                                Command::new(commands::ARRANGEMENT_COLOR_SELECTED_BLOCKS, color)
+                            } else {
+                                Command::new(
+                                    commands::SET_AUDIO_BLOCK_COLOR,
+                                    (block.audio_block_id, color),
+                                )
+                            },
+                        ));
+                    }
+
+                    let remove = if in_selection {
+                        MenuItem::new(
+                            LocalizedString::new("Remove Selected Blocks"),
+                            Command::new(commands::ARRANGEMENT_REMOVE_SELECTED_BLOCKS, ()),
+                        )
+                    } else {
+                        MenuItem::new(
+                            LocalizedString::new("Remove Block"),
+                            Command::new(commands::ARRANGEMENT_REMOVE_BLOCK, index),
+                        )
+                    };
+
+                    let menu = menu
+                        .append(remove)
+                        .append(MenuItem::new(
+                            LocalizedString::new("Duplicate"),
+                            Command::new(commands::ARRANGEMENT_DUPLICATE_BLOCK, index),
+                        ))
+                        .append_if(
+                            MenuItem::new(
+                                LocalizedString::new("Split"),
+                                Command::new(
+                                    commands::ARRANGEMENT_SPLIT_BLOCK,
+                                    (self.idx, block_index, split_beat),
+                                ),
+                            ),
+                            || split_beat > block.bounds.start && split_beat < block.bounds.end,
+                        )
+                        .append_separator()
+                        .append(MenuItem::new(
+                            LocalizedString::new("Normalize"),
+                            Command::new(commands::ARRANGEMENT_NORMALIZE_BLOCK, index),
+                        ))
+                        .append(MenuItem::new(
+                            LocalizedString::new("Reverse"),
+                            Command::new(commands::ARRANGEMENT_REVERSE_BLOCK, index),
+                        ))
+                        .append(MenuItem::new(
+                            LocalizedString::new("Quantize to Beat"),
+                            Command::new(
+                                commands::ARRANGEMENT_QUANTIZE_BLOCK,
+                                (self.idx, block_index, 1),
+                            ),
+                        ))
+                        .append(MenuItem::new(
+                            LocalizedString::new("Quantize to Bar"),
+                            Command::new(
+                                commands::ARRANGEMENT_QUANTIZE_BLOCK,
+                                (self.idx, block_index, data.arrangement.beats),
+                            ),
+                        ))
+                        .append_separator()
+                        .append(color_menu);
+
+                    ctx.show_context_menu(ContextMenu::new(menu, mouse_event.window_pos));
+                    return;
+                }
+
+                if track.frozen.is_some() {
+                    menu = menu.append(MenuItem::new(
+                        LocalizedString::new("Unfreeze"),
+                        Command::new(commands::ARRANGEMENT_UNFREEZE_TRACK, self.idx),
+                    ));
+                } else if !track.blocks.is_empty() {
+                    menu = menu.append(MenuItem::new(
+                        LocalizedString::new("Bounce"),
+                        Command::new(commands::ARRANGEMENT_BOUNCE_TRACK, self.idx),
+                    ));
+                }
+
+                let armed = data.armed_track == Some(self.idx);
+
                 let menu = ContextMenu::new(
-                    MenuDesc::<AppState>::empty().append(MenuItem::new(
-                        LocalizedString::new("Remove"),
+                    menu.append(
+                        MenuItem::new(
+                            LocalizedString::new("Arm for Recording"),
+                            Command::new(commands::ARRANGEMENT_ARM_TRACK, self.idx),
+                        )
+                        .selected_if(|| armed),
+                    )
+                    .append(filter_menu(self.idx, track.filter))
+                    .append(send_menu(self.idx, track.delay_send))
+                    .append_separator()
+                    .append_if(
+                        MenuItem::new(
+                            LocalizedString::new("Clear"),
+                            Command::new(commands::ARRANGEMENT_CLEAR_TRACK, self.idx),
+                        ),
+                        || !track.is_empty(),
+                    )
+                    .append(MenuItem::new(
+                        // tracks with blocks ask before they are removed
+                        LocalizedString::new(if track.blocks.is_empty() {
+                            "Remove"
+                        } else {
+                            "Remove..."
+                        }),
                         Command::new(commands::ARRANGEMENT_REMOVE_TRACK, self.idx),
                     )),
                     mouse_event.window_pos,
@@ -250,16 +1119,28 @@ impl Widget<AppState> for TrackWidget {
             }
 
             Event::MouseMove(mouse_event) => {
+                if let Some((pos, _)) = self.click {
+                    if pos.distance(mouse_event.pos) < CLICK_THRESHOLD {
+                        return;
+                    }
+
+                    self.click = None;
+                }
+
                 let beat_size = env.get(settings::ARRANGEMENT_BEAT_SIZE);
                 let beat = (mouse_event.pos.x / beat_size).round() as usize;
 
                 if let Some(selection) = self.selection.clone() {
                     let track = &mut Arc::make_mut(&mut data.arrangement.tracks)[self.idx];
+                    let overlap = env.get(settings::ARRANGEMENT_CROSSFADE_BEATS) as usize;
 
                     match selection {
                         Selection::Some(selected_beat, block_index) => {
-                            if track.move_block_bound(block_index, selected_beat, beat) {
+                            if track.move_block_bound(block_index, selected_beat, beat, overlap) {
                                 self.selection = Some(Selection::Some(beat, block_index));
+                                data.selected_block =
+                                    Some((self.idx, track.blocks[block_index].bounds.start));
+                                data.history.label("Resized Block");
                             }
                         }
 
@@ -269,12 +1150,18 @@ impl Widget<AppState> for TrackWidget {
                                     let audio_block =
                                         data.audio_blocks[&selected_audio_block_id].clone();
 
-                                    if let Some(index) = track.add_block(Block::new(
-                                        beat.min(selected_beat)..beat.max(selected_beat),
-                                        selected_audio_block_id,
-                                        audio_block.format,
-                                    )) {
+                                    if let Some(index) = track.add_block(
+                                        Block::new(
+                                            beat.min(selected_beat)..beat.max(selected_beat),
+                                            selected_audio_block_id,
+                                            audio_block.format,
+                                        ),
+                                        overlap,
+                                    ) {
                                         self.selection = Some(Selection::Some(beat, index));
+                                        data.selected_block =
+                                            Some((self.idx, track.blocks[index].bounds.start));
+                                        data.history.label("Added Block");
                                     }
                                 }
                             }
@@ -318,6 +1205,33 @@ impl Widget<AppState> for TrackWidget {
 
         let track = &data.arrangement.tracks[self.idx];
 
+        if data.armed_track == Some(self.idx) {
+            let rect = ctx.size().to_rect();
+            ctx.fill(rect, &env.get(theme::ARRANGEMENT_ARMED_TRACK_COLOR));
+        }
+
+        let selected = data
+            .selected_block
+            .iter()
+            .chain(data.selected_blocks.iter())
+            .filter(|(idx, _)| *idx == self.idx);
+
+        for &(_, start) in selected {
+            if let Some(block) = track
+                .blocks
+                .iter()
+                .find(|block| block.bounds.start == start)
+            {
+                let rect = Rect::new(
+                    block.bounds.start as f64 * beat_size,
+                    0.0,
+                    block.bounds.end as f64 * beat_size,
+                    ctx.size().height,
+                );
+                ctx.fill(rect, &env.get(theme::ARRANGEMENT_SELECTED_BLOCK_COLOR));
+            }
+        }
+
         while place < ctx.size().width {
             let beat = (place / beat_size).floor() as usize;
             let block = track.get_block(beat);
@@ -377,5 +1291,68 @@ impl Widget<AppState> for TrackWidget {
 
             place += beat_size;
         }
+
+        // draw the names at the start of the blocks, clipped to the block
+        let height = ctx.size().height;
+
+        for block in &track.blocks {
+            let start = block.bounds.start as f64 * beat_size;
+            let end = block.bounds.end as f64 * beat_size;
+
+            if end - start < MIN_NAMED_BLOCK_WIDTH || start > ctx.size().width {
+                continue;
+            }
+
+            let audio_block = &data.audio_blocks[&block.audio_block_id];
+            let loops = audio_block.loops(&block.bounds);
+            // a block playing its audio block more than once says how often
+            let name = if loops > 1.0 {
+                format!("{} \u{d7}{}", audio_block.name, crate::format_loops(loops))
+            } else {
+                audio_block.name.clone()
+            };
+
+            ctx.with_save(|ctx| {
+                ctx.clip(Rect::new(start + 8.0, 0.0, end - 4.0, height));
+
+                widgets::draw_text(
+                    ctx,
+                    &name,
+                    (start + 8.0, height / 2.0 - 4.0),
+                    10.0,
+                    &audio_block.color,
+                    env,
+                );
+            });
+        }
+
+        // draw an X over the crossfade where blocks overlap
+        for block_index in 0..track.blocks.len() {
+            let overlap = track.get_overlap(block_index);
+
+            if overlap.is_empty() {
+                continue;
+            }
+
+            let color = &data.audio_blocks[&track.blocks[block_index].audio_block_id].color;
+            let next_color =
+                &data.audio_blocks[&track.blocks[block_index + 1].audio_block_id].color;
+
+            let start = overlap.start as f64 * beat_size;
+            let end = overlap.end as f64 * beat_size;
+            let top = ctx.size().height / 2.0 - 8.0;
+            let bottom = ctx.size().height / 2.0 + 8.0;
+
+            ctx.stroke(kurbo::Line::new((start, top), (end, bottom)), color, 1.5);
+            ctx.stroke(
+                kurbo::Line::new((start, bottom), (end, top)),
+                next_color,
+                1.5,
+            );
+        }
+
+        if data.show_automation {
+            Self::paint_automation(ctx, track, env);
+        }
     }
 }
@@ -1,10 +1,12 @@
-use crate::{audio::AudioSource, audio_clip::AudioClip, theme, AudioBlock};
+use crate::{audio::AudioSource, audio_clip::AudioClip, commands, theme, widgets, AudioBlock};
 use druid::*;
 
 pub struct AudioClipEditor {
     scroll: f64,
     selected: bool,
     prev_mouse_pos: Point,
+    /// Where playback is in the block, in beats.
+    play_line: Option<f64>,
 }
 
 impl AudioClipEditor {
@@ -13,6 +15,7 @@ impl AudioClipEditor {
             scroll: 0.5,
             selected: false,
             prev_mouse_pos: Point::new(0.0, 0.0),
+            play_line: None,
         }
     }
 }
@@ -33,6 +36,16 @@ impl Widget<(AudioClip, AudioBlock)> for AudioClipEditor {
             event.transform_scroll(Vec2::new(-scroll_offset, 0.0), size.to_rect(), false)
         {
             match event {
+                Event::Command(cmd) if cmd.is(commands::AUDIO_CLIP_EDITOR_UPDATE_PLAY_LINE) => {
+                    let play_line =
+                        *cmd.get_unchecked(commands::AUDIO_CLIP_EDITOR_UPDATE_PLAY_LINE);
+
+                    if play_line != self.play_line {
+                        self.play_line = play_line;
+                        ctx.request_paint();
+                    }
+                }
+
                 Event::Wheel(mouse_event) => {
                     self.scroll += mouse_event.wheel_delta.y * 0.0001;
 
@@ -150,6 +163,7 @@ impl Widget<(AudioClip, AudioBlock)> for AudioClipEditor {
                 };
 
                 ctx.fill(rect, &color);
+                widgets::draw_subdivisions(ctx, beat, beat_size, ctx.size().height, env);
 
                 beat += beat_size;
                 beat_num += 1;
@@ -165,20 +179,31 @@ impl Widget<(AudioClip, AudioBlock)> for AudioClipEditor {
             let bar_frames =
                 (env.get(theme::AUDIO_CLIP_EDITOR_RESOLUTION) * format.sample_rate as f64) as u32;
 
-            for bar in 0..num_bars {
-                let bar_height = audio_clip
-                    .get_sample(bar * bar_frames, 0, format.beats_per_second)
-                    .unwrap_or(0.0) as f64;
+            // stereo clips get one lane per channel, left on top and right on the bottom
+            let lanes = format.channels.clamp(1, 2);
+            let lane_height = size.height / lanes as f64;
 
-                let rect = Rect::from_center_size(
-                    (
-                        bar as f64 * bar_width + bar_width / 2.0 + audio_block.offset as f64 * scale / format.beats_per_second,
-                        size.height / 2.0,
-                    ),
-                    (bar_width + 1.0, bar_height * 300.0),
-                );
+            for lane in 0..lanes {
+                let lane_center = lane_height * lane as f64 + lane_height / 2.0;
+
+                for bar in 0..num_bars {
+                    let peak = audio_clip.window_peak(bar * bar_frames, bar_frames, lane) as f64;
+                    let rms = audio_clip.window_rms(bar * bar_frames, bar_frames, lane) as f64;
+
+                    let center = (
+                        bar as f64 * bar_width
+                            + bar_width / 2.0
+                            + audio_block.offset as f64 * scale / format.beats_per_second,
+                        lane_center,
+                    );
 
-                ctx.fill(rect, &env.get(theme::AUDIO_CLIP_EDITOR_BAR_COLOR));
+                    let rect =
+                        Rect::from_center_size(center, (bar_width + 1.0, peak * lane_height));
+                    ctx.fill(rect, &env.get(theme::AUDIO_CLIP_EDITOR_BAR_COLOR));
+
+                    let rect = Rect::from_center_size(center, (bar_width + 1.0, rms * lane_height));
+                    ctx.fill(rect, &env.get(theme::AUDIO_CLIP_EDITOR_RMS_COLOR));
+                }
             }
 
             let circle = kurbo::Circle::new((0.0, size.height / 2.0), 4.0);
@@ -203,6 +228,16 @@ impl Widget<(AudioClip, AudioBlock)> for AudioClipEditor {
             );
 
             ctx.fill(circle, &audio_block.color);
+
+            if let Some(play_line) = self.play_line {
+                let width = env.get(theme::ARRANGEMENT_PLAY_LINE_WIDTH);
+                let rect = Rect::from_origin_size(
+                    (play_line * beat_size - width / 2.0, 0.0),
+                    (width, size.height),
+                );
+
+                ctx.fill(rect, &env.get(theme::ARRANGEMENT_PLAY_LINE_COLOR));
+            }
         });
     }
 }
@@ -0,0 +1,131 @@
+use druid::*;
+
+/// Keeps its child between a minimum and maximum size, without forcing an exact size like
+/// `SizedBox` does.
+pub struct MaxBox<T> {
+    child: Box<dyn Widget<T>>,
+    max_width: f64,
+    max_height: f64,
+    min_width: f64,
+    min_height: f64,
+}
+
+// not every bound is used yet, they come as a set
+#[allow(dead_code)]
+impl<T> MaxBox<T> {
+    pub fn new(child: impl Widget<T> + 'static) -> Self {
+        Self {
+            child: Box::new(child),
+            max_width: f64::INFINITY,
+            max_height: f64::INFINITY,
+            min_width: 0.0,
+            min_height: 0.0,
+        }
+    }
+
+    pub fn max_width(mut self, max_width: f64) -> Self {
+        self.max_width = max_width;
+        self
+    }
+
+    pub fn max_height(mut self, max_height: f64) -> Self {
+        self.max_height = max_height;
+        self
+    }
+
+    pub fn min_width(mut self, min_width: f64) -> Self {
+        self.min_width = min_width;
+        self
+    }
+
+    pub fn min_height(mut self, min_height: f64) -> Self {
+        self.min_height = min_height;
+        self
+    }
+
+    /// The constraints of the parent narrowed by ours, where they conflict the maximum wins.
+    fn child_constraints(&self, bc: &BoxConstraints) -> BoxConstraints {
+        let max = Size::new(
+            bc.max().width.min(self.max_width).max(bc.min().width),
+            bc.max().height.min(self.max_height).max(bc.min().height),
+        );
+        let min = Size::new(
+            bc.min().width.max(self.min_width).min(max.width),
+            bc.min().height.max(self.min_height).min(max.height),
+        );
+
+        BoxConstraints::new(min, max)
+    }
+}
+
+impl<T: Data> Widget<T> for MaxBox<T> {
+    fn event(&mut self, ctx: &mut EventCtx, event: &Event, data: &mut T, env: &Env) {
+        self.child.event(ctx, event, data, env);
+    }
+
+    fn lifecycle(&mut self, ctx: &mut LifeCycleCtx, event: &LifeCycle, data: &T, env: &Env) {
+        self.child.lifecycle(ctx, event, data, env);
+    }
+
+    fn update(&mut self, ctx: &mut UpdateCtx, old_data: &T, data: &T, env: &Env) {
+        self.child.update(ctx, old_data, data, env);
+    }
+
+    fn layout(&mut self, ctx: &mut LayoutCtx, bc: &BoxConstraints, data: &T, env: &Env) -> Size {
+        let child_bc = self.child_constraints(bc);
+        let size = self.child.layout(ctx, &child_bc, data, env);
+
+        child_bc.constrain(size)
+    }
+
+    fn paint(&mut self, ctx: &mut PaintCtx, data: &T, env: &Env) {
+        self.child.paint(ctx, data, env);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn max_box() -> MaxBox<()> {
+        MaxBox::new(widget::SizedBox::empty())
+    }
+
+    #[test]
+    fn narrows_the_parent_constraints() {
+        let bc = BoxConstraints::new(Size::new(10.0, 10.0), Size::new(500.0, 500.0));
+        let child_bc = max_box()
+            .max_width(200.0)
+            .min_height(50.0)
+            .child_constraints(&bc);
+
+        assert_eq!(child_bc.min(), Size::new(10.0, 50.0));
+        assert_eq!(child_bc.max(), Size::new(200.0, 500.0));
+    }
+
+    #[test]
+    fn maximum_wins_over_minimum() {
+        let bc = BoxConstraints::new(Size::ZERO, Size::new(500.0, 500.0));
+        let child_bc = max_box()
+            .min_width(300.0)
+            .max_width(100.0)
+            .min_height(80.0)
+            .max_height(40.0)
+            .child_constraints(&bc);
+
+        assert_eq!(child_bc.min(), Size::new(100.0, 40.0));
+        assert_eq!(child_bc.max(), Size::new(100.0, 40.0));
+    }
+
+    #[test]
+    fn parent_minimum_wins_over_maximum() {
+        let bc = BoxConstraints::new(Size::new(150.0, 150.0), Size::new(500.0, 500.0));
+        let child_bc = max_box()
+            .max_width(100.0)
+            .max_height(100.0)
+            .child_constraints(&bc);
+
+        assert_eq!(child_bc.min(), Size::new(150.0, 150.0));
+        assert_eq!(child_bc.max(), Size::new(150.0, 150.0));
+    }
+}
@@ -0,0 +1,151 @@
+use crate::{commands, theme, widgets::arrangement::CONTENT_PADDING_BEATS, AppState};
+use druid::*;
+
+/// Tracks get no thinner than this, more of them than fit are left out.
+const MIN_ROW_HEIGHT: f64 = 2.0;
+
+/// The whole arrangement squeezed into the width of the widget, with a box around the beats in
+/// view in the [`ArrangementWidget`](super::arrangement::ArrangementWidget).
+///
+/// The arrangement tells it what's in view with [`commands::MINIMAP_SET_VIEW`], dragging the box
+/// scrolls the arrangement with [`commands::ARRANGEMENT_SCROLL_TO`].
+pub struct MinimapWidget {
+    /// The first beat in view in the arrangement, and how many beats are.
+    view: (f64, f64),
+    /// Where in the box it was grabbed, in beats from its start, while it's dragged.
+    dragged: Option<f64>,
+}
+
+impl MinimapWidget {
+    pub fn new() -> Self {
+        Self {
+            view: (0.0, 0.0),
+            dragged: None,
+        }
+    }
+
+    /// How many beats the width stands for, the content and the room after it, like the
+    /// scrollbar of the arrangement.
+    fn len_beats(&self, data: &AppState) -> f64 {
+        ((data.arrangement.end_beat() + CONTENT_PADDING_BEATS) as f64)
+            .max(self.view.0 + self.view.1)
+    }
+
+    fn scroll_to(&mut self, ctx: &mut EventCtx, beat: f64) {
+        self.view.0 = beat;
+
+        ctx.submit_command(
+            Command::new(commands::ARRANGEMENT_SCROLL_TO, beat),
+            Target::Widget(crate::ARRANGEMENT_WIDGET_ID),
+        );
+        ctx.request_paint();
+    }
+}
+
+impl Widget<AppState> for MinimapWidget {
+    fn event(&mut self, ctx: &mut EventCtx, event: &Event, data: &mut AppState, _env: &Env) {
+        let beat_size = ctx.size().width / self.len_beats(data);
+
+        match event {
+            Event::MouseDown(mouse_event) if mouse_event.button.is_left() => {
+                let beat = mouse_event.pos.x / beat_size;
+
+                // clicking next to the box jumps there, grabbing it by the middle
+                let grab = if beat >= self.view.0 && beat <= self.view.0 + self.view.1 {
+                    beat - self.view.0
+                } else {
+                    self.view.1 / 2.0
+                };
+
+                self.dragged = Some(grab);
+                self.scroll_to(ctx, beat - grab);
+                ctx.set_active(true);
+            }
+
+            Event::MouseMove(mouse_event) if self.dragged.is_some() => {
+                let grab = self.dragged.unwrap();
+
+                self.scroll_to(ctx, mouse_event.pos.x / beat_size - grab);
+            }
+
+            Event::MouseUp(_) if self.dragged.is_some() => {
+                self.dragged = None;
+                ctx.set_active(false);
+            }
+
+            Event::Command(cmd) if cmd.is(commands::MINIMAP_SET_VIEW) => {
+                self.view = *cmd.get_unchecked(commands::MINIMAP_SET_VIEW);
+                ctx.request_paint();
+            }
+
+            _ => (),
+        }
+    }
+
+    fn lifecycle(
+        &mut self,
+        _ctx: &mut LifeCycleCtx,
+        _event: &LifeCycle,
+        _data: &AppState,
+        _env: &Env,
+    ) {
+    }
+
+    fn update(&mut self, ctx: &mut UpdateCtx, old_data: &AppState, data: &AppState, _env: &Env) {
+        if !old_data.arrangement.same(&data.arrangement)
+            || !old_data.audio_blocks.same(&data.audio_blocks)
+        {
+            ctx.request_paint();
+        }
+    }
+
+    fn layout(
+        &mut self,
+        _ctx: &mut LayoutCtx,
+        bc: &BoxConstraints,
+        _data: &AppState,
+        _env: &Env,
+    ) -> Size {
+        bc.max()
+    }
+
+    fn paint(&mut self, ctx: &mut PaintCtx, data: &AppState, env: &Env) {
+        let size = ctx.size();
+        let beat_size = size.width / self.len_beats(data);
+        let tracks = &data.arrangement.tracks;
+        let row_height = (size.height / tracks.len().max(1) as f64).max(MIN_ROW_HEIGHT);
+
+        ctx.fill(size.to_rect(), &env.get(theme::ARRANGEMENT_RULER_COLOR));
+
+        for (track_index, track) in tracks.iter().enumerate() {
+            let y = track_index as f64 * row_height;
+
+            if y >= size.height {
+                break;
+            }
+
+            for block in &track.blocks {
+                let rect = Rect::new(
+                    block.bounds.start as f64 * beat_size,
+                    y,
+                    block.bounds.end as f64 * beat_size,
+                    y + row_height,
+                )
+                .inset((0.0, -0.5));
+
+                ctx.fill(rect, &data.audio_blocks[&block.audio_block_id].color);
+            }
+        }
+
+        let view = Rect::new(
+            self.view.0 * beat_size,
+            0.0,
+            (self.view.0 + self.view.1) * beat_size,
+            size.height,
+        )
+        .inset(-0.5);
+
+        ctx.fill(view, &env.get(theme::ARRANGEMENT_SELECTED_BLOCK_COLOR));
+        ctx.stroke(view, &env.get(theme::ARRANGEMENT_RUBBER_BAND_COLOR), 1.0);
+    }
+}
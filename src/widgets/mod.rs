@@ -1,2 +1,107 @@
 pub mod arrangement;
 pub mod audio_clip_editor;
+mod max_box;
+pub mod minimap;
+pub mod piano_roll;
+mod splitter;
+
+pub use max_box::*;
+pub use splitter::*;
+
+use crate::{arrangement::MAX_SWING, settings, theme};
+use druid::{
+    piet::{FontBuilder, Text, TextLayout, TextLayoutBuilder},
+    Color, Env, PaintCtx, Point, Rect, RenderContext,
+};
+
+/// Subdivision lines closer together than this are left out, they would only be noise.
+const MIN_SUBDIVISION_SPACING: f64 = 5.0;
+
+/// Draws `text` with the baseline starting at `origin`, returns the width of the drawn text.
+pub fn draw_text(
+    ctx: &mut PaintCtx,
+    text: &str,
+    origin: impl Into<Point>,
+    size: f64,
+    color: &Color,
+    env: &Env,
+) -> f64 {
+    let font = ctx
+        .text()
+        .new_font_by_name(env.get(druid::theme::FONT_NAME), size)
+        .build()
+        .unwrap();
+    let layout = ctx
+        .text()
+        .new_text_layout(&font, text, f64::INFINITY)
+        .build()
+        .unwrap();
+
+    ctx.draw_text(&layout, origin, color);
+
+    layout.width()
+}
+
+/// Where subdivision line `index` of a beat is, as a fraction of the beat. Swing pushes every odd
+/// line back, by half a subdivision at a triplet feel.
+pub fn subdivision_line(index: u64, env: &Env) -> f64 {
+    let subdivision = env.get(settings::ARRANGEMENT_BEAT_SUBDIVISION).max(1);
+    let swing = if index % 2 == 1 {
+        env.get(settings::ARRANGEMENT_SWING).clamp(0.0, MAX_SWING) / 2.0
+    } else {
+        0.0
+    };
+
+    (index.min(subdivision) as f64 + swing).min(subdivision as f64) / subdivision as f64
+}
+
+/// The subdivision lines around `beat`, the one at or before it and the one after it.
+pub fn subdivision_lines_around(beat: f64, env: &Env) -> (f64, f64) {
+    let subdivision = env.get(settings::ARRANGEMENT_BEAT_SUBDIVISION).max(1);
+    let whole = beat.floor();
+    let mut before = whole;
+
+    for index in 1..subdivision {
+        let line = whole + subdivision_line(index, env);
+
+        if line > beat {
+            return (before, line);
+        }
+
+        before = line;
+    }
+
+    (before, whole + 1.0)
+}
+
+/// The subdivision line closest to `beat`.
+pub fn snap_to_subdivision(beat: f64, env: &Env) -> f64 {
+    let (before, after) = subdivision_lines_around(beat, env);
+
+    if beat - before < after - beat {
+        before
+    } else {
+        after
+    }
+}
+
+/// Draws the lines dividing the beat starting at `x`, a beat being `beat_size` wide.
+pub fn draw_subdivisions(ctx: &mut PaintCtx, x: f64, beat_size: f64, height: f64, env: &Env) {
+    let subdivision = env.get(settings::ARRANGEMENT_BEAT_SUBDIVISION).max(1);
+    let spacing = beat_size / subdivision as f64;
+
+    if spacing < MIN_SUBDIVISION_SPACING {
+        return;
+    }
+
+    // half as wide as the beat lines, so they read as the lesser lines
+    let width = env.get(theme::ARRANGEMENT_BEAT_LINE_WIDTH) / 2.0;
+    let color = env.get(theme::ARRANGEMENT_SUBDIVISION_LINE_COLOR);
+
+    for i in 1..subdivision {
+        let line_x = x + beat_size * subdivision_line(i, env);
+        let rect = Rect::from_origin_size((line_x - width / 2.0, 0.0), (width, height));
+
+        ctx.fill(rect, &color);
+    }
+}
@@ -0,0 +1,243 @@
+use crate::{
+    midi_clip::{MidiClip, Note},
+    theme, widgets,
+};
+use druid::*;
+use std::sync::Arc;
+
+/// The beats in a bar of the arrangement, set by the source editor from
+/// [`Arrangement::beats`](crate::arrangement::Arrangement::beats).
+pub const BEATS_PER_BAR: Key<u64> = Key::new("piano-roll.beats-per-bar");
+
+/// The range of pitches shown, C2 to C6.
+const LOWEST_PITCH: u8 = 36;
+const HIGHEST_PITCH: u8 = 84;
+
+/// A grid of pitches over beats for drawing the notes of a [`MidiClip`].
+///
+/// Left click draws a note, dragging sets its length, right click removes one. Notes snap to the
+/// beat subdivision.
+pub struct PianoRoll {
+    /// Index of the note whose length is being dragged.
+    drawing: Option<usize>,
+}
+
+impl PianoRoll {
+    pub fn new() -> Self {
+        Self { drawing: None }
+    }
+}
+
+fn rows() -> f64 {
+    (HIGHEST_PITCH - LOWEST_PITCH + 1) as f64
+}
+
+fn is_black_key(pitch: u8) -> bool {
+    matches!(pitch % 12, 1 | 3 | 6 | 8 | 10)
+}
+
+fn pitch_at(y: f64, height: f64) -> u8 {
+    let row = (y / (height / rows())).floor().clamp(0.0, rows() - 1.0);
+
+    HIGHEST_PITCH - row as u8
+}
+
+/// Where the note is drawn, `None` if its pitch is outside the grid, as imported notes can be.
+fn note_rect(note: &Note, beat_size: f64, row_height: f64) -> Option<Rect> {
+    if !(LOWEST_PITCH..=HIGHEST_PITCH).contains(&note.pitch) {
+        return None;
+    }
+
+    let row = (HIGHEST_PITCH - note.pitch) as f64;
+
+    Some(Rect::from_origin_size(
+        (note.start * beat_size, row * row_height),
+        (note.len * beat_size, row_height),
+    ))
+}
+
+/// The index of the shown note at `pos`.
+fn note_at(notes: &[Note], pos: Point, beat_size: f64, row_height: f64) -> Option<usize> {
+    notes.iter().position(|note| {
+        note_rect(note, beat_size, row_height).is_some_and(|rect| rect.contains(pos))
+    })
+}
+
+impl Widget<MidiClip> for PianoRoll {
+    fn event(&mut self, ctx: &mut EventCtx, event: &Event, data: &mut MidiClip, env: &Env) {
+        let size = ctx.size();
+        let len_beats = data.len_beats() as f64;
+        let beat_size = size.width / len_beats;
+        let row_height = size.height / rows();
+
+        match event {
+            Event::MouseDown(mouse_event) => {
+                let under_cursor = note_at(&data.notes, mouse_event.pos, beat_size, row_height);
+
+                if mouse_event.button.is_right() {
+                    if let Some(index) = under_cursor {
+                        Arc::make_mut(&mut data.notes).remove(index);
+                    }
+                } else if mouse_event.button.is_left() {
+                    let index = match under_cursor {
+                        Some(index) => index,
+                        None => {
+                            let (start, end) = widgets::subdivision_lines_around(
+                                mouse_event.pos.x / beat_size,
+                                env,
+                            );
+
+                            if start < 0.0 || start >= len_beats {
+                                return;
+                            }
+
+                            let notes = Arc::make_mut(&mut data.notes);
+                            notes.push(Note {
+                                pitch: pitch_at(mouse_event.pos.y, size.height),
+                                start,
+                                len: end - start,
+                            });
+
+                            notes.len() - 1
+                        }
+                    };
+
+                    self.drawing = Some(index);
+                    ctx.set_active(true);
+                }
+            }
+
+            Event::MouseMove(mouse_event) => {
+                if let Some(index) = self.drawing {
+                    let note = data.notes[index];
+                    let (before, after) =
+                        widgets::subdivision_lines_around(mouse_event.pos.x / beat_size, env);
+                    let end = if before == mouse_event.pos.x / beat_size {
+                        before
+                    } else {
+                        after
+                    };
+                    // at least up to the next line, a note never ends where it starts
+                    let end = end
+                        .min(len_beats)
+                        .max(widgets::subdivision_lines_around(note.start, env).1);
+
+                    if end != note.end() {
+                        Arc::make_mut(&mut data.notes)[index].len = end - note.start;
+                    }
+                }
+            }
+
+            Event::MouseUp(_) if self.drawing.is_some() => {
+                self.drawing = None;
+                ctx.set_active(false);
+            }
+
+            _ => (),
+        }
+    }
+
+    fn lifecycle(
+        &mut self,
+        _ctx: &mut LifeCycleCtx,
+        _event: &LifeCycle,
+        _data: &MidiClip,
+        _env: &Env,
+    ) {
+    }
+
+    fn update(&mut self, ctx: &mut UpdateCtx, old_data: &MidiClip, data: &MidiClip, _env: &Env) {
+        if !old_data.same(data) {
+            ctx.request_paint();
+        }
+    }
+
+    fn layout(
+        &mut self,
+        _ctx: &mut LayoutCtx,
+        bc: &BoxConstraints,
+        _data: &MidiClip,
+        _env: &Env,
+    ) -> Size {
+        bc.max()
+    }
+
+    fn paint(&mut self, ctx: &mut PaintCtx, data: &MidiClip, env: &Env) {
+        let size = ctx.size();
+        let len_beats = data.len_beats();
+        let beat_size = size.width / len_beats as f64;
+        let row_height = size.height / rows();
+
+        for pitch in LOWEST_PITCH..=HIGHEST_PITCH {
+            if is_black_key(pitch) {
+                let row = (HIGHEST_PITCH - pitch) as f64;
+                let rect =
+                    Rect::from_origin_size((0.0, row * row_height), (size.width, row_height));
+
+                ctx.fill(rect, &env.get(theme::PIANO_ROLL_BLACK_KEY_COLOR));
+            }
+        }
+
+        let beat_line_width = env.get(theme::ARRANGEMENT_BEAT_LINE_WIDTH);
+        let beats_per_bar = env.get(BEATS_PER_BAR).max(1) as usize;
+
+        for beat_num in 0..len_beats {
+            let beat = beat_num as f64 * beat_size;
+            let rect = Rect::from_origin_size(
+                (beat - beat_line_width / 2.0, 0.0),
+                (beat_line_width, size.height),
+            );
+
+            let color = if beat_num % beats_per_bar == 0 {
+                env.get(theme::ARRANGEMENT_TACT_LINE_COLOR)
+            } else {
+                env.get(theme::ARRANGEMENT_BEAT_LINE_COLOR)
+            };
+
+            ctx.fill(rect, &color);
+            widgets::draw_subdivisions(ctx, beat, beat_size, size.height, env);
+        }
+
+        for note in data.notes.iter() {
+            let rect = match note_rect(note, beat_size, row_height) {
+                Some(rect) => rect.inset(-1.0),
+                None => continue,
+            };
+
+            ctx.fill(
+                rect.to_rounded_rect(2.0),
+                &env.get(theme::PIANO_ROLL_NOTE_COLOR),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(pitch: u8, start: f64) -> Note {
+        Note {
+            pitch,
+            start,
+            len: 1.0,
+        }
+    }
+
+    #[test]
+    fn notes_outside_the_grid_are_skipped() {
+        // an imported clip can go all the way up to 127
+        let notes = [note(100, 0.0), note(0, 0.0), note(HIGHEST_PITCH, 1.0)];
+
+        assert!(note_rect(&notes[0], 10.0, 10.0).is_none());
+        assert!(note_rect(&notes[1], 10.0, 10.0).is_none());
+        assert_eq!(
+            note_rect(&notes[2], 10.0, 10.0),
+            Some(Rect::new(10.0, 0.0, 20.0, 10.0))
+        );
+
+        // the top row, where a note above the grid would have ended up
+        assert_eq!(note_at(&notes, Point::new(5.0, 5.0), 10.0, 10.0), None);
+        assert_eq!(note_at(&notes, Point::new(15.0, 5.0), 10.0, 10.0), Some(2));
+    }
+}
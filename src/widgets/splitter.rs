@@ -0,0 +1,244 @@
+use crate::theme;
+use druid::*;
+
+/// Thickness of the bar between the children, the part that can be dragged.
+const BAR_SIZE: f64 = 6.0;
+
+type MovedHandler = Box<dyn Fn(&mut EventCtx, f64)>;
+
+/// Two children next to each other, or on top of each other, with a bar between them that can be
+/// dragged to share the room differently.
+///
+/// The split is the share of the room the first child gets, it stays the same when the splitter
+/// is resized. The splitter keeps it itself, [`Splitter::on_moved`] is told when a drag ends so
+/// the split can be stored.
+pub struct Splitter<T> {
+    first: WidgetPod<T, Box<dyn Widget<T>>>,
+    second: WidgetPod<T, Box<dyn Widget<T>>>,
+    /// Stacked top to bottom, instead of left to right.
+    rows: bool,
+    split: f64,
+    /// Neither child gets less than this, as long as there is room for both.
+    min_size: f64,
+    dragged: bool,
+    on_moved: MovedHandler,
+}
+
+impl<T> Splitter<T> {
+    fn new(first: impl Widget<T> + 'static, second: impl Widget<T> + 'static, rows: bool) -> Self {
+        Self {
+            first: WidgetPod::new(Box::new(first)),
+            second: WidgetPod::new(Box::new(second)),
+            rows,
+            split: 0.5,
+            min_size: 0.0,
+            dragged: false,
+            on_moved: Box::new(|_, _| ()),
+        }
+    }
+
+    /// `first` on top of `second`.
+    pub fn rows(first: impl Widget<T> + 'static, second: impl Widget<T> + 'static) -> Self {
+        Self::new(first, second, true)
+    }
+
+    /// `first` left of `second`.
+    pub fn columns(first: impl Widget<T> + 'static, second: impl Widget<T> + 'static) -> Self {
+        Self::new(first, second, false)
+    }
+
+    pub fn split(mut self, split: f64) -> Self {
+        self.split = split.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn min_size(mut self, min_size: f64) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    pub fn on_moved(mut self, on_moved: impl Fn(&mut EventCtx, f64) + 'static) -> Self {
+        self.on_moved = Box::new(on_moved);
+        self
+    }
+
+    /// The length along the split of `size`.
+    fn main_axis(&self, size: Size) -> f64 {
+        if self.rows {
+            size.height
+        } else {
+            size.width
+        }
+    }
+
+    /// The room there is for the two children, without the bar.
+    fn room(&self, size: Size) -> f64 {
+        (self.main_axis(size) - BAR_SIZE).max(0.0)
+    }
+
+    /// How long the first child is in a splitter of `size`.
+    fn first_len(&self, size: Size) -> f64 {
+        let room = self.room(size);
+        let len = self.split * room;
+
+        // when there isn't room for both minimums the split alone decides
+        if room >= self.min_size * 2.0 {
+            len.clamp(self.min_size, room - self.min_size)
+        } else {
+            len
+        }
+    }
+
+    fn bar_rect(&self, size: Size) -> Rect {
+        let start = self.first_len(size);
+
+        if self.rows {
+            Rect::new(0.0, start, size.width, start + BAR_SIZE)
+        } else {
+            Rect::new(start, 0.0, start + BAR_SIZE, size.height)
+        }
+    }
+
+    fn cursor(&self) -> Cursor {
+        if self.rows {
+            Cursor::ResizeUpDown
+        } else {
+            Cursor::ResizeLeftRight
+        }
+    }
+}
+
+impl<T: Data> Widget<T> for Splitter<T> {
+    fn event(&mut self, ctx: &mut EventCtx, event: &Event, data: &mut T, env: &Env) {
+        let size = ctx.size();
+
+        match event {
+            Event::MouseDown(mouse_event)
+                if mouse_event.button.is_left()
+                    && self.bar_rect(size).contains(mouse_event.pos) =>
+            {
+                self.dragged = true;
+                ctx.set_active(true);
+                ctx.set_handled();
+                return;
+            }
+
+            Event::MouseMove(mouse_event) if self.dragged => {
+                let pos = if self.rows {
+                    mouse_event.pos.y
+                } else {
+                    mouse_event.pos.x
+                };
+
+                // grabbed by the middle of the bar
+                let room = self.room(size);
+
+                if room > 0.0 {
+                    self.split = ((pos - BAR_SIZE / 2.0) / room).clamp(0.0, 1.0);
+                }
+
+                ctx.set_cursor(&self.cursor());
+                ctx.request_layout();
+                ctx.request_paint();
+                return;
+            }
+
+            Event::MouseUp(_) if self.dragged => {
+                self.dragged = false;
+                ctx.set_active(false);
+
+                // the clamped split, so a drag past the minimum doesn't store more than is shown
+                let room = self.room(size);
+
+                if room > 0.0 {
+                    self.split = self.first_len(size) / room;
+                }
+
+                (self.on_moved)(ctx, self.split);
+
+                ctx.request_paint();
+                return;
+            }
+
+            Event::MouseMove(mouse_event) if self.bar_rect(size).contains(mouse_event.pos) => {
+                ctx.set_cursor(&self.cursor());
+            }
+
+            _ => (),
+        }
+
+        self.first.event(ctx, event, data, env);
+        self.second.event(ctx, event, data, env);
+    }
+
+    fn lifecycle(&mut self, ctx: &mut LifeCycleCtx, event: &LifeCycle, data: &T, env: &Env) {
+        self.first.lifecycle(ctx, event, data, env);
+        self.second.lifecycle(ctx, event, data, env);
+    }
+
+    fn update(&mut self, ctx: &mut UpdateCtx, _old_data: &T, data: &T, env: &Env) {
+        self.first.update(ctx, data, env);
+        self.second.update(ctx, data, env);
+    }
+
+    fn layout(&mut self, ctx: &mut LayoutCtx, bc: &BoxConstraints, data: &T, env: &Env) -> Size {
+        let size = bc.max();
+        let first_len = self.first_len(size);
+        let second_len = (self.room(size) - first_len).max(0.0);
+
+        let (first_size, second_origin, second_size) = if self.rows {
+            (
+                Size::new(size.width, first_len),
+                Point::new(0.0, first_len + BAR_SIZE),
+                Size::new(size.width, second_len),
+            )
+        } else {
+            (
+                Size::new(first_len, size.height),
+                Point::new(first_len + BAR_SIZE, 0.0),
+                Size::new(second_len, size.height),
+            )
+        };
+
+        self.first
+            .layout(ctx, &BoxConstraints::tight(first_size), data, env);
+        self.first.set_layout_rect(
+            ctx,
+            data,
+            env,
+            Rect::from_origin_size(Point::ORIGIN, first_size),
+        );
+
+        self.second
+            .layout(ctx, &BoxConstraints::tight(second_size), data, env);
+        self.second.set_layout_rect(
+            ctx,
+            data,
+            env,
+            Rect::from_origin_size(second_origin, second_size),
+        );
+
+        size
+    }
+
+    fn paint(&mut self, ctx: &mut PaintCtx, data: &T, env: &Env) {
+        self.first.paint(ctx, data, env);
+        self.second.paint(ctx, data, env);
+
+        // only a line down the middle of the bar, the rest of it is a gap
+        let bar = self.bar_rect(ctx.size());
+        let line = if self.rows {
+            Rect::from_center_size(bar.center(), (bar.width(), 1.0))
+        } else {
+            Rect::from_center_size(bar.center(), (1.0, bar.height()))
+        };
+
+        let color = if self.dragged {
+            env.get(theme::ARRANGEMENT_RUBBER_BAND_COLOR)
+        } else {
+            env.get(theme::BORDER_COLOR)
+        };
+
+        ctx.fill(line, &color);
+    }
+}